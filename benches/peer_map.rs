@@ -0,0 +1,138 @@
+//! Many connections sending at once, through the sharded `PeerMap` and
+//! through the `Mutex<HashMap>` it replaced: once as broadcasts to every
+//! peer, and once as private messages to one peer at a time.
+//!
+//! Run with `cargo bench --bench peer_map`.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Barrier, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use async_tungstenite::tungstenite::Message;
+use ws_async::{
+    queue::{self, Rx},
+    ConnectionId, OverflowPolicy, PeerMap, Tx,
+};
+
+const PEERS: u64 = 256;
+const SENDERS: u64 = 16;
+const BROADCASTS: usize = 200;
+const PRIVATE_MESSAGES: u64 = 20_000;
+
+trait Peers: Send + Sync + 'static {
+    /// Sends `msg` to every peer but `from`.
+    fn broadcast(&self, from: ConnectionId, msg: &Message);
+
+    /// Sends `msg` to `to` alone.
+    fn send_to(&self, to: ConnectionId, msg: &Message);
+}
+
+impl Peers for PeerMap {
+    fn broadcast(&self, from: ConnectionId, msg: &Message) {
+        for peer in self.iter().filter(|peer| *peer.key() != from) {
+            let _ = peer.value().send(msg.clone());
+        }
+    }
+
+    fn send_to(&self, to: ConnectionId, msg: &Message) {
+        if let Some(tx) = self.get(&to) {
+            let _ = tx.send(msg.clone());
+        }
+    }
+}
+
+struct Locked(Mutex<HashMap<ConnectionId, Tx>>);
+
+impl Peers for Locked {
+    fn broadcast(&self, from: ConnectionId, msg: &Message) {
+        let peers = self.0.lock().unwrap();
+        for (_, tx) in peers.iter().filter(|(id, _)| **id != from) {
+            let _ = tx.send(msg.clone());
+        }
+    }
+
+    fn send_to(&self, to: ConnectionId, msg: &Message) {
+        if let Some(tx) = self.0.lock().unwrap().get(&to) {
+            let _ = tx.send(msg.clone());
+        }
+    }
+}
+
+fn queues() -> Vec<(ConnectionId, Tx, Rx)> {
+    (0..PEERS)
+        .map(|id| {
+            let (tx, rx) = queue::channel(64, OverflowPolicy::DropOldest);
+            (ConnectionId(id), tx, rx)
+        })
+        .collect()
+}
+
+/// Time for `SENDERS` threads to each run `send` with their own id.
+fn run<P: Peers>(peers: &Arc<P>, send: fn(&P, ConnectionId, &Message)) -> Duration {
+    let start = Arc::new(Barrier::new(SENDERS as usize + 1));
+    let senders: Vec<_> = (0..SENDERS)
+        .map(|sender| {
+            let peers = peers.clone();
+            let start = start.clone();
+            thread::spawn(move || {
+                let msg = Message::text("hello");
+                start.wait();
+                send(&peers, ConnectionId(sender), &msg);
+            })
+        })
+        .collect();
+    start.wait();
+    let started = Instant::now();
+    for sender in senders {
+        sender.join().unwrap();
+    }
+    started.elapsed()
+}
+
+fn broadcasts<P: Peers>(peers: &P, from: ConnectionId, msg: &Message) {
+    for _ in 0..BROADCASTS {
+        peers.broadcast(from, msg);
+    }
+}
+
+/// Spread over the peers differently by each sender.
+fn private_messages<P: Peers>(peers: &P, from: ConnectionId, msg: &Message) {
+    for i in 0..PRIVATE_MESSAGES {
+        peers.send_to(ConnectionId((from.0 * 7919 + i) % PEERS), msg);
+    }
+}
+
+fn main() {
+    let sharded_queues = queues();
+    let sharded = PeerMap::default();
+    for (id, tx, _) in &sharded_queues {
+        sharded.insert(*id, tx.clone());
+    }
+
+    let locked_queues = queues();
+    let locked = Locked(Mutex::new(
+        locked_queues
+            .iter()
+            .map(|(id, tx, _)| (*id, tx.clone()))
+            .collect(),
+    ));
+
+    let (locked, sharded) = (Arc::new(locked), Arc::new(sharded));
+
+    println!(
+        "{} senders broadcasting {} messages each to {} peers:",
+        SENDERS, BROADCASTS, PEERS
+    );
+    println!("  Mutex<HashMap>: {:?}", run(&locked, broadcasts));
+    println!("  PeerMap:        {:?}", run(&sharded, broadcasts));
+
+    println!(
+        "{} senders sending {} private messages each among {} peers:",
+        SENDERS, PRIVATE_MESSAGES, PEERS
+    );
+    println!("  Mutex<HashMap>: {:?}", run(&locked, private_messages));
+    println!("  PeerMap:        {:?}", run(&sharded, private_messages));
+}
@@ -0,0 +1,262 @@
+//! Server logs streamed to operators over WebSocket.
+//!
+//! A `LogFeed` collects log lines through `LogLayer`, a `tracing` layer, and
+//! hands each one to every admin client subscribed to it.
+//! `LogStreamPlugin` sets the layer up in place of Bevy's `LogPlugin`. With
+//! `ServerConfig.admin_logs` set, clients connecting to `/admin/logs` with a
+//! token its validator accepts receive every line logged from then on, one
+//! text message per line; other handshakes for that path are refused with a
+//! 401. Nothing they send is handled.
+//!
+//! An admin client that falls behind misses lines rather than slowing the
+//! server down. Whatever is logged while serving an admin client is kept out
+//! of the feed, so the stream can't feed itself.
+
+use std::{
+    fmt::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+use async_tungstenite::{tungstenite::protocol::Message, WebSocketStream};
+use bevy::{
+    log::{info_span, LogSettings},
+    prelude::*,
+    utils::tracing::{
+        field::{Field, Visit},
+        subscriber, Event, Instrument, Subscriber,
+    },
+};
+use futures::{future, pin_mut, prelude::*};
+use tracing_subscriber::{
+    layer::Context, prelude::*, registry::LookupSpan, EnvFilter, Layer, Registry,
+};
+
+use crate::{
+    auth::TokenValidator,
+    config::ServerConfig,
+    connection::WsConnection,
+    queue::{self, OverflowPolicy, SendError, Tx},
+    runtime::AsyncStream,
+    ConnectionId,
+};
+
+/// The request path admin clients stream logs from.
+pub const LOGS_PATH: &str = "/admin/logs";
+
+/// Name of the span admin connections are served in. Events inside it
+/// aren't published.
+const ADMIN_SPAN: &str = "admin_logs";
+
+/// Log lines fanned out to the subscribed admin clients. Cloning shares the
+/// feed.
+#[derive(Clone, Default)]
+pub struct LogFeed(Arc<Mutex<Vec<Tx>>>);
+
+impl LogFeed {
+    pub fn new() -> Self {
+        LogFeed::default()
+    }
+
+    /// A `tracing` layer publishing every event to this feed.
+    pub fn layer(&self) -> LogLayer {
+        LogLayer(self.clone())
+    }
+
+    /// Queues `line` for every subscriber, dropping it for those whose
+    /// queue is full.
+    pub fn publish(&self, line: &str) {
+        let mut subscribers = self.0.lock().unwrap();
+        subscribers.retain(|tx| {
+            !matches!(
+                tx.send(Message::text(line)),
+                Err(SendError::Disconnected | SendError::Closing)
+            )
+        });
+    }
+
+    /// Number of admin clients currently receiving the feed.
+    pub fn subscribers(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    fn subscribe(&self, tx: Tx) {
+        self.0.lock().unwrap().push(tx);
+    }
+}
+
+impl fmt::Debug for LogFeed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("LogFeed").field(&self.subscribers()).finish()
+    }
+}
+
+/// Publishes each event to a `LogFeed` as a line like
+/// `INFO ws_async: #1 connected`, fields following the message as
+/// `name=value`.
+#[derive(Debug, Clone)]
+pub struct LogLayer(LogFeed);
+
+impl<S> Layer<S> for LogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let from_admin = ctx
+            .event_scope(event)
+            .is_some_and(|mut scope| scope.any(|span| span.name() == ADMIN_SPAN));
+        if from_admin {
+            return;
+        }
+        let metadata = event.metadata();
+        let mut line = format!("{} {}:", metadata.level(), metadata.target());
+        event.record(&mut LineVisitor(&mut line));
+        self.0.publish(&line);
+    }
+}
+
+struct LineVisitor<'a>(&'a mut String);
+
+impl Visit for LineVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let _ = if field.name() == "message" {
+            write!(self.0, " {:?}", value)
+        } else {
+            write!(self.0, " {}={:?}", field.name(), value)
+        };
+    }
+}
+
+/// Sets up logging like Bevy's `LogPlugin`, which it replaces, with every
+/// event also published to `feed`. Put the same feed in
+/// `ServerConfig.admin_logs` to stream it.
+#[derive(Debug, Clone, Default)]
+pub struct LogStreamPlugin {
+    pub feed: LogFeed,
+}
+
+impl LogStreamPlugin {
+    pub fn new(feed: LogFeed) -> Self {
+        LogStreamPlugin { feed }
+    }
+}
+
+impl Plugin for LogStreamPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        let default_filter = {
+            let settings = app
+                .world_mut()
+                .get_resource_or_insert_with(LogSettings::default);
+            format!("{},{}", settings.level, settings.filter)
+        };
+        let filter = EnvFilter::try_from_default_env()
+            .or_else(|_| EnvFilter::try_new(&default_filter))
+            .unwrap();
+        let subscriber = Registry::default()
+            .with(filter)
+            .with(tracing_subscriber::fmt::Layer::default())
+            .with(self.feed.layer());
+        subscriber::set_global_default(subscriber)
+            .expect("Could not set the global tracing subscriber; is LogPlugin added as well?");
+    }
+}
+
+/// Where admin clients stream logs from, and who may.
+#[derive(Debug, Clone)]
+pub struct AdminLogs {
+    pub feed: LogFeed,
+    /// Checks the token an admin client must present, as `auth` does for
+    /// other clients.
+    pub auth: TokenValidator,
+    /// Lines queued for an admin client before newer ones are dropped, at
+    /// least one.
+    pub buffer: usize,
+}
+
+impl AdminLogs {
+    pub fn new(feed: LogFeed, auth: TokenValidator) -> Self {
+        AdminLogs {
+            feed,
+            auth,
+            buffer: 256,
+        }
+    }
+}
+
+/// Streams the feed to an admin client until it disconnects.
+pub(crate) async fn serve_logs<S: AsyncStream>(
+    ws_stream: WebSocketStream<S>,
+    id: ConnectionId,
+    logs: &AdminLogs,
+    config: &ServerConfig,
+) {
+    let (tx, rx) = queue::channel(logs.buffer.max(1), OverflowPolicy::DropNewest);
+    logs.feed.subscribe(tx.clone());
+    let connection = WsConnection::new(id, ws_stream, tx, rx);
+    let (incoming, writer) = connection.into_parts(
+        config.write_timeout,
+        None,
+        None,
+        config.close_codes.clone(),
+        config.clock.clone(),
+    );
+    // Reading still has to go on for the client's Close to be noticed.
+    let reader = incoming.try_for_each(|_| future::ready(Ok(())));
+    let serve = async move {
+        pin_mut!(reader, writer);
+        future::select(reader, writer).await;
+    };
+    serve.instrument(info_span!(ADMIN_SPAN)).await;
+    info!("Admin log stream {} ended", id);
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{FutureExt, StreamExt};
+
+    use super::*;
+    use crate::queue::Rx;
+
+    fn subscribed(feed: &LogFeed, buffer: usize) -> Rx {
+        let (tx, rx) = queue::channel(buffer, OverflowPolicy::DropNewest);
+        feed.subscribe(tx);
+        rx
+    }
+
+    fn lines(rx: &mut Rx) -> Vec<Message> {
+        std::iter::from_fn(|| rx.next().now_or_never().flatten()).collect()
+    }
+
+    #[test]
+    fn slow_subscribers_miss_lines_and_gone_ones_are_dropped() {
+        let feed = LogFeed::new();
+        let mut slow = subscribed(&feed, 2);
+        let gone = subscribed(&feed, 2);
+        drop(gone);
+        for line in ["one", "two", "three"] {
+            feed.publish(line);
+        }
+        assert_eq!(feed.subscribers(), 1);
+        assert_eq!(
+            lines(&mut slow),
+            [Message::text("one"), Message::text("two")]
+        );
+    }
+
+    #[test]
+    fn events_are_published_except_from_admin_connections() {
+        let feed = LogFeed::new();
+        let mut rx = subscribed(&feed, 8);
+        let subscriber = Registry::default().with(feed.layer());
+        subscriber::with_default(subscriber, || {
+            info!(user = "ops", "hello");
+            info_span!(ADMIN_SPAN).in_scope(|| info!("about the admin client"));
+        });
+        assert_eq!(
+            lines(&mut rx),
+            [Message::text(format!(
+                "INFO {}: hello user=\"ops\"",
+                module_path!()
+            ))]
+        );
+    }
+}
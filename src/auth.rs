@@ -0,0 +1,101 @@
+//! Token authentication during the handshake.
+//!
+//! With `ServerConfig.auth` set, every client must present a token, either
+//! as a `?token=` query parameter or in an `Authorization` header (with or
+//! without a `Bearer ` prefix). Handshakes without a token the validator
+//! accepts are refused with a 401; accepted ones are recorded with the
+//! resulting `UserId` in the `Directory`. With `anonymous_limits` set,
+//! clients without a token are let in under those limits and may send
+//! `/auth <token>` later.
+
+use std::{fmt, sync::Arc};
+
+use async_tungstenite::tungstenite::{handshake::server::Request, http::header};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Who an authenticated connection belongs to. One user may have several
+/// connections open at once.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct UserId(pub String);
+
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Maps a client's token to the user it belongs to, or `None` if the token
+/// isn't valid.
+#[derive(Clone)]
+pub struct TokenValidator(Arc<dyn Fn(&str) -> Option<UserId> + Send + Sync>);
+
+impl TokenValidator {
+    pub fn new(validate: impl Fn(&str) -> Option<UserId> + Send + Sync + 'static) -> Self {
+        TokenValidator(Arc::new(validate))
+    }
+
+    pub fn validate(&self, token: &str) -> Option<UserId> {
+        (self.0)(token)
+    }
+}
+
+impl fmt::Debug for TokenValidator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TokenValidator")
+    }
+}
+
+/// The token a handshake request carries, preferring the query string over
+/// the `Authorization` header.
+pub fn token(request: &Request) -> Option<&str> {
+    let from_query = request.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("token="))
+    });
+    let from_header = || {
+        let value = request
+            .headers()
+            .get(header::AUTHORIZATION)?
+            .to_str()
+            .ok()?;
+        Some(value.strip_prefix("Bearer ").unwrap_or(value).trim())
+    };
+    from_query
+        .or_else(from_header)
+        .filter(|token| !token.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(uri: &str, authorization: Option<&str>) -> Request {
+        let mut request = Request::builder().uri(uri);
+        if let Some(value) = authorization {
+            request = request.header(header::AUTHORIZATION, value);
+        }
+        request.body(()).unwrap()
+    }
+
+    #[test]
+    fn tokens_come_from_the_query_or_the_header() {
+        assert_eq!(token(&request("/?room=a&token=abc", None)), Some("abc"));
+        assert_eq!(token(&request("/", Some("Bearer abc"))), Some("abc"));
+        assert_eq!(token(&request("/", Some("abc"))), Some("abc"));
+        assert_eq!(
+            token(&request("/?token=query", Some("Bearer header"))),
+            Some("query")
+        );
+    }
+
+    #[test]
+    fn empty_tokens_are_no_tokens() {
+        assert_eq!(token(&request("/", None)), None);
+        assert_eq!(token(&request("/?token=", None)), None);
+        assert_eq!(token(&request("/", Some("Bearer "))), None);
+    }
+}
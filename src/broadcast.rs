@@ -0,0 +1,194 @@
+//! Who a message relayed from a chat client goes to.
+//!
+//! The server hands every relayed message to
+//! `ServerConfig.broadcast_strategy`, a `BroadcastStrategy`. The default
+//! `RoomBroadcast` sends it to the rest of the sender's room;
+//! `GlobalBroadcast` sends it to every other client.
+//! Deployments wanting other fan-out, such as by predicate, implement the
+//! trait themselves. Under `ServerConfig.total_order` the strategy runs on
+//! the single broadcaster, so it keeps the ordering.
+
+use std::{collections::HashSet, fmt, sync::Arc};
+
+use async_tungstenite::tungstenite::protocol::Message;
+
+use crate::{queue::SendError, ConnectionId, PeerMap};
+
+/// A message relayed from a client, as handed to a `BroadcastStrategy`.
+#[derive(Debug, Clone, Copy)]
+pub struct Relayed<'a> {
+    pub from: ConnectionId,
+    /// The members of the sender's room, the sender included.
+    pub room: &'a HashSet<ConnectionId>,
+    /// Connections left out of broadcasts (see `DeafSet`).
+    pub deaf: &'a HashSet<ConnectionId>,
+}
+
+/// How many peers a relayed message was queued for, and which peers turned
+/// out to be unreachable.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Delivery {
+    /// Reported to the sender in delivery receipts.
+    pub delivered: usize,
+    /// Removed from the `PeerMap` afterwards.
+    pub unreachable: Vec<ConnectionId>,
+}
+
+pub trait BroadcastStrategy: Send + Sync + 'static {
+    /// Queues `msg` for the peers that should receive it.
+    fn deliver(&self, peers: &PeerMap, relayed: Relayed<'_>, msg: Message) -> Delivery;
+}
+
+/// Sends to the other members of the sender's room.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RoomBroadcast;
+
+impl BroadcastStrategy for RoomBroadcast {
+    fn deliver(&self, peers: &PeerMap, relayed: Relayed<'_>, msg: Message) -> Delivery {
+        send_each(peers, &msg, |id| {
+            *id != relayed.from && relayed.room.contains(id) && !relayed.deaf.contains(id)
+        })
+    }
+}
+
+/// Sends to every other client, whatever room it is in.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GlobalBroadcast;
+
+impl BroadcastStrategy for GlobalBroadcast {
+    fn deliver(&self, peers: &PeerMap, relayed: Relayed<'_>, msg: Message) -> Delivery {
+        send_each(peers, &msg, |id| {
+            *id != relayed.from && !relayed.deaf.contains(id)
+        })
+    }
+}
+
+/// Queues `msg` for every peer `pick` accepts, for strategies of your own.
+/// Peers that are closing are skipped without counting as unreachable.
+pub fn send_each(peers: &PeerMap, msg: &Message, pick: impl Fn(&ConnectionId) -> bool) -> Delivery {
+    // Removing the closed peers here while iterating would deadlock on the
+    // shard locks, so they are left for the caller to evict.
+    let mut delivery = Delivery::default();
+    for peer in peers.iter().filter(|peer| pick(peer.key())) {
+        match peer.value().send(msg.clone()) {
+            Ok(()) => delivery.delivered += 1,
+            Err(SendError::Closing) => {}
+            Err(SendError::Disconnected | SendError::Overflow) => {
+                delivery.unreachable.push(*peer.key())
+            }
+        }
+    }
+    delivery
+}
+
+/// The `BroadcastStrategy` the server relays with. Defaults to
+/// `RoomBroadcast`.
+#[derive(Clone)]
+pub struct SharedStrategy(Arc<dyn BroadcastStrategy>);
+
+impl SharedStrategy {
+    pub fn new(strategy: impl BroadcastStrategy) -> Self {
+        SharedStrategy(Arc::new(strategy))
+    }
+
+    pub fn deliver(&self, peers: &PeerMap, relayed: Relayed<'_>, msg: Message) -> Delivery {
+        self.0.deliver(peers, relayed, msg)
+    }
+}
+
+impl Default for SharedStrategy {
+    fn default() -> Self {
+        SharedStrategy::new(RoomBroadcast)
+    }
+}
+
+impl fmt::Debug for SharedStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedStrategy")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{FutureExt, StreamExt};
+
+    use super::*;
+    use crate::queue::{self, OverflowPolicy, Rx};
+
+    struct EvenIds;
+
+    impl BroadcastStrategy for EvenIds {
+        fn deliver(&self, peers: &PeerMap, _: Relayed<'_>, msg: Message) -> Delivery {
+            send_each(peers, &msg, |id| id.0 % 2 == 0)
+        }
+    }
+
+    /// Peers 1 to 4, with 1 and 2 in the sender's room.
+    fn peers() -> (PeerMap, Vec<Rx>, HashSet<ConnectionId>) {
+        let peers = PeerMap::default();
+        let mut receivers = Vec::new();
+        for id in 1..=4 {
+            let (tx, rx) = queue::channel(4, OverflowPolicy::DropOldest);
+            peers.insert(ConnectionId(id), tx);
+            receivers.push(rx);
+        }
+        let room = [ConnectionId(1), ConnectionId(2)].iter().copied().collect();
+        (peers, receivers, room)
+    }
+
+    /// The ids of the peers that have something queued.
+    fn reached(receivers: &mut [Rx]) -> Vec<u64> {
+        (1..)
+            .zip(receivers)
+            .filter_map(|(id, rx)| rx.next().now_or_never().flatten().map(|_| id))
+            .collect()
+    }
+
+    fn relay(strategy: impl BroadcastStrategy, deaf: &[u64]) -> (Delivery, Vec<u64>) {
+        let (peers, mut receivers, room) = peers();
+        let deaf = deaf.iter().copied().map(ConnectionId).collect();
+        let relayed = Relayed {
+            from: ConnectionId(1),
+            room: &room,
+            deaf: &deaf,
+        };
+        let delivery = SharedStrategy::new(strategy).deliver(&peers, relayed, Message::text("hi"));
+        (delivery, reached(&mut receivers))
+    }
+
+    #[test]
+    fn rooms_keep_to_themselves() {
+        let (delivery, reached) = relay(RoomBroadcast, &[]);
+        assert_eq!(delivery.delivered, 1);
+        assert_eq!(reached, [2]);
+    }
+
+    #[test]
+    fn global_broadcasts_skip_the_sender_and_the_deaf() {
+        let (delivery, reached) = relay(GlobalBroadcast, &[3]);
+        assert_eq!(delivery.delivered, 2);
+        assert_eq!(reached, [2, 4]);
+    }
+
+    #[test]
+    fn strategies_of_our_own_pick_the_recipients() {
+        let (peers, mut receivers, _) = peers();
+        drop(receivers.remove(3));
+        let nobody = HashSet::new();
+        let relayed = Relayed {
+            from: ConnectionId(1),
+            room: &nobody,
+            deaf: &nobody,
+        };
+        let delivery = EvenIds.deliver(&peers, relayed, Message::text("hi"));
+        // Peer 4 is gone, so it is reported rather than counted.
+        assert_eq!(
+            delivery,
+            Delivery {
+                delivered: 1,
+                unreachable: vec![ConnectionId(4)],
+            }
+        );
+        assert_eq!(reached(&mut receivers), [2]);
+    }
+}
@@ -0,0 +1,142 @@
+//! The channels carrying messages and events from the connection tasks into
+//! the Bevy world.
+//!
+//! `BridgeChannel` is implemented by the receiving end of each kind of
+//! channel the bridge can use: crossbeam's by default, and the standard
+//! library's `mpsc` with the `std-channels` feature. `BridgeSender` and
+//! `BridgeReceiver` name the selected one, as used by `Server` and the pump
+//! systems. The outbox back to the server is a crossbeam channel either way.
+
+use std::sync::{mpsc, Arc, Mutex};
+
+pub trait BridgeChannel<T>: Clone + Send + Sync + Sized + 'static {
+    type Sender: Clone + Send + Sync + 'static;
+
+    /// Creates a channel without a bound.
+    fn unbounded() -> (Self::Sender, Self);
+
+    /// Sends `msg`, handing it back if the receiver is gone.
+    fn send(sender: &Self::Sender, msg: T) -> Result<(), T>;
+
+    /// The next message, if one is waiting.
+    fn try_recv(&self) -> Option<T>;
+
+    /// Waits for the next message. `None` once every sender is gone.
+    fn recv(&self) -> Option<T>;
+}
+
+impl<T: Send + 'static> BridgeChannel<T> for crossbeam_channel::Receiver<T> {
+    type Sender = crossbeam_channel::Sender<T>;
+
+    fn unbounded() -> (Self::Sender, Self) {
+        crossbeam_channel::unbounded()
+    }
+
+    fn send(sender: &Self::Sender, msg: T) -> Result<(), T> {
+        sender.send(msg).map_err(|e| e.into_inner())
+    }
+
+    fn try_recv(&self) -> Option<T> {
+        crossbeam_channel::Receiver::try_recv(self).ok()
+    }
+
+    fn recv(&self) -> Option<T> {
+        crossbeam_channel::Receiver::recv(self).ok()
+    }
+}
+
+/// The receiving end of a `std::sync::mpsc` channel, made shareable like a
+/// crossbeam one. Cloning shares the messages.
+pub struct StdReceiver<T>(Arc<Mutex<mpsc::Receiver<T>>>);
+
+impl<T> Clone for StdReceiver<T> {
+    fn clone(&self) -> Self {
+        StdReceiver(self.0.clone())
+    }
+}
+
+impl<T: Send + 'static> BridgeChannel<T> for StdReceiver<T> {
+    type Sender = mpsc::Sender<T>;
+
+    fn unbounded() -> (Self::Sender, Self) {
+        let (sender, receiver) = mpsc::channel();
+        (sender, StdReceiver(Arc::new(Mutex::new(receiver))))
+    }
+
+    fn send(sender: &Self::Sender, msg: T) -> Result<(), T> {
+        sender.send(msg).map_err(|e| e.0)
+    }
+
+    fn try_recv(&self) -> Option<T> {
+        // A clone blocked in `recv` holds the lock and gets the message
+        // instead, so there is no need to wait for it.
+        self.0.try_lock().ok()?.try_recv().ok()
+    }
+
+    fn recv(&self) -> Option<T> {
+        self.0.lock().unwrap().recv().ok()
+    }
+}
+
+#[cfg(not(feature = "std-channels"))]
+pub type BridgeReceiver<T> = crossbeam_channel::Receiver<T>;
+#[cfg(feature = "std-channels")]
+pub type BridgeReceiver<T> = StdReceiver<T>;
+
+pub type BridgeSender<T> = <BridgeReceiver<T> as BridgeChannel<T>>::Sender;
+
+/// Creates a bridge channel of the selected kind.
+pub fn unbounded<T: Send + 'static>() -> (BridgeSender<T>, BridgeReceiver<T>) {
+    BridgeReceiver::unbounded()
+}
+
+/// Every message waiting on `receiver`, without blocking.
+pub fn drain<T, R: BridgeChannel<T>>(receiver: &R) -> impl Iterator<Item = T> + '_ {
+    std::iter::from_fn(move || receiver.try_recv())
+}
+
+/// Every message arriving on `receiver`, blocking in between, until every
+/// sender is gone.
+pub fn iter<T, R: BridgeChannel<T>>(receiver: &R) -> impl Iterator<Item = T> + '_ {
+    std::iter::from_fn(move || receiver.recv())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the same checks against either kind of channel.
+    fn exercise<R: BridgeChannel<u32>>() {
+        let (sender, receiver) = R::unbounded();
+        assert_eq!(receiver.try_recv(), None);
+        for n in 1..=3 {
+            R::send(&sender, n).unwrap();
+        }
+        // Clones share the messages rather than each seeing all of them.
+        let clone = receiver.clone();
+        assert_eq!(clone.try_recv(), Some(1));
+        assert_eq!(drain(&receiver).collect::<Vec<_>>(), [2, 3]);
+
+        let sending = std::thread::spawn(move || {
+            for n in 4..=5 {
+                R::send(&sender, n).unwrap();
+            }
+        });
+        assert_eq!(iter(&receiver).collect::<Vec<_>>(), [4, 5]);
+        sending.join().unwrap();
+
+        let (sender, receiver) = R::unbounded();
+        drop(receiver);
+        assert_eq!(R::send(&sender, 6), Err(6));
+    }
+
+    #[test]
+    fn crossbeam_channels_bridge() {
+        exercise::<crossbeam_channel::Receiver<u32>>();
+    }
+
+    #[test]
+    fn std_channels_bridge() {
+        exercise::<StdReceiver<u32>>();
+    }
+}
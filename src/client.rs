@@ -0,0 +1,430 @@
+//! Client side: connecting to a server, optionally reconnecting with
+//! exponential backoff when the connection drops.
+//!
+//! `connect` opens a single connection. `spawn_client` keeps one open in the
+//! background and exposes it to Bevy as the `WsClient` resource; add
+//! `pump_client_events` to turn what it reports into `ClientEvent` events.
+//! A `ClientPool` keeps connections to several servers open for sending,
+//! e.g. to relay messages between federated servers.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_tungstenite::{
+    tungstenite::{
+        client::IntoClientRequest,
+        error::{Error as WsError, UrlError},
+        protocol::Message,
+    },
+    WebSocketStream,
+};
+use bevy::prelude::*;
+use crossbeam_channel::{Receiver, Sender};
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    future, pin_mut,
+    prelude::*,
+    stream::{SplitSink, SplitStream},
+};
+use rand::Rng;
+
+use crate::{
+    heartbeat::HeartbeatConfig,
+    runtime::{self, AsyncStream},
+};
+
+/// A client connection, over TLS for `wss://` URLs.
+pub type ClientStream = WebSocketStream<Box<dyn AsyncStream>>;
+
+/// Write half of a client connection.
+pub type ClientSink = SplitSink<ClientStream, Message>;
+
+/// Read half of a client connection.
+pub type ClientSource = SplitStream<ClientStream>;
+
+/// Connects to a `ws://` or `wss://` URL and returns the two halves of the
+/// connection. `wss://` needs the `tls` feature.
+///
+/// The TCP (and TLS) stream is set up here and the handshake done with
+/// `async_tungstenite::client_async`, so this works with either runtime.
+pub async fn connect(url: &str) -> Result<(ClientSink, ClientSource), WsError> {
+    let request = url.into_client_request()?;
+    let uri = request.uri();
+    let secure = match uri.scheme_str() {
+        Some("ws") => false,
+        Some("wss") => true,
+        _ => return Err(WsError::Url(UrlError::UnsupportedUrlScheme)),
+    };
+    let host = uri
+        .host()
+        .ok_or(WsError::Url(UrlError::NoHostName))?
+        .to_string();
+    let port = uri.port_u16().unwrap_or(if secure { 443 } else { 80 });
+
+    let tcp = runtime::connect(&format!("{}:{}", host, port)).await?;
+    let stream: Box<dyn AsyncStream> = if secure {
+        tls_stream(tcp, &host).await?
+    } else {
+        Box::new(tcp)
+    };
+
+    let (ws_stream, _) = async_tungstenite::client_async(request, stream).await?;
+    Ok(ws_stream.split())
+}
+
+#[cfg(feature = "tls")]
+async fn tls_stream(tcp: runtime::TcpStream, host: &str) -> Result<Box<dyn AsyncStream>, WsError> {
+    use std::convert::TryFrom;
+
+    use futures_rustls::rustls::ServerName;
+
+    let name = ServerName::try_from(host).map_err(|_| WsError::Url(UrlError::NoHostName))?;
+    let stream = crate::tls::client_connector().connect(name, tcp).await?;
+    Ok(Box::new(stream))
+}
+
+#[cfg(not(feature = "tls"))]
+async fn tls_stream(
+    _tcp: runtime::TcpStream,
+    _host: &str,
+) -> Result<Box<dyn AsyncStream>, WsError> {
+    Err(WsError::Url(UrlError::TlsFeatureNotEnabled))
+}
+
+/// How long `spawn_client` waits between reconnection attempts.
+#[derive(Debug, Clone)]
+pub struct BackoffConfig {
+    /// Wait before the first retry; doubled after every failed attempt.
+    pub base: Duration,
+    /// Longest wait between attempts.
+    pub max: Duration,
+    /// Random variation applied to each wait, as a fraction of it.
+    pub jitter: f64,
+    /// Give up after this many failed attempts in a row. `None` retries
+    /// forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        BackoffConfig {
+            base: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+            jitter: 0.2,
+            max_attempts: Some(10),
+        }
+    }
+}
+
+impl BackoffConfig {
+    /// The wait before retry number `attempt`, counting from 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let doubled = self
+            .base
+            .checked_mul(1 << attempt.saturating_sub(1).min(31))
+            .unwrap_or(self.max);
+        let delay = doubled.min(self.max);
+        if self.jitter <= 0.0 {
+            return delay;
+        }
+        let factor = 1.0 + rand::thread_rng().gen_range(-self.jitter..=self.jitter);
+        delay.mul_f64(factor.max(0.0))
+    }
+}
+
+/// What a client spawned with `spawn_client` reports back.
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    Connected,
+    /// The connection failed or dropped; retry number `attempt` follows
+    /// after `delay`.
+    Reconnecting {
+        attempt: u32,
+        delay: Duration,
+    },
+    /// `BackoffConfig.max_attempts` was reached; the client has stopped.
+    GaveUp,
+    Message(Message),
+}
+
+/// Resource for talking to a server through a client spawned with
+/// `spawn_client`.
+pub struct WsClient {
+    outgoing: UnboundedSender<Message>,
+    events: Receiver<ClientEvent>,
+}
+
+impl WsClient {
+    /// Queues a message for the server. Messages sent while disconnected go
+    /// out once the client has reconnected.
+    pub fn send(&self, msg: Message) {
+        // Only fails once the client has given up.
+        let _ = self.outgoing.unbounded_send(msg);
+    }
+}
+
+/// Starts a client that stays connected to `url`, reconnecting with
+/// backoff whenever the connection can't be made or drops. The client stops
+/// when the returned `WsClient` is dropped.
+pub fn spawn_client(url: String, backoff: BackoffConfig) -> WsClient {
+    let (outgoing, outgoing_rx) = mpsc::unbounded();
+    let (events_tx, events) = crossbeam_channel::unbounded();
+    runtime::spawn(keep_connected(url, backoff, outgoing_rx, events_tx));
+    WsClient { outgoing, events }
+}
+
+async fn keep_connected(
+    url: String,
+    backoff: BackoffConfig,
+    mut outgoing: UnboundedReceiver<Message>,
+    events: Sender<ClientEvent>,
+) {
+    let mut attempt = 0;
+    loop {
+        match connect(&url).await {
+            Ok((sink, source)) => {
+                attempt = 0;
+                let _ = events.send(ClientEvent::Connected);
+                if !relay(sink, source, &mut outgoing, &events).await {
+                    return;
+                }
+            }
+            Err(e) => warn!("Connecting to {} failed: {}", url, e),
+        }
+
+        attempt += 1;
+        if backoff.max_attempts.is_some_and(|max| attempt > max) {
+            let _ = events.send(ClientEvent::GaveUp);
+            return;
+        }
+        let delay = backoff.delay(attempt);
+        let _ = events.send(ClientEvent::Reconnecting { attempt, delay });
+        runtime::sleep(delay).await;
+    }
+}
+
+/// Pumps messages both ways until the connection drops, returning `false`
+/// instead if the `WsClient` went away and the client should stop.
+async fn relay(
+    mut sink: ClientSink,
+    mut source: ClientSource,
+    outgoing: &mut UnboundedReceiver<Message>,
+    events: &Sender<ClientEvent>,
+) -> bool {
+    loop {
+        match future::select(source.next(), outgoing.next()).await {
+            future::Either::Left((Some(Ok(msg)), _)) => {
+                let _ = events.send(ClientEvent::Message(msg));
+            }
+            future::Either::Left((Some(Err(e)), _)) => {
+                warn!("Connection lost: {}", e);
+                return true;
+            }
+            future::Either::Left((None, _)) => return true,
+            future::Either::Right((Some(msg), _)) => {
+                if sink.send(msg).await.is_err() {
+                    return true;
+                }
+            }
+            future::Either::Right((None, _)) => {
+                let _ = sink.close().await;
+                return false;
+            }
+        }
+    }
+}
+
+/// Emits everything a `WsClient` resource has reported as `ClientEvent`
+/// events.
+pub fn pump_client_events(client: Res<WsClient>, mut events: EventWriter<ClientEvent>) {
+    for event in client.events.try_iter() {
+        events.send(event);
+    }
+}
+
+/// Connections to other servers, keyed by URL, made on first use and kept
+/// open for later sends. Cloning shares the connections.
+///
+/// Each connection is pinged on `health.interval` and dropped once the
+/// server has been silent for `health.timeout`; the next send to that URL
+/// connects again. Messages the servers send back are discarded.
+#[derive(Clone, Default)]
+pub struct ClientPool {
+    health: HeartbeatConfig,
+    connections: Arc<Mutex<HashMap<String, UnboundedSender<Message>>>>,
+}
+
+impl ClientPool {
+    pub fn new(health: HeartbeatConfig) -> Self {
+        ClientPool {
+            health,
+            connections: Arc::default(),
+        }
+    }
+
+    /// Sends `msg` to the server at `url`, connecting first if no
+    /// connection to it is open. Delivery is best effort: messages queued on
+    /// a connection that fails or drops are lost.
+    pub fn send(&self, url: &str, msg: Message) {
+        let mut connections = self.connections.lock().unwrap();
+        let msg = match connections.get(url) {
+            Some(outgoing) => match outgoing.unbounded_send(msg) {
+                Ok(()) => return,
+                // The connection has ended since it was last used.
+                Err(e) => e.into_inner(),
+            },
+            None => msg,
+        };
+
+        let (outgoing, outgoing_rx) = mpsc::unbounded();
+        let _ = outgoing.unbounded_send(msg);
+        connections.insert(url.to_string(), outgoing);
+        runtime::spawn(pooled_connection(url.to_string(), self.health, outgoing_rx));
+    }
+
+    /// Whether a connection to `url` is open or being opened.
+    pub fn is_connected(&self, url: &str) -> bool {
+        let connections = self.connections.lock().unwrap();
+        connections
+            .get(url)
+            .is_some_and(|outgoing| !outgoing.is_closed())
+    }
+}
+
+/// Writes out everything sent to one URL until the connection fails, goes
+/// quiet, or the pool is dropped.
+async fn pooled_connection(
+    url: String,
+    health: HeartbeatConfig,
+    mut outgoing: UnboundedReceiver<Message>,
+) {
+    let (mut sink, mut source) = match connect(&url).await {
+        Ok(halves) => halves,
+        Err(e) => {
+            warn!("Connecting to {} failed: {}", url, e);
+            return;
+        }
+    };
+
+    let mut last_heard = Instant::now();
+    let ticks = runtime::interval(health.interval);
+    pin_mut!(ticks);
+    loop {
+        let next = future::select(source.next(), future::select(outgoing.next(), ticks.next()));
+        match next.await {
+            future::Either::Left((Some(Ok(_)), _)) => last_heard = Instant::now(),
+            future::Either::Left((Some(Err(e)), _)) => {
+                warn!("Connection to {} lost: {}", url, e);
+                return;
+            }
+            future::Either::Left((None, _)) => return,
+            future::Either::Right((future::Either::Left((Some(msg), _)), _)) => {
+                if sink.send(msg).await.is_err() {
+                    return;
+                }
+            }
+            future::Either::Right((future::Either::Left((None, _)), _)) => {
+                let _ = sink.close().await;
+                return;
+            }
+            future::Either::Right((future::Either::Right(_), _)) => {
+                if last_heard.elapsed() > health.timeout {
+                    warn!("Dropping the connection to {}: no answer", url);
+                    return;
+                }
+                if sink.send(Message::Ping(Vec::new())).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{channel::BridgeChannel, Server, ServerConfig, WsMessageReceived};
+
+    const PATIENCE: Duration = Duration::from_secs(5);
+
+    /// The next event the client reports that `pick` accepts.
+    async fn wait_for<T>(client: &WsClient, pick: impl Fn(ClientEvent) -> Option<T>) -> T {
+        let started = Instant::now();
+        loop {
+            if let Some(found) = client.events.try_iter().find_map(&pick) {
+                return found;
+            }
+            assert!(started.elapsed() < PATIENCE, "Gave up waiting");
+            runtime::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    fn connected(event: ClientEvent) -> Option<()> {
+        matches!(event, ClientEvent::Connected).then_some(())
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        let backoff = BackoffConfig {
+            base: Duration::from_millis(100),
+            max: Duration::from_millis(350),
+            jitter: 0.0,
+            max_attempts: None,
+        };
+        let delays: Vec<_> = (1..=4).map(|attempt| backoff.delay(attempt)).collect();
+        assert_eq!(
+            delays,
+            [100, 200, 350, 350].map(Duration::from_millis).to_vec()
+        );
+        assert_eq!(backoff.delay(100), backoff.max);
+    }
+
+    #[test]
+    fn clients_reconnect_once_the_server_is_back() {
+        runtime::block_on(async {
+            let config = || ServerConfig {
+                member_list_delay: None,
+                ..ServerConfig::default()
+            };
+            let server = Server::start(&["127.0.0.1:0".to_string()], config())
+                .await
+                .unwrap();
+            let addr = server.local_addrs()[0];
+            let client = spawn_client(
+                format!("ws://{}", addr),
+                BackoffConfig {
+                    base: Duration::from_millis(50),
+                    max: Duration::from_millis(200),
+                    jitter: 0.0,
+                    max_attempts: None,
+                },
+            );
+            wait_for(&client, connected).await;
+
+            server.shutdown().await;
+            wait_for(&client, |event| match event {
+                ClientEvent::Reconnecting { .. } => Some(()),
+                _ => None,
+            })
+            .await;
+
+            let server = Server::start(&[addr.to_string()], config()).await.unwrap();
+            wait_for(&client, connected).await;
+            client.send(Message::text("back again"));
+            let started = Instant::now();
+            let received = loop {
+                if let Some(WsMessageReceived { msg, .. }) =
+                    BridgeChannel::try_recv(&server.messages)
+                {
+                    break msg;
+                }
+                assert!(started.elapsed() < PATIENCE, "Gave up waiting");
+                runtime::sleep(Duration::from_millis(10)).await;
+            };
+            assert_eq!(received, Message::text("back again"));
+        });
+    }
+}
@@ -0,0 +1,173 @@
+//! The time source behind heartbeats, rate limits and write timeouts.
+//!
+//! The server reads the time and sleeps through `ServerConfig.clock`, which
+//! is the system clock unless replaced. A `MockClock` only moves when
+//! `advance` is called, so timeouts can be triggered instantly and
+//! deterministically.
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    task::{Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use futures::{future::BoxFuture, pin_mut, prelude::*, stream};
+
+use crate::runtime;
+
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> Instant;
+
+    /// Completes once `duration` has passed on this clock.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// The operating system's monotonic clock and the runtime's timers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        runtime::sleep(duration).boxed()
+    }
+}
+
+struct MockState {
+    now: Instant,
+    sleepers: Vec<Waker>,
+}
+
+/// A clock that stands still until `advance` moves it forward. Cloning
+/// shares the clock.
+#[derive(Clone)]
+pub struct MockClock(Arc<Mutex<MockState>>);
+
+impl MockClock {
+    pub fn new() -> Self {
+        MockClock(Arc::new(Mutex::new(MockState {
+            now: Instant::now(),
+            sleepers: Vec::new(),
+        })))
+    }
+
+    /// Moves the clock forward, waking every sleep that is now over.
+    pub fn advance(&self, by: Duration) {
+        let mut state = self.0.lock().unwrap();
+        state.now += by;
+        // Sleeps that aren't over yet register again when polled.
+        for waker in state.sleepers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.0.lock().unwrap().now
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let deadline = self.now() + duration;
+        let state = self.0.clone();
+        future::poll_fn(move |cx| {
+            let mut state = state.lock().unwrap();
+            if state.now >= deadline {
+                return Poll::Ready(());
+            }
+            state.sleepers.push(cx.waker().clone());
+            Poll::Pending
+        })
+        .boxed()
+    }
+}
+
+/// A `Clock` shared by everything in the server. Defaults to the
+/// `SystemClock`.
+#[derive(Clone)]
+pub struct SharedClock(Arc<dyn Clock>);
+
+impl SharedClock {
+    pub fn new(clock: impl Clock) -> Self {
+        SharedClock(Arc::new(clock))
+    }
+
+    pub fn now(&self) -> Instant {
+        self.0.now()
+    }
+
+    pub fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        self.0.sleep(duration)
+    }
+
+    /// Runs `future`, giving up with `None` once `duration` has passed.
+    pub async fn timeout<F: Future>(&self, duration: Duration, future: F) -> Option<F::Output> {
+        let sleep = self.sleep(duration);
+        pin_mut!(future);
+        match future::select(future, sleep).await {
+            future::Either::Left((output, _)) => Some(output),
+            future::Either::Right(((), _)) => None,
+        }
+    }
+
+    /// A stream that yields once every `period`, starting one period from
+    /// now.
+    pub fn interval(&self, period: Duration) -> impl Stream<Item = ()> {
+        stream::unfold(self.clone(), move |clock| async move {
+            clock.sleep(period).await;
+            Some(((), clock))
+        })
+    }
+}
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        SharedClock::new(SystemClock)
+    }
+}
+
+impl fmt::Debug for SharedClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedClock")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_sleeps_end_only_once_advanced_past() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let mut sleep = clock.sleep(Duration::from_secs(10));
+        assert!((&mut sleep).now_or_never().is_none());
+        clock.advance(Duration::from_secs(9));
+        assert!((&mut sleep).now_or_never().is_none());
+        clock.advance(Duration::from_secs(1));
+        assert!(sleep.now_or_never().is_some());
+        assert_eq!(clock.now() - start, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn mock_timeouts_fire_without_waiting() {
+        let clock = MockClock::new();
+        let shared = SharedClock::new(clock.clone());
+        let mut timeout = shared
+            .timeout(Duration::from_secs(30), future::pending::<()>())
+            .boxed();
+        assert!((&mut timeout).now_or_never().is_none());
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(timeout.now_or_never(), Some(None));
+    }
+}
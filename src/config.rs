@@ -0,0 +1,515 @@
+//! Server settings. Insert a `ServerConfig` resource before `setup` runs to
+//! override the defaults, and send an `UpdateServerConfig` event to change
+//! them while the server runs.
+
+#[cfg(feature = "serde")]
+use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use async_tungstenite::tungstenite::protocol::{Message, WebSocketConfig};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admin::AdminLogs,
+    auth::TokenValidator,
+    broadcast::SharedStrategy,
+    clock::SharedClock,
+    filter::{AcceptCallback, MessageFilter, MessageHandler},
+    heartbeat::HeartbeatConfig,
+    logging::{MessageLogging, Redaction},
+    queue::OverflowPolicy,
+    ratelimit::{FrameLimit, RateLimitConfig},
+    wire::WireFormat,
+};
+
+/// The rate limit and heartbeat a connection is held to.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectionLimits {
+    pub rate_limit: RateLimitConfig,
+    pub heartbeat: HeartbeatConfig,
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub heartbeat: HeartbeatConfig,
+    pub rate_limit: RateLimitConfig,
+    /// Clients going over the rate limit are also muted this long: their
+    /// chat and private messages are dropped, with one notice, until the
+    /// mute lifts. `None` only drops the excess.
+    pub flood_mute: Option<Duration>,
+    /// Limits for connections without an authenticated user, used instead
+    /// of `rate_limit` and `heartbeat` until the client authenticates. With
+    /// `auth` set, this also lets clients in without a token; they can send
+    /// `/auth <token>` later. `None` holds everyone to the same limits and
+    /// refuses clients without a token.
+    pub anonymous_limits: Option<ConnectionLimits>,
+    /// Clients declaring a protocol version below this are closed with
+    /// `DisconnectReason::OutdatedProtocol`. Clients that never declare one
+    /// aren't checked; only JSON commands (the `serde` feature) can.
+    pub min_protocol_version: u32,
+    /// Caps the frames a client sends per second regardless of their size,
+    /// since a flood of tiny frames costs as much to handle as big ones.
+    /// Frames over the cap never reach the rate limit. `None` means no cap.
+    pub frame_limit: Option<FrameLimit>,
+    /// Clients that send no messages for this long are kicked by
+    /// `reap_idle_connections`, however well they answer heartbeats.
+    /// `None` lets them idle.
+    pub max_idle: Option<Duration>,
+    /// Count heartbeat Pongs as activity for `max_idle`, so only clients
+    /// that stop answering are reaped.
+    pub idle_counts_pongs: bool,
+    /// Sent to each new client on its own, before anything else, as it
+    /// connects. `None` sends nothing.
+    pub motd: Option<Message>,
+    /// Hold the `motd` back from clients that connect without a token until
+    /// they authenticate with `/auth`. Either way it is sent only once.
+    pub motd_after_auth: bool,
+    /// Number of recent messages replayed to newly connected clients.
+    pub history_size: usize,
+    /// File the history is saved to as JSON lines and loaded from at
+    /// startup, so a restart keeps each room's backlog. Rooms then keep
+    /// their history when they empty. `None` keeps it in memory only.
+    #[cfg(feature = "serde")]
+    pub history_path: Option<PathBuf>,
+    /// Connections beyond this many are turned away with a Close frame.
+    pub max_connections: usize,
+    /// Connections from one IP address beyond this many are turned away
+    /// with a Close frame, so a single host can't take every slot. Unix
+    /// socket clients aren't limited this way. `None` means no limit.
+    pub max_per_ip: Option<usize>,
+    /// Handshakes (including TLS) allowed to run at once. Clients beyond
+    /// this wait to be accepted rather than being turned away. A client
+    /// holds its slot until its handshake finishes or fails. 0 counts as 1.
+    pub max_inflight_handshakes: usize,
+    /// Bytes a connection may move in both directions together before it is
+    /// closed with a policy violation. `None` means no limit.
+    pub byte_quota: Option<u64>,
+    /// Largest message a client may send, in bytes, counting every fragment
+    /// of a fragmented one; fragments are reassembled before the message is
+    /// handled. Clients exceeding it are disconnected. `None` means no limit.
+    pub max_message_size: Option<usize>,
+    /// Largest single frame a client may send, in bytes. `None` means no
+    /// limit.
+    pub max_frame_size: Option<usize>,
+    /// Negotiate permessage-deflate with clients that offer it. Every
+    /// message is then compressed on its own, and those that wouldn't get
+    /// smaller are sent as they are. Clients that don't offer it are served
+    /// uncompressed either way.
+    #[cfg(feature = "deflate")]
+    pub compression: bool,
+    /// Origins browsers may connect from. Handshakes from any other origin,
+    /// or without an `Origin` header, are refused with a 403. `None` allows
+    /// every client.
+    pub allowed_origins: Option<Vec<String>>,
+    /// Checks the token every client must present (see `auth`). `None`
+    /// lets clients connect without one.
+    pub auth: Option<TokenValidator>,
+    /// Run on every handshake that passed the origin and token checks. `None` accepts
+    /// everyone.
+    pub accept_callback: Option<AcceptCallback>,
+    /// Subprotocols the server speaks, in no particular order. The first one
+    /// a client offers is echoed back in the handshake.
+    pub subprotocols: Vec<String>,
+    /// Refuse clients that don't offer any of `subprotocols` with a 400,
+    /// instead of proceeding without one.
+    pub require_subprotocol: bool,
+    /// Connections that haven't completed the WebSocket handshake (TLS
+    /// included) this long after being accepted are dropped.
+    pub handshake_timeout: Duration,
+    /// Clients that take longer than this to accept a message are
+    /// disconnected.
+    pub write_timeout: Duration,
+    /// Text messages queued for a client within this long of each other are
+    /// written as one frame, see `protocol::batch_message`. Clients must
+    /// then expect every text frame to be a batch. `None` writes each
+    /// message on its own.
+    pub batch_window: Option<Duration>,
+    /// Number of messages queued for a client before `overflow_policy`
+    /// applies. A queue always holds at least one, so 0 counts as 1.
+    pub peer_buffer: usize,
+    pub overflow_policy: OverflowPolicy,
+    /// Applied to every message before it is relayed.
+    pub message_filter: MessageFilter,
+    /// Sees every message with its connection's `ConnState`. `None` skips
+    /// this step.
+    pub message_handler: Option<MessageHandler>,
+    pub close_policy: ClosePolicy,
+    /// Custom close codes for the reasons connections end.
+    pub close_codes: CloseCodes,
+    /// Who the messages clients send are relayed to (see `broadcast`).
+    pub broadcast_strategy: SharedStrategy,
+    /// Relay every client's messages through a single broadcaster so all
+    /// recipients see them in the same order, at the cost of relaying one
+    /// message at a time.
+    pub total_order: bool,
+    /// After clients join or leave a room (or change their name), its
+    /// members are sent the updated member list once this long has passed,
+    /// so a burst of changes leads to a single list. `None` sends no lists.
+    pub member_list_delay: Option<Duration>,
+    /// A `/join` or `/nick` identical to the last one that took effect for
+    /// a client within this long is ignored, so a flapping client can't
+    /// keep its room replaying history and recomputing member lists. A
+    /// refused `/nick` can be retried straight away. Only the chat endpoint
+    /// applies the commands itself, so game clients' are never ignored.
+    /// `None`, the default, handles every one.
+    pub command_debounce: Option<Duration>,
+    /// Also hand Pings from clients to the Bevy systems as
+    /// `WsMessageReceived`. They are answered with a Pong either way, as the
+    /// protocol requires.
+    pub forward_pings: bool,
+    /// Which endpoint each request path leads to, e.g. `/chat` and `/game`.
+    /// Handshakes for any other path are refused with a 404. Empty treats
+    /// every path as `Endpoint::Chat`.
+    pub routes: HashMap<String, Endpoint>,
+    /// Format for clients that don't ask for one (see `wire`).
+    pub wire_format: WireFormat,
+    /// Sets `SO_REUSEADDR` on the listeners, so a restarted server can bind
+    /// its port while connections of the last one linger in `TIME_WAIT`.
+    /// Ignored on Windows.
+    pub reuse_address: bool,
+    /// Sets `SO_REUSEPORT` on the listeners, so several servers can listen
+    /// on the same port. For a restart without downtime, start the new
+    /// server while the old one runs, then shut the old one down: it stops
+    /// accepting at once, leaving newcomers to the new server, and drains
+    /// its own connections. Binding fails where the OS doesn't support it.
+    pub reuse_port: bool,
+    /// Disables Nagle's algorithm on accepted sockets, so small messages
+    /// go out at once instead of waiting to be coalesced with the next
+    /// ones. That suits games, where a late update is worse than a few
+    /// extra packets; bulk transfers may prefer it off.
+    pub tcp_nodelay: bool,
+    /// Has the OS probe idle connections after this long, and between
+    /// probes, so peers that vanished without closing are noticed even when
+    /// the heartbeat is slow. `None` keeps the OS default, usually off.
+    pub tcp_keepalive: Option<Duration>,
+    /// Address to serve `render_metrics` on over HTTP, separately from the
+    /// WebSocket listeners. `None` doesn't serve them.
+    pub metrics_addr: Option<String>,
+    pub log_messages: MessageLogging,
+    /// Applied to message text before it is logged under
+    /// `MessageLogging::Full`.
+    pub log_redaction: Option<Redaction>,
+    /// Serves the server's logs to admin clients at `/admin/logs` (see
+    /// `admin`). `None` leaves that path to `routes`.
+    pub admin_logs: Option<AdminLogs>,
+    /// Time source for heartbeats, rate limits, write timeouts and
+    /// connection timestamps. Replace it with a `MockClock` to control time
+    /// in tests.
+    pub clock: SharedClock,
+}
+
+/// The settings a running server reads, which can be replaced without
+/// restarting it. Cloning shares the settings.
+///
+/// Not everything takes effect at once after `replace`:
+/// - the message filter and handler, message logging, `history_size`,
+///   `close_policy`, `close_codes`, `broadcast_strategy`,
+///   `member_list_delay`, `command_debounce`, `forward_pings`,
+///   `rate_limit`, `flood_mute`, `frame_limit`, `max_idle`,
+///   `idle_counts_pongs`, `min_protocol_version`, `motd`,
+///   `motd_after_auth`, the rate limit in `anonymous_limits`,
+///   `max_connections` and `max_per_ip` apply from the next message or
+///   connection;
+/// - everything checked in the handshake (origins, auth, routes,
+///   subprotocols, `wire_format`, `admin_logs`), `max_message_size`, `max_frame_size`,
+///   `heartbeat`, `byte_quota`, `handshake_timeout`, `write_timeout`,
+///   `batch_window`, `peer_buffer`, `overflow_policy`, `tcp_nodelay`,
+///   `tcp_keepalive` and `clock` only apply to connections made
+///   afterwards;
+/// - `max_inflight_handshakes`, `total_order`, `history_path`,
+///   `reuse_address`, `reuse_port` and `metrics_addr` are only read at
+///   startup.
+#[derive(Debug, Clone)]
+pub struct LiveConfig(Arc<RwLock<Arc<ServerConfig>>>);
+
+impl LiveConfig {
+    pub fn new(config: ServerConfig) -> Self {
+        LiveConfig(Arc::new(RwLock::new(Arc::new(config))))
+    }
+
+    /// The settings currently in force.
+    pub fn current(&self) -> Arc<ServerConfig> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn replace(&self, config: ServerConfig) {
+        *self.0.write().unwrap() = Arc::new(config);
+    }
+}
+
+/// Event replacing the running server's settings (see `LiveConfig`).
+#[derive(Debug, Clone)]
+pub struct UpdateServerConfig(pub ServerConfig);
+
+/// How the server treats a connection, chosen by its request path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Endpoint {
+    /// Rooms, commands and relaying between clients.
+    Chat,
+    /// Messages and commands only go to the Bevy systems, and the client
+    /// only hears from them.
+    Game,
+}
+
+/// What the rest of a room sees when one of its clients sends a Close frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ClosePolicy {
+    /// Nothing; the Close frame only ends the sender's connection.
+    #[default]
+    Ignore,
+    /// The other members are sent a `* <name> left` notice.
+    Relay,
+    /// The Close frame is forwarded to the other members, closing their
+    /// connections too.
+    Propagate,
+}
+
+/// Application close codes (3000 to 4999) sent instead of the standard ones
+/// when a connection ends for one of the given reasons, e.g. `4001` for a
+/// client kicked for `"cheating detected"`. Cloning shares the codes.
+#[derive(Debug, Clone, Default)]
+pub struct CloseCodes {
+    /// By `DisconnectReason::name`.
+    reasons: Arc<HashMap<String, (u16, String)>>,
+    /// By the reason given for a kick, which can be anything.
+    kicks: Arc<HashMap<String, (u16, String)>>,
+}
+
+/// A close code outside the range applications may use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidCloseCode(pub u16);
+
+impl fmt::Display for InvalidCloseCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "close code {} is outside the application range 3000-4999",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidCloseCode {}
+
+impl CloseCodes {
+    /// Closes connections ending for `reason` with `code` and the reason
+    /// text `text`. `reason` is the name of a `DisconnectReason` (see
+    /// `DisconnectReason::name`), such as `server_full`; `kicked` covers
+    /// every kick without a code of its own from `insert_kick`.
+    pub fn insert(
+        &mut self,
+        reason: impl Into<String>,
+        code: u16,
+        text: impl Into<String>,
+    ) -> Result<(), InvalidCloseCode> {
+        insert(&mut self.reasons, reason.into(), code, text.into())
+    }
+
+    /// Closes connections kicked for `reason` with `code` and the reason
+    /// text `text`. Kick reasons are kept apart from the names `insert`
+    /// takes, so a kick for `"server_full"` is still a kick.
+    pub fn insert_kick(
+        &mut self,
+        reason: impl Into<String>,
+        code: u16,
+        text: impl Into<String>,
+    ) -> Result<(), InvalidCloseCode> {
+        insert(&mut self.kicks, reason.into(), code, text.into())
+    }
+
+    /// The code and text for the `DisconnectReason` named `reason`, if one
+    /// was inserted.
+    pub fn get(&self, reason: &str) -> Option<(u16, &str)> {
+        self.reasons
+            .get(reason)
+            .map(|(code, text)| (*code, text.as_str()))
+    }
+
+    /// The code and text for a kick for `reason`, if one was inserted with
+    /// `insert_kick`.
+    pub fn get_kick(&self, reason: &str) -> Option<(u16, &str)> {
+        self.kicks
+            .get(reason)
+            .map(|(code, text)| (*code, text.as_str()))
+    }
+}
+
+fn insert(
+    codes: &mut Arc<HashMap<String, (u16, String)>>,
+    reason: String,
+    code: u16,
+    text: String,
+) -> Result<(), InvalidCloseCode> {
+    if !(3000..=4999).contains(&code) {
+        return Err(InvalidCloseCode(code));
+    }
+    Arc::make_mut(codes).insert(reason, (code, text));
+    Ok(())
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            heartbeat: HeartbeatConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            flood_mute: None,
+            anonymous_limits: None,
+            min_protocol_version: 0,
+            frame_limit: None,
+            max_idle: None,
+            idle_counts_pongs: false,
+            motd: None,
+            motd_after_auth: false,
+            history_size: 50,
+            #[cfg(feature = "serde")]
+            history_path: None,
+            max_connections: 1024,
+            max_per_ip: None,
+            max_inflight_handshakes: 64,
+            byte_quota: None,
+            max_message_size: Some(64 << 20),
+            max_frame_size: Some(16 << 20),
+            #[cfg(feature = "deflate")]
+            compression: false,
+            allowed_origins: None,
+            auth: None,
+            accept_callback: None,
+            subprotocols: Vec::new(),
+            require_subprotocol: false,
+            handshake_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            batch_window: None,
+            peer_buffer: 1024,
+            overflow_policy: OverflowPolicy::default(),
+            message_filter: MessageFilter::default(),
+            message_handler: None,
+            close_policy: ClosePolicy::default(),
+            close_codes: CloseCodes::default(),
+            broadcast_strategy: SharedStrategy::default(),
+            total_order: false,
+            member_list_delay: Some(Duration::from_millis(250)),
+            command_debounce: None,
+            forward_pings: false,
+            routes: HashMap::new(),
+            wire_format: WireFormat::default(),
+            reuse_address: true,
+            reuse_port: false,
+            tcp_nodelay: true,
+            tcp_keepalive: None,
+            metrics_addr: None,
+            log_messages: MessageLogging::default(),
+            log_redaction: None,
+            admin_logs: None,
+            clock: SharedClock::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// The limits for a connection, depending on whether its client has
+    /// authenticated.
+    pub fn limits(&self, authenticated: bool) -> ConnectionLimits {
+        match self.anonymous_limits {
+            Some(limits) if !authenticated => limits,
+            _ => ConnectionLimits {
+                rate_limit: self.rate_limit,
+                heartbeat: self.heartbeat,
+            },
+        }
+    }
+
+    /// Whether a handshake carrying this `Origin` header may proceed.
+    pub fn origin_allowed(&self, origin: Option<&str>) -> bool {
+        match (&self.allowed_origins, origin) {
+            (None, _) => true,
+            (Some(allowed), Some(origin)) => allowed.iter().any(|o| o == origin),
+            (Some(_), None) => false,
+        }
+    }
+
+    /// The endpoint a request for `path` leads to, if any.
+    pub fn route(&self, path: &str) -> Option<Endpoint> {
+        if self.routes.is_empty() {
+            return Some(Endpoint::Chat);
+        }
+        self.routes.get(path).copied()
+    }
+
+    /// The first of the client's `offered` subprotocols that the server
+    /// speaks.
+    pub fn choose_subprotocol<'a>(
+        &self,
+        mut offered: impl Iterator<Item = &'a str>,
+    ) -> Option<String> {
+        offered
+            .find(|protocol| self.subprotocols.iter().any(|ours| ours == protocol))
+            .map(str::to_string)
+    }
+
+    /// The tungstenite settings applied to every accepted connection.
+    pub fn websocket_config(&self) -> WebSocketConfig {
+        WebSocketConfig {
+            max_message_size: self.max_message_size,
+            max_frame_size: self.max_frame_size,
+            ..WebSocketConfig::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn origins_are_only_checked_when_listed() {
+        let open = ServerConfig::default();
+        assert!(open.origin_allowed(None));
+        assert!(open.origin_allowed(Some("https://anywhere.example")));
+
+        let listed = ServerConfig {
+            allowed_origins: Some(vec!["https://game.example".to_string()]),
+            ..ServerConfig::default()
+        };
+        assert!(listed.origin_allowed(Some("https://game.example")));
+        assert!(!listed.origin_allowed(Some("https://evil.example")));
+        assert!(!listed.origin_allowed(None));
+    }
+
+    #[test]
+    fn close_codes_must_be_in_the_application_range() {
+        let mut codes = CloseCodes::default();
+        assert_eq!(
+            codes.insert_kick("cheating", 1008, "no"),
+            Err(InvalidCloseCode(1008))
+        );
+        assert_eq!(
+            codes.insert("server_full", 5000, "no"),
+            Err(InvalidCloseCode(5000))
+        );
+        assert_eq!(codes.get_kick("cheating"), None);
+        assert_eq!(codes.get("server_full"), None);
+
+        codes
+            .insert_kick("cheating", 4001, "cheating detected")
+            .unwrap();
+        codes
+            .insert("server_full", 3000, "come back later")
+            .unwrap();
+        assert_eq!(
+            codes.get_kick("cheating"),
+            Some((4001, "cheating detected"))
+        );
+        assert_eq!(codes.get("server_full"), Some((3000, "come back later")));
+        // Kick reasons and reason names don't mix.
+        assert_eq!(codes.get("cheating"), None);
+        assert_eq!(codes.get_kick("server_full"), None);
+    }
+}
@@ -0,0 +1,76 @@
+//! Per-connection scratch state for message handlers.
+//!
+//! Every connection task owns a `ConnState` and lends it to the
+//! `ServerConfig.message_handler` for each message, so a handler can keep
+//! counters, buffers and the like for one client without a shared map or a
+//! lock. Values are keyed by their type and dropped with the connection.
+
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt,
+};
+
+#[derive(Default)]
+pub struct ConnState(HashMap<TypeId, Box<dyn Any + Send>>);
+
+impl ConnState {
+    pub fn get<T: Any + Send>(&self) -> Option<&T> {
+        self.0.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+
+    pub fn get_mut<T: Any + Send>(&mut self) -> Option<&mut T> {
+        self.0.get_mut(&TypeId::of::<T>())?.downcast_mut()
+    }
+
+    /// The value of type `T`, inserting `T::default()` first if there is
+    /// none yet.
+    pub fn get_or_default<T: Any + Send + Default>(&mut self) -> &mut T {
+        self.0
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(T::default()))
+            .downcast_mut()
+            .expect("values are stored under their own type")
+    }
+
+    /// Stores `value`, returning the previous value of the same type.
+    pub fn insert<T: Any + Send>(&mut self, value: T) -> Option<T> {
+        let old = self.0.insert(TypeId::of::<T>(), Box::new(value))?;
+        old.downcast().ok().map(|old| *old)
+    }
+
+    pub fn remove<T: Any + Send>(&mut self) -> Option<T> {
+        let value = self.0.remove(&TypeId::of::<T>())?;
+        value.downcast().ok().map(|value| *value)
+    }
+}
+
+impl fmt::Debug for ConnState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnState")
+            .field("values", &self.0.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Default, PartialEq)]
+    struct Count(u32);
+
+    #[test]
+    fn values_are_kept_by_type() {
+        let mut state = ConnState::default();
+        assert_eq!(state.get::<Count>(), None);
+        state.get_or_default::<Count>().0 += 2;
+        assert_eq!(state.insert("buffered".to_string()), None);
+        assert_eq!(state.get::<Count>(), Some(&Count(2)));
+        assert_eq!(state.get::<String>().map(String::as_str), Some("buffered"));
+
+        assert_eq!(state.insert(Count(5)), Some(Count(2)));
+        assert_eq!(state.remove::<Count>(), Some(Count(5)));
+        assert_eq!(state.get_mut::<Count>(), None);
+    }
+}
@@ -0,0 +1,546 @@
+//! A single accepted peer.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_tungstenite::{
+    tungstenite::{error::Error as WsError, protocol::frame::coding::CloseCode, Message},
+    WebSocketStream,
+};
+use futures::{
+    future, pin_mut,
+    prelude::*,
+    stream::{SplitSink, SplitStream},
+};
+
+use crate::{
+    clock::SharedClock,
+    config::CloseCodes,
+    fragments::Marker,
+    metrics, protocol,
+    queue::{Outgoing, Position, Rx, SendError},
+    runtime::AsyncStream,
+    ConnectionId, DisconnectReason, Tx,
+};
+
+/// Bytes a connection has sent and received, counting message payloads.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+impl Traffic {
+    /// Bytes received from the client.
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    /// Bytes written to the client.
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+
+    /// Both directions together, as counted against
+    /// `ServerConfig.byte_quota`.
+    pub fn total(&self) -> u64 {
+        self.bytes_in() + self.bytes_out()
+    }
+
+    pub(crate) fn record_in(&self, msg: &Message) {
+        self.bytes_in.fetch_add(msg.len() as u64, Ordering::Relaxed);
+    }
+
+    fn record_out(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// A peer's WebSocket, split into its read and write halves, together with
+/// the queue everything sent to the peer goes through.
+///
+/// Messages are never written to the socket directly: `send` queues them and
+/// the writer returned by `into_parts` writes the queue out in order.
+pub struct WsConnection<S> {
+    id: ConnectionId,
+    tx: Tx,
+    rx: Rx,
+    traffic: Arc<Traffic>,
+    sink: SplitSink<WebSocketStream<S>, Message>,
+    stream: SplitStream<WebSocketStream<S>>,
+    marker: Option<Marker>,
+}
+
+impl<S: AsyncStream> WsConnection<S> {
+    pub fn new(id: ConnectionId, ws_stream: WebSocketStream<S>, tx: Tx, rx: Rx) -> Self {
+        let (sink, stream) = ws_stream.split();
+        WsConnection {
+            id,
+            tx,
+            rx,
+            traffic: Arc::default(),
+            sink,
+            stream,
+            marker: None,
+        }
+    }
+
+    /// Has streamed messages written in fragments, marked out through
+    /// `marker` for the `Fragmenting` socket underneath. Without one, a
+    /// streamed message is gathered and written whole.
+    pub(crate) fn with_marker(mut self, marker: Marker) -> Self {
+        self.marker = Some(marker);
+        self
+    }
+
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    /// The queue other tasks send to this peer through.
+    pub fn handle(&self) -> &Tx {
+        &self.tx
+    }
+
+    /// The connection's byte counters. Only what `into_parts` writes is
+    /// counted automatically; received messages are counted by the reader.
+    pub fn traffic(&self) -> &Arc<Traffic> {
+        &self.traffic
+    }
+
+    /// Queues `msg` for the peer.
+    pub fn send(&self, msg: Message) -> Result<(), SendError> {
+        self.tx.send(msg)
+    }
+
+    /// The next message from the peer, or `None` once its stream has ended.
+    pub async fn next(&mut self) -> Option<Result<Message, WsError>> {
+        self.stream.next().await
+    }
+
+    /// Closes the connection once everything already queued has been
+    /// written.
+    pub fn close(&self, code: CloseCode, reason: impl Into<String>) {
+        crate::drain_and_close(&self.tx, code, reason);
+    }
+
+    /// Splits the connection into its read half and a future that writes
+    /// queued messages to the socket. The writer fails if a write takes
+    /// longer than `write_timeout` or the queue overflows, and otherwise
+    /// runs until the connection ends. It also fails once the connection has
+    /// moved more than `byte_quota` bytes, and closes an overflowed client
+    /// with the code `close_codes` has for it. Write timeouts and batch
+    /// windows are measured on `clock`.
+    ///
+    /// With a `batch_window`, a text message waits that long for more text
+    /// to follow, and all of it is written as one `protocol::batch_message`
+    /// frame. Any other message ends the batch early and is written after
+    /// it.
+    ///
+    /// The fragments of a streamed message are written as they come.
+    pub fn into_parts(
+        self,
+        write_timeout: Duration,
+        batch_window: Option<Duration>,
+        byte_quota: Option<u64>,
+        close_codes: CloseCodes,
+        clock: SharedClock,
+    ) -> (
+        SplitStream<WebSocketStream<S>>,
+        impl Future<Output = Result<(), DisconnectReason>>,
+    ) {
+        let WsConnection {
+            mut sink,
+            mut rx,
+            traffic,
+            stream,
+            marker,
+            ..
+        } = self;
+        let writer = async move {
+            let mut held = None;
+            // A streamed message being gathered, without a marker.
+            let mut gathered = Vec::new();
+            loop {
+                let outgoing = match held.take() {
+                    Some(outgoing) => outgoing,
+                    None => match rx.next_outgoing().await {
+                        Some(outgoing) => outgoing,
+                        None => break,
+                    },
+                };
+                let msg = match (outgoing, &marker) {
+                    (Outgoing::Message(msg), _) => msg,
+                    (Outgoing::Fragment(data, position), Some(marker)) => {
+                        marker.mark(position);
+                        Message::Binary(data)
+                    }
+                    (Outgoing::Fragment(data, position), None) => {
+                        gathered.extend_from_slice(&data);
+                        if position != Position::Last {
+                            continue;
+                        }
+                        Message::Binary(std::mem::take(&mut gathered))
+                    }
+                };
+                let msg = match (batch_window, msg) {
+                    (Some(window), Message::Text(text)) => {
+                        let mut texts = vec![text];
+                        let deadline = clock.sleep(window);
+                        pin_mut!(deadline);
+                        loop {
+                            match future::select(rx.next_outgoing(), deadline.as_mut()).await {
+                                future::Either::Left((
+                                    Some(Outgoing::Message(Message::Text(text))),
+                                    _,
+                                )) => texts.push(text),
+                                future::Either::Left((Some(other), _)) => {
+                                    held = Some(other);
+                                    break;
+                                }
+                                future::Either::Left((None, _)) | future::Either::Right(_) => break,
+                            }
+                        }
+                        protocol::batch_message(&texts)
+                    }
+                    (_, msg) => msg,
+                };
+                let bytes = msg.len();
+                match clock.timeout(write_timeout, sink.send(msg)).await {
+                    Some(Ok(())) => {
+                        metrics::message_sent();
+                        traffic.record_out(bytes);
+                        if byte_quota.is_some_and(|quota| traffic.total() > quota) {
+                            return Err(DisconnectReason::QuotaExceeded);
+                        }
+                    }
+                    Some(Err(e)) => return Err(DisconnectReason::from_ws_error(&e)),
+                    None => return Err(DisconnectReason::WriteTimeout),
+                }
+            }
+            if rx.overflowed() {
+                // The queue has been cleared, so the Close goes out directly.
+                let frame = DisconnectReason::Overflow.close_frame_with(&close_codes);
+                let close = Message::Close(Some(frame));
+                let _ = clock.timeout(write_timeout, sink.send(close)).await;
+                Err(DisconnectReason::Overflow)
+            } else {
+                Ok(())
+            }
+        };
+        (stream, writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        io,
+        pin::Pin,
+        sync::Mutex,
+        task::{Context, Poll, Waker},
+    };
+
+    use async_tungstenite::tungstenite::protocol::{CloseFrame, Role};
+    use futures::io::{AsyncRead, AsyncWrite};
+
+    use super::*;
+    use crate::{
+        fragments::Fragmenting,
+        queue::{self, OverflowPolicy},
+        runtime,
+    };
+
+    #[derive(Default)]
+    struct Pipe {
+        bytes: VecDeque<u8>,
+        closed: bool,
+        /// Writes fail as if the peer had gone away.
+        broken: bool,
+        reader: Option<Waker>,
+    }
+
+    /// One end of an in-memory byte stream.
+    struct End {
+        incoming: Arc<Mutex<Pipe>>,
+        outgoing: Arc<Mutex<Pipe>>,
+    }
+
+    fn duplex() -> (End, End) {
+        let (a, b) = (Arc::default(), Arc::default());
+        let first = End {
+            incoming: Arc::clone(&a),
+            outgoing: Arc::clone(&b),
+        };
+        (
+            first,
+            End {
+                incoming: b,
+                outgoing: a,
+            },
+        )
+    }
+
+    impl End {
+        fn shut(&self) {
+            let mut pipe = self.outgoing.lock().unwrap();
+            pipe.closed = true;
+            if let Some(waker) = pipe.reader.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    impl Drop for End {
+        fn drop(&mut self) {
+            self.shut();
+        }
+    }
+
+    impl AsyncRead for End {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            let mut pipe = self.incoming.lock().unwrap();
+            if pipe.bytes.is_empty() && !pipe.closed {
+                pipe.reader = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            let n = buf.len().min(pipe.bytes.len());
+            for (byte, slot) in pipe.bytes.drain(..n).zip(buf.iter_mut()) {
+                *slot = byte;
+            }
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    impl AsyncWrite for End {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let mut pipe = self.outgoing.lock().unwrap();
+            if pipe.broken {
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            pipe.bytes.extend(buf);
+            if let Some(waker) = pipe.reader.take() {
+                waker.wake();
+            }
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.shut();
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// A connection over an in-memory stream, with the client's end of it.
+    async fn connection() -> (WsConnection<End>, WebSocketStream<End>) {
+        let (server, client) = duplex();
+        let (tx, rx) = queue::channel(16, OverflowPolicy::DropNewest);
+        let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        let client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+        (WsConnection::new(ConnectionId(1), server, tx, rx), client)
+    }
+
+    /// A connection that writes streamed messages in fragments, its queue,
+    /// and the client's end of the stream, without a WebSocket on it.
+    async fn fragmenting() -> (WsConnection<Fragmenting<End>>, Tx, End) {
+        let (server, client) = duplex();
+        let marker = Marker::default();
+        let mut server = Fragmenting::new(server, marker.clone());
+        server.start();
+        let (tx, rx) = queue::channel(16, OverflowPolicy::DropNewest);
+        let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        let connection = WsConnection::new(ConnectionId(1), server, tx.clone(), rx);
+        (connection.with_marker(marker), tx, client)
+    }
+
+    /// Runs the connection's writer while the client reads `count` messages.
+    async fn receive<S: AsyncStream>(
+        connection: WsConnection<S>,
+        client: &mut WebSocketStream<End>,
+        count: usize,
+        batch_window: Option<Duration>,
+    ) -> Vec<Message> {
+        let (_stream, writer) = connection.into_parts(
+            Duration::from_secs(5),
+            batch_window,
+            None,
+            CloseCodes::default(),
+            SharedClock::default(),
+        );
+        let reading = client.take(count).map(Result::unwrap).collect::<Vec<_>>();
+        pin_mut!(writer, reading);
+        match future::select(writer, reading).await {
+            future::Either::Right((received, _)) => received,
+            future::Either::Left((ended, _)) => panic!("The writer ended: {:?}", ended),
+        }
+    }
+
+    #[test]
+    fn sent_messages_are_written_in_order() {
+        runtime::block_on(async {
+            let (connection, mut client) = connection().await;
+            connection.send(Message::text("one")).unwrap();
+            connection.send(Message::binary(vec![2])).unwrap();
+            let traffic = Arc::clone(connection.traffic());
+            let received = receive(connection, &mut client, 2, None).await;
+            assert_eq!(received, [Message::text("one"), Message::binary(vec![2])]);
+            assert_eq!(traffic.bytes_out(), 4);
+        });
+    }
+
+    #[test]
+    fn closing_writes_what_was_queued_then_the_close_frame() {
+        runtime::block_on(async {
+            let (connection, mut client) = connection().await;
+            connection.send(Message::text("last words")).unwrap();
+            connection.close(CloseCode::Away, "bye");
+            assert_eq!(
+                connection.send(Message::text("too late")),
+                Err(SendError::Closing)
+            );
+            let received = receive(connection, &mut client, 2, None).await;
+            assert_eq!(
+                received,
+                [
+                    Message::text("last words"),
+                    Message::Close(Some(CloseFrame {
+                        code: CloseCode::Away,
+                        reason: "bye".into(),
+                    })),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn the_handle_queues_for_the_connection() {
+        runtime::block_on(async {
+            let (connection, mut client) = connection().await;
+            let handle = connection.handle().clone();
+            handle.send(Message::text("via the handle")).unwrap();
+            let received = receive(connection, &mut client, 1, None).await;
+            assert_eq!(received, [Message::text("via the handle")]);
+        });
+    }
+
+    #[test]
+    fn next_reads_what_the_client_sent() {
+        runtime::block_on(async {
+            let (mut connection, mut client) = connection().await;
+            client.send(Message::text("hello")).await.unwrap();
+            let msg = connection.next().await.unwrap().unwrap();
+            assert_eq!(msg, Message::text("hello"));
+            drop(client);
+            assert!(!matches!(connection.next().await, Some(Ok(_))));
+        });
+    }
+
+    #[test]
+    fn a_failing_write_ends_the_writer_with_the_reason() {
+        runtime::block_on(async {
+            let (connection, client) = connection().await;
+            client.get_ref().incoming.lock().unwrap().broken = true;
+            connection.send(Message::text("lost")).unwrap();
+            let (_stream, writer) = connection.into_parts(
+                Duration::from_secs(5),
+                None,
+                None,
+                CloseCodes::default(),
+                SharedClock::default(),
+            );
+            let reason = writer.await.unwrap_err();
+            assert!(matches!(reason, DisconnectReason::Reset(_)), "{:?}", reason);
+            assert!(reason.is_transient());
+        });
+    }
+
+    #[test]
+    fn texts_within_the_batch_window_are_written_as_one_frame() {
+        runtime::block_on(async {
+            let (connection, mut client) = connection().await;
+            for text in ["one", "two", "three"].iter() {
+                connection.send(Message::text(*text)).unwrap();
+            }
+            connection.send(Message::binary(vec![4])).unwrap();
+            let window = Some(Duration::from_millis(50));
+            let received = receive(connection, &mut client, 2, window).await;
+            let texts = ["one".to_string(), "two".to_string(), "three".to_string()];
+            assert_eq!(
+                received,
+                [protocol::batch_message(&texts), Message::binary(vec![4])]
+            );
+        });
+    }
+
+    #[test]
+    fn streamed_messages_are_written_as_fragments() {
+        runtime::block_on(async {
+            let (connection, tx, mut client) = fragmenting().await;
+            let mut streaming = tx.start_stream().await.unwrap();
+            tx.send(Message::text("after")).unwrap();
+            for chunk in ["ab", "cd", "ef"].iter() {
+                streaming.send(chunk.as_bytes().to_vec()).await.unwrap();
+            }
+            streaming.finish();
+            let (_stream, writer) = connection.into_parts(
+                Duration::from_secs(5),
+                None,
+                None,
+                CloseCodes::default(),
+                SharedClock::default(),
+            );
+            let mut expected = vec![0x02, 2, b'a', b'b', 0x00, 2, b'c', b'd'];
+            expected.extend_from_slice(&[0x80, 2, b'e', b'f', 0x81, 5]);
+            expected.extend_from_slice(b"after");
+            let mut written = vec![0; expected.len()];
+            let reading = client.read_exact(&mut written);
+            pin_mut!(writer);
+            if let future::Either::Left((ended, _)) = future::select(writer, reading).await {
+                panic!("The writer ended: {:?}", ended);
+            }
+            assert_eq!(written, expected);
+        });
+    }
+
+    #[test]
+    fn streamed_messages_reach_the_client_whole() {
+        runtime::block_on(async {
+            let (connection, tx, client) = fragmenting().await;
+            let mut client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+            let payload: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+            let streaming = async {
+                let mut streaming = tx.start_stream().await.unwrap();
+                tx.send(Message::text("after")).unwrap();
+                // More fragments than the queue holds at a time.
+                for chunk in payload.chunks(1000) {
+                    streaming.send(chunk.to_vec()).await.unwrap();
+                }
+                streaming.finish();
+            };
+            let receiving = receive(connection, &mut client, 2, None);
+            let (received, ()) = future::join(receiving, streaming).await;
+            assert_eq!(
+                received,
+                [Message::binary(payload.clone()), Message::text("after")]
+            );
+        });
+    }
+}
@@ -0,0 +1,466 @@
+//! permessage-deflate compression (RFC 7692), with the `deflate` feature.
+//!
+//! tungstenite 0.15 doesn't implement any extension and refuses frames with
+//! a reserved bit set, so compression happens underneath it. `Deflate` wraps
+//! the socket; once `start` is called after a handshake that negotiated the
+//! extension, compressed messages from the client are inflated and handed
+//! to tungstenite as plain frames, and every single-frame message the server
+//! writes is compressed if that makes it smaller. Fragmented messages go out
+//! uncompressed, which the extension allows.
+//!
+//! The server always asks for no context takeover in either direction, so
+//! each message is compressed on its own and nothing is kept between them.
+
+use std::{
+    io::{self, Cursor},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{
+    io::{AsyncRead, AsyncWrite},
+    ready,
+};
+use miniz_oxide::{
+    deflate::compress_to_vec,
+    inflate::{
+        core::{decompress, inflate_flags, DecompressorOxide},
+        TINFLStatus,
+    },
+};
+
+use crate::runtime::AsyncStream;
+
+/// The `Sec-WebSocket-Extensions` value accepting an offer.
+pub(crate) const RESPONSE: &str =
+    "permessage-deflate; server_no_context_takeover; client_no_context_takeover";
+
+/// The end of an empty stored block, which senders leave off each message,
+/// followed by an empty final block so the inflater knows it is done.
+const TAIL: [u8; 6] = [0x00, 0x00, 0xff, 0xff, 0x03, 0x00];
+
+/// The compression level, from 0 to 10.
+const LEVEL: u8 = 6;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+
+/// Whether any of the client's `offers`, the values of its
+/// `Sec-WebSocket-Extensions` headers, is a permessage-deflate offer the
+/// server can accept. Offers asking the server for a smaller window than
+/// the full 32KB, or with parameters it doesn't know, are declined.
+pub(crate) fn accept<'a>(mut offers: impl Iterator<Item = &'a str>) -> bool {
+    offers.any(|value| {
+        value.split(',').any(|offer| {
+            let mut params = offer.split(';').map(str::trim);
+            params.next() == Some("permessage-deflate")
+                && params.all(|param| {
+                    let (name, value) = match param.split_once('=') {
+                        Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                        None => (param, None),
+                    };
+                    match name {
+                        "server_no_context_takeover" | "client_no_context_takeover" => {
+                            value.is_none()
+                        }
+                        "client_max_window_bits" => value.is_none_or(|bits| {
+                            bits.parse::<u8>()
+                                .is_ok_and(|bits| (8..=15).contains(&bits))
+                        }),
+                        "server_max_window_bits" => value == Some("15"),
+                        _ => false,
+                    }
+                })
+        })
+    })
+}
+
+/// A frame header, as far as compressing its frame is concerned.
+struct Header {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    /// Length of the header itself.
+    len: usize,
+    payload: u64,
+}
+
+impl Header {
+    /// Parses the header at the start of `buf`, if all of it is there.
+    fn parse(buf: &[u8]) -> Option<Header> {
+        let (first, second) = (*buf.first()?, *buf.get(1)?);
+        let (payload, mut len) = match second & 0x7f {
+            126 => (
+                u64::from(u16::from_be_bytes([*buf.get(2)?, *buf.get(3)?])),
+                4,
+            ),
+            127 => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(buf.get(2..10)?);
+                (u64::from_be_bytes(bytes), 10)
+            }
+            n => (u64::from(n), 2),
+        };
+        let mask = match second & 0x80 {
+            0 => None,
+            _ => {
+                let key = buf.get(len..len + 4)?;
+                len += 4;
+                Some([key[0], key[1], key[2], key[3]])
+            }
+        };
+        Some(Header {
+            fin: first & 0x80 != 0,
+            rsv1: first & 0x40 != 0,
+            opcode: first & 0x0f,
+            mask,
+            len,
+            payload,
+        })
+    }
+
+    /// The length of the whole frame.
+    fn frame_len(&self) -> u64 {
+        self.len as u64 + self.payload
+    }
+}
+
+/// Appends a frame carrying `payload` to `out`, masked with zeroes if
+/// `masked` so the payload is written as it is.
+fn write_frame(out: &mut Vec<u8>, fin: bool, rsv1: bool, opcode: u8, masked: bool, payload: &[u8]) {
+    out.push(if fin { 0x80 } else { 0 } | if rsv1 { 0x40 } else { 0 } | opcode);
+    let mask = if masked { 0x80 } else { 0 };
+    match payload.len() {
+        len if len < 126 => out.push(mask | len as u8),
+        len if len <= usize::from(u16::MAX) => {
+            out.push(mask | 126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(mask | 127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    if masked {
+        out.extend_from_slice(&[0; 4]);
+    }
+    out.extend_from_slice(payload);
+}
+
+/// Compresses a whole message. Ending the stream with a final block is
+/// allowed, and the empty stored block after it leaves the `0x00` the
+/// receiver expects in place of the tail it appends.
+pub(crate) fn compress(payload: &[u8]) -> Vec<u8> {
+    let mut compressed = compress_to_vec(payload, LEVEL);
+    compressed.push(0x00);
+    compressed
+}
+
+/// Inflates a whole compressed message. Inflating stops once the output
+/// passes `limit`, leaving tungstenite to refuse the message as too big.
+pub(crate) fn inflate(compressed: &[u8], limit: Option<usize>) -> io::Result<Vec<u8>> {
+    let mut input = compressed.to_vec();
+    input.extend_from_slice(&TAIL);
+    let mut decompressor = Box::<DecompressorOxide>::default();
+    let mut output = vec![0; input.len() * 2];
+    let (mut read, mut written) = (0, 0);
+    loop {
+        let (status, more_read, more_written) = {
+            let mut cursor = Cursor::new(output.as_mut_slice());
+            cursor.set_position(written as u64);
+            let flags = inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF;
+            decompress(&mut decompressor, &input[read..], &mut cursor, flags)
+        };
+        read += more_read;
+        written += more_written;
+        match status {
+            TINFLStatus::Done => break,
+            TINFLStatus::HasMoreOutput if limit.is_some_and(|limit| written > limit) => break,
+            TINFLStatus::HasMoreOutput => output.resize(output.len() * 2, 0),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid permessage-deflate data",
+                ))
+            }
+        }
+    }
+    output.truncate(written);
+    Ok(output)
+}
+
+/// A server-side socket that compresses and inflates messages once
+/// `start`ed, and passes everything through unchanged until then.
+pub(crate) struct Deflate<S> {
+    inner: S,
+    active: bool,
+    max_message_size: Option<usize>,
+    max_frame_size: Option<usize>,
+    /// Read from the socket but not yet a whole frame.
+    read_raw: Vec<u8>,
+    /// Ready for tungstenite to read, from `read_pos` on.
+    read_ready: Vec<u8>,
+    read_pos: usize,
+    /// The opcode and compressed payload of a fragmented message still
+    /// being read.
+    message: Option<(u8, Vec<u8>)>,
+    /// Written by tungstenite but not yet a whole frame.
+    write_raw: Vec<u8>,
+    /// Ready for the socket, from `write_pos` on.
+    write_ready: Vec<u8>,
+    write_pos: usize,
+}
+
+impl<S: AsyncStream> Deflate<S> {
+    /// Wraps `inner`, inflating messages up to `max_message_size` and
+    /// handing them on in frames of at most `max_frame_size`.
+    pub(crate) fn new(
+        inner: S,
+        max_message_size: Option<usize>,
+        max_frame_size: Option<usize>,
+    ) -> Self {
+        Deflate {
+            inner,
+            active: false,
+            max_message_size,
+            max_frame_size,
+            read_raw: Vec::new(),
+            read_ready: Vec::new(),
+            read_pos: 0,
+            message: None,
+            write_raw: Vec::new(),
+            write_ready: Vec::new(),
+            write_pos: 0,
+        }
+    }
+
+    /// Starts compressing, once the handshake has negotiated it.
+    pub(crate) fn start(&mut self) {
+        self.active = true;
+    }
+
+    /// Handles a whole frame from the client.
+    fn inbound(&mut self, header: &Header, frame: &[u8]) -> io::Result<()> {
+        let mut payload = frame[header.len..].to_vec();
+        if let Some(mask) = header.mask {
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+        }
+        let opcode = match (header.opcode, &mut self.message) {
+            (TEXT | BINARY, None) if header.rsv1 => {
+                self.message = Some((header.opcode, payload));
+                header.opcode
+            }
+            (CONTINUATION, Some((opcode, compressed))) if !header.rsv1 => {
+                compressed.extend_from_slice(&payload);
+                *opcode
+            }
+            // Everything else is left for tungstenite, including frames it
+            // is to refuse.
+            _ => {
+                self.read_ready.extend_from_slice(frame);
+                return Ok(());
+            }
+        };
+        let compressed_len = self.message.as_ref().map_or(0, |(_, data)| data.len());
+        if self
+            .max_message_size
+            .is_some_and(|limit| compressed_len > limit)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "compressed message over the size limit",
+            ));
+        }
+        if !header.fin {
+            return Ok(());
+        }
+
+        let (_, compressed) = self.message.take().unwrap_or_default();
+        let message = inflate(&compressed, self.max_message_size)?;
+        let chunk = self.max_frame_size.unwrap_or(usize::MAX).max(1);
+        let mut chunks = message.chunks(chunk).peekable();
+        let mut first = true;
+        loop {
+            let payload = chunks.next().unwrap_or_default();
+            let fin = chunks.peek().is_none();
+            let opcode = if first { opcode } else { CONTINUATION };
+            write_frame(&mut self.read_ready, fin, false, opcode, true, payload);
+            first = false;
+            if fin {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Handles a whole frame from tungstenite.
+    fn outbound(&mut self, header: &Header, frame: &[u8]) {
+        let payload = &frame[header.len..];
+        if header.fin && matches!(header.opcode, TEXT | BINARY) {
+            let compressed = compress(payload);
+            if compressed.len() < payload.len() {
+                write_frame(
+                    &mut self.write_ready,
+                    true,
+                    true,
+                    header.opcode,
+                    false,
+                    &compressed,
+                );
+                return;
+            }
+        }
+        self.write_ready.extend_from_slice(frame);
+    }
+
+    /// Writes out what is ready for the socket.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_ready.len() {
+            let buf = &self.write_ready[self.write_pos..];
+            match ready!(Pin::new(&mut self.inner).poll_write(cx, buf))? {
+                0 => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                n => self.write_pos += n,
+            }
+        }
+        self.write_ready.clear();
+        self.write_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncStream> AsyncRead for Deflate<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        loop {
+            if this.read_pos < this.read_ready.len() {
+                let ready = &this.read_ready[this.read_pos..];
+                let n = buf.len().min(ready.len());
+                buf[..n].copy_from_slice(&ready[..n]);
+                this.read_pos += n;
+                return Poll::Ready(Ok(n));
+            }
+            this.read_ready.clear();
+            this.read_pos = 0;
+            if !this.active {
+                return Pin::new(&mut this.inner).poll_read(cx, buf);
+            }
+
+            if let Some(header) = Header::parse(&this.read_raw) {
+                let too_big = this
+                    .max_frame_size
+                    .is_some_and(|limit| header.payload > limit as u64);
+                if too_big {
+                    // tungstenite refuses the frame from its header alone,
+                    // so it needn't be buffered here.
+                    this.active = false;
+                    this.read_ready = std::mem::take(&mut this.read_raw);
+                    continue;
+                }
+                if this.read_raw.len() as u64 >= header.frame_len() {
+                    let frame: Vec<u8> =
+                        this.read_raw.drain(..header.frame_len() as usize).collect();
+                    this.inbound(&header, &frame)?;
+                    continue;
+                }
+            }
+            let mut chunk = [0; 4096];
+            match ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))? {
+                // A frame cut short is tungstenite's to report.
+                0 => {
+                    this.active = false;
+                    this.read_ready = std::mem::take(&mut this.read_raw);
+                    if this.read_ready.is_empty() {
+                        return Poll::Ready(Ok(0));
+                    }
+                }
+                n => this.read_raw.extend_from_slice(&chunk[..n]),
+            }
+        }
+    }
+}
+
+impl<S: AsyncStream> AsyncWrite for Deflate<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if !this.active {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        // What was compressed before is written first, so a slow client
+        // holds tungstenite back instead of filling this buffer.
+        ready!(this.poll_drain(cx))?;
+        this.write_raw.extend_from_slice(buf);
+        while let Some(header) = Header::parse(&this.write_raw) {
+            if (this.write_raw.len() as u64) < header.frame_len() {
+                break;
+            }
+            let frame: Vec<u8> = this
+                .write_raw
+                .drain(..header.frame_len() as usize)
+                .collect();
+            this.outbound(&header, &frame);
+        }
+        // Anything not written now is by the next write or flush.
+        let _ = this.poll_drain(cx)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offers_the_server_can_honour_are_accepted() {
+        let accepts = |offer: &str| accept(std::iter::once(offer));
+        assert!(accepts("permessage-deflate"));
+        assert!(accepts("permessage-deflate; client_max_window_bits"));
+        assert!(accepts("permessage-deflate; server_max_window_bits=15"));
+        assert!(accepts(
+            "permessage-deflate; server_max_window_bits=10, permessage-deflate"
+        ));
+        assert!(!accepts("permessage-deflate; server_max_window_bits=10"));
+        assert!(!accepts("permessage-deflate; client_max_window_bits=20"));
+        assert!(!accepts("permessage-deflate; future_param"));
+        assert!(!accepts("x-webkit-deflate-frame"));
+        assert!(!accept(std::iter::empty()));
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let message = "all work and no play ".repeat(100).into_bytes();
+        let compressed = compress(&message);
+        assert!(compressed.len() < message.len());
+        assert_eq!(inflate(&compressed, None).unwrap(), message);
+        // The RFC's own example, flushed rather than finished.
+        let hello = [0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00];
+        assert_eq!(inflate(&hello, None).unwrap(), b"Hello");
+    }
+
+    #[test]
+    fn inflating_stops_past_the_limit() {
+        let compressed = compress(&[0; 100_000]);
+        let inflated = inflate(&compressed, Some(1000)).unwrap();
+        assert!(inflated.len() > 1000 && inflated.len() < 100_000);
+    }
+}
@@ -0,0 +1,122 @@
+//! Server load reported through Bevy's diagnostics.
+
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+
+use bevy::{
+    core::Time,
+    diagnostic::{Diagnostic, DiagnosticId, Diagnostics},
+    prelude::*,
+};
+
+use crate::Connections;
+
+/// Counters updated by the connection tasks and read by
+/// `WsDiagnosticsPlugin`. Cloning shares the same counters.
+#[derive(Debug, Clone, Default)]
+pub struct WsStats(Arc<Counters>);
+
+#[derive(Debug, Default)]
+struct Counters {
+    connections: AtomicUsize,
+    messages: AtomicU64,
+}
+
+impl WsStats {
+    /// Number of peers currently in the peer map.
+    pub fn connections(&self) -> usize {
+        self.0.connections.load(Ordering::Relaxed)
+    }
+
+    /// Total number of messages received since the server started.
+    pub fn messages(&self) -> u64 {
+        self.0.messages.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_connections(&self, connections: usize) {
+        self.0.connections.store(connections, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_message(&self) {
+        self.0.messages.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Adds the "ws_connections", "ws_messages_per_second" and
+/// "ws_average_latency_ms" diagnostics. Requires `DiagnosticsPlugin` and the
+/// `WsStats` and `Connections` resources inserted by `setup`.
+#[derive(Default)]
+pub struct WsDiagnosticsPlugin;
+
+pub struct WsDiagnosticsState {
+    window_start: f64,
+    window_messages: u64,
+}
+
+impl Plugin for WsDiagnosticsPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_startup_system(Self::setup_system.system())
+            .insert_resource(WsDiagnosticsState {
+                window_start: 0.0,
+                window_messages: 0,
+            })
+            .add_system(update_ws_diagnostics.system());
+    }
+}
+
+impl WsDiagnosticsPlugin {
+    pub const CONNECTIONS: DiagnosticId =
+        DiagnosticId::from_u128(202909248556766013114230369309743614599);
+    pub const MESSAGES_PER_SECOND: DiagnosticId =
+        DiagnosticId::from_u128(111065692380262275930272593285161302547);
+    pub const AVERAGE_LATENCY: DiagnosticId =
+        DiagnosticId::from_u128(263402803114252850358589391608153336071);
+
+    pub fn setup_system(mut diagnostics: ResMut<Diagnostics>) {
+        diagnostics.add(Diagnostic::new(Self::CONNECTIONS, "ws_connections", 20));
+        diagnostics.add(Diagnostic::new(
+            Self::MESSAGES_PER_SECOND,
+            "ws_messages_per_second",
+            20,
+        ));
+        diagnostics.add(Diagnostic::new(
+            Self::AVERAGE_LATENCY,
+            "ws_average_latency_ms",
+            20,
+        ));
+    }
+}
+
+/// Samples `WsStats` into the diagnostics. The message rate is measured over
+/// one second windows, since frames are usually far shorter than that.
+pub fn update_ws_diagnostics(
+    mut diagnostics: ResMut<Diagnostics>,
+    time: Res<Time>,
+    stats: Res<WsStats>,
+    connections: Res<Connections>,
+    mut state: ResMut<WsDiagnosticsState>,
+) {
+    diagnostics.add_measurement(WsDiagnosticsPlugin::CONNECTIONS, stats.connections() as f64);
+
+    // Only connections that have answered a Ping have a latency yet.
+    let latencies: Vec<f64> = connections
+        .iter()
+        .filter_map(|(_, info)| info.latency_ms)
+        .collect();
+    if !latencies.is_empty() {
+        let average = latencies.iter().sum::<f64>() / latencies.len() as f64;
+        diagnostics.add_measurement(WsDiagnosticsPlugin::AVERAGE_LATENCY, average);
+    }
+
+    let elapsed = time.seconds_since_startup() - state.window_start;
+    if elapsed >= 1.0 {
+        let messages = stats.messages();
+        let rate = (messages - state.window_messages) as f64 / elapsed;
+        diagnostics.add_measurement(WsDiagnosticsPlugin::MESSAGES_PER_SECOND, rate);
+
+        state.window_start = time.seconds_since_startup();
+        state.window_messages = messages;
+    }
+}
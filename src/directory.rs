@@ -0,0 +1,188 @@
+//! Connection metadata for game systems.
+//!
+//! The connection tasks keep a `Directory` of every live connection up to
+//! date, and `snapshot_connections` copies it into the `Connections`
+//! resource once per frame so systems can read it without locking anything.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+    time::Instant,
+};
+
+use bevy::prelude::*;
+use dashmap::DashMap;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{queue::QueueDepth, ConnectionId, Endpoint, Traffic, UserId, WireFormat};
+
+/// What is known about a live connection.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub addr: SocketAddr,
+    pub endpoint: Endpoint,
+    /// How game structs sent to this client should be encoded (see `wire`).
+    pub wire_format: WireFormat,
+    /// The nickname registered with `/nick`, if any.
+    pub name: Option<String>,
+    /// Empty for `Endpoint::Game` connections, which aren't in a room.
+    pub room: String,
+    pub connected_at: Instant,
+    /// When the client last sent anything, including Pongs.
+    pub last_seen: Instant,
+    /// When the client last sent a text or binary message, or connected.
+    /// Pongs only count under `ServerConfig.idle_counts_pongs`.
+    pub last_inbound: Instant,
+    /// Round-trip time measured by the last answered heartbeat Ping.
+    pub latency_ms: Option<f64>,
+    /// The user the client authenticated as, when `ServerConfig.auth` is
+    /// set.
+    pub user: Option<UserId>,
+    /// Bytes moved so far. Shared with the connection task, so even a
+    /// snapshot reads the live counts.
+    pub traffic: Arc<Traffic>,
+    /// Messages waiting to be written to the client, read live like
+    /// `traffic`. A depth that stays high marks a client falling behind.
+    pub queue_depth: QueueDepth,
+    /// Until when the client's chat is dropped, after flooding (see
+    /// `ServerConfig.flood_mute`) or a `MuteRequest`.
+    pub muted_until: Option<Instant>,
+    /// The protocol version the client last declared (see `protocol`).
+    pub protocol_version: Option<u32>,
+    /// Labels set by the app with `add_tag`, e.g. a team or region, for
+    /// addressing groups with `OutboundMessage::ToTag`.
+    pub tags: HashSet<String>,
+}
+
+/// Picks connections by what is known about them, as plain data so it can
+/// be sent through the outbox (see `OutboundMessage::Where`) or from another
+/// process.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Selector {
+    /// Every connection.
+    All,
+    /// Connections to this endpoint.
+    Endpoint(Endpoint),
+    /// Chat clients in this room.
+    Room(String),
+    /// Clients registered under this nickname.
+    Name(String),
+    /// Connections of this authenticated user.
+    User(UserId),
+    /// Connections carrying this tag.
+    Tag(String),
+    Not(Box<Selector>),
+    /// Connections every one of the selectors picks.
+    AllOf(Vec<Selector>),
+    /// Connections any of the selectors picks.
+    AnyOf(Vec<Selector>),
+}
+
+impl Selector {
+    pub fn matches(&self, info: &ConnectionInfo) -> bool {
+        match self {
+            Selector::All => true,
+            Selector::Endpoint(endpoint) => info.endpoint == *endpoint,
+            Selector::Room(room) => info.room == *room,
+            Selector::Name(name) => info.name.as_ref() == Some(name),
+            Selector::User(user) => info.user.as_ref() == Some(user),
+            Selector::Tag(tag) => info.tags.contains(tag),
+            Selector::Not(selector) => !selector.matches(info),
+            Selector::AllOf(selectors) => selectors.iter().all(|s| s.matches(info)),
+            Selector::AnyOf(selectors) => selectors.iter().any(|s| s.matches(info)),
+        }
+    }
+}
+
+/// Live view of every connection, written by the connection tasks.
+pub type Directory = Arc<DashMap<ConnectionId, ConnectionInfo>>;
+
+/// Applies `change` to the entry for `id`, if it is still connected.
+pub(crate) fn update(
+    directory: &Directory,
+    id: ConnectionId,
+    change: impl FnOnce(&mut ConnectionInfo),
+) {
+    if let Some(mut info) = directory.get_mut(&id) {
+        change(&mut info);
+    }
+}
+
+/// Tags the connection `id`. Returns false if it isn't connected.
+pub fn add_tag(directory: &Directory, id: ConnectionId, tag: impl Into<String>) -> bool {
+    match directory.get_mut(&id) {
+        Some(mut info) => {
+            info.tags.insert(tag.into());
+            true
+        }
+        None => false,
+    }
+}
+
+/// Removes a tag from the connection `id`. Returns whether it had the tag.
+pub fn remove_tag(directory: &Directory, id: ConnectionId, tag: &str) -> bool {
+    directory
+        .get_mut(&id)
+        .is_some_and(|mut info| info.tags.remove(tag))
+}
+
+/// Resource holding a snapshot of the `Directory`, refreshed every frame.
+/// Cloning shares the snapshot.
+#[derive(Debug, Clone, Default)]
+pub struct Connections(Arc<HashMap<ConnectionId, ConnectionInfo>>);
+
+impl Connections {
+    pub fn get(&self, id: ConnectionId) -> Option<&ConnectionInfo> {
+        self.0.get(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&ConnectionId, &ConnectionInfo)> {
+        self.0.iter()
+    }
+
+    /// The connections carrying `tag`.
+    pub fn tagged<'a>(&'a self, tag: &'a str) -> impl Iterator<Item = ConnectionId> + 'a {
+        self.0
+            .iter()
+            .filter(move |(_, info)| info.tags.contains(tag))
+            .map(|(id, _)| *id)
+    }
+
+    /// Every connection, the one that has gone longest without sending a
+    /// message first.
+    pub fn most_idle_first(&self) -> Vec<(ConnectionId, &ConnectionInfo)> {
+        let mut connections: Vec<_> = self.0.iter().map(|(id, info)| (*id, info)).collect();
+        connections.sort_by_key(|(id, info)| (info.last_inbound, *id));
+        connections
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// The connections that haven't sent a message since `cutoff`.
+pub fn idle_since(directory: &Directory, cutoff: Instant) -> Vec<ConnectionId> {
+    directory
+        .iter()
+        .filter(|info| info.last_inbound < cutoff)
+        .map(|info| *info.key())
+        .collect()
+}
+
+/// Refreshes the `Connections` resource from the `Directory`.
+pub fn snapshot_connections(directory: Res<Directory>, mut connections: ResMut<Connections>) {
+    let snapshot = directory
+        .iter()
+        .map(|entry| (*entry.key(), entry.value().clone()))
+        .collect();
+    *connections = Connections(Arc::new(snapshot));
+}
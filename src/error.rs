@@ -0,0 +1,53 @@
+//! Why a server couldn't start or stopped early.
+//!
+//! Failures of a single connection, such as a refused handshake, never end
+//! the server; they are logged and reported as a `DisconnectReason`.
+
+use std::{error::Error, fmt, io};
+
+#[derive(Debug)]
+pub enum ServerError {
+    /// A listening socket couldn't be bound, e.g. because the port is taken.
+    Bind { addr: String, source: io::Error },
+    /// The TLS certificate chain or private key couldn't be loaded.
+    Tls(io::Error),
+    /// Accepting a connection failed, which ended the accept loop.
+    Accept(io::Error),
+    /// Any other IO failure, e.g. writing the history file.
+    Io(io::Error),
+}
+
+impl ServerError {
+    /// The IO error underneath.
+    pub fn io_error(&self) -> &io::Error {
+        match self {
+            ServerError::Bind { source, .. } => source,
+            ServerError::Tls(e) | ServerError::Accept(e) | ServerError::Io(e) => e,
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::Bind { addr, source } => {
+                write!(f, "couldn't listen on {}: {}", addr, source)
+            }
+            ServerError::Tls(e) => write!(f, "couldn't load the TLS certificate: {}", e),
+            ServerError::Accept(e) => write!(f, "accepting connections failed: {}", e),
+            ServerError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for ServerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.io_error())
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(e: io::Error) -> Self {
+        ServerError::Io(e)
+    }
+}
@@ -0,0 +1,99 @@
+//! Hooks for turning away connections, for handling messages with
+//! per-connection state and for rewriting or dropping relayed messages.
+
+use std::{fmt, sync::Arc};
+
+use async_tungstenite::tungstenite::{
+    handshake::server::Request, http::StatusCode, protocol::Message,
+};
+
+use crate::{ConnState, ConnectionId};
+
+/// Runs on every message before it is relayed to the room. Returning `None`
+/// drops the message; returning `Some` relays the (possibly rewritten)
+/// message instead. Chat text is seen before the sender's name is added.
+#[derive(Clone)]
+pub struct MessageFilter(Arc<dyn Fn(ConnectionId, &Message) -> Option<Message> + Send + Sync>);
+
+impl MessageFilter {
+    pub fn new(
+        filter: impl Fn(ConnectionId, &Message) -> Option<Message> + Send + Sync + 'static,
+    ) -> Self {
+        MessageFilter(Arc::new(filter))
+    }
+
+    pub fn apply(&self, id: ConnectionId, msg: &Message) -> Option<Message> {
+        (self.0)(id, msg)
+    }
+}
+
+/// Relays every message unchanged.
+impl Default for MessageFilter {
+    fn default() -> Self {
+        MessageFilter::new(|_, msg| Some(msg.clone()))
+    }
+}
+
+impl fmt::Debug for MessageFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MessageFilter")
+    }
+}
+
+/// Runs on every text and binary message a client sends, before it is
+/// handled or relayed, with the connection's own `ConnState`. A returned
+/// message is sent back to that client.
+#[derive(Clone)]
+pub struct MessageHandler(
+    Arc<dyn Fn(ConnectionId, &Message, &mut ConnState) -> Option<Message> + Send + Sync>,
+);
+
+impl MessageHandler {
+    pub fn new(
+        handler: impl Fn(ConnectionId, &Message, &mut ConnState) -> Option<Message>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        MessageHandler(Arc::new(handler))
+    }
+
+    pub fn handle(
+        &self,
+        id: ConnectionId,
+        msg: &Message,
+        state: &mut ConnState,
+    ) -> Option<Message> {
+        (self.0)(id, msg, state)
+    }
+}
+
+impl fmt::Debug for MessageHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MessageHandler")
+    }
+}
+
+/// Decides whether a handshake may proceed, given the client's request with
+/// its path, query string and headers. Returning `Err` refuses the
+/// connection with that HTTP status and body.
+#[derive(Clone)]
+pub struct AcceptCallback(Arc<dyn Fn(&Request) -> Result<(), (StatusCode, String)> + Send + Sync>);
+
+impl AcceptCallback {
+    pub fn new(
+        callback: impl Fn(&Request) -> Result<(), (StatusCode, String)> + Send + Sync + 'static,
+    ) -> Self {
+        AcceptCallback(Arc::new(callback))
+    }
+
+    pub fn check(&self, request: &Request) -> Result<(), (StatusCode, String)> {
+        (self.0)(request)
+    }
+}
+
+impl fmt::Debug for AcceptCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AcceptCallback")
+    }
+}
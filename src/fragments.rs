@@ -0,0 +1,156 @@
+//! Fragmented writes for streamed messages (see `send_streamed`).
+//!
+//! tungstenite 0.15 writes every message as a single frame, so the writer
+//! sends each fragment of a streamed message as a binary message of its own,
+//! and `Fragmenting`, underneath tungstenite, rewrites the first byte of
+//! those frames: the first fragment keeps the binary opcode but loses FIN,
+//! and the rest become continuation frames, with FIN set on the last. The
+//! writer tells it which position the next binary frame has through a
+//! `Marker`.
+
+use std::{
+    io::{self, Cursor},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use async_tungstenite::tungstenite::protocol::frame::FrameHeader;
+use futures::{
+    io::{AsyncRead, AsyncWrite},
+    ready,
+};
+
+use crate::{queue::Position, runtime::AsyncStream};
+
+const BINARY: u8 = 0x2;
+
+/// Where the next binary frame goes in a streamed message, set by the
+/// writer before sending it. Each message is written out before the next is
+/// sent, so one slot is enough.
+#[derive(Clone, Default)]
+pub(crate) struct Marker(Arc<Mutex<Option<Position>>>);
+
+impl Marker {
+    pub(crate) fn mark(&self, position: Position) {
+        *self.0.lock().unwrap() = Some(position);
+    }
+
+    fn take(&self) -> Option<Position> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+/// The FIN bit and opcode of a fragment's frame.
+fn first_byte(position: Position) -> u8 {
+    match position {
+        Position::First => BINARY,
+        Position::Middle => 0x00,
+        Position::Last => 0x80,
+    }
+}
+
+/// The length of the frame at the start of `buf`, once all of it is there.
+fn whole_frame(buf: &[u8]) -> Option<usize> {
+    let mut cursor = Cursor::new(buf);
+    let (_, payload) = FrameHeader::parse(&mut cursor).ok()??;
+    let len = cursor.position() as usize + payload as usize;
+    (buf.len() >= len).then_some(len)
+}
+
+/// A server-side socket that turns marked binary frames into fragments once
+/// `start`ed, and passes everything through unchanged until then.
+pub(crate) struct Fragmenting<S> {
+    inner: S,
+    active: bool,
+    marker: Marker,
+    /// Written by tungstenite but not yet a whole frame.
+    write_raw: Vec<u8>,
+    /// Ready for the socket, from `write_pos` on.
+    write_ready: Vec<u8>,
+    write_pos: usize,
+}
+
+impl<S: AsyncStream> Fragmenting<S> {
+    pub(crate) fn new(inner: S, marker: Marker) -> Self {
+        Fragmenting {
+            inner,
+            active: false,
+            marker,
+            write_raw: Vec::new(),
+            write_ready: Vec::new(),
+            write_pos: 0,
+        }
+    }
+
+    /// Starts looking for frames, once the handshake is over.
+    pub(crate) fn start(&mut self) {
+        self.active = true;
+    }
+
+    #[cfg(feature = "deflate")]
+    pub(crate) fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Writes out what is ready for the socket.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_ready.len() {
+            let buf = &self.write_ready[self.write_pos..];
+            match ready!(Pin::new(&mut self.inner).poll_write(cx, buf))? {
+                0 => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                n => self.write_pos += n,
+            }
+        }
+        self.write_ready.clear();
+        self.write_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncStream> AsyncRead for Fragmenting<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncStream> AsyncWrite for Fragmenting<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if !this.active {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        ready!(this.poll_drain(cx))?;
+        this.write_raw.extend_from_slice(buf);
+        while let Some(len) = whole_frame(&this.write_raw) {
+            let start = this.write_ready.len();
+            this.write_ready.extend(this.write_raw.drain(..len));
+            if this.write_ready[start] & 0x0f == BINARY {
+                if let Some(position) = this.marker.take() {
+                    this.write_ready[start] = first_byte(position);
+                }
+            }
+        }
+        // Anything not written now is by the next write or flush.
+        let _ = this.poll_drain(cx)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
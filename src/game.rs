@@ -0,0 +1,217 @@
+//! A minimal game-sync layer on top of the connection entities: every
+//! connection gets a `Player` that moves with `Move` commands, and
+//! `tick_message` snapshots every player's position for a game tick.
+//!
+//! Broadcast a `tick_message` from a system on a `FixedTimestep` to control
+//! how often the state goes out, as the example app's `game_loop` does.
+
+use bevy::prelude::*;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use async_tungstenite::tungstenite::protocol::Message;
+
+use crate::{ClientCommand, ClientCommandReceived, Connection, ConnectionEntities, ConnectionId};
+
+/// The player controlled by a connection.
+#[derive(Debug, Clone)]
+pub struct Player {
+    pub id: ConnectionId,
+    pub position: Vec2,
+}
+
+/// Gives every new connection entity a `Player` at the origin.
+pub fn spawn_players(
+    mut commands: Commands,
+    connections: Query<(Entity, &Connection), Added<Connection>>,
+) {
+    for (entity, connection) in connections.iter() {
+        commands.entity(entity).insert(Player {
+            id: connection.id,
+            position: Vec2::ZERO,
+        });
+    }
+}
+
+/// Applies `Move` commands to the sender's player.
+pub fn apply_moves(
+    mut commands: EventReader<ClientCommandReceived>,
+    entities: Res<ConnectionEntities>,
+    mut players: Query<&mut Player>,
+) {
+    for received in commands.iter() {
+        if let ClientCommand::Move { dx, dy } = received.command {
+            let player = entities
+                .0
+                .get(&received.id)
+                .and_then(|entity| players.get_mut(*entity).ok());
+            if let Some(mut player) = player {
+                player.position += Vec2::new(dx, dy);
+            }
+        }
+    }
+}
+
+/// How `tick_message` encodes a tick.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TickFormat {
+    /// A text frame listing every player's position.
+    #[default]
+    Text,
+    /// A binary frame: the tick number as a big-endian `u64`, then for every
+    /// player its id as a `u64` and its position as two `f32`s, all
+    /// big-endian.
+    Binary,
+}
+
+/// A snapshot of every player's position for game tick `tick`.
+pub fn tick_message(tick: u64, players: &[&Player], format: TickFormat) -> Message {
+    match format {
+        TickFormat::Text => Message::text(tick_text(tick, players)),
+        TickFormat::Binary => {
+            let mut data = Vec::with_capacity(8 + players.len() * 16);
+            data.extend_from_slice(&tick.to_be_bytes());
+            for player in players {
+                data.extend_from_slice(&player.id.0.to_be_bytes());
+                data.extend_from_slice(&player.position.x.to_be_bytes());
+                data.extend_from_slice(&player.position.y.to_be_bytes());
+            }
+            Message::binary(data)
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+#[derive(Serialize)]
+struct PlayerState {
+    id: u64,
+    x: f32,
+    y: f32,
+}
+
+#[cfg(feature = "serde")]
+fn player_states(players: &[&Player]) -> Vec<PlayerState> {
+    players
+        .iter()
+        .map(|player| PlayerState {
+            id: player.id.0,
+            x: player.position.x,
+            y: player.position.y,
+        })
+        .collect()
+}
+
+/// The tick as a JSON object, e.g.
+/// `{"type":"tick","tick":7,"players":[{"id":1,"x":0.0,"y":2.5}]}`.
+#[cfg(feature = "serde")]
+fn tick_text(tick: u64, players: &[&Player]) -> String {
+    serde_json::json!({ "type": "tick", "tick": tick, "players": player_states(players) })
+        .to_string()
+}
+
+#[cfg(not(feature = "serde"))]
+fn player_positions(players: &[&Player]) -> String {
+    let positions: Vec<String> = players
+        .iter()
+        .map(|player| format!("{} {} {}", player.id, player.position.x, player.position.y))
+        .collect();
+    positions.join(", ")
+}
+
+/// The tick as a line of text, e.g. `tick 7 #1 0 2.5, #2 -1 0`.
+#[cfg(not(feature = "serde"))]
+fn tick_text(tick: u64, players: &[&Player]) -> String {
+    if players.is_empty() {
+        return format!("tick {}", tick);
+    }
+    format!("tick {} {}", tick, player_positions(players))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use bevy::app::Events;
+
+    use super::*;
+
+    /// An app moving players, with the entity for connection `#1` spawned.
+    fn game_app() -> (App, Entity) {
+        let mut builder = App::build();
+        builder
+            .add_event::<ClientCommandReceived>()
+            .init_resource::<ConnectionEntities>()
+            .add_system(spawn_players.system())
+            .add_system(apply_moves.system());
+        let mut app = builder.app;
+        let entity = app
+            .world
+            .spawn()
+            .insert(Connection {
+                id: ConnectionId(1),
+                addr: "127.0.0.1:4000".parse().unwrap(),
+                connected_at: Instant::now(),
+                subprotocol: None,
+            })
+            .id();
+        app.world
+            .get_resource_mut::<ConnectionEntities>()
+            .unwrap()
+            .0
+            .insert(ConnectionId(1), entity);
+        app.update();
+        (app, entity)
+    }
+
+    #[test]
+    fn moves_show_up_in_the_next_tick() {
+        let (mut app, entity) = game_app();
+        app.world
+            .get_resource_mut::<Events<ClientCommandReceived>>()
+            .unwrap()
+            .send(ClientCommandReceived {
+                id: ConnectionId(1),
+                command: ClientCommand::Move { dx: 1.5, dy: -2.0 },
+            });
+        app.update();
+
+        let player = app.world.get::<Player>(entity).unwrap();
+        assert_eq!(player.position, Vec2::new(1.5, -2.0));
+        let tick = tick_message(3, &[player], TickFormat::Binary);
+        let mut expected = 3u64.to_be_bytes().to_vec();
+        expected.extend_from_slice(&1u64.to_be_bytes());
+        expected.extend_from_slice(&1.5f32.to_be_bytes());
+        expected.extend_from_slice(&(-2.0f32).to_be_bytes());
+        assert_eq!(tick, Message::Binary(expected));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn text_ticks_list_every_player() {
+        let player = Player {
+            id: ConnectionId(7),
+            position: Vec2::new(0.0, 2.5),
+        };
+        assert_eq!(
+            tick_message(7, &[&player], TickFormat::Text),
+            Message::text(r#"{"players":[{"id":7,"x":0.0,"y":2.5}],"tick":7,"type":"tick"}"#)
+        );
+    }
+
+    #[cfg(not(feature = "serde"))]
+    #[test]
+    fn text_ticks_list_every_player() {
+        let player = Player {
+            id: ConnectionId(7),
+            position: Vec2::new(0.0, 2.5),
+        };
+        assert_eq!(
+            tick_message(7, &[&player], TickFormat::Text),
+            Message::text("tick 7 #7 0 2.5")
+        );
+        assert_eq!(
+            tick_message(8, &[], TickFormat::Text),
+            Message::text("tick 8")
+        );
+    }
+}
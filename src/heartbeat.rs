@@ -0,0 +1,161 @@
+//! Per-connection keepalive. Each connection is pinged periodically and
+//! dropped if it stops answering with Pongs. The Pongs also give the
+//! connection's round-trip time.
+
+use std::{
+    convert::TryInto,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use async_tungstenite::tungstenite::protocol::Message;
+
+use crate::{clock::SharedClock, queue::SendError, CloseCodes, DisconnectReason, Tx};
+
+/// Controls how often peers are pinged and how long they may stay silent.
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    /// Time between Pings.
+    pub interval: Duration,
+    /// A peer that hasn't sent a Pong for this long is disconnected.
+    pub timeout: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Heartbeat state shared between a connection's reader and its
+/// `heartbeat` task.
+pub(crate) struct Liveness {
+    clock: SharedClock,
+    started: Instant,
+    last_pong: Mutex<Instant>,
+    /// Payload of the last Ping sent, until its Pong comes back.
+    pending: Mutex<Option<u64>>,
+    /// Set once `heartbeat` has given up on the peer.
+    timed_out: AtomicBool,
+}
+
+impl Liveness {
+    pub(crate) fn new(clock: SharedClock) -> Self {
+        let now = clock.now();
+        Liveness {
+            clock,
+            started: now,
+            last_pong: Mutex::new(now),
+            pending: Mutex::new(None),
+            timed_out: AtomicBool::new(false),
+        }
+    }
+
+    /// Builds a Ping carrying the current time, in microseconds since the
+    /// connection started.
+    fn ping(&self) -> Message {
+        let sent = self.elapsed().as_micros() as u64;
+        *self.pending.lock().unwrap() = Some(sent);
+        Message::Ping(sent.to_be_bytes().to_vec())
+    }
+
+    /// Records a Pong. Any Pong shows the peer is alive, but only the answer
+    /// to our last Ping yields a round-trip time.
+    pub(crate) fn pong(&self, payload: &[u8]) -> Option<Duration> {
+        *self.last_pong.lock().unwrap() = self.clock.now();
+
+        let mut pending = self.pending.lock().unwrap();
+        let sent = u64::from_be_bytes(payload.try_into().ok()?);
+        if *pending != Some(sent) {
+            return None;
+        }
+        *pending = None;
+        Some(self.elapsed() - Duration::from_micros(sent))
+    }
+
+    /// Whether the peer was closed for not answering, even if it managed to
+    /// acknowledge the Close.
+    pub(crate) fn timed_out(&self) -> bool {
+        self.timed_out.load(Ordering::Relaxed)
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.clock.now() - self.started
+    }
+}
+
+/// Pings the peer every `interval` and returns once it has gone `timeout`
+/// without a Pong, or its channel has closed. A peer that timed out is sent
+/// a Close frame, with the code `close_codes` gives, and `CLOSE_TIMEOUT` to
+/// take it. `config` is asked for the settings before every Ping, so they
+/// may change as the connection goes on.
+pub(crate) async fn heartbeat(
+    config: impl Fn() -> HeartbeatConfig,
+    close_codes: impl Fn() -> CloseCodes,
+    tx: Tx,
+    liveness: &Liveness,
+) {
+    loop {
+        let config = config();
+        liveness.clock.sleep(config.interval).await;
+        let last_pong = *liveness.last_pong.lock().unwrap();
+        if liveness.clock.now() - last_pong > config.timeout {
+            liveness.timed_out.store(true, Ordering::Relaxed);
+            crate::close_with_reason(&tx, &DisconnectReason::Timeout, &close_codes());
+            liveness.clock.sleep(crate::CLOSE_TIMEOUT).await;
+            return;
+        }
+        // Once the connection is closing there is nothing more to ping, but
+        // the timeout still ends it if the client never answers the Close.
+        match tx.send(liveness.ping()) {
+            Ok(()) | Err(SendError::Closing) => {}
+            Err(_) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, MockClock};
+
+    fn payload(ping: &Message) -> Vec<u8> {
+        match ping {
+            Message::Ping(payload) => payload.clone(),
+            other => panic!("Expected a Ping, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn the_answer_to_a_ping_gives_the_round_trip() {
+        let clock = MockClock::new();
+        let liveness = Liveness::new(SharedClock::new(clock.clone()));
+        clock.advance(Duration::from_secs(1));
+        let ping = payload(&liveness.ping());
+
+        clock.advance(Duration::from_millis(40));
+        assert_eq!(liveness.pong(&ping), Some(Duration::from_millis(40)));
+        // Answered already, so a repeat says nothing about the round trip.
+        assert_eq!(liveness.pong(&ping), None);
+    }
+
+    #[test]
+    fn spurious_pongs_give_no_round_trip() {
+        let clock = MockClock::new();
+        let liveness = Liveness::new(SharedClock::new(clock.clone()));
+        let ping = payload(&liveness.ping());
+        clock.advance(Duration::from_millis(40));
+
+        assert_eq!(liveness.pong(b"hello"), None);
+        assert_eq!(liveness.pong(&12345u64.to_be_bytes()), None);
+        // They still show the client is alive.
+        assert_eq!(*liveness.last_pong.lock().unwrap(), clock.now());
+        assert_eq!(liveness.pong(&ping), Some(Duration::from_millis(40)));
+    }
+}
@@ -0,0 +1,344 @@
+//! Recent message history for each room, replayed to clients when they
+//! enter it.
+//!
+//! With the `serde` feature the history can also be kept in a file of JSON
+//! lines, one per message, so it survives restarts: `load` reads it back
+//! and `HistoryLog` appends to it.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+#[cfg(feature = "serde")]
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use async_tungstenite::tungstenite::protocol::Message;
+#[cfg(feature = "serde")]
+use bevy::log::warn;
+#[cfg(feature = "serde")]
+use crossbeam_channel::Sender;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "serde")]
+use crate::runtime;
+use crate::Tx;
+
+/// A room's recent messages and the last sequence number handed out in it.
+/// Sequence numbers start again from 1 if the room empties and is reused.
+#[derive(Debug, Default)]
+pub struct RoomHistory {
+    messages: VecDeque<Message>,
+    last_seq: u64,
+}
+
+/// Recent messages, keyed by room name.
+pub type History = Arc<Mutex<HashMap<String, RoomHistory>>>;
+
+/// Appends `msg` to `room`'s history, dropping the oldest entries beyond
+/// `capacity`.
+pub fn record(history: &History, room: &str, msg: &Message, capacity: usize) {
+    let mut history = history.lock().unwrap();
+    push(
+        history.entry(room.to_string()).or_default(),
+        msg.clone(),
+        capacity,
+    );
+}
+
+/// Like `record`, for a message that carries a sequence number: `make`
+/// builds it from `room`'s next number. Returns the message built.
+pub fn record_numbered(
+    history: &History,
+    room: &str,
+    capacity: usize,
+    make: impl FnOnce(u64) -> Message,
+) -> Message {
+    let mut history = history.lock().unwrap();
+    let room = history.entry(room.to_string()).or_default();
+    room.last_seq += 1;
+    let msg = make(room.last_seq);
+    push(room, msg.clone(), capacity);
+    msg
+}
+
+/// The sequence number of the last numbered message in `room`, or 0.
+pub fn last_seq(history: &History, room: &str) -> u64 {
+    history
+        .lock()
+        .unwrap()
+        .get(room)
+        .map_or(0, |room| room.last_seq)
+}
+
+/// Queues every message recorded in `room`, oldest first, on `tx`.
+pub fn replay(history: &History, room: &str, tx: &Tx) {
+    if let Some(room) = history.lock().unwrap().get(room) {
+        for msg in &room.messages {
+            let _ = tx.send(msg.clone());
+        }
+    }
+}
+
+/// Drops `room`'s history, once the room has emptied.
+pub fn forget(history: &History, room: &str) {
+    history.lock().unwrap().remove(room);
+}
+
+fn push(room: &mut RoomHistory, msg: Message, capacity: usize) {
+    room.messages.push_back(msg);
+    while room.messages.len() > capacity {
+        room.messages.pop_front();
+    }
+}
+
+/// One line of a history file.
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    room: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    binary: Option<Vec<u8>>,
+}
+
+#[cfg(feature = "serde")]
+impl Entry {
+    fn new(room: &str, seq: Option<u64>, msg: &Message) -> Option<Entry> {
+        let (text, binary) = match msg {
+            Message::Text(text) => (Some(text.clone()), None),
+            Message::Binary(data) => (None, Some(data.clone())),
+            _ => return None,
+        };
+        Some(Entry {
+            room: room.to_string(),
+            seq,
+            text,
+            binary,
+        })
+    }
+
+    fn into_message(self) -> Option<Message> {
+        match (self.text, self.binary) {
+            (Some(text), _) => Some(Message::Text(text)),
+            (None, Some(data)) => Some(Message::Binary(data)),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Reads the history saved at `path`, keeping the last `capacity` messages
+/// of each room. A missing file gives an empty history, and lines that
+/// can't be read are skipped, so a file cut off by a crash still loads.
+#[cfg(feature = "serde")]
+pub fn load(path: &Path, capacity: usize) -> History {
+    let history = History::default();
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("Couldn't read history from {}: {}", path.display(), e);
+            }
+            return history;
+        }
+    };
+
+    let mut skipped = 0;
+    {
+        let mut rooms = history.lock().unwrap();
+        for line in BufReader::new(file).lines() {
+            let entry = match line.map(|line| serde_json::from_str::<Entry>(&line)) {
+                Ok(Ok(entry)) => entry,
+                _ => {
+                    skipped += 1;
+                    continue;
+                }
+            };
+            let room = rooms.entry(entry.room.clone()).or_default();
+            if let Some(seq) = entry.seq {
+                room.last_seq = room.last_seq.max(seq);
+            }
+            if let Some(msg) = entry.into_message() {
+                push(room, msg, capacity);
+            }
+        }
+    }
+    if skipped > 0 {
+        warn!(
+            "Skipped {} unreadable lines of history in {}",
+            skipped,
+            path.display()
+        );
+    }
+    history
+}
+
+/// Appends recorded messages to a history file from a thread of its own, so
+/// relaying never waits on the disk. Cloning shares the file.
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone)]
+pub struct HistoryLog {
+    lines: Sender<String>,
+}
+
+#[cfg(feature = "serde")]
+impl HistoryLog {
+    /// Rewrites the file at `path` to hold just `history`, dropping what
+    /// `load` left out, then appends to it from then on.
+    pub fn open(path: PathBuf, history: &History) -> io::Result<HistoryLog> {
+        let mut contents = String::new();
+        for (name, room) in history.lock().unwrap().iter() {
+            for msg in &room.messages {
+                if let Some(entry) = Entry::new(name, None, msg) {
+                    contents.push_str(&serde_json::to_string(&entry)?);
+                    contents.push('\n');
+                }
+            }
+            // Keep the room's numbering going after the next restart.
+            let numbering = Entry {
+                room: name.clone(),
+                seq: Some(room.last_seq),
+                text: None,
+                binary: None,
+            };
+            contents.push_str(&serde_json::to_string(&numbering)?);
+            contents.push('\n');
+        }
+        fs::write(&path, contents)?;
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+
+        let (lines, written) = crossbeam_channel::unbounded::<String>();
+        runtime::spawn_blocking(move || {
+            for line in written.iter() {
+                if let Err(e) = file.write_all(line.as_bytes()) {
+                    warn!("Couldn't save history to {}: {}", path.display(), e);
+                }
+            }
+        });
+        Ok(HistoryLog { lines })
+    }
+
+    /// Saves `msg` as the newest message in `room`, with its sequence
+    /// number if it has one.
+    pub fn append(&self, room: &str, seq: Option<u64>, msg: &Message) {
+        let entry = match Entry::new(room, seq, msg) {
+            Some(entry) => entry,
+            None => return,
+        };
+        if let Ok(mut line) = serde_json::to_string(&entry) {
+            line.push('\n');
+            // The writer thread only stops along with the server.
+            let _ = self.lines.send(line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{FutureExt, StreamExt};
+
+    use super::*;
+    use crate::queue::{self, OverflowPolicy};
+
+    fn replayed(history: &History, room: &str) -> Vec<Message> {
+        let (tx, mut rx) = queue::channel(16, OverflowPolicy::DropOldest);
+        replay(history, room, &tx);
+        std::iter::from_fn(|| rx.next().now_or_never().flatten()).collect()
+    }
+
+    #[test]
+    fn each_room_keeps_its_own_recent_messages() {
+        let history = History::default();
+        for text in ["1", "2", "3"] {
+            record(&history, "lobby", &Message::text(text), 2);
+        }
+        record(&history, "arena", &Message::text("a"), 2);
+
+        assert_eq!(
+            replayed(&history, "lobby"),
+            [Message::text("2"), Message::text("3")]
+        );
+        assert_eq!(replayed(&history, "arena"), [Message::text("a")]);
+        assert!(replayed(&history, "attic").is_empty());
+    }
+
+    #[test]
+    fn numbering_starts_over_in_a_forgotten_room() {
+        let history = History::default();
+        let numbered = |seq: u64| Message::text(seq.to_string());
+        record_numbered(&history, "lobby", 10, numbered);
+        record_numbered(&history, "lobby", 10, numbered);
+        assert_eq!(last_seq(&history, "lobby"), 2);
+        assert_eq!(last_seq(&history, "arena"), 0);
+
+        forget(&history, "lobby");
+        assert!(replayed(&history, "lobby").is_empty());
+        assert_eq!(
+            record_numbered(&history, "lobby", 10, numbered),
+            Message::text("1")
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    mod saved {
+        use super::*;
+
+        /// A file of the test's own under the temp directory, removed
+        /// first in case an earlier run left it behind.
+        fn temp_file(name: &str) -> PathBuf {
+            let path = std::env::temp_dir().join(format!(
+                "ws_async-{}-{}.jsonl",
+                std::process::id(),
+                name
+            ));
+            let _ = fs::remove_file(&path);
+            path
+        }
+
+        #[test]
+        fn saved_history_loads_back() {
+            let path = temp_file("saved");
+            let history = History::default();
+            for text in ["one", "two", "three"] {
+                record_numbered(&history, "lobby", 10, |_| Message::text(text));
+            }
+            record(&history, "arena", &Message::binary(vec![1, 2]), 10);
+            HistoryLog::open(path.clone(), &history).unwrap();
+
+            let loaded = load(&path, 2);
+            assert_eq!(
+                replayed(&loaded, "lobby"),
+                [Message::text("two"), Message::text("three")]
+            );
+            assert_eq!(last_seq(&loaded, "lobby"), 3);
+            assert_eq!(replayed(&loaded, "arena"), [Message::binary(vec![1, 2])]);
+            let _ = fs::remove_file(&path);
+        }
+
+        #[test]
+        fn unreadable_lines_are_skipped() {
+            let path = temp_file("corrupt");
+            fs::write(
+                &path,
+                "{\"room\":\"lobby\",\"text\":\"kept\"}\nnot json\n{\"room\":\"lob",
+            )
+            .unwrap();
+            assert_eq!(replayed(&load(&path, 10), "lobby"), [Message::text("kept")]);
+            let _ = fs::remove_file(&path);
+        }
+
+        #[test]
+        fn a_missing_file_gives_an_empty_history() {
+            let path = temp_file("missing");
+            assert!(load(&path, 10).lock().unwrap().is_empty());
+        }
+    }
+}
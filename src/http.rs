@@ -0,0 +1,118 @@
+//! Plain HTTP requests on the WebSocket port.
+//!
+//! Load balancers probe with an ordinary `GET /health`, which tungstenite
+//! would reject as a failed handshake. `intercept` reads the request
+//! headers first and answers requests that don't ask for a WebSocket
+//! upgrade itself: `/health` with `200 OK` and `{"status":"ok"}`, any other
+//! path with `426 Upgrade Required`. During shutdown every plain request
+//! gets `503 Service Unavailable`, so load balancers stop sending clients.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{
+    io::{AsyncRead, AsyncWrite},
+    prelude::*,
+};
+
+use crate::runtime::AsyncStream;
+
+/// Requests with larger headers are left for tungstenite to refuse.
+const MAX_HEADER_BYTES: usize = 8192;
+
+/// A stream with the bytes already read from it put back in front.
+pub(crate) struct Rewind<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S: AsyncStream> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.pos < self.prefix.len() {
+            let n = buf.len().min(self.prefix.len() - self.pos);
+            buf[..n].copy_from_slice(&self.prefix[self.pos..self.pos + n]);
+            self.pos += n;
+            return Poll::Ready(Ok(n));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncStream> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Reads the request headers from `stream`. Upgrade requests come back
+/// with the headers replayed for the WebSocket handshake; plain HTTP
+/// requests are answered here and give `None`.
+pub(crate) async fn intercept<S: AsyncStream>(
+    mut stream: S,
+    shutting_down: bool,
+) -> io::Result<Option<Rewind<S>>> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_HEADER_BYTES {
+        match stream.read(&mut buf).await? {
+            0 => break,
+            n => request.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    let head = String::from_utf8_lossy(&request);
+    let is_upgrade = head.lines().skip(1).any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("upgrade")
+                && value.to_ascii_lowercase().contains("websocket")
+        })
+    });
+    if is_upgrade || request.len() >= MAX_HEADER_BYTES {
+        return Ok(Some(Rewind {
+            prefix: request,
+            pos: 0,
+            inner: stream,
+        }));
+    }
+
+    let path = head
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .map(|target| target.split('?').next().unwrap_or(target));
+    let response = match path {
+        _ if shutting_down => "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+        Some("/health") => {
+            let body = r#"{"status":"ok"}"#;
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 426 Upgrade Required\r\nUpgrade: websocket\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.close().await?;
+    Ok(None)
+}
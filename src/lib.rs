@@ -0,0 +1,3051 @@
+//! WebSocket server core shared with the Bevy app.
+//!
+//! Connections are accepted and driven on async tasks, using async-std or
+//! tokio depending on which runtime feature is enabled (see `runtime`). Every inbound
+//! message is relayed to the other peers and also forwarded over a
+//! bridge channel (see `channel`) into the Bevy world, where `pump_incoming_messages`
+//! turns it into a `WsMessageReceived` event for gameplay systems. Systems
+//! talk back to clients by queueing an `OutboundMessage` on the `WsOutbox`
+//! resource. Each accepted connection is also mirrored as an entity with a
+//! `Connection` component, kept in sync by `sync_connections`, which also
+//! sends `ConnectionOpened` and `ConnectionClosed` events; `CleanupHooks`
+//! tear down an entity's game state before it is despawned. The `Connections`
+//! resource, refreshed by `snapshot_connections`, has each client's address,
+//! name and room, and the `Rooms` resource, refreshed by `snapshot_rooms`,
+//! has every room's members.
+//!
+//! Peers start out in the `lobby` room, or the one a `?room=` query
+//! parameter names, and can move with `/join <room>`;
+//! relayed messages only reach the other members of the sender's room,
+//! unless `ServerConfig.broadcast_strategy` says otherwise (see `broadcast`).
+//! `/rooms` lists every room with its number of members.
+//! `/nick <name>` registers a unique name that relayed text is prefixed with,
+//! and `/msg <name> <text>` sends a private message to a named peer. Every
+//! command is also emitted as a `ClientCommandReceived` event, and with the
+//! `serde` feature commands are JSON objects instead (see `protocol`).
+//!
+//! Systems can disconnect a client by sending a `KickRequest` event, and
+//! clients from addresses added to the `BanList` resource are dropped
+//! before their handshake. A `MuteRequest` drops what a client says
+//! for a while, and a `SetDeaf` event stops it hearing broadcasts.
+//!
+//! `WsDiagnosticsPlugin` reports the connection count and message rate
+//! through Bevy's diagnostics, and `render_metrics` has process-wide
+//! counters in the Prometheus format. Plain HTTP `GET /health` requests on
+//! the WebSocket port are answered without an upgrade, for load balancers.
+//! With `ServerConfig.admin_logs` set, operators can stream the server's
+//! logs from `/admin/logs` (see `admin`).
+//!
+//! Settings come from the `ServerConfig` resource, and an
+//! `UpdateServerConfig` event changes them while the server runs. Peers are pinged on its
+//! heartbeat interval and dropped when they stop answering, and newly
+//! connected peers are sent the most recent messages relayed in their room,
+//! as are peers joining another room. Clients that send faster than the
+//! configured rate limit have the excess dropped.
+//!
+//! With the `tls` feature, inserting a `TlsConfig` resource (or calling
+//! `run_tls` or `Server::start_tls` directly) serves `wss://` instead of
+//! plain `ws://`. With the
+//! `uds` feature on Unix, `run_uds` serves local clients on a Unix domain
+//! socket instead of TCP. With the `deflate` feature,
+//! `ServerConfig.compression` negotiates permessage-deflate (see `deflate`).
+//!
+//! When the app sends `AppExit`, `shutdown_on_exit` stops the accept loop and
+//! every peer is sent a Close frame before the server task finishes.
+//!
+//! The `client` module connects to a server from the other side, with
+//! automatic reconnection.
+//!
+//! `Server` runs the same server from plain async code, without Bevy.
+
+// Configure clippy for Bevy usage
+#![allow(clippy::type_complexity)]
+#![allow(clippy::too_many_arguments)]
+#![allow(clippy::too_many_lines)]
+#![allow(clippy::must_use_candidate)]
+#![allow(clippy::needless_pass_by_value)]
+#![allow(clippy::enum_glob_use)]
+
+use bevy::{app::AppExit, log::info_span, prelude::*, utils::tracing::Instrument};
+
+pub mod admin;
+pub mod auth;
+pub mod broadcast;
+pub mod channel;
+pub mod client;
+pub mod clock;
+pub mod config;
+pub mod conn_state;
+pub mod connection;
+#[cfg(feature = "deflate")]
+mod deflate;
+pub mod diagnostics;
+pub mod directory;
+pub mod error;
+pub mod filter;
+mod fragments;
+pub mod game;
+pub mod heartbeat;
+pub mod history;
+mod http;
+pub mod lifecycle;
+pub mod logging;
+pub mod metrics;
+pub mod names;
+pub mod protocol;
+pub mod queue;
+pub mod ratelimit;
+pub mod rooms;
+pub mod router;
+pub mod runtime;
+mod semaphore;
+pub mod server;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod wire;
+
+pub use admin::{AdminLogs, LogFeed, LogLayer, LogStreamPlugin};
+pub use auth::{TokenValidator, UserId};
+pub use broadcast::{BroadcastStrategy, GlobalBroadcast, RoomBroadcast, SharedStrategy};
+pub use channel::{BridgeChannel, BridgeReceiver, BridgeSender, StdReceiver};
+pub use clock::{Clock, MockClock, SharedClock, SystemClock};
+pub use config::{
+    CloseCodes, ClosePolicy, ConnectionLimits, Endpoint, InvalidCloseCode, LiveConfig,
+    ServerConfig, UpdateServerConfig,
+};
+pub use conn_state::ConnState;
+pub use connection::{Traffic, WsConnection};
+pub use diagnostics::{WsDiagnosticsPlugin, WsStats};
+pub use directory::{ConnectionInfo, Connections, Directory, Selector};
+pub use error::ServerError;
+pub use filter::{AcceptCallback, MessageFilter, MessageHandler};
+pub use heartbeat::HeartbeatConfig;
+pub use history::History;
+pub use lifecycle::{CleanupHooks, PlayerJoined, PlayerLeft, PlayerLifecyclePlugin};
+pub use logging::{MessageLogging, Redaction};
+pub use metrics::render_metrics;
+pub use names::NameMap;
+pub use protocol::{ClientCommand, ClientCommandReceived};
+pub use queue::{OverflowPolicy, Priority, Streaming, Tx};
+pub use ratelimit::{FrameLimit, FrameLimitAction, RateLimitConfig};
+pub use rooms::{RoomMap, Rooms};
+pub use router::CommandRouter;
+pub use server::{MessageStream, Server};
+#[cfg(feature = "tls")]
+pub use tls::TlsConfig;
+pub use wire::WireFormat;
+#[cfg(feature = "serde")]
+pub use wire::{broadcast_event, send_event_to};
+
+#[cfg(any(feature = "tls", all(unix, feature = "uds")))]
+use std::path::Path;
+use std::{
+    collections::{HashMap, HashSet},
+    env, fmt,
+    io::{self, Error as IoError},
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::{Receiver, Sender};
+use dashmap::DashMap;
+use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
+
+use futures::prelude::*;
+use futures::{channel::oneshot, future, pin_mut, stream};
+
+use async_tungstenite::tungstenite::{
+    error::{Error as WsError, ProtocolError},
+    handshake::server::{ErrorResponse, Request, Response},
+    http::{header, HeaderValue, StatusCode},
+    protocol::{
+        frame::{coding::CloseCode, CloseFrame},
+        Message,
+    },
+};
+use broadcast::Delivery;
+use queue::SendError;
+use ratelimit::{FrameCounter, TokenBucket};
+use runtime::{AsyncStream, TcpListener};
+use semaphore::{Permit, Semaphore};
+
+/// How long shutdown waits for peers to finish the close handshake.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+
+/// How long a rejected client gets to answer our Close frame.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Identifies a connection for as long as the server runs. Unlike the peer's
+/// address it is never reused, and it keeps the client's IP out of the logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId(pub u64);
+
+impl ConnectionId {
+    /// Mints an id no other connection has had.
+    fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        ConnectionId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// Sharded so connections broadcasting at the same time don't all contend on
+/// a single lock.
+pub type PeerMap = Arc<DashMap<ConnectionId, Tx>>;
+/// Which connection each connection entity belongs to, kept up to date by
+/// `sync_connections` so `OutboundMessage::ToEntity` can be resolved when it
+/// is dispatched.
+pub type EntityMap = Arc<DashMap<Entity, ConnectionId>>;
+/// Addresses whose connections are dropped as soon as they are accepted,
+/// before any handshake.
+pub type BanList = Arc<Mutex<HashSet<IpAddr>>>;
+/// Connections left out of every broadcast, while they can still send. A
+/// deaf client isn't told; messages sent to it directly still arrive.
+pub type DeafSet = Arc<Mutex<HashSet<ConnectionId>>>;
+
+/// A message queued by a Bevy system for delivery to connected clients.
+#[derive(Debug, Clone)]
+pub enum OutboundMessage {
+    /// Send to every connected client.
+    Broadcast(Message),
+    /// Send to a single client.
+    To(ConnectionId, Message),
+    /// Send to the client whose connection entity this is. Dropped if the
+    /// entity doesn't belong to a live connection.
+    ToEntity(Entity, Message),
+    /// Send to every client except the given one.
+    Except(ConnectionId, Message),
+    /// Send to every connection of an authenticated user.
+    ToUser(UserId, Message),
+    /// Send to every client carrying the tag (see `directory::add_tag`).
+    ToTag(String, Message),
+    /// Send to every client the selector picks.
+    Where(Selector, Message),
+    /// Close the client's connection with the given reason.
+    Kick(ConnectionId, String),
+    /// Send a value to every client, encoded in each one's `WireFormat`
+    /// (see `wire::broadcast_event`).
+    #[cfg(feature = "serde")]
+    BroadcastEvent(wire::Encoded),
+    /// Send a value to a single client, encoded in its `WireFormat`.
+    #[cfg(feature = "serde")]
+    EventTo(ConnectionId, wire::Encoded),
+    /// Deliver the wrapped message at `Priority::High`, ahead of anything
+    /// already queued for its recipients. An urgent `Kick` closes the
+    /// connection without writing the backlog first.
+    Urgent(Box<OutboundMessage>),
+}
+
+impl OutboundMessage {
+    /// Wraps the message in `OutboundMessage::Urgent`.
+    pub fn urgent(self) -> Self {
+        OutboundMessage::Urgent(Box::new(self))
+    }
+}
+
+/// Resource used by systems to send messages to connected clients.
+#[derive(Clone)]
+pub struct WsOutbox(Sender<OutboundMessage>);
+
+impl WsOutbox {
+    pub fn send(&self, msg: OutboundMessage) {
+        // Only fails once the server side is gone, in which case there is
+        // nobody left to deliver to.
+        let _ = self.0.send(msg);
+    }
+}
+
+/// Resource for answering whoever sent a `WsMessageReceived` or
+/// `ClientCommandReceived`, by the event's `id`. Replies to a client that has
+/// disconnected in the meantime are dropped.
+#[derive(Clone)]
+pub struct WsReplyHandle(WsOutbox);
+
+impl WsReplyHandle {
+    pub fn to(&self, id: ConnectionId, msg: Message) {
+        self.0.send(OutboundMessage::To(id, msg));
+    }
+}
+
+/// A message received from a connected client.
+#[derive(Debug, Clone)]
+pub struct WsMessageReceived {
+    pub id: ConnectionId,
+    pub msg: Message,
+}
+
+/// Component attached to the entity spawned for each live connection.
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub id: ConnectionId,
+    pub addr: SocketAddr,
+    pub connected_at: Instant,
+    /// Subprotocol agreed on in the handshake, if any.
+    pub subprotocol: Option<String>,
+}
+
+/// Connection lifecycle notifications sent from the async side to
+/// `sync_connections`.
+#[derive(Debug, Clone)]
+pub enum ConnectionEvent {
+    Connected {
+        id: ConnectionId,
+        addr: SocketAddr,
+        connected_at: Instant,
+        subprotocol: Option<String>,
+    },
+    Disconnected {
+        id: ConnectionId,
+        addr: SocketAddr,
+        reason: DisconnectReason,
+    },
+}
+
+/// Why a connection ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The client closed the connection or the server shut it down.
+    Normal,
+    /// The connection dropped without a close handshake, e.g. a broken pipe
+    /// or a reset. Usually worth reconnecting.
+    Reset(String),
+    /// The client broke the WebSocket protocol, e.g. with a malformed frame,
+    /// invalid UTF-8 or a message over the size limits.
+    Protocol(String),
+    /// Reading from or writing to the client failed some other way.
+    Error(String),
+    /// The client stopped answering heartbeat Pings.
+    Timeout,
+    /// Sending to the client took longer than `ServerConfig.write_timeout`.
+    WriteTimeout,
+    /// The client's queue overflowed under `OverflowPolicy::Disconnect`.
+    Overflow,
+    /// The connection moved more than `ServerConfig.byte_quota` bytes.
+    QuotaExceeded,
+    /// The client sent more frames than `ServerConfig.frame_limit` allows.
+    FrameLimitExceeded,
+    /// The client declared a protocol version below
+    /// `ServerConfig.min_protocol_version`.
+    OutdatedProtocol { version: u32, minimum: u32 },
+    /// The app kicked the client, for the given reason.
+    Kicked(String),
+    /// The server was at `ServerConfig.max_connections`.
+    ServerFull,
+    /// The client's address already had `ServerConfig.max_per_ip`
+    /// connections open.
+    TooManyFromAddress,
+    /// The server is shutting down.
+    Shutdown,
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectReason::Normal => write!(f, "closed"),
+            DisconnectReason::Reset(e) => write!(f, "connection lost: {}", e),
+            DisconnectReason::Protocol(e) => write!(f, "protocol violation: {}", e),
+            DisconnectReason::Error(e) => write!(f, "error: {}", e),
+            DisconnectReason::Timeout => write!(f, "timed out"),
+            DisconnectReason::WriteTimeout => write!(f, "too slow to receive"),
+            DisconnectReason::Overflow => write!(f, "fell too far behind"),
+            DisconnectReason::QuotaExceeded => write!(f, "used up its byte quota"),
+            DisconnectReason::FrameLimitExceeded => write!(f, "sent too many frames"),
+            DisconnectReason::OutdatedProtocol { version, minimum } => write!(
+                f,
+                "speaks protocol version {}, older than {}",
+                version, minimum
+            ),
+            DisconnectReason::Kicked(reason) => write!(f, "kicked: {}", reason),
+            DisconnectReason::ServerFull => write!(f, "turned away, server full"),
+            DisconnectReason::TooManyFromAddress => {
+                write!(f, "turned away, too many connections from its address")
+            }
+            DisconnectReason::Shutdown => write!(f, "closed for shutdown"),
+        }
+    }
+}
+
+impl DisconnectReason {
+    /// Classifies an error reading from or writing to the client.
+    pub fn from_ws_error(e: &WsError) -> DisconnectReason {
+        match e {
+            WsError::ConnectionClosed
+            | WsError::AlreadyClosed
+            | WsError::Protocol(ProtocolError::ResetWithoutClosingHandshake) => {
+                DisconnectReason::Reset(e.to_string())
+            }
+            WsError::Io(io) if is_connection_lost(io.kind()) => {
+                DisconnectReason::Reset(e.to_string())
+            }
+            WsError::Protocol(_) | WsError::Capacity(_) | WsError::Utf8 => {
+                DisconnectReason::Protocol(e.to_string())
+            }
+            _ => DisconnectReason::Error(e.to_string()),
+        }
+    }
+
+    /// The Close frame telling the client why it is being disconnected, with
+    /// the close code browsers pass on to scripts.
+    pub fn close_frame(&self) -> CloseFrame<'static> {
+        let (code, reason) = match self {
+            DisconnectReason::Normal => (CloseCode::Normal, String::new()),
+            // Nothing can be sent on a connection that is already gone.
+            DisconnectReason::Reset(_) | DisconnectReason::Error(_) => {
+                (CloseCode::Error, "server error".to_string())
+            }
+            DisconnectReason::Protocol(e) => (CloseCode::Protocol, e.clone()),
+            DisconnectReason::Timeout => (CloseCode::Away, "heartbeat timed out".to_string()),
+            DisconnectReason::WriteTimeout => (CloseCode::Again, "too slow to receive".to_string()),
+            DisconnectReason::Overflow => (CloseCode::Again, "fell too far behind".to_string()),
+            DisconnectReason::QuotaExceeded => {
+                (CloseCode::Policy, "byte quota exceeded".to_string())
+            }
+            DisconnectReason::FrameLimitExceeded => {
+                (CloseCode::Policy, "too many frames".to_string())
+            }
+            DisconnectReason::OutdatedProtocol { version, minimum } => (
+                CloseCode::Protocol,
+                format!(
+                    "protocol version {} is no longer supported, {} or later required",
+                    version, minimum
+                ),
+            ),
+            DisconnectReason::Kicked(reason) => (CloseCode::Policy, reason.clone()),
+            DisconnectReason::ServerFull => (CloseCode::Again, "server full".to_string()),
+            DisconnectReason::TooManyFromAddress => (
+                CloseCode::Policy,
+                "too many connections from your address, try again later".to_string(),
+            ),
+            DisconnectReason::Shutdown => (CloseCode::Away, "server shutting down".to_string()),
+        };
+        CloseFrame {
+            code,
+            reason: truncate_close_reason(reason).into(),
+        }
+    }
+
+    /// `close_frame`, unless `codes` has a custom code for this reason.
+    pub fn close_frame_with(&self, codes: &CloseCodes) -> CloseFrame<'static> {
+        let custom = match self {
+            DisconnectReason::Kicked(reason) => {
+                codes.get_kick(reason).or_else(|| codes.get(self.name()))
+            }
+            _ => codes.get(self.name()),
+        };
+        match custom {
+            Some((code, text)) => CloseFrame {
+                code: CloseCode::from(code),
+                reason: truncate_close_reason(text.to_string()).into(),
+            },
+            None => self.close_frame(),
+        }
+    }
+
+    /// What `CloseCodes` knows the reason by: the variant's name in snake
+    /// case, e.g. `server_full`. Kicks, all named `kicked`, can also have a
+    /// code for each kick reason (see `CloseCodes::insert_kick`).
+    pub fn name(&self) -> &'static str {
+        match self {
+            DisconnectReason::Normal => "normal",
+            DisconnectReason::Reset(_) => "reset",
+            DisconnectReason::Protocol(_) => "protocol",
+            DisconnectReason::Error(_) => "error",
+            DisconnectReason::Timeout => "timeout",
+            DisconnectReason::WriteTimeout => "write_timeout",
+            DisconnectReason::Overflow => "overflow",
+            DisconnectReason::QuotaExceeded => "quota_exceeded",
+            DisconnectReason::FrameLimitExceeded => "frame_limit_exceeded",
+            DisconnectReason::OutdatedProtocol { .. } => "outdated_protocol",
+            DisconnectReason::Kicked(_) => "kicked",
+            DisconnectReason::ServerFull => "server_full",
+            DisconnectReason::TooManyFromAddress => "too_many_from_address",
+            DisconnectReason::Shutdown => "shutdown",
+        }
+    }
+
+    /// Whether the same client reconnecting would likely succeed, as opposed
+    /// to being closed or refused again for the same reason.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            DisconnectReason::Reset(_)
+                | DisconnectReason::Timeout
+                | DisconnectReason::WriteTimeout
+                | DisconnectReason::Overflow
+        )
+    }
+}
+
+/// Close reasons must fit in a control frame alongside the code.
+fn truncate_close_reason(mut reason: String) -> String {
+    const MAX_CLOSE_REASON: usize = 123;
+    if reason.len() > MAX_CLOSE_REASON {
+        let mut end = MAX_CLOSE_REASON;
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        reason.truncate(end);
+    }
+    reason
+}
+
+fn is_connection_lost(kind: io::ErrorKind) -> bool {
+    matches!(
+        kind,
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::TimedOut
+    )
+}
+
+/// Event sent when a client has completed the WebSocket handshake.
+#[derive(Debug, Clone)]
+pub struct ConnectionOpened {
+    pub id: ConnectionId,
+    pub addr: SocketAddr,
+    pub subprotocol: Option<String>,
+}
+
+/// Event sent when a connection has gone away.
+#[derive(Debug, Clone)]
+pub struct ConnectionClosed {
+    pub id: ConnectionId,
+    pub addr: SocketAddr,
+    pub reason: DisconnectReason,
+    /// The connection's entity. Already despawned unless `CleanupHooks` is
+    /// in use (see `lifecycle`).
+    pub entity: Option<Entity>,
+}
+
+/// Resource mapping each live connection to its entity.
+#[derive(Debug, Default)]
+pub struct ConnectionEntities(pub HashMap<ConnectionId, Entity>);
+
+/// Channels and shared state linking the connection tasks with the Bevy
+/// world.
+#[derive(Clone)]
+pub struct Bridge {
+    pub messages: BridgeSender<WsMessageReceived>,
+    pub connections: BridgeSender<ConnectionEvent>,
+    pub commands: BridgeSender<ClientCommandReceived>,
+    pub stats: WsStats,
+    pub bans: BanList,
+    pub deaf: DeafSet,
+    pub directory: Directory,
+    /// The connected clients' queues, filled in by the server.
+    pub peers: PeerMap,
+    pub entities: EntityMap,
+    pub rooms: RoomMap,
+    pub(crate) streams: server::MessageStreams,
+    /// The settings the server runs with, replaced by the `ServerConfig` it
+    /// is started with.
+    pub config: LiveConfig,
+}
+
+impl Bridge {
+    /// Hands a message from a client to the `MessageStream`s open on the
+    /// server, or to the Bevy world through `messages` while there are none.
+    /// The receiver only goes away when the app is shutting down, so a
+    /// failed send is not an error.
+    fn receive(&self, received: WsMessageReceived) {
+        if let Some(received) = self.streams.deliver(received) {
+            let _ = self.messages.send(received);
+        }
+    }
+}
+
+/// Everything a connection task shares with the rest of the server.
+#[derive(Clone)]
+struct ServerState {
+    peers: PeerMap,
+    rooms: RoomMap,
+    names: NameMap,
+    history: History,
+    /// Where the history is saved under `ServerConfig.history_path`.
+    #[cfg(feature = "serde")]
+    history_log: Option<history::HistoryLog>,
+    bridge: Bridge,
+    /// Rooms with a member list waiting to be sent.
+    pending_member_lists: Arc<Mutex<HashSet<String>>>,
+    /// Where relayed messages go under `ServerConfig.total_order`.
+    ordered_relays: Option<Sender<Relay>>,
+    /// Connections admitted and not yet finished, including ones still in
+    /// the handshake.
+    active: Arc<AtomicUsize>,
+    /// How many of the `active` connections each address has.
+    active_per_ip: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    /// Set once shutdown has begun. Clients arriving while the open
+    /// connections close are turned away.
+    shutting_down: Arc<AtomicBool>,
+}
+
+impl ServerState {
+    /// Reserves a connection slot for a client from `addr`, or returns why
+    /// it is turned away.
+    fn admit(&self, addr: SocketAddr) -> Result<Admission, DisconnectReason> {
+        if self.shutting_down.load(Ordering::SeqCst) {
+            return Err(DisconnectReason::Shutdown);
+        }
+        let config = self.bridge.config.current();
+        let ip = addr.ip();
+        {
+            let mut per_ip = self.active_per_ip.lock().unwrap();
+            let count = per_ip.entry(ip).or_insert(0);
+            // Unix socket peers all share one address, so they only count
+            // towards `max_connections`.
+            let limit = config.max_per_ip.filter(|_| !ip.is_unspecified());
+            if limit.is_some_and(|max| *count >= max) {
+                if *count == 0 {
+                    per_ip.remove(&ip);
+                }
+                return Err(DisconnectReason::TooManyFromAddress);
+            }
+            *count += 1;
+        }
+        // Made now, so turning the client away below releases its count.
+        let admission = Admission {
+            active: self.active.clone(),
+            per_ip: self.active_per_ip.clone(),
+            ip,
+        };
+        if self.active.fetch_add(1, Ordering::SeqCst) >= config.max_connections {
+            return Err(DisconnectReason::ServerFull);
+        }
+        Ok(admission)
+    }
+
+    /// Sends a message on to the other members of its room.
+    fn relay(&self, relay: Relay) {
+        let Relay {
+            from,
+            ack,
+            room,
+            msg,
+        } = relay;
+
+        // Let the room know who is talking. Text is numbered per room so
+        // clients can spot gaps, and duplicates between a replay and live
+        // messages.
+        let config = self.bridge.config.current();
+        let history_size = config.history_size;
+        let mut numbered = None;
+        let msg = match msg {
+            Message::Text(text) => {
+                let name = names::display_name(&self.names, from);
+                history::record_numbered(&self.history, &room, history_size, |seq| {
+                    numbered = Some(seq);
+                    protocol::chat_message(seq, &name, &text)
+                })
+            }
+            other => {
+                history::record(&self.history, &room, &other, history_size);
+                other
+            }
+        };
+        #[cfg(feature = "serde")]
+        if let Some(log) = &self.history_log {
+            log.append(&room, numbered, &msg);
+        }
+
+        // By default everyone in our room except ourselves gets the
+        // message. A failed send means that peer has already gone away or
+        // is being disconnected for falling behind, so it is evicted.
+        let members = rooms::members_of(&self.rooms, &room);
+        let deaf = self.bridge.deaf.lock().unwrap().clone();
+        let relayed = broadcast::Relayed {
+            from,
+            room: &members,
+            deaf: &deaf,
+        };
+        let Delivery {
+            delivered,
+            unreachable,
+        } = config.broadcast_strategy.deliver(&self.peers, relayed, msg);
+        for peer_id in unreachable {
+            info!("Removing unreachable peer {}", peer_id);
+            self.peers.remove(&peer_id);
+        }
+        self.bridge.stats.set_connections(self.peers.len());
+
+        if let Some(ack) = ack {
+            if let Some(tx) = self.peers.get(&from) {
+                let _ = tx.send(protocol::delivery_ack(&ack, delivered));
+            }
+        }
+    }
+
+    /// Drops the history of a room that has emptied, unless it is being
+    /// saved to disk: a persistent room keeps its backlog for whoever
+    /// comes next.
+    fn forget_history(&self, room: &str) {
+        #[cfg(feature = "serde")]
+        if self.history_log.is_some() {
+            return;
+        }
+        history::forget(&self.history, room);
+    }
+
+    /// Sends `room` its member list once `member_list_delay` has passed,
+    /// unless a list is already on its way.
+    fn announce_members(&self, room: &str) {
+        let config = self.bridge.config.current();
+        let delay = match config.member_list_delay {
+            Some(delay) => delay,
+            None => return,
+        };
+        if !self
+            .pending_member_lists
+            .lock()
+            .unwrap()
+            .insert(room.to_string())
+        {
+            return;
+        }
+
+        let state = self.clone();
+        let room = room.to_string();
+        let wait = config.clock.sleep(delay);
+        runtime::spawn(async move {
+            wait.await;
+            state.pending_member_lists.lock().unwrap().remove(&room);
+            let members = rooms::members_of(&state.rooms, &room);
+            let mut names: Vec<String> = members
+                .iter()
+                .map(|id| names::display_name(&state.names, *id))
+                .collect();
+            names.sort();
+            let msg = protocol::room_members_message(&room, &names);
+            for id in &members {
+                if let Some(tx) = state.peers.get(id) {
+                    let _ = tx.send(msg.clone());
+                }
+            }
+        });
+    }
+}
+
+/// A message from a client on its way to the rest of its room.
+struct Relay {
+    from: ConnectionId,
+    /// The id the sender wants a receipt for.
+    ack: Option<String>,
+    room: String,
+    msg: Message,
+}
+
+/// A reserved connection slot, released when dropped.
+struct Admission {
+    active: Arc<AtomicUsize>,
+    per_ip: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    ip: IpAddr,
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+        let mut per_ip = self.per_ip.lock().unwrap();
+        if let Some(count) = per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Spawns the task running a connection, named after it and inside a
+/// `connection` span carrying its id and address. Tracing events from the
+/// task, the app's filters and handlers included, can then be told apart by
+/// connection.
+///
+/// The task is boxed first: the connection future is tens of kilobytes, and
+/// the runtime moves what it spawns through enough layers that, unboxed, it
+/// can overflow a default thread stack in unoptimized builds.
+fn spawn_connection(
+    id: ConnectionId,
+    addr: SocketAddr,
+    task: impl Future<Output = ()> + Send + 'static,
+) {
+    let span = info_span!("connection", id = %id, %addr);
+    runtime::spawn_named(
+        format!("connection {} ({})", id, addr),
+        Box::pin(task.instrument(span)),
+    );
+}
+
+/// Runs an accepted connection, or turns it away if it wasn't admitted.
+async fn start_connection<S: AsyncStream>(
+    state: ServerState,
+    stream: S,
+    id: ConnectionId,
+    addr: SocketAddr,
+    admission: Result<Admission, DisconnectReason>,
+    handshake: Permit,
+) {
+    match admission {
+        Ok(_admission) => handle_connection(state, stream, id, addr, handshake).await,
+        Err(reason) => {
+            info!("Rejecting {}: {}", id, reason);
+            let config = state.bridge.config.current();
+            reject(
+                stream,
+                id,
+                config.handshake_timeout,
+                reason,
+                &config.close_codes,
+            )
+            .await;
+        }
+    }
+}
+
+/// Completes the WebSocket handshake only to close the connection again
+/// with the Close frame for `reason`. Plain HTTP requests are answered as
+/// usual, except with a 503 during shutdown.
+async fn reject<S: AsyncStream>(
+    stream: S,
+    id: ConnectionId,
+    handshake_timeout: Duration,
+    reason: DisconnectReason,
+    close_codes: &CloseCodes,
+) {
+    let shutting_down = matches!(reason, DisconnectReason::Shutdown);
+    let refusal = async {
+        match http::intercept(stream, shutting_down).await {
+            Ok(Some(stream)) => async_tungstenite::accept_async(stream).await.map(Some),
+            Ok(None) => Ok(None),
+            Err(e) => Err(WsError::Io(e)),
+        }
+    };
+    let mut ws_stream = match runtime::timeout(handshake_timeout, refusal).await {
+        Some(Ok(Some(ws_stream))) => ws_stream,
+        Some(Ok(None)) => return,
+        Some(Err(e)) => {
+            warn!("WebSocket handshake with {} failed: {}", id, e);
+            return;
+        }
+        None => {
+            info!(
+                "Dropping {}: no handshake within {:?}",
+                id, handshake_timeout
+            );
+            return;
+        }
+    };
+
+    let frame = reason.close_frame_with(close_codes);
+    if ws_stream.close(Some(frame)).await.is_ok() {
+        // Wait for the client to acknowledge so the frame isn't lost to a
+        // reset connection.
+        let drain = ws_stream.for_each(|_| future::ready(()));
+        let _ = runtime::timeout(CLOSE_TIMEOUT, drain).await;
+    }
+}
+
+async fn handle_connection<S: AsyncStream>(
+    state: ServerState,
+    raw_stream: S,
+    id: ConnectionId,
+    addr: SocketAddr,
+    handshake: Permit,
+) {
+    // Settings read here hold for the whole connection; the rest are read
+    // again for every message.
+    let config = state.bridge.config.current();
+
+    // Clients get `handshake_timeout` for the request and the upgrade
+    // together, so one that trickles in its headers can't hold a task.
+    let started = config.clock.now();
+    let timed_out = || {
+        info!(
+            "Dropping {}: no handshake within {:?}",
+            id, config.handshake_timeout
+        );
+        metrics::handshake_failed();
+    };
+
+    // Health checks and other plain HTTP requests end here.
+    let intercept = http::intercept(raw_stream, state.shutting_down.load(Ordering::SeqCst));
+    let raw_stream = match config
+        .clock
+        .timeout(config.handshake_timeout, intercept)
+        .await
+    {
+        Some(Ok(Some(raw_stream))) => raw_stream,
+        Some(Ok(None)) => return,
+        Some(Err(e)) => {
+            warn!("Reading the request from {} failed: {}", id, e);
+            metrics::handshake_failed();
+            return;
+        }
+        None => return timed_out(),
+    };
+    // Clients whose handshake was under way when shutdown began are turned
+    // away like new ones.
+    if state.shutting_down.load(Ordering::SeqCst) {
+        let remaining = config
+            .handshake_timeout
+            .saturating_sub(config.clock.now() - started);
+        let reason = DisconnectReason::Shutdown;
+        return reject(raw_stream, id, remaining, reason, &config.close_codes).await;
+    }
+
+    let ServerState {
+        peers: peer_map,
+        rooms,
+        names,
+        history,
+        bridge,
+        ..
+    } = state.clone();
+
+    let mut subprotocol = None;
+    let mut user = None;
+    let mut endpoint = Endpoint::Chat;
+    let mut wire_format = config.wire_format;
+    let mut requested_room = None;
+    let mut admin_client = false;
+    #[cfg(feature = "deflate")]
+    let mut compressed = false;
+    // The error type is fixed by tungstenite's handshake callback.
+    #[allow(clippy::result_large_err)]
+    let check_handshake = |request: &Request, mut response: Response| {
+        let refuse = |status, reason: &str| {
+            let mut refusal = ErrorResponse::new(Some(reason.to_string()));
+            *refusal.status_mut() = status;
+            Err(refusal)
+        };
+
+        // Admin clients only need their token.
+        let admin_logs = config
+            .admin_logs
+            .as_ref()
+            .filter(|_| request.uri().path() == admin::LOGS_PATH);
+        if let Some(logs) = admin_logs {
+            return match auth::token(request).and_then(|token| logs.auth.validate(token)) {
+                Some(user) => {
+                    info!("{} is streaming logs as {}", id, user);
+                    admin_client = true;
+                    Ok(response)
+                }
+                None => {
+                    info!("Rejecting {}: no valid admin token", id);
+                    refuse(StatusCode::UNAUTHORIZED, "Invalid admin token")
+                }
+            };
+        }
+
+        match config.route(request.uri().path()) {
+            Some(route) => endpoint = route,
+            None => {
+                info!("Rejecting {}: no endpoint at {}", id, request.uri().path());
+                return refuse(StatusCode::NOT_FOUND, "No such endpoint");
+            }
+        }
+
+        let origin = request
+            .headers()
+            .get(header::ORIGIN)
+            .and_then(|origin| origin.to_str().ok());
+        if !config.origin_allowed(origin) {
+            info!("Rejecting {}: origin {:?} not allowed", id, origin);
+            return refuse(StatusCode::FORBIDDEN, "Origin not allowed");
+        }
+
+        if let Some(validator) = &config.auth {
+            match auth::token(request).map(|token| validator.validate(token)) {
+                Some(Some(id)) => user = Some(id),
+                Some(None) => {
+                    info!("Rejecting {}: invalid token", id);
+                    return refuse(StatusCode::UNAUTHORIZED, "Invalid token");
+                }
+                // Anonymous clients are let in under stricter limits if
+                // there are any.
+                None if config.anonymous_limits.is_some() => {}
+                None => {
+                    info!("Rejecting {}: no token", id);
+                    return refuse(StatusCode::UNAUTHORIZED, "Missing token");
+                }
+            }
+        }
+
+        if let Some(callback) = &config.accept_callback {
+            if let Err((status, reason)) = callback.check(request) {
+                info!("Rejecting {}: {} {}", id, status, reason);
+                return refuse(status, &reason);
+            }
+        }
+
+        let offered = request
+            .headers()
+            .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim);
+        match config.choose_subprotocol(offered) {
+            Some(protocol) => {
+                if let Ok(value) = HeaderValue::from_str(&protocol) {
+                    response
+                        .headers_mut()
+                        .insert(header::SEC_WEBSOCKET_PROTOCOL, value);
+                }
+                subprotocol = Some(protocol);
+            }
+            None if config.require_subprotocol => {
+                info!("Rejecting {}: no supported subprotocol", id);
+                return refuse(StatusCode::BAD_REQUEST, "No supported subprotocol");
+            }
+            None => {}
+        }
+
+        #[cfg(feature = "deflate")]
+        if config.compression {
+            let offers = request
+                .headers()
+                .get_all(header::SEC_WEBSOCKET_EXTENSIONS)
+                .iter()
+                .filter_map(|value| value.to_str().ok());
+            if deflate::accept(offers) {
+                response.headers_mut().insert(
+                    header::SEC_WEBSOCKET_EXTENSIONS,
+                    HeaderValue::from_static(deflate::RESPONSE),
+                );
+                compressed = true;
+            }
+        }
+
+        // An explicit `?format=` wins over a format-named subprotocol.
+        match WireFormat::from_query(request.uri().query()) {
+            Ok(Some(format)) => wire_format = format,
+            Ok(None) => {
+                if let Some(format) = subprotocol.as_deref().and_then(WireFormat::from_name) {
+                    wire_format = format;
+                }
+            }
+            Err(name) => {
+                info!("Rejecting {}: unsupported format {}", id, name);
+                return refuse(StatusCode::BAD_REQUEST, "Unsupported format");
+            }
+        }
+
+        match rooms::from_query(request.uri().query()) {
+            Ok(room) => requested_room = room,
+            Err(name) => {
+                info!("Rejecting {}: invalid room name {:?}", id, name);
+                return refuse(StatusCode::BAD_REQUEST, "Invalid room name");
+            }
+        }
+        Ok(response)
+    };
+
+    // Compression happens underneath tungstenite, which only sees plain
+    // frames.
+    #[cfg(feature = "deflate")]
+    let raw_stream =
+        deflate::Deflate::new(raw_stream, config.max_message_size, config.max_frame_size);
+    // So are the fragments of streamed messages, as tungstenite only writes
+    // whole ones.
+    let marker = fragments::Marker::default();
+    let raw_stream = fragments::Fragmenting::new(raw_stream, marker.clone());
+    let ws_config = config.websocket_config();
+    let accept = async_tungstenite::accept_hdr_async_with_config(
+        raw_stream,
+        check_handshake,
+        Some(ws_config),
+    );
+    let remaining = config
+        .handshake_timeout
+        .saturating_sub(config.clock.now() - started);
+    let mut ws_stream = match config.clock.timeout(remaining, accept).await {
+        Some(Ok(ws_stream)) => ws_stream,
+        Some(Err(e)) => {
+            warn!("WebSocket handshake with {} failed: {}", id, e);
+            metrics::handshake_failed();
+            return;
+        }
+        None => return timed_out(),
+    };
+    drop(handshake);
+    ws_stream.get_mut().start();
+    #[cfg(feature = "deflate")]
+    if compressed {
+        ws_stream.get_mut().get_mut().start();
+    }
+
+    // Admin clients are only sent logs, and aren't peers.
+    if let Some(logs) = config.admin_logs.as_ref().filter(|_| admin_client) {
+        return admin::serve_logs(ws_stream, id, logs, &config).await;
+    }
+
+    // Insert the write part of this peer to the peer map.
+    let (tx, rx) = queue::channel(config.peer_buffer.max(1), config.overflow_policy);
+    let connection = WsConnection::new(id, ws_stream, tx.clone(), rx).with_marker(marker);
+    let traffic = connection.traffic().clone();
+
+    // Ahead of the history and any broadcast, since the peer isn't in the
+    // map yet.
+    let motd_pending = AtomicBool::new(config.motd.is_some());
+    if let Some(motd) = &config.motd {
+        if user.is_some() || !config.motd_after_auth {
+            let _ = tx.send(motd.clone());
+            motd_pending.store(false, Ordering::Relaxed);
+        }
+    }
+
+    // Game clients aren't in any room. Chat clients are caught up on their
+    // room, the one they asked for or the lobby, before they can receive
+    // live broadcasts.
+    let room = match endpoint {
+        Endpoint::Chat => {
+            let room = requested_room.as_deref().unwrap_or(rooms::DEFAULT_ROOM);
+            history::replay(&history, room, &tx);
+            room
+        }
+        Endpoint::Game => "",
+    };
+
+    peer_map.insert(id, tx.clone());
+    metrics::connection_opened();
+    bridge.stats.set_connections(peer_map.len());
+    if endpoint == Endpoint::Chat {
+        // The new connection isn't in a room yet, so none can have emptied.
+        let _ = rooms::join(&rooms, id, room);
+        state.announce_members(room);
+    }
+    let connected_at = config.clock.now();
+    bridge.directory.insert(
+        id,
+        ConnectionInfo {
+            addr,
+            endpoint,
+            wire_format,
+            name: None,
+            room: room.to_string(),
+            connected_at,
+            last_seen: connected_at,
+            last_inbound: connected_at,
+            latency_ms: None,
+            user: user.clone(),
+            traffic: traffic.clone(),
+            queue_depth: tx.depth(),
+            muted_until: None,
+            protocol_version: None,
+            tags: HashSet::new(),
+        },
+    );
+    // From here on, however this task ends the connection is cleaned up.
+    let mut registration = Registration {
+        state: state.clone(),
+        id,
+        addr,
+        reason: DisconnectReason::Error("connection task ended early".to_string()),
+    };
+    let _ = bridge.connections.send(ConnectionEvent::Connected {
+        id,
+        addr,
+        connected_at,
+        subprotocol: subprotocol.clone(),
+    });
+
+    // A client that stops reading would otherwise stall its writer forever
+    // while its queue fills up.
+    let (incoming, receive_from_others) = connection.into_parts(
+        config.write_timeout,
+        config.batch_window,
+        config.byte_quota,
+        config.close_codes.clone(),
+        config.clock.clone(),
+    );
+    // Authenticating later with `/auth` lifts the anonymous limits.
+    let authenticated = AtomicBool::new(user.is_some());
+    let liveness = heartbeat::Liveness::new(config.clock.clone());
+    let mut bucket = TokenBucket::with_clock(
+        config.limits(user.is_some()).rate_limit,
+        config.clock.clone(),
+    );
+    let mut throttled = false;
+    let mut quota_closed = false;
+    let mut frames = FrameCounter::new(config.clock.clone());
+    let mut frame_limit_closed = false;
+    let mut outdated = None;
+    let mut conn_state = ConnState::default();
+    // The mute the client was last told about.
+    let mut mute_notified = None;
+    // The last `/join` or `/nick` that took effect, for `command_debounce`.
+    let mut last_membership: Option<(ClientCommand, Instant)> = None;
+
+    let broadcast_incoming = incoming
+        .try_filter(|msg| {
+            let active = match msg {
+                Message::Text(_) | Message::Binary(_) => true,
+                Message::Pong(_) => bridge.config.current().idle_counts_pongs,
+                _ => false,
+            };
+            directory::update(&bridge.directory, id, |info| {
+                info.last_seen = config.clock.now();
+                if active {
+                    info.last_inbound = info.last_seen;
+                }
+            });
+
+            // Past its quota the client is closed and nothing more it sends
+            // is handled.
+            traffic.record_in(msg);
+            if let Some(quota) = config.byte_quota {
+                if traffic.total() > quota {
+                    if !quota_closed {
+                        quota_closed = true;
+                        info!("Disconnecting {}: byte quota exceeded", id);
+                        close_with_reason(
+                            &tx,
+                            &DisconnectReason::QuotaExceeded,
+                            &bridge.config.current().close_codes,
+                        );
+                    }
+                    return future::ready(false);
+                }
+            }
+
+            // Pongs belong to the heartbeat, which sees them even over the
+            // frame cap so a client at its limit isn't timed out while it
+            // still answers.
+            if let Message::Pong(payload) = msg {
+                if let Some(rtt) = liveness.pong(payload) {
+                    directory::update(&bridge.directory, id, |info| {
+                        info.latency_ms = Some(rtt.as_secs_f64() * 1000.0)
+                    });
+                }
+            }
+
+            // Frames over the cap are dropped before anything else looks at
+            // them. Under `FrameLimitAction::Disconnect` the first one also
+            // closes the connection.
+            if let Some(limit) = bridge.config.current().frame_limit {
+                if !frames.count(msg, &limit) {
+                    if limit.action == FrameLimitAction::Disconnect && !frame_limit_closed {
+                        frame_limit_closed = true;
+                        info!("Disconnecting {}: too many frames", id);
+                        close_with_reason(
+                            &tx,
+                            &DisconnectReason::FrameLimitExceeded,
+                            &bridge.config.current().close_codes,
+                        );
+                    }
+                    return future::ready(false);
+                }
+            }
+
+            // Only text and binary messages go on to be relayed; control
+            // frames are dealt with here.
+            match msg {
+                Message::Text(_) | Message::Binary(_) => future::ready(true),
+                // tungstenite has already queued the Pong the protocol
+                // requires.
+                Message::Ping(_) => {
+                    if bridge.config.current().forward_pings {
+                        bridge.receive(WsMessageReceived {
+                            id,
+                            msg: msg.clone(),
+                        });
+                    }
+                    future::ready(false)
+                }
+                // Already handed to the heartbeat above.
+                Message::Pong(_) => future::ready(false),
+                // Relaying a Close message as-is would close the other
+                // clients too, so that only happens if the policy asks for it.
+                Message::Close(_) => {
+                    let notice = match bridge.config.current().close_policy {
+                        ClosePolicy::Ignore => None,
+                        ClosePolicy::Relay => Some(Message::text(format!(
+                            "* {} left",
+                            names::display_name(&names, id)
+                        ))),
+                        ClosePolicy::Propagate => Some(msg.clone()),
+                    };
+                    if let Some(notice) = notice {
+                        // This connection is ending anyway; peers that can't
+                        // be reached are evicted by the next broadcast.
+                        let members = rooms::room_members(&rooms, id);
+                        let deaf = bridge.deaf.lock().unwrap().clone();
+                        let relayed = broadcast::Relayed {
+                            from: id,
+                            room: &members,
+                            deaf: &deaf,
+                        };
+                        RoomBroadcast.deliver(&peer_map, relayed, notice);
+                    }
+                    future::ready(false)
+                }
+            }
+        })
+        .try_for_each(|msg| {
+            // A client being closed for its protocol version gets nothing
+            // more handled.
+            if outdated.is_some() {
+                return future::ok(());
+            }
+            metrics::message_received();
+            let current = bridge.config.current();
+            let line = logging::format_message(
+                current.log_messages,
+                current.log_redaction.as_ref(),
+                id,
+                &msg,
+            );
+            if let Some(line) = line {
+                info!("{}", line);
+            }
+
+            // Over the rate limit the message is dropped; the client hears
+            // about it once until it slows down again.
+            bucket.reconfigure(
+                current
+                    .limits(authenticated.load(Ordering::Relaxed))
+                    .rate_limit,
+            );
+            if !bucket.try_take() {
+                // Flooding on while muted doesn't extend the mute.
+                if let Some(duration) = current.flood_mute {
+                    let now = current.clock.now();
+                    directory::update(&bridge.directory, id, |info| {
+                        if info.muted_until.is_none_or(|until| until <= now) {
+                            info!("Muting {} for {:?}: flooding", id, duration);
+                            info.muted_until = Some(now + duration);
+                        }
+                    });
+                }
+                if !throttled {
+                    throttled = true;
+                    info!("Throttling {}", id);
+                    let _ = tx.send(Message::text(
+                        "You are sending messages too quickly, some were dropped",
+                    ));
+                }
+                return future::ok(());
+            }
+            throttled = false;
+
+            bridge.stats.record_message();
+
+            if let Some(handler) = &current.message_handler {
+                if let Some(reply) = handler.handle(id, &msg, &mut conn_state) {
+                    let _ = tx.send(reply);
+                }
+            }
+
+            // Hand the message to the Bevy world, or the async consumers.
+            bridge.receive(WsMessageReceived {
+                id,
+                msg: msg.clone(),
+            });
+
+            // Muted clients can still use commands, but nothing they say
+            // reaches anyone. They hear about each mute once.
+            let muted_until = bridge
+                .directory
+                .get(&id)
+                .and_then(|info| info.muted_until)
+                .filter(|until| *until > current.clock.now());
+            let mut muted = || match muted_until {
+                Some(until) => {
+                    if mute_notified != Some(until) {
+                        mute_notified = Some(until);
+                        let left = until - current.clock.now();
+                        let _ = tx.send(Message::text(format!(
+                            "You are muted for another {}s",
+                            left.as_secs() + 1
+                        )));
+                    }
+                    true
+                }
+                None => false,
+            };
+
+            // Commands are only recognised in text frames; binary payloads are
+            // relayed untouched.
+            let (msg, ack) = match msg {
+                Message::Text(text) => {
+                    let protocol::Envelope { version, command } =
+                        match protocol::parse_envelope(&text) {
+                            Ok(envelope) => envelope,
+                            Err(reason) => {
+                                let _ = tx.send(Message::text(format!("Error: {}", reason)));
+                                return future::ok(());
+                            }
+                        };
+                    if let Some(version) = version {
+                        directory::update(&bridge.directory, id, |info| {
+                            info.protocol_version = Some(version)
+                        });
+                        if version < current.min_protocol_version {
+                            let reason = DisconnectReason::OutdatedProtocol {
+                                version,
+                                minimum: current.min_protocol_version,
+                            };
+                            info!("Disconnecting {}: {}", id, reason);
+                            close_with_reason(&tx, &reason, &current.close_codes);
+                            outdated = Some(reason);
+                            return future::ok(());
+                        }
+                    }
+                    // Repeats are dropped before the Bevy systems see them.
+                    let membership = matches!(
+                        command,
+                        ClientCommand::Join { .. } | ClientCommand::Nick { .. }
+                    );
+                    let now = current.clock.now();
+                    if let Some(window) = current.command_debounce.filter(|_| membership) {
+                        let repeated = last_membership.as_ref().is_some_and(|(last, at)| {
+                            *last == command && now.saturating_duration_since(*at) < window
+                        });
+                        if repeated {
+                            return future::ok(());
+                        }
+                    }
+                    let _ = bridge.commands.send(ClientCommandReceived {
+                        id,
+                        command: command.clone(),
+                    });
+
+                    // Game clients only talk to the Bevy systems.
+                    if endpoint == Endpoint::Game {
+                        return future::ok(());
+                    }
+
+                    match command {
+                        ClientCommand::Join { room } => {
+                            info!("{} joined room {}", id, room);
+                            last_membership =
+                                Some((ClientCommand::Join { room: room.clone() }, now));
+                            history::replay(&history, &room, &tx);
+                            let left = rooms::room_of(&rooms, id);
+                            if let Some(emptied) = rooms::join(&rooms, id, &room) {
+                                state.forget_history(&emptied);
+                            }
+                            if let Some(left) = left.filter(|left| *left != room) {
+                                state.announce_members(&left);
+                            }
+                            state.announce_members(&room);
+                            directory::update(&bridge.directory, id, |info| info.room = room);
+                            return future::ok(());
+                        }
+                        ClientCommand::Nick { name } => {
+                            match names::register(&names, id, &name) {
+                                Ok(()) => {
+                                    info!("{} is now known as {}", id, name);
+                                    let nick = ClientCommand::Nick { name: name.clone() };
+                                    last_membership = Some((nick, now));
+                                    if let Some(room) = rooms::room_of(&rooms, id) {
+                                        state.announce_members(&room);
+                                    }
+                                    directory::update(&bridge.directory, id, |info| {
+                                        info.name = Some(name)
+                                    });
+                                }
+                                Err(reason) => {
+                                    let _ = tx.send(Message::text(format!("Error: {}", reason)));
+                                }
+                            }
+                            return future::ok(());
+                        }
+                        ClientCommand::Msg { .. } if muted() => return future::ok(()),
+                        ClientCommand::Msg { to, text, id: ack } => {
+                            let reply = match names::lookup(&names, &to) {
+                                Some(target) => {
+                                    let private = Message::text(format!(
+                                        "[private] {}: {}",
+                                        names::display_name(&names, id),
+                                        text
+                                    ));
+                                    match peer_map.get(&target) {
+                                        Some(target_tx) if target_tx.send(private).is_ok() => {
+                                            ack.map(|ack| protocol::recipient_ack(&ack, &to))
+                                        }
+                                        _ => Some(Message::text(format!(
+                                            "Error: {} is not connected",
+                                            to
+                                        ))),
+                                    }
+                                }
+                                None => {
+                                    Some(Message::text(format!("Error: No one is called {}", to)))
+                                }
+                            };
+                            if let Some(reply) = reply {
+                                let _ = tx.send(reply);
+                            }
+                            return future::ok(());
+                        }
+                        ClientCommand::Ping => {
+                            let _ = tx.send(Message::text("pong"));
+                            return future::ok(());
+                        }
+                        ClientCommand::Rooms => {
+                            let occupancy = Rooms::snapshot(&rooms).occupancy();
+                            let _ = tx.send(protocol::rooms_message(&occupancy));
+                            return future::ok(());
+                        }
+                        ClientCommand::Auth { token } => {
+                            let reply = match current.auth.as_ref().map(|v| v.validate(&token)) {
+                                Some(Some(user)) => {
+                                    info!("{} authenticated as {}", id, user);
+                                    authenticated.store(true, Ordering::Relaxed);
+                                    if motd_pending.swap(false, Ordering::Relaxed) {
+                                        if let Some(motd) = &current.motd {
+                                            let _ = tx.send(motd.clone());
+                                        }
+                                    }
+                                    let reply = format!("* authenticated as {}", user);
+                                    directory::update(&bridge.directory, id, |info| {
+                                        info.user = Some(user)
+                                    });
+                                    reply
+                                }
+                                Some(None) => "Error: Invalid token".to_string(),
+                                None => "Error: Authentication is not enabled".to_string(),
+                            };
+                            let _ = tx.send(Message::text(reply));
+                            return future::ok(());
+                        }
+                        // Movement and custom commands are up to the game
+                        // systems.
+                        ClientCommand::Move { .. } | ClientCommand::Custom { .. } => {
+                            return future::ok(())
+                        }
+                        ClientCommand::Chat { .. } if muted() => return future::ok(()),
+                        ClientCommand::Chat { text, id: ack } => (Message::text(text), ack),
+                    }
+                }
+                _ if endpoint == Endpoint::Game => return future::ok(()),
+                _ if muted() => return future::ok(()),
+                other => (other, None),
+            };
+
+            let msg = match current.message_filter.apply(id, &msg) {
+                Some(msg) => msg,
+                None => {
+                    if let Some(ack) = ack {
+                        let _ = tx.send(protocol::delivery_ack(&ack, 0));
+                    }
+                    return future::ok(());
+                }
+            };
+
+            let relay = Relay {
+                from: id,
+                ack,
+                room: rooms::room_of(&rooms, id).unwrap_or_else(|| rooms::DEFAULT_ROOM.to_string()),
+                msg,
+            };
+            match &state.ordered_relays {
+                // The broadcaster only goes away along with the server.
+                Some(relays) => {
+                    let _ = relays.send(relay);
+                }
+                None => state.relay(relay),
+            }
+
+            future::ok(())
+        });
+
+    let keepalive = heartbeat::heartbeat(
+        || {
+            config
+                .limits(authenticated.load(Ordering::Relaxed))
+                .heartbeat
+        },
+        || bridge.config.current().close_codes.clone(),
+        tx.clone(),
+        &liveness,
+    );
+
+    pin_mut!(broadcast_incoming, receive_from_others, keepalive);
+    let finished = future::select(
+        broadcast_incoming,
+        future::select(receive_from_others, keepalive),
+    )
+    .await;
+
+    // Read errors include frames over the configured size limits.
+    let reason = match finished {
+        future::Either::Left((Ok(()), _))
+        | future::Either::Right((future::Either::Left((Ok(()), _)), _)) => DisconnectReason::Normal,
+        future::Either::Left((Err(e), _)) => DisconnectReason::from_ws_error(&e),
+        future::Either::Right((future::Either::Left((Err(reason), _)), _)) => reason,
+        future::Either::Right((future::Either::Right(((), _)), _)) => DisconnectReason::Timeout,
+    };
+
+    // A client kicked, closed for its quota or for not answering Pings may
+    // well finish the close handshake cleanly.
+    let over_quota = config
+        .byte_quota
+        .is_some_and(|quota| traffic.total() > quota);
+    let reason = match (reason, tx.kicked()) {
+        (DisconnectReason::Normal, Some(kick)) => DisconnectReason::Kicked(kick),
+        (reason, _) => reason,
+    };
+    registration.reason = match reason {
+        DisconnectReason::Normal if over_quota => DisconnectReason::QuotaExceeded,
+        DisconnectReason::Normal if frame_limit_closed => DisconnectReason::FrameLimitExceeded,
+        DisconnectReason::Normal if liveness.timed_out() => DisconnectReason::Timeout,
+        DisconnectReason::Normal => outdated.unwrap_or(DisconnectReason::Normal),
+        reason => reason,
+    };
+}
+
+/// Unregisters a connection when dropped, so that cleanup runs exactly once
+/// whether the connection task finishes, returns early or panics.
+struct Registration {
+    state: ServerState,
+    id: ConnectionId,
+    addr: SocketAddr,
+    /// Reported in the `Disconnected` event.
+    reason: DisconnectReason,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let reason = std::mem::replace(&mut self.reason, DisconnectReason::Normal);
+        on_disconnect(&self.state, self.id, self.addr, reason);
+    }
+}
+
+/// Removes `id` from every map the server keeps and reports the
+/// disconnection. Only the first call for a connection does anything.
+fn on_disconnect(
+    state: &ServerState,
+    id: ConnectionId,
+    addr: SocketAddr,
+    reason: DisconnectReason,
+) {
+    // The directory entry marks a registered connection; the peer itself
+    // may already have been evicted from the peer map.
+    if state.bridge.directory.remove(&id).is_none() {
+        return;
+    }
+    state.peers.remove(&id);
+    metrics::connection_closed();
+    state.bridge.stats.set_connections(state.peers.len());
+    let room = rooms::room_of(&state.rooms, id);
+    if let Some(emptied) = rooms::leave(&state.rooms, id) {
+        state.forget_history(&emptied);
+    }
+    if let Some(room) = room {
+        state.announce_members(&room);
+    }
+    names::release(&state.names, id);
+    state.bridge.deaf.lock().unwrap().remove(&id);
+    let _ = state
+        .bridge
+        .connections
+        .send(ConnectionEvent::Disconnected { id, addr, reason });
+}
+
+/// Sends `msg` to every peer in `members` except `from`, returning the peers
+/// whose queue is closed or overflowed.
+pub fn broadcast(
+    peer_map: &PeerMap,
+    from: ConnectionId,
+    members: &HashSet<ConnectionId>,
+    msg: &Message,
+) -> Vec<ConnectionId> {
+    broadcast::send_each(peer_map, msg, |id| *id != from && members.contains(id)).unreachable
+}
+
+/// Sends `msg` to every peer whose `Directory` entry satisfies `pred`.
+/// Returns the peers that couldn't be reached, like `broadcast`.
+pub fn broadcast_where(
+    peer_map: &PeerMap,
+    directory: &Directory,
+    pred: impl Fn(&ConnectionInfo) -> bool,
+    msg: &Message,
+) -> Vec<ConnectionId> {
+    send_where(
+        peer_map,
+        directory,
+        &HashSet::new(),
+        pred,
+        msg,
+        Priority::Low,
+    )
+}
+
+/// `broadcast_where`, skipping the `deaf` peers.
+fn send_where(
+    peer_map: &PeerMap,
+    directory: &Directory,
+    deaf: &HashSet<ConnectionId>,
+    pred: impl Fn(&ConnectionInfo) -> bool,
+    msg: &Message,
+    priority: Priority,
+) -> Vec<ConnectionId> {
+    // Collected first so the directory isn't locked while sending.
+    let recipients: Vec<ConnectionId> = directory
+        .iter()
+        .filter(|info| !deaf.contains(info.key()) && pred(info.value()))
+        .map(|info| *info.key())
+        .collect();
+    recipients
+        .into_iter()
+        .filter(|id| match peer_map.get(id) {
+            Some(tx) => matches!(
+                tx.send_with(msg.clone(), priority),
+                Err(SendError::Disconnected | SendError::Overflow)
+            ),
+            None => false,
+        })
+        .collect()
+}
+
+/// Streams the bytes `chunks` yields to the peer as one binary message, a
+/// fragment per chunk, without gathering the payload first (see
+/// `Tx::start_stream`). The fragments are written back to back: other data
+/// messages sent to the peer meanwhile wait until the last one has gone,
+/// while Pings and Pongs may come between them, as RFC 6455 allows.
+///
+/// Clients see a single message: WebSocket clients, browsers included,
+/// reassemble fragments before handing a message over. Fragmented messages
+/// from clients are likewise reassembled before they are handled, up to
+/// `ServerConfig.max_message_size`. If sending fails, or this future is
+/// dropped, once part of the message has been written, the peer is
+/// disconnected.
+pub async fn send_streamed(tx: &Tx, chunks: impl Stream<Item = Vec<u8>>) -> Result<(), SendError> {
+    let mut streaming = tx.start_stream().await?;
+    pin_mut!(chunks);
+    while let Some(chunk) = chunks.next().await {
+        streaming.send(chunk).await?;
+    }
+    streaming.finish();
+    Ok(())
+}
+
+/// Sends `msg` to every peer, waiting up to `deadline` for room in each
+/// peer's queue instead of applying its overflow policy. Peers still full
+/// after the deadline are disconnected and returned.
+///
+/// Slower than `broadcast`, so meant for messages that must not be dropped.
+pub async fn broadcast_reliable(
+    peer_map: &PeerMap,
+    msg: &Message,
+    deadline: Duration,
+) -> Vec<ConnectionId> {
+    // Clone the senders out first; holding the shard locks across the wait
+    // would block every other broadcast.
+    let peers: Vec<(ConnectionId, Tx)> = peer_map
+        .iter()
+        .map(|peer| (*peer.key(), peer.value().clone()))
+        .collect();
+
+    let sends = peers.into_iter().map(|(id, tx)| async move {
+        match runtime::timeout(deadline, tx.send_ready(msg.clone())).await {
+            Some(Ok(())) | Some(Err(SendError::Closing)) => None,
+            _ => {
+                tx.disconnect();
+                Some(id)
+            }
+        }
+    });
+    let laggards: Vec<ConnectionId> = future::join_all(sends)
+        .await
+        .into_iter()
+        .flatten()
+        .collect();
+
+    for id in &laggards {
+        info!("Disconnecting {}: couldn't keep up", id);
+        peer_map.remove(id);
+    }
+    laggards
+}
+
+fn dispatch(
+    peer_map: &PeerMap,
+    directory: &Directory,
+    entities: &EntityMap,
+    deaf: &DeafSet,
+    outbound: OutboundMessage,
+    priority: Priority,
+    close_codes: &CloseCodes,
+) {
+    // A peer may have disconnected without being removed from the map yet,
+    // so a failed send is skipped rather than treated as an error. Deaf
+    // peers only get what is addressed to them alone or to their user.
+    match outbound {
+        OutboundMessage::Broadcast(msg) => {
+            let deaf = deaf.lock().unwrap().clone();
+            for peer in peer_map.iter().filter(|peer| !deaf.contains(peer.key())) {
+                let _ = peer.value().send_with(msg.clone(), priority);
+            }
+        }
+        OutboundMessage::To(id, msg) => {
+            if let Some(recp) = peer_map.get(&id) {
+                let _ = recp.send_with(msg, priority);
+            }
+        }
+        OutboundMessage::ToEntity(entity, msg) => {
+            let id = entities.get(&entity).map(|id| *id);
+            if let Some(recp) = id.and_then(|id| peer_map.get(&id)) {
+                let _ = recp.send_with(msg, priority);
+            }
+        }
+        OutboundMessage::Except(id, msg) => {
+            let deaf = deaf.lock().unwrap().clone();
+            let recipients = peer_map
+                .iter()
+                .filter(|peer| peer.key() != &id && !deaf.contains(peer.key()));
+            for peer in recipients {
+                let _ = peer.value().send_with(msg.clone(), priority);
+            }
+        }
+        OutboundMessage::ToUser(user, msg) => {
+            let ids = directory
+                .iter()
+                .filter(|info| info.user.as_ref() == Some(&user))
+                .map(|info| *info.key());
+            for id in ids {
+                if let Some(recp) = peer_map.get(&id) {
+                    let _ = recp.send_with(msg.clone(), priority);
+                }
+            }
+        }
+        OutboundMessage::ToTag(tag, msg) => {
+            let deaf = deaf.lock().unwrap().clone();
+            let _ = send_where(
+                peer_map,
+                directory,
+                &deaf,
+                |info| info.tags.contains(&tag),
+                &msg,
+                priority,
+            );
+        }
+        OutboundMessage::Where(selector, msg) => {
+            let deaf = deaf.lock().unwrap().clone();
+            let _ = send_where(
+                peer_map,
+                directory,
+                &deaf,
+                |info| selector.matches(info),
+                &msg,
+                priority,
+            );
+        }
+        OutboundMessage::Kick(id, reason) => {
+            // The connection task finishes once the client answers the Close
+            // frame; removing the peer now stops it receiving anything else.
+            if let Some((_, recp)) = peer_map.remove(&id) {
+                info!("Kicking {}: {}", id, reason);
+                let frame = DisconnectReason::Kicked(reason.clone()).close_frame_with(close_codes);
+                recp.kick(frame, priority, reason);
+            }
+        }
+        #[cfg(feature = "serde")]
+        OutboundMessage::BroadcastEvent(event) => {
+            let deaf = deaf.lock().unwrap().clone();
+            // Collected first so the directory isn't locked while sending.
+            let recipients: Vec<(ConnectionId, WireFormat)> = directory
+                .iter()
+                .filter(|info| !deaf.contains(info.key()))
+                .map(|info| (*info.key(), info.wire_format))
+                .collect();
+            for (id, format) in recipients {
+                if let Some(recp) = peer_map.get(&id) {
+                    let _ = recp.send_with(event.get(format).clone(), priority);
+                }
+            }
+        }
+        #[cfg(feature = "serde")]
+        OutboundMessage::EventTo(id, event) => {
+            let format = directory.get(&id).map(|info| info.wire_format);
+            if let (Some(format), Some(recp)) = (format, peer_map.get(&id)) {
+                let _ = recp.send_with(event.get(format).clone(), priority);
+            }
+        }
+        OutboundMessage::Urgent(outbound) => dispatch(
+            peer_map,
+            directory,
+            entities,
+            deaf,
+            *outbound,
+            Priority::High,
+            close_codes,
+        ),
+    }
+}
+
+/// Serves on the addresses given on the command line until the process
+/// exits. Fails if none of them can be bound, or if accepting connections
+/// fails.
+pub async fn run(
+    bridge: Bridge,
+    outbox: Receiver<OutboundMessage>,
+    config: ServerConfig,
+) -> Result<(), ServerError> {
+    run_with_shutdown(bridge, outbox, config, future::pending()).await
+}
+
+/// Like `run`, but stops accepting connections once `shutdown` resolves and
+/// closes every open connection before returning.
+pub async fn run_with_shutdown(
+    bridge: Bridge,
+    outbox: Receiver<OutboundMessage>,
+    config: ServerConfig,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), ServerError> {
+    let listeners = bind_all_with(&listen_addrs(), &config).await?;
+    run_on_all(listeners, bridge, outbox, config, shutdown).await
+}
+
+/// Like `run_with_shutdown`, but serves on a listener returned by `bind`
+/// instead of binding one itself.
+pub async fn run_on(
+    listener: TcpListener,
+    bridge: Bridge,
+    outbox: Receiver<OutboundMessage>,
+    config: ServerConfig,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), ServerError> {
+    run_on_all(vec![listener], bridge, outbox, config, shutdown).await
+}
+
+/// Like `run_on`, but accepts connections on all of `listeners`, e.g. one
+/// for IPv4 and one for IPv6. They share the same peers and rooms.
+pub async fn run_on_all(
+    listeners: Vec<TcpListener>,
+    bridge: Bridge,
+    outbox: Receiver<OutboundMessage>,
+    config: ServerConfig,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), ServerError> {
+    let accepted = tcp_accepts(listeners)?;
+    serve(
+        accepted,
+        configure_socket,
+        bridge,
+        outbox,
+        config,
+        #[cfg(feature = "tls")]
+        None,
+        shutdown,
+    )
+    .await
+}
+
+/// Like `run_with_shutdown`, but serves `wss://` using the PEM certificate
+/// chain and private key at the given paths. Fails without binding if they
+/// can't be loaded.
+#[cfg(feature = "tls")]
+pub async fn run_tls(
+    bridge: Bridge,
+    outbox: Receiver<OutboundMessage>,
+    config: ServerConfig,
+    cert_path: &Path,
+    key_path: &Path,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), ServerError> {
+    let acceptor = tls::load_acceptor(cert_path, key_path).map_err(ServerError::Tls)?;
+    let listeners = bind_all_with(&listen_addrs(), &config).await?;
+    serve_tls(listeners, acceptor, bridge, outbox, config, shutdown).await
+}
+
+/// Serves `wss://` on listeners already bound.
+#[cfg(feature = "tls")]
+pub(crate) async fn serve_tls(
+    listeners: Vec<TcpListener>,
+    acceptor: tls::TlsAcceptor,
+    bridge: Bridge,
+    outbox: Receiver<OutboundMessage>,
+    config: ServerConfig,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), ServerError> {
+    let accepted = tcp_accepts(listeners)?;
+    serve(
+        accepted,
+        configure_socket,
+        bridge,
+        outbox,
+        config,
+        Some(acceptor),
+        shutdown,
+    )
+    .await
+}
+
+/// Unix socket peers have no address of their own, so they are all reported
+/// as coming from this one and told apart by their `ConnectionId`.
+#[cfg(all(unix, feature = "uds"))]
+pub const UDS_PEER_ADDR: SocketAddr =
+    SocketAddr::new(IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED), 0);
+
+/// Like `run_with_shutdown`, but serves clients connecting to a Unix domain
+/// socket at `path` instead of listening on TCP, e.g. for services on the
+/// same host. Binding fails if `path` already exists; the socket file is
+/// removed again once the server stops.
+#[cfg(all(unix, feature = "uds"))]
+pub async fn run_uds(
+    path: impl AsRef<Path>,
+    bridge: Bridge,
+    outbox: Receiver<OutboundMessage>,
+    config: ServerConfig,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), ServerError> {
+    let path = path.as_ref();
+    let listener = runtime::bind_unix(path)
+        .await
+        .map_err(|source| ServerError::Bind {
+            addr: path.display().to_string(),
+            source,
+        })?;
+    serve_uds(
+        listener,
+        path.to_path_buf(),
+        bridge,
+        outbox,
+        config,
+        shutdown,
+    )
+    .await
+}
+
+/// Serves on a Unix socket bound at `path`, removing it afterwards.
+#[cfg(all(unix, feature = "uds"))]
+pub(crate) async fn serve_uds(
+    listener: runtime::UnixListener,
+    path: std::path::PathBuf,
+    bridge: Bridge,
+    outbox: Receiver<OutboundMessage>,
+    config: ServerConfig,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), ServerError> {
+    info!("Listening on: {}", path.display());
+    let accepted = stream::unfold(listener, |listener| async move {
+        let accepted = runtime::accept_unix(&listener)
+            .await
+            .map(|stream| (stream, UDS_PEER_ADDR));
+        Some((accepted, listener))
+    })
+    .boxed();
+    let served = serve(
+        accepted,
+        |_, _, _| {},
+        bridge,
+        outbox,
+        config,
+        #[cfg(feature = "tls")]
+        None,
+        shutdown,
+    )
+    .await;
+    // A socket file left behind would make the next bind fail.
+    let _ = std::fs::remove_file(&path);
+    served
+}
+
+/// Binds the listening socket, also returning the address it ended up on.
+/// Bind to port 0 to have the OS pick a free port.
+pub async fn bind(addr: &str) -> Result<(TcpListener, SocketAddr), IoError> {
+    bind_with(addr, &ServerConfig::default()).await
+}
+
+/// Like `bind`, with the listener options in `config`
+/// (`reuse_address` and `reuse_port`).
+pub async fn bind_with(
+    addr: &str,
+    config: &ServerConfig,
+) -> Result<(TcpListener, SocketAddr), IoError> {
+    let mut last_error = None;
+    for addr in addr.to_socket_addrs()? {
+        match bind_socket(addr, config) {
+            Ok(listener) => {
+                let listener = runtime::listener_from_std(listener)?;
+                let local_addr = listener.local_addr()?;
+                return Ok((listener, local_addr));
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        IoError::new(io::ErrorKind::InvalidInput, "address resolved to nothing")
+    }))
+}
+
+fn bind_socket(addr: SocketAddr, config: &ServerConfig) -> io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // As in std, Windows is left alone: there SO_REUSEADDR lets another
+    // process bind a port that is still in use.
+    #[cfg(not(windows))]
+    socket.set_reuse_address(config.reuse_address)?;
+    if config.reuse_port {
+        set_reuse_port(&socket)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+))]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+)))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    Err(IoError::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT isn't available on this platform",
+    ))
+}
+
+/// Binds a listener on each of `addrs`. An address that can't be bound is
+/// logged and skipped; this only fails if none of them could be, with the
+/// last address's error.
+pub async fn bind_all(addrs: &[String]) -> Result<Vec<TcpListener>, ServerError> {
+    bind_all_with(addrs, &ServerConfig::default()).await
+}
+
+/// Like `bind_all`, with the listener options in `config`.
+pub async fn bind_all_with(
+    addrs: &[String],
+    config: &ServerConfig,
+) -> Result<Vec<TcpListener>, ServerError> {
+    let mut listeners = Vec::new();
+    let mut last_error = None;
+    for addr in addrs {
+        match bind_with(addr, config).await {
+            Ok((listener, _)) => listeners.push(listener),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse && !config.reuse_port => {
+                warn!(
+                    "Couldn't listen on {}: {}. If the old server is still draining, \
+                     start both with ServerConfig.reuse_port",
+                    addr, e
+                );
+                last_error = Some((addr, e));
+            }
+            Err(e) => {
+                warn!("Couldn't listen on {}: {}", addr, e);
+                last_error = Some((addr, e));
+            }
+        }
+    }
+    match last_error {
+        Some((addr, source)) if listeners.is_empty() => Err(ServerError::Bind {
+            addr: addr.clone(),
+            source,
+        }),
+        _ => Ok(listeners),
+    }
+}
+
+/// The comma-separated addresses given on the command line, e.g.
+/// `127.0.0.1:8080,[::1]:8080`, or the default one.
+fn listen_addrs() -> Vec<String> {
+    env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:8080".to_string())
+        .split(',')
+        .map(|addr| addr.trim().to_string())
+        .filter(|addr| !addr.is_empty())
+        .collect()
+}
+
+/// One stream of accepted connections across all of `listeners`.
+fn tcp_accepts(
+    listeners: Vec<TcpListener>,
+) -> Result<impl Stream<Item = io::Result<(runtime::TcpStream, SocketAddr)>> + Unpin, IoError> {
+    for listener in &listeners {
+        info!("Listening on: {}", listener.local_addr()?);
+    }
+    Ok(stream::select_all(listeners.into_iter().map(|listener| {
+        stream::unfold(listener, |listener| async move {
+            let accepted = runtime::accept(&listener).await;
+            Some((accepted, listener))
+        })
+        .boxed()
+    })))
+}
+
+/// Serves the connections `accepted` yields, applying `configure` to each
+/// stream before anything is read from it.
+async fn serve<S: AsyncStream>(
+    mut accepted: impl Stream<Item = io::Result<(S, SocketAddr)>> + Unpin,
+    configure: fn(&S, ConnectionId, &ServerConfig),
+    bridge: Bridge,
+    outbox: Receiver<OutboundMessage>,
+    config: ServerConfig,
+    #[cfg(feature = "tls")] tls: Option<tls::TlsAcceptor>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), ServerError> {
+    bridge.config.replace(config);
+    let config = bridge.config.current();
+
+    // A history file that can't be read leaves the history empty, but one
+    // that can't be written to stops the server from starting.
+    #[cfg(feature = "serde")]
+    let (history, history_log) = match &config.history_path {
+        Some(path) => {
+            let history = history::load(path, config.history_size);
+            let log = history::HistoryLog::open(path.clone(), &history)?;
+            (history, Some(log))
+        }
+        None => (History::default(), None),
+    };
+    #[cfg(not(feature = "serde"))]
+    let history = History::default();
+
+    let mut state = ServerState {
+        peers: bridge.peers.clone(),
+        rooms: bridge.rooms.clone(),
+        names: NameMap::default(),
+        history,
+        #[cfg(feature = "serde")]
+        history_log,
+        bridge,
+        pending_member_lists: Arc::default(),
+        ordered_relays: None,
+        active: Arc::default(),
+        active_per_ip: Arc::default(),
+        shutting_down: Arc::default(),
+    };
+
+    // Connection tasks relaying concurrently can reach two recipients in
+    // different orders. Funnelling every relay through one thread gives all
+    // of them the same order. The thread's own state has no sender, so it
+    // stops once the connection tasks are gone.
+    if config.total_order {
+        let (relay_sender, relays) = crossbeam_channel::unbounded::<Relay>();
+        let broadcaster = state.clone();
+        runtime::spawn_blocking(move || {
+            for relay in relays.iter() {
+                broadcaster.relay(relay);
+            }
+        });
+        state.ordered_relays = Some(relay_sender);
+    }
+
+    // The outbox is a blocking crossbeam channel, so it gets its own thread
+    // instead of tying up one of the async workers.
+    let outbox_peers = state.peers.clone();
+    let outbox_directory = state.bridge.directory.clone();
+    let outbox_entities = state.bridge.entities.clone();
+    let outbox_deaf = state.bridge.deaf.clone();
+    let outbox_config = state.bridge.config.clone();
+    runtime::spawn_blocking(move || {
+        for outbound in outbox.iter() {
+            dispatch(
+                &outbox_peers,
+                &outbox_directory,
+                &outbox_entities,
+                &outbox_deaf,
+                outbound,
+                Priority::Low,
+                &outbox_config.current().close_codes,
+            );
+        }
+    });
+
+    if let Some(addr) = &config.metrics_addr {
+        let (listener, local_addr) =
+            bind_with(addr, &config)
+                .await
+                .map_err(|source| ServerError::Bind {
+                    addr: addr.clone(),
+                    source,
+                })?;
+        info!("Serving metrics on: {}", local_addr);
+        runtime::spawn(async move {
+            if let Err(e) = metrics::serve(listener).await {
+                warn!("Metrics endpoint stopped: {}", e);
+            }
+        });
+    }
+
+    // Let's spawn the handling of each connection in a separate task, until
+    // the open connections have closed after shutdown, or an accept fails.
+    // Clients arriving during shutdown are still answered, with a 503 or a
+    // Close frame, so they know to go elsewhere. Under `reuse_port` another
+    // server may share the port, so accepting stops at once and newcomers
+    // go to that one instead.
+    //
+    // Nothing more is accepted while `max_inflight_handshakes` are under
+    // way; further clients wait in the listen backlog. With no slots at
+    // all nothing would ever be accepted, so there is always one.
+    let handshakes = Semaphore::new(config.max_inflight_handshakes.max(1));
+    let hand_off = config.reuse_port;
+    let closed = async {
+        shutdown.await;
+        state.shutting_down.store(true, Ordering::SeqCst);
+        if !hand_off {
+            close_all(&state.peers, &state.bridge.config.current().close_codes).await;
+        }
+    };
+    pin_mut!(closed);
+    let mut finished = false;
+    let mut failed = None;
+    loop {
+        let next = Box::pin(async {
+            let permit = handshakes.acquire().await;
+            (permit, accepted.next().await)
+        });
+        let (handshake, stream, addr) = match future::select(next, closed.as_mut()).await {
+            future::Either::Left(((permit, Some(Ok((stream, addr)))), _)) => (permit, stream, addr),
+            future::Either::Left(((_, Some(Err(e))), _)) => {
+                error!("Accepting connections failed: {}", e);
+                failed = Some(ServerError::Accept(e));
+                break;
+            }
+            future::Either::Left(((_, None), _)) => break,
+            future::Either::Right(_) => {
+                finished = !hand_off;
+                break;
+            }
+        };
+
+        // The address is only logged here; everything after refers
+        // to the connection by its id.
+        let id = ConnectionId::next();
+        info!("Incoming TCP connection from: {} as {}", addr, id);
+        // Banned clients don't get as far as a handshake.
+        if state.bridge.bans.lock().unwrap().contains(&addr.ip()) {
+            info!("Dropping {}: banned", id);
+            continue;
+        }
+        configure(&stream, id, &state.bridge.config.current());
+        let admission = state.admit(addr);
+
+        #[cfg(feature = "tls")]
+        if let Some(acceptor) = tls.clone() {
+            let state = state.clone();
+            spawn_connection(id, addr, async move {
+                // The TLS handshake counts against `handshake_timeout` too,
+                // though the WebSocket handshake then gets a fresh one.
+                let timeout = state.bridge.config.current().handshake_timeout;
+                match runtime::timeout(timeout, acceptor.accept(stream)).await {
+                    Some(Ok(stream)) => {
+                        start_connection(state, stream, id, addr, admission, handshake).await
+                    }
+                    Some(Err(e)) => {
+                        warn!("TLS handshake with {} failed: {}", id, e);
+                        metrics::handshake_failed();
+                    }
+                    None => {
+                        info!("Dropping {}: no TLS handshake within {:?}", id, timeout);
+                        metrics::handshake_failed();
+                    }
+                }
+            });
+            continue;
+        }
+
+        spawn_connection(
+            id,
+            addr,
+            start_connection(state.clone(), stream, id, addr, admission, handshake),
+        );
+    }
+
+    // Closing the listeners before draining lets the OS hand new clients
+    // to any other server on the port.
+    drop(accepted);
+    if !finished {
+        state.shutting_down.store(true, Ordering::SeqCst);
+        close_all(&state.peers, &state.bridge.config.current().close_codes).await;
+    }
+
+    match failed {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Applies the TCP options in `config` to a freshly accepted socket. A
+/// socket that refuses them is still served.
+fn configure_socket(stream: &runtime::TcpStream, id: ConnectionId, config: &ServerConfig) {
+    if let Err(e) = runtime::set_nodelay(stream, config.tcp_nodelay) {
+        warn!("Setting TCP_NODELAY on {} failed: {}", id, e);
+    }
+    if let Some(interval) = config.tcp_keepalive {
+        let keepalive = TcpKeepalive::new()
+            .with_time(interval)
+            .with_interval(interval);
+        if let Err(e) = runtime::set_keepalive(stream, &keepalive) {
+            warn!("Enabling TCP keepalive on {} failed: {}", id, e);
+        }
+    }
+}
+
+/// Closes a peer's connection with the Close frame for `reason`, or the one
+/// `codes` has for it, once everything already queued for it has been
+/// written.
+pub fn close_with_reason(tx: &Tx, reason: &DisconnectReason, codes: &CloseCodes) {
+    tx.close(reason.close_frame_with(codes));
+}
+
+/// Closes a peer's connection once everything already queued for it has been
+/// written. Anything sent to it afterwards is refused.
+fn drain_and_close(tx: &Tx, code: CloseCode, reason: impl Into<String>) {
+    tx.close(CloseFrame {
+        code,
+        reason: reason.into().into(),
+    });
+}
+
+/// Sends a Close frame to every peer and waits for the connection tasks to
+/// remove themselves from the map, giving up after `SHUTDOWN_GRACE`.
+async fn close_all(peer_map: &PeerMap, codes: &CloseCodes) {
+    info!("Shutting down, closing all connections");
+    // Handshakes already under way may still add peers, so the map is
+    // closed again each round; closing a peer twice does nothing.
+    let drained = async {
+        loop {
+            for peer in peer_map.iter() {
+                close_with_reason(peer.value(), &DisconnectReason::Shutdown, codes);
+            }
+            if peer_map.is_empty() {
+                break;
+            }
+            runtime::sleep(Duration::from_millis(20)).await;
+        }
+    };
+    if runtime::timeout(SHUTDOWN_GRACE, drained).await.is_none() {
+        warn!("Timed out waiting for connections to close");
+    }
+}
+
+/// Resource used to stop the server from the Bevy side.
+pub struct ShutdownHandle {
+    trigger: Option<oneshot::Sender<()>>,
+    finished: Receiver<()>,
+}
+
+impl ShutdownHandle {
+    /// Signals the server to shut down and blocks until it has closed its
+    /// connections, or `SHUTDOWN_GRACE` has passed.
+    pub fn shutdown(&mut self) {
+        if let Some(trigger) = self.trigger.take() {
+            let _ = trigger.send(());
+            let _ = self.finished.recv_timeout(SHUTDOWN_GRACE);
+        }
+    }
+}
+
+/// Starts a `Server` on the async runtime and stores its parts as
+/// resources. A `ServerConfig` resource, if
+/// present, overrides the default settings, and a `TlsConfig` resource
+/// switches the server to `wss://`.
+pub fn setup(
+    mut commands: Commands,
+    config: Option<Res<ServerConfig>>,
+    #[cfg(feature = "tls")] tls: Option<Res<TlsConfig>>,
+) {
+    let config = config.map(|config| config.clone()).unwrap_or_default();
+    #[cfg(feature = "tls")]
+    let tls = tls.map(|config| config.clone());
+
+    let server = Server::launch(config, move |bridge, outbox, config, shutdown| {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = tls {
+            return async move {
+                run_tls(
+                    bridge,
+                    outbox,
+                    config,
+                    &tls.cert_path,
+                    &tls.key_path,
+                    shutdown,
+                )
+                .await
+            }
+            .boxed();
+        }
+        run_with_shutdown(bridge, outbox, config, shutdown).boxed()
+    });
+
+    commands.insert_resource(server.messages);
+    commands.insert_resource(server.message_sender);
+    commands.insert_resource(server.connections);
+    commands.insert_resource(server.commands);
+    commands.insert_resource(server.stats);
+    commands.insert_resource(server.bans);
+    commands.insert_resource(server.deaf);
+    commands.insert_resource(server.directory);
+    commands.insert_resource(server.config);
+    commands.insert_resource(Connections::default());
+    commands.insert_resource(CommandRouter::default());
+    commands.insert_resource(ConnectionEntities::default());
+    commands.insert_resource(server.entities);
+    commands.insert_resource(server.rooms);
+    commands.insert_resource(Rooms::default());
+    commands.insert_resource(WsReplyHandle(server.outbox.clone()));
+    commands.insert_resource(server.outbox);
+    commands.insert_resource(server.handle);
+}
+
+/// Shuts the server down cleanly when the app is exiting.
+pub fn shutdown_on_exit(mut exits: EventReader<AppExit>, mut handle: ResMut<ShutdownHandle>) {
+    if exits.iter().next().is_some() {
+        handle.shutdown();
+    }
+}
+
+/// Drains messages forwarded by the connection tasks and emits them as
+/// `WsMessageReceived` events.
+pub fn pump_incoming_messages(
+    receiver: Res<BridgeReceiver<WsMessageReceived>>,
+    mut events: EventWriter<WsMessageReceived>,
+) {
+    for received in channel::drain(&*receiver) {
+        events.send(received);
+    }
+}
+
+/// Event asking for a client to be disconnected.
+#[derive(Debug, Clone)]
+pub struct KickRequest {
+    pub id: ConnectionId,
+    pub reason: String,
+}
+
+/// Passes `KickRequest` events on to the server, which closes the
+/// connection with the given reason.
+pub fn process_kick_requests(mut kicks: EventReader<KickRequest>, outbox: Res<WsOutbox>) {
+    for kick in kicks.iter() {
+        outbox.send(OutboundMessage::Kick(kick.id, kick.reason.clone()));
+    }
+}
+
+/// Kicks the connections that have been idle for longer than
+/// `ServerConfig.max_idle`. Run it on a `FixedTimestep` to sweep less
+/// often than every frame.
+pub fn reap_idle_connections(
+    directory: Res<Directory>,
+    config: Res<LiveConfig>,
+    outbox: Res<WsOutbox>,
+) {
+    let config = config.current();
+    let max_idle = match config.max_idle {
+        Some(max_idle) => max_idle,
+        None => return,
+    };
+    // Nothing can have idled for longer than the clock has run.
+    let cutoff = match config.clock.now().checked_sub(max_idle) {
+        Some(cutoff) => cutoff,
+        None => return,
+    };
+    // A connection already kicked stays in the directory until it has
+    // closed, but kicking it again does nothing.
+    for id in directory::idle_since(&directory, cutoff) {
+        outbox.send(OutboundMessage::Kick(
+            id,
+            format!("idle for more than {:?}", max_idle),
+        ));
+    }
+}
+
+/// Event making a client deaf to broadcasts (see `DeafSet`), or letting it
+/// hear them again.
+#[derive(Debug, Clone)]
+pub struct SetDeaf {
+    pub id: ConnectionId,
+    pub deaf: bool,
+}
+
+/// Records `SetDeaf` events in the `DeafSet`.
+pub fn process_deaf_requests(mut requests: EventReader<SetDeaf>, deaf: Res<DeafSet>) {
+    let mut deaf = deaf.lock().unwrap();
+    for request in requests.iter() {
+        if request.deaf {
+            info!("Deafening {}", request.id);
+            deaf.insert(request.id);
+        } else {
+            info!("Undeafening {}", request.id);
+            deaf.remove(&request.id);
+        }
+    }
+}
+
+/// Event asking for a client's chat to be dropped for `duration`. A later
+/// request replaces the mute, so a zero duration lifts it.
+#[derive(Debug, Clone)]
+pub struct MuteRequest {
+    pub id: ConnectionId,
+    pub duration: Duration,
+}
+
+/// Records `MuteRequest` events in the `Directory`, where the connection
+/// tasks check for them.
+pub fn process_mute_requests(
+    mut mutes: EventReader<MuteRequest>,
+    directory: Res<Directory>,
+    config: Res<LiveConfig>,
+) {
+    let now = config.current().clock.now();
+    for mute in mutes.iter() {
+        info!("Muting {} for {:?}", mute.id, mute.duration);
+        directory::update(&directory, mute.id, |info| {
+            info.muted_until = Some(now + mute.duration)
+        });
+    }
+}
+
+/// Hands `UpdateServerConfig` events to the running server. When several
+/// arrive in one frame the last one wins.
+pub fn apply_config_updates(mut updates: EventReader<UpdateServerConfig>, config: Res<LiveConfig>) {
+    if let Some(UpdateServerConfig(update)) = updates.iter().last() {
+        info!("Applying updated server settings");
+        config.replace(update.clone());
+    }
+}
+
+/// Drains commands parsed by the connection tasks and emits them as
+/// `ClientCommandReceived` events.
+pub fn pump_client_commands(
+    receiver: Res<BridgeReceiver<ClientCommandReceived>>,
+    mut events: EventWriter<ClientCommandReceived>,
+) {
+    for received in channel::drain(&*receiver) {
+        events.send(received);
+    }
+}
+
+/// Spawns an entity for every new connection and despawns it again once the
+/// connection goes away, sending `ConnectionOpened` and `ConnectionClosed`
+/// events as it does. With a `CleanupHooks` resource the despawn is left to
+/// `cleanup_on_disconnect`.
+pub fn sync_connections(
+    mut commands: Commands,
+    receiver: Res<BridgeReceiver<ConnectionEvent>>,
+    cleanup: Option<Res<CleanupHooks>>,
+    mut entities: ResMut<ConnectionEntities>,
+    entity_map: Res<EntityMap>,
+    mut opened: EventWriter<ConnectionOpened>,
+    mut closed: EventWriter<ConnectionClosed>,
+) {
+    for event in channel::drain(&*receiver) {
+        match event {
+            ConnectionEvent::Connected {
+                id,
+                addr,
+                connected_at,
+                subprotocol,
+            } => {
+                let entity = commands
+                    .spawn()
+                    .insert(Connection {
+                        id,
+                        addr,
+                        connected_at,
+                        subprotocol: subprotocol.clone(),
+                    })
+                    .id();
+                entities.0.insert(id, entity);
+                entity_map.insert(entity, id);
+                opened.send(ConnectionOpened {
+                    id,
+                    addr,
+                    subprotocol,
+                });
+            }
+            ConnectionEvent::Disconnected { id, addr, reason } => {
+                let entity = entities.0.remove(&id);
+                if let Some(entity) = entity {
+                    entity_map.remove(&entity);
+                }
+                if let (Some(entity), None) = (entity, &cleanup) {
+                    commands.entity(entity).despawn();
+                }
+                closed.send(ConnectionClosed {
+                    id,
+                    addr,
+                    reason,
+                    entity,
+                });
+            }
+        }
+    }
+}
+
+/// Logs connection events through Bevy's `LogPlugin`.
+pub fn log_connection_events(
+    mut opened: EventReader<ConnectionOpened>,
+    mut closed: EventReader<ConnectionClosed>,
+) {
+    for event in opened.iter() {
+        info!("{} connected", event.id);
+    }
+    for event in closed.iter() {
+        match event.reason {
+            DisconnectReason::Normal => info!("{} disconnected", event.id),
+            _ if event.reason.is_transient() => {
+                info!("{} dropped, may reconnect: {}", event.id, event.reason)
+            }
+            _ => warn!("{} disconnected: {}", event.id, event.reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::app::Events;
+
+    use super::*;
+
+    /// An app running `sync_connections`, with the sender feeding it.
+    fn connections_app() -> (App, BridgeSender<ConnectionEvent>) {
+        let (sender, receiver) = channel::unbounded::<ConnectionEvent>();
+        let mut builder = App::build();
+        builder
+            .add_event::<ConnectionOpened>()
+            .add_event::<ConnectionClosed>()
+            .insert_resource(receiver)
+            .insert_resource(EntityMap::default())
+            .init_resource::<ConnectionEntities>()
+            .add_system(sync_connections.system());
+        (builder.app, sender)
+    }
+
+    fn connection_count(app: &mut App) -> usize {
+        app.world.query::<&Connection>().iter(&app.world).count()
+    }
+
+    fn connected(id: u64) -> ConnectionEvent {
+        ConnectionEvent::Connected {
+            id: ConnectionId(id),
+            addr: "127.0.0.1:4000".parse().unwrap(),
+            connected_at: Instant::now(),
+            subprotocol: None,
+        }
+    }
+
+    #[test]
+    fn connecting_spawns_an_entity() {
+        let (mut app, sender) = connections_app();
+        app.update();
+        assert_eq!(connection_count(&mut app), 0);
+
+        sender.send(connected(1)).unwrap();
+        sender.send(connected(2)).unwrap();
+        app.update();
+        assert_eq!(connection_count(&mut app), 2);
+        let entities = app.world.get_resource::<ConnectionEntities>().unwrap();
+        let entity = entities.0[&ConnectionId(1)];
+        let connection = app.world.get::<Connection>(entity).unwrap();
+        assert_eq!(connection.id, ConnectionId(1));
+    }
+
+    #[test]
+    fn disconnecting_despawns_the_entity() {
+        let (mut app, sender) = connections_app();
+        sender.send(connected(1)).unwrap();
+        app.update();
+
+        sender
+            .send(ConnectionEvent::Disconnected {
+                id: ConnectionId(1),
+                addr: "127.0.0.1:4000".parse().unwrap(),
+                reason: DisconnectReason::Normal,
+            })
+            .unwrap();
+        app.update();
+        assert_eq!(connection_count(&mut app), 0);
+        assert!(app.world.get_resource::<EntityMap>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn connection_ids_increase() {
+        let first = ConnectionId::next();
+        let second = ConnectionId::next();
+        assert!(second > first);
+    }
+
+    /// Peers `#1` to `#count`, along with their queues.
+    fn peers(count: u64) -> (PeerMap, Vec<queue::Rx>) {
+        let peers = PeerMap::default();
+        let mut receivers = Vec::new();
+        for id in 1..=count {
+            let (tx, rx) = queue::channel(8, OverflowPolicy::DropOldest);
+            peers.insert(ConnectionId(id), tx);
+            receivers.push(rx);
+        }
+        (peers, receivers)
+    }
+
+    #[test]
+    fn broadcast_reaches_every_other_member() {
+        let (peers, mut receivers) = peers(4);
+        // The fourth is in another room.
+        let members = (1..=3).map(ConnectionId).collect();
+
+        let msg = Message::text("hello");
+        let stale = broadcast(&peers, ConnectionId(1), &members, &msg);
+        assert!(stale.is_empty());
+        let received: Vec<_> = receivers
+            .iter_mut()
+            .map(|rx| rx.next().now_or_never())
+            .collect();
+        assert_eq!(
+            received,
+            vec![None, Some(Some(msg.clone())), Some(Some(msg)), None]
+        );
+    }
+
+    #[test]
+    fn broadcast_reports_closed_peers() {
+        let (peers, mut receivers) = peers(3);
+        // The second peer went away without being removed yet.
+        drop(receivers.remove(1));
+        let members = (1..=3).map(ConnectionId).collect();
+
+        let msg = Message::text("hello");
+        let stale = broadcast(&peers, ConnectionId(1), &members, &msg);
+        assert_eq!(stale, vec![ConnectionId(2)]);
+        assert_eq!(receivers[1].next().now_or_never(), Some(Some(msg)));
+        assert_eq!(receivers[0].next().now_or_never(), None);
+    }
+
+    #[test]
+    fn reliable_broadcast_waits_for_room() {
+        let peers = PeerMap::default();
+        let mut receivers = Vec::new();
+        for id in 1..=2 {
+            let (tx, rx) = queue::channel(1, OverflowPolicy::DropNewest);
+            tx.send(Message::text("backlog")).unwrap();
+            peers.insert(ConnectionId(id), tx);
+            receivers.push(rx);
+        }
+        let mut stuck = receivers.pop().unwrap();
+        let mut draining = receivers.pop().unwrap();
+
+        let msg = Message::text("important");
+        let drain = async {
+            runtime::sleep(Duration::from_millis(50)).await;
+            draining.next().await
+        };
+        let (laggards, drained) = runtime::block_on(future::join(
+            broadcast_reliable(&peers, &msg, Duration::from_millis(500)),
+            drain,
+        ));
+        assert_eq!(drained, Some(Message::text("backlog")));
+        assert_eq!(draining.next().now_or_never(), Some(Some(msg)));
+        // The peer that never made room is dropped instead.
+        assert_eq!(laggards, vec![ConnectionId(2)]);
+        assert!(!peers.contains_key(&ConnectionId(2)));
+        assert_eq!(stuck.next().now_or_never(), Some(None));
+    }
+
+    #[test]
+    fn errors_are_classified_by_whether_retrying_helps() {
+        let lost = WsError::Io(io::Error::from(io::ErrorKind::BrokenPipe));
+        let reason = DisconnectReason::from_ws_error(&lost);
+        assert!(matches!(reason, DisconnectReason::Reset(_)), "{:?}", reason);
+        assert!(reason.is_transient());
+
+        let reason = DisconnectReason::from_ws_error(&WsError::ConnectionClosed);
+        assert!(matches!(reason, DisconnectReason::Reset(_)), "{:?}", reason);
+
+        let broken = WsError::Protocol(ProtocolError::NonZeroReservedBits);
+        let reason = DisconnectReason::from_ws_error(&broken);
+        assert!(
+            matches!(reason, DisconnectReason::Protocol(_)),
+            "{:?}",
+            reason
+        );
+        assert!(!reason.is_transient());
+
+        let denied = WsError::Io(io::Error::from(io::ErrorKind::PermissionDenied));
+        let reason = DisconnectReason::from_ws_error(&denied);
+        assert!(matches!(reason, DisconnectReason::Error(_)), "{:?}", reason);
+        assert!(!reason.is_transient());
+    }
+
+    fn echo(mut received: EventReader<WsMessageReceived>, reply: Res<WsReplyHandle>) {
+        for WsMessageReceived { id, msg } in received.iter() {
+            reply.to(*id, msg.clone());
+        }
+    }
+
+    #[test]
+    fn replies_go_back_to_their_sender() {
+        let (sender, outbox) = crossbeam_channel::unbounded();
+        let mut builder = App::build();
+        builder
+            .add_event::<WsMessageReceived>()
+            .insert_resource(WsReplyHandle(WsOutbox(sender)))
+            .add_system(echo.system());
+        let mut app = builder.app;
+        let mut received = app
+            .world
+            .get_resource_mut::<Events<WsMessageReceived>>()
+            .unwrap();
+        for (id, text) in [(1, "from one"), (2, "from two")].iter() {
+            received.send(WsMessageReceived {
+                id: ConnectionId(*id),
+                msg: Message::text(*text),
+            });
+        }
+        app.update();
+
+        let replies: Vec<_> = outbox
+            .try_iter()
+            .map(|outbound| match outbound {
+                OutboundMessage::To(id, msg) => (id, msg),
+                other => panic!("Expected a reply, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            replies,
+            [
+                (ConnectionId(1), Message::text("from one")),
+                (ConnectionId(2), Message::text("from two")),
+            ]
+        );
+    }
+
+    #[test]
+    fn each_reason_closes_with_its_code() {
+        let cases = vec![
+            (DisconnectReason::Normal, 1000),
+            (DisconnectReason::Reset("gone".to_string()), 1011),
+            (DisconnectReason::Protocol("bad frame".to_string()), 1002),
+            (DisconnectReason::Error("oops".to_string()), 1011),
+            (DisconnectReason::Timeout, 1001),
+            (DisconnectReason::WriteTimeout, 1013),
+            (DisconnectReason::Overflow, 1013),
+            (DisconnectReason::QuotaExceeded, 1008),
+            (DisconnectReason::FrameLimitExceeded, 1008),
+            (
+                DisconnectReason::OutdatedProtocol {
+                    version: 1,
+                    minimum: 2,
+                },
+                1002,
+            ),
+            (DisconnectReason::Kicked("cheating".to_string()), 1008),
+            (DisconnectReason::ServerFull, 1013),
+            (DisconnectReason::TooManyFromAddress, 1008),
+            (DisconnectReason::Shutdown, 1001),
+        ];
+        for (reason, code) in cases {
+            assert_eq!(u16::from(reason.close_frame().code), code, "{:?}", reason);
+        }
+        let kicked = DisconnectReason::Kicked("cheating".to_string()).close_frame();
+        assert_eq!(kicked.reason, "cheating");
+    }
+
+    #[test]
+    fn long_close_reasons_are_cut_at_a_char_boundary() {
+        let frame = DisconnectReason::Kicked("\u{e9}".repeat(100)).close_frame();
+        assert_eq!(frame.reason.len(), 122);
+        assert!(frame.reason.chars().all(|c| c == '\u{e9}'));
+    }
+
+    #[test]
+    fn messages_to_an_entity_reach_its_connection() {
+        let (mut app, sender) = connections_app();
+        sender.send(connected(1)).unwrap();
+        app.update();
+        let entity = app.world.get_resource::<ConnectionEntities>().unwrap().0[&ConnectionId(1)];
+        let entities = app.world.get_resource::<EntityMap>().unwrap().clone();
+        let (peers, mut receivers) = peers(2);
+        let send = |msg: &str| {
+            dispatch(
+                &peers,
+                &Directory::default(),
+                &entities,
+                &DeafSet::default(),
+                OutboundMessage::ToEntity(entity, Message::text(msg)),
+                Priority::Low,
+                &CloseCodes::default(),
+            )
+        };
+
+        send("for the entity");
+        assert_eq!(
+            receivers[0].next().now_or_never(),
+            Some(Some(Message::text("for the entity")))
+        );
+        assert!(receivers[1].next().now_or_never().is_none());
+
+        // Once the connection is gone, so is the entity's mapping.
+        sender
+            .send(ConnectionEvent::Disconnected {
+                id: ConnectionId(1),
+                addr: "127.0.0.1:4000".parse().unwrap(),
+                reason: DisconnectReason::Normal,
+            })
+            .unwrap();
+        app.update();
+        send("too late");
+        assert!(receivers[0].next().now_or_never().is_none());
+    }
+
+    #[test]
+    fn urgent_messages_overtake_what_is_queued() {
+        let (peers, mut receivers) = peers(1);
+        let send = |outbound: OutboundMessage| {
+            dispatch(
+                &peers,
+                &Directory::default(),
+                &EntityMap::default(),
+                &DeafSet::default(),
+                outbound,
+                Priority::Low,
+                &CloseCodes::default(),
+            )
+        };
+        for text in ["1", "2"] {
+            send(OutboundMessage::To(ConnectionId(1), Message::text(text)));
+        }
+        send(OutboundMessage::To(ConnectionId(1), Message::text("now")).urgent());
+
+        let received: Vec<_> =
+            std::iter::from_fn(|| receivers[0].next().now_or_never().flatten()).collect();
+        assert_eq!(
+            received,
+            [Message::text("now"), Message::text("1"), Message::text("2")]
+        );
+    }
+
+    #[test]
+    fn accepted_sockets_get_the_configured_options() {
+        runtime::block_on(async {
+            let (listener, addr) = bind("127.0.0.1:0").await.unwrap();
+            let mut accepted = tcp_accepts(vec![listener]).unwrap();
+            for nodelay in [true, false].iter() {
+                let _client = runtime::connect(&addr.to_string()).await.unwrap();
+                let (stream, _) = accepted.next().await.unwrap().unwrap();
+                let config = ServerConfig {
+                    tcp_nodelay: *nodelay,
+                    tcp_keepalive: Some(Duration::from_secs(30)),
+                    ..ServerConfig::default()
+                };
+                configure_socket(&stream, ConnectionId(1), &config);
+                #[cfg(feature = "tokio-runtime")]
+                let socket = socket2::SockRef::from(stream.get_ref());
+                #[cfg(not(feature = "tokio-runtime"))]
+                let socket = socket2::SockRef::from(&stream);
+                assert_eq!(socket.tcp_nodelay().unwrap(), *nodelay);
+                assert!(socket.keepalive().unwrap());
+            }
+        });
+    }
+}
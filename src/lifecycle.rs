@@ -0,0 +1,275 @@
+//! Players coming and going, on top of the raw connection events.
+//!
+//! `announce_players` sends `PlayerJoined` once a connection has registered
+//! a name, with its room and user filled in, and `announce_departures`
+//! sends `PlayerLeft` when a connection announced that way closes.
+//! `PlayerLifecyclePlugin` registers both events and adds both systems.
+//!
+//! Hooks registered on the `CleanupHooks` resource can save or drop the
+//! components on a connection's entity. While the resource exists,
+//! `sync_connections` leaves the entities of closed connections to
+//! `cleanup_on_disconnect`, which runs the hooks and only then despawns the
+//! entity.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use bevy::{
+    app::{Events, ManualEventReader},
+    prelude::*,
+};
+
+use crate::{
+    ConnectionClosed, ConnectionEntities, ConnectionId, Connections, DisconnectReason, UserId,
+};
+
+/// Event sent by `announce_players` when a connection first has a name, for
+/// apps that register it with `add_event` or `PlayerLifecyclePlugin`.
+/// Clients that never pick one with `/nick` never join.
+#[derive(Debug, Clone)]
+pub struct PlayerJoined {
+    pub entity: Entity,
+    pub id: ConnectionId,
+    pub name: String,
+    pub room: String,
+    /// Set when the client authenticated, in the handshake or with `/auth`
+    /// before it picked its name.
+    pub user: Option<UserId>,
+}
+
+/// Sends `PlayerJoined` for every connection that has registered a name and
+/// has an entity, once per connection.
+pub fn announce_players(
+    connections: Res<Connections>,
+    entities: Res<ConnectionEntities>,
+    mut announced: Local<HashSet<ConnectionId>>,
+    joined: Option<ResMut<Events<PlayerJoined>>>,
+) {
+    let mut joined = match joined {
+        Some(joined) => joined,
+        None => return,
+    };
+    announced.retain(|id| connections.get(*id).is_some());
+    for (id, info) in connections.iter() {
+        let (name, entity) = match (&info.name, entities.0.get(id)) {
+            (Some(name), Some(entity)) => (name, entity),
+            _ => continue,
+        };
+        if announced.insert(*id) {
+            joined.send(PlayerJoined {
+                entity: *entity,
+                id: *id,
+                name: name.clone(),
+                room: info.room.clone(),
+                user: info.user.clone(),
+            });
+        }
+    }
+}
+
+/// Event sent by `announce_departures` when a connection that got a
+/// `PlayerJoined` closes. Its entity has been despawned by then, or is about
+/// to be once the `CleanupHooks` have run.
+#[derive(Debug, Clone)]
+pub struct PlayerLeft {
+    pub entity: Entity,
+    pub id: ConnectionId,
+    pub reason: DisconnectReason,
+}
+
+/// Sends `PlayerLeft` for every closed connection that was announced with
+/// `PlayerJoined`, and for no other.
+pub fn announce_departures(
+    mut joined: EventReader<PlayerJoined>,
+    mut closed: EventReader<ConnectionClosed>,
+    mut players: Local<HashMap<ConnectionId, Entity>>,
+    mut left: EventWriter<PlayerLeft>,
+) {
+    // Joins first, so a player joining and leaving in the same frame still
+    // leaves.
+    for event in joined.iter() {
+        players.insert(event.id, event.entity);
+    }
+    for event in closed.iter() {
+        if let Some(entity) = players.remove(&event.id) {
+            left.send(PlayerLeft {
+                entity,
+                id: event.id,
+                reason: event.reason.clone(),
+            });
+        }
+    }
+}
+
+/// Registers `PlayerJoined` and `PlayerLeft` and adds the systems sending
+/// them. Requires the `ConnectionClosed` event.
+#[derive(Default)]
+pub struct PlayerLifecyclePlugin;
+
+impl Plugin for PlayerLifecyclePlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_event::<PlayerJoined>()
+            .add_event::<PlayerLeft>()
+            .add_system(announce_players.system())
+            .add_system(announce_departures.system());
+    }
+}
+
+type Hook = Arc<dyn Fn(&mut World, Entity, &ConnectionClosed) + Send + Sync>;
+
+/// Resource holding the hooks run for every closed connection, in the order
+/// they were added.
+#[derive(Default)]
+pub struct CleanupHooks {
+    hooks: Vec<Hook>,
+    closed: ManualEventReader<ConnectionClosed>,
+}
+
+impl CleanupHooks {
+    pub fn add(
+        &mut self,
+        hook: impl Fn(&mut World, Entity, &ConnectionClosed) + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+}
+
+/// Runs the `CleanupHooks` for every `ConnectionClosed` event and despawns
+/// the connection's entity. Add it with `exclusive_system`.
+pub fn cleanup_on_disconnect(world: &mut World) {
+    if !world.contains_resource::<CleanupHooks>() {
+        return;
+    }
+    world.resource_scope(|world, mut hooks: Mut<CleanupHooks>| {
+        let closed: Vec<ConnectionClosed> = match world.get_resource::<Events<ConnectionClosed>>() {
+            Some(events) => hooks.closed.iter(events).cloned().collect(),
+            None => return,
+        };
+
+        for event in closed {
+            let entity = match event.entity {
+                Some(entity) => entity,
+                None => continue,
+            };
+            for hook in &hooks.hooks {
+                hook(world, entity, &event);
+            }
+            world.despawn(entity);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Mutex, time::Instant};
+
+    use super::*;
+    use crate::{
+        channel::{self, BridgeSender},
+        sync_connections, ConnectionEvent, ConnectionOpened, EntityMap,
+    };
+
+    struct Score(u32);
+
+    /// An app leaving despawns to `cleanup_on_disconnect`, with a hook
+    /// saving each closed connection's score.
+    fn cleanup_app() -> (App, BridgeSender<ConnectionEvent>, Arc<Mutex<Vec<u32>>>) {
+        let (sender, receiver) = channel::unbounded::<ConnectionEvent>();
+        let saved = Arc::new(Mutex::new(Vec::new()));
+        let mut hooks = CleanupHooks::default();
+        let save = Arc::clone(&saved);
+        hooks.add(move |world, entity, _| {
+            if let Some(score) = world.get::<Score>(entity) {
+                save.lock().unwrap().push(score.0);
+            }
+        });
+        let mut builder = App::build();
+        builder
+            .add_event::<ConnectionOpened>()
+            .add_event::<ConnectionClosed>()
+            .insert_resource(receiver)
+            .insert_resource(EntityMap::default())
+            .insert_resource(hooks)
+            .init_resource::<ConnectionEntities>()
+            .add_system(sync_connections.system())
+            .add_system(cleanup_on_disconnect.exclusive_system());
+        (builder.app, sender, saved)
+    }
+
+    fn connected(id: u64) -> ConnectionEvent {
+        ConnectionEvent::Connected {
+            id: ConnectionId(id),
+            addr: "127.0.0.1:4000".parse().unwrap(),
+            connected_at: Instant::now(),
+            subprotocol: None,
+        }
+    }
+
+    fn disconnected(id: u64) -> ConnectionEvent {
+        ConnectionEvent::Disconnected {
+            id: ConnectionId(id),
+            addr: "127.0.0.1:4000".parse().unwrap(),
+            reason: DisconnectReason::Normal,
+        }
+    }
+
+    #[test]
+    fn hooks_see_the_components_before_the_despawn() {
+        let (mut app, sender, saved) = cleanup_app();
+        sender.send(connected(1)).unwrap();
+        app.update();
+        let entity = app.world.get_resource::<ConnectionEntities>().unwrap().0[&ConnectionId(1)];
+        app.world.entity_mut(entity).insert(Score(7));
+
+        sender.send(disconnected(1)).unwrap();
+        app.update();
+        app.update();
+        assert_eq!(*saved.lock().unwrap(), [7]);
+        assert!(app.world.get_entity(entity).is_none());
+    }
+
+    #[test]
+    fn only_announced_players_leave() {
+        let mut builder = App::build();
+        builder
+            .add_event::<ConnectionClosed>()
+            .add_event::<PlayerJoined>()
+            .add_event::<PlayerLeft>()
+            .add_system(announce_departures.system());
+        let mut app = builder.app;
+        let entity = app.world.spawn().id();
+        app.world
+            .get_resource_mut::<Events<PlayerJoined>>()
+            .unwrap()
+            .send(PlayerJoined {
+                entity,
+                id: ConnectionId(1),
+                name: "alice".to_string(),
+                room: "lobby".to_string(),
+                user: None,
+            });
+        let mut closed = app
+            .world
+            .get_resource_mut::<Events<ConnectionClosed>>()
+            .unwrap();
+        for id in [1, 2].iter() {
+            closed.send(ConnectionClosed {
+                id: ConnectionId(*id),
+                addr: "127.0.0.1:4000".parse().unwrap(),
+                reason: DisconnectReason::Normal,
+                entity: None,
+            });
+        }
+        app.update();
+
+        let left = app.world.get_resource::<Events<PlayerLeft>>().unwrap();
+        let left: Vec<_> = ManualEventReader::default()
+            .iter(left)
+            .map(|event| event.id)
+            .collect();
+        assert_eq!(left, [ConnectionId(1)]);
+    }
+}
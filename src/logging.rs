@@ -0,0 +1,143 @@
+//! How much of each received message ends up in the server's log, where
+//! it is logged at the `info` level.
+
+use std::{fmt, sync::Arc};
+
+use async_tungstenite::tungstenite::protocol::Message;
+
+use crate::ConnectionId;
+
+/// What is logged for every text or binary message a client sends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MessageLogging {
+    /// Nothing.
+    None,
+    /// The sender and the size of the message.
+    #[default]
+    Lengths,
+    /// The sender and the message text, passed through
+    /// `ServerConfig.log_redaction` if set. Binary messages are still only
+    /// logged by size.
+    Full,
+}
+
+/// Rewrites message text before it is logged under `MessageLogging::Full`,
+/// e.g. to mask anything that looks like an email address.
+#[derive(Clone)]
+pub struct Redaction(Arc<dyn Fn(&str) -> String + Send + Sync>);
+
+impl Redaction {
+    pub fn new(redact: impl Fn(&str) -> String + Send + Sync + 'static) -> Self {
+        Redaction(Arc::new(redact))
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        (self.0)(text)
+    }
+}
+
+impl fmt::Debug for Redaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Redaction")
+    }
+}
+
+/// The log line for `msg` from `id`, or `None` if nothing should be logged.
+pub fn format_message(
+    logging: MessageLogging,
+    redaction: Option<&Redaction>,
+    id: ConnectionId,
+    msg: &Message,
+) -> Option<String> {
+    match (logging, msg) {
+        (MessageLogging::None, _) => None,
+        (MessageLogging::Full, Message::Text(text)) => {
+            let text = match redaction {
+                Some(redaction) => redaction.apply(text),
+                None => text.clone(),
+            };
+            Some(format!("Received a message from {}: {}", id, text))
+        }
+        (_, Message::Text(text)) => {
+            Some(format!("Received {} bytes of text from {}", text.len(), id))
+        }
+        (_, Message::Binary(data)) => Some(format!(
+            "Received {} bytes of binary data from {}",
+            data.len(),
+            id
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logged(
+        logging: MessageLogging,
+        redaction: Option<&Redaction>,
+        msg: Message,
+    ) -> Option<String> {
+        format_message(logging, redaction, ConnectionId(7), &msg)
+    }
+
+    #[test]
+    fn nothing_is_logged_under_none() {
+        assert_eq!(
+            logged(MessageLogging::None, None, Message::text("hello")),
+            None
+        );
+        assert_eq!(
+            logged(MessageLogging::None, None, Message::binary(vec![1, 2])),
+            None
+        );
+    }
+
+    #[test]
+    fn only_sizes_are_logged_under_lengths() {
+        assert_eq!(
+            logged(MessageLogging::Lengths, None, Message::text("hello")).unwrap(),
+            "Received 5 bytes of text from #7"
+        );
+        assert_eq!(
+            logged(MessageLogging::Lengths, None, Message::binary(vec![1, 2])).unwrap(),
+            "Received 2 bytes of binary data from #7"
+        );
+    }
+
+    #[test]
+    fn text_is_logged_redacted_under_full() {
+        assert_eq!(
+            logged(MessageLogging::Full, None, Message::text("hello")).unwrap(),
+            "Received a message from #7: hello"
+        );
+        let redaction = Redaction::new(|text| text.replace("secret", "******"));
+        assert_eq!(
+            logged(
+                MessageLogging::Full,
+                Some(&redaction),
+                Message::text("my secret")
+            )
+            .unwrap(),
+            "Received a message from #7: my ******"
+        );
+        assert_eq!(
+            logged(
+                MessageLogging::Full,
+                Some(&redaction),
+                Message::binary(vec![1, 2])
+            )
+            .unwrap(),
+            "Received 2 bytes of binary data from #7"
+        );
+    }
+
+    #[test]
+    fn control_frames_are_not_logged() {
+        assert_eq!(
+            logged(MessageLogging::Full, None, Message::Ping(vec![])),
+            None
+        );
+    }
+}
@@ -13,9 +13,22 @@
 //!     cargo run --features="async-std-runtime" --example client ws://127.0.0.1:12345/
 //!
 //! You can run the second command in multiple windows and then chat between the
-//! two, seeing the messages from the other client as they're received. For all
-//! connected clients they'll all join the same room and see everyone else's
-//! messages.
+//! two, seeing the messages from the other client as they're received. Every
+//! client starts out in the `lobby` room and can switch rooms by sending
+//! `/join <room>`; messages are only seen by the other members of a room,
+//! and `/rooms` lists the rooms with how many are in each.
+//! Sending `/nick <name>` picks the name your messages are shown with, and
+//! `/msg <name> <text>` sends a private message to just that client.
+//! Every client also controls a player: `/move <dx> <dy>` moves it, and the
+//! positions of all players are sent to everyone five times a second as
+//! numbered ticks.
+//! `/roll <sides>` rolls a die.
+//!
+//! With `WS_ADMIN_TOKEN` set, connecting to `/admin/logs?token=<token>`
+//! streams the server's log lines.
+//!
+//! Pressing Ctrl-C exits the app, which first closes every connection with a
+//! proper Close frame.
 //! 
 
 // Configure clippy for Bevy usage
@@ -27,132 +40,86 @@
 #![allow(clippy::enum_glob_use)]
 
 use bevy::{
-    core::{FixedTimestep, FixedTimesteps, CorePlugin},
-    app::{ScheduleRunnerPlugin, Events},
-    log::LogPlugin,
-    asset::AssetPlugin,
-    scene::ScenePlugin,
-    transform::TransformPlugin,
-    diagnostic::DiagnosticsPlugin,
-    prelude::*, 
-    tasks::IoTaskPool};
-
-use std::{
-    collections::HashMap,
-    env,
-    io::Error as IoError,
-    net::SocketAddr,
-    sync::{Arc, Mutex},
+    core::{FixedTimestep, CorePlugin},
+    app::{AppExit, ScheduleRunnerPlugin},
+    diagnostic::{DiagnosticsPlugin, LogDiagnosticsPlugin},
+    prelude::*};
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
 };
 
-use crossbeam_channel::{Receiver, Sender};
-
-use futures::prelude::*;
-use futures::{
-    channel::mpsc::{unbounded, UnboundedSender},
-    future, pin_mut,
+use rand::Rng;
+
+use async_tungstenite::tungstenite::Message;
+use ws_async::directory::snapshot_connections;
+use ws_async::game::{apply_moves, spawn_players, tick_message, Player, TickFormat};
+use ws_async::lifecycle::cleanup_on_disconnect;
+use ws_async::rooms::snapshot_rooms;
+use ws_async::router::route_commands;
+use ws_async::{
+    apply_config_updates, log_connection_events, process_deaf_requests, process_kick_requests,
+    process_mute_requests, pump_client_commands, pump_incoming_messages, reap_idle_connections,
+    setup, shutdown_on_exit, sync_connections, AdminLogs, CleanupHooks, ClientCommandReceived,
+    CommandRouter, ConnectionClosed, ConnectionOpened, KickRequest, LogFeed, LogStreamPlugin,
+    MuteRequest, OutboundMessage, PlayerLifecyclePlugin, ServerConfig, SetDeaf, TokenValidator,
+    UpdateServerConfig, UserId, WsDiagnosticsPlugin, WsMessageReceived, WsOutbox,
 };
 
-use async_std::net::{TcpListener, TcpStream};
-use async_std::task;
-use async_tungstenite::tungstenite::protocol::Message;
-
 
 const TIMESTEP_5_PER_SECOND: f64 = 12.0 / 60.0;
 
 
-type Tx = UnboundedSender<Message>;
-type PeerMap = Arc<Mutex<HashMap<SocketAddr, Tx>>>;
-
-
-async fn handle_connection(peer_map: PeerMap, raw_stream: TcpStream, addr: SocketAddr) {
-    println!("Incoming TCP connection from: {}", addr);
-
-    let ws_stream = async_tungstenite::accept_async(raw_stream)
-        .await
-        .expect("Error during the websocket handshake occurred");
-    println!("WebSocket connection established: {}", addr);
-
-    // Insert the write part of this peer to the peer map.
-    let (tx, rx) = unbounded();
-    peer_map.lock().unwrap().insert(addr, tx);
-
-    let (outgoing, incoming) = ws_stream.split();
-
-    let broadcast_incoming = incoming
-        .try_filter(|msg| {
-            // Broadcasting a Close message from one client
-            // will close the other clients.
-            future::ready(!msg.is_close())
-        })
-        .try_for_each(|msg| {
-            println!(
-                "Received a message from {}: {}",
-                addr,
-                msg.to_text().unwrap()
-            );
-            let peers = peer_map.lock().unwrap();
-
-            // We want to broadcast the message to everyone except ourselves.
-            let broadcast_recipients = peers
-                .iter()
-                .filter(|(peer_addr, _)| peer_addr != &&addr)
-                .map(|(_, ws_sink)| ws_sink);
-
-            for recp in broadcast_recipients {
-                recp.unbounded_send(msg.clone()).unwrap();
-            }
-
-            future::ok(())
-        });
-
-    let receive_from_others = rx.map(Ok).forward(outgoing);
-
-    pin_mut!(broadcast_incoming, receive_from_others);
-    future::select(broadcast_incoming, receive_from_others).await;
-
-    println!("{} disconnected", &addr);
-    peer_map.lock().unwrap().remove(&addr);
-}
-
-async fn run() -> Result<(), IoError> {
-    let addr = env::args()
-        .nth(1)
-        .unwrap_or_else(|| "127.0.0.1:8080".to_string());
-
-    let state = PeerMap::new(Mutex::new(HashMap::new()));
-
-    // Create the event loop and TCP listener we'll accept connections on.
-    let try_socket = TcpListener::bind(&addr).await;
-    let listener = try_socket.expect("Failed to bind");
-    println!("Listening on: {}", addr);
-
-    // Let's spawn the handling of each connection in a separate task.
-    while let Ok((stream, addr)) = listener.accept().await {
-        task::spawn(handle_connection(state.clone(), stream, addr));
-    }
-
-    Ok(())
-}
-
-fn setup(mut commands: Commands, task_pool: Res<IoTaskPool>) {
-
-    //Channel setup
-    //let (sender, receiver) = unbounded::<String>();
-    //let (sender2, receiver2) = unbounded::<String>();
-
-    task_pool.spawn(run()).detach();
-
-    //commands.insert_resource(receiver);
-    //commands.insert_resource(sender2); 
-}
+/// Set by the Ctrl-C handler so the app can exit through `AppExit`.
+struct Interrupted(Arc<AtomicBool>);
 
 fn main() {
+    let logs = LogFeed::new();
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let handler_flag = interrupted.clone();
+    ctrlc::set_handler(move || handler_flag.store(true, Ordering::SeqCst))
+        .expect("Failed to install the Ctrl-C handler");
+
     App::build()
-        .add_plugin(CorePlugin::default())
+        .add_plugin(CorePlugin)
         .add_plugin(ScheduleRunnerPlugin::default())
-        .add_plugin(LogPlugin::default())
+        .add_plugin(LogStreamPlugin::new(logs.clone()))
+        .add_plugin(DiagnosticsPlugin)
+        .add_plugin(WsDiagnosticsPlugin)
+        .add_plugin(LogDiagnosticsPlugin::default())
+        .add_plugin(PlayerLifecyclePlugin)
+        .add_event::<WsMessageReceived>()
+        .add_event::<ConnectionOpened>()
+        .add_event::<ConnectionClosed>()
+        .add_event::<ClientCommandReceived>()
+        .add_event::<KickRequest>()
+        .add_event::<MuteRequest>()
+        .add_event::<SetDeaf>()
+        .add_event::<UpdateServerConfig>()
+        .insert_resource(Interrupted(interrupted))
+        .insert_resource(server_config(logs))
+        .insert_resource(TickFormat::Text)
+        .insert_resource(cleanup_hooks())
         .add_startup_system(setup.system())
+        .add_startup_system_to_stage(StartupStage::PostStartup, register_commands.system())
+        .add_system(pump_incoming_messages.system())
+        .add_system(pump_client_commands.system())
+        .add_system(sync_connections.system())
+        .add_system(cleanup_on_disconnect.exclusive_system())
+        .add_system(snapshot_connections.system())
+        .add_system(snapshot_rooms.system())
+        .add_system(log_connection_events.system())
+        .add_system(process_kick_requests.system())
+        .add_system(process_mute_requests.system())
+        .add_system(process_deaf_requests.system())
+        .add_system(reap_idle_connections.system())
+        .add_system(apply_config_updates.system())
+        .add_system(spawn_players.system())
+        .add_system(apply_moves.system())
+        .add_system(route_commands.system())
+        .add_system(exit_on_interrupt.system())
+        .add_system_to_stage(CoreStage::Last, shutdown_on_exit.system())
         .add_system_set(
             SystemSet::new()
                 // This prints out "goodbye world" twice every second
@@ -162,6 +129,101 @@ fn main() {
         .run();
 }
 
-fn game_loop() {
-    println!("Game loop");
+/// Streams the logs to admin clients presenting the token in
+/// `WS_ADMIN_TOKEN`, if it is set.
+fn server_config(logs: LogFeed) -> ServerConfig {
+    let admin_logs = std::env::var("WS_ADMIN_TOKEN").ok().map(|token| {
+        let auth = TokenValidator::new(move |given| {
+            (given == token).then(|| UserId("admin".to_string()))
+        });
+        AdminLogs::new(logs, auth)
+    });
+    ServerConfig { admin_logs, ..ServerConfig::default() }
+}
+
+/// Adds `/roll <sides>`, which answers with a random number.
+fn register_commands(mut router: ResMut<CommandRouter>, outbox: Res<WsOutbox>) {
+    let outbox = outbox.clone();
+    router.register("roll", move |id, args| {
+        let sides = args.parse::<u32>().unwrap_or(6).max(1);
+        let roll = rand::thread_rng().gen_range(1..=sides);
+        outbox.send(OutboundMessage::To(id, Message::text(format!("You rolled {}", roll))));
+    });
+}
+
+/// Logs where each player was when they left.
+fn cleanup_hooks() -> CleanupHooks {
+    let mut hooks = CleanupHooks::default();
+    hooks.add(|world, entity, closed| {
+        if let Some(player) = world.get::<Player>(entity) {
+            info!("{} left at {}", closed.id, player.position);
+        }
+    });
+    hooks
+}
+
+fn exit_on_interrupt(interrupted: Res<Interrupted>, mut exits: EventWriter<AppExit>) {
+    if interrupted.0.load(Ordering::SeqCst) {
+        exits.send(AppExit);
+    }
+}
+
+/// Sends every client a snapshot of the players on each tick.
+fn game_loop(
+    mut tick: Local<u64>,
+    format: Res<TickFormat>,
+    players: Query<&Player>,
+    outbox: Res<WsOutbox>,
+) {
+    *tick += 1;
+    let players: Vec<&Player> = players.iter().collect();
+    outbox.send(OutboundMessage::Broadcast(tick_message(*tick, &players, *format)));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::prelude::*;
+    use ws_async::{client, runtime, Server};
+
+    use super::*;
+
+    #[test]
+    fn every_tick_is_broadcast() {
+        runtime::block_on(async {
+            let config = ServerConfig {
+                member_list_delay: None,
+                ..ServerConfig::default()
+            };
+            let server = Server::start(&["127.0.0.1:0".to_string()], config)
+                .await
+                .unwrap();
+            let url = format!("ws://{}", server.local_addrs()[0]);
+            let (_sink, mut source) = client::connect(&url).await.unwrap();
+            while server.directory.is_empty() {
+                runtime::sleep(Duration::from_millis(10)).await;
+            }
+
+            let mut builder = App::build();
+            builder
+                .insert_resource(TickFormat::Text)
+                .insert_resource(server.outbox())
+                .add_system(game_loop.system());
+            let mut app = builder.app;
+            for _ in 0..3 {
+                app.update();
+            }
+
+            for tick in 1..=3 {
+                let received = runtime::timeout(Duration::from_secs(5), source.next())
+                    .await
+                    .expect("No tick in time");
+                assert_eq!(
+                    received.unwrap().unwrap(),
+                    tick_message(tick, &[], TickFormat::Text)
+                );
+            }
+        });
+    }
 }
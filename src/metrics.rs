@@ -0,0 +1,178 @@
+//! Process-wide counters in the Prometheus text format.
+//!
+//! The connection tasks update the counters as they go; `render_metrics`
+//! formats them for a scraper. Setting `ServerConfig.metrics_addr` also
+//! serves them over plain HTTP on that address.
+
+use std::{
+    io,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use futures::prelude::*;
+
+use crate::runtime::{self, TcpListener};
+
+/// How long a scraper gets to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+static MESSAGES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+static MESSAGES_SENT: AtomicU64 = AtomicU64::new(0);
+static CONNECTIONS_TOTAL: AtomicU64 = AtomicU64::new(0);
+static CONNECTIONS_ACTIVE: AtomicU64 = AtomicU64::new(0);
+static HANDSHAKE_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn message_received() {
+    MESSAGES_RECEIVED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn message_sent() {
+    MESSAGES_SENT.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn connection_opened() {
+    CONNECTIONS_TOTAL.fetch_add(1, Ordering::Relaxed);
+    CONNECTIONS_ACTIVE.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn connection_closed() {
+    CONNECTIONS_ACTIVE.fetch_sub(1, Ordering::Relaxed);
+}
+
+pub(crate) fn handshake_failed() {
+    HANDSHAKE_FAILURES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The current value of every counter, in the Prometheus text exposition
+/// format.
+pub fn render_metrics() -> String {
+    let metrics = [
+        (
+            "ws_messages_received_total",
+            "counter",
+            "Messages received from clients.",
+            &MESSAGES_RECEIVED,
+        ),
+        (
+            "ws_messages_sent_total",
+            "counter",
+            "Messages written to clients.",
+            &MESSAGES_SENT,
+        ),
+        (
+            "ws_connections_total",
+            "counter",
+            "Connections that completed the WebSocket handshake.",
+            &CONNECTIONS_TOTAL,
+        ),
+        (
+            "ws_connections_active",
+            "gauge",
+            "Connections currently open.",
+            &CONNECTIONS_ACTIVE,
+        ),
+        (
+            "ws_handshake_failures_total",
+            "counter",
+            "Handshakes that failed or were refused.",
+            &HANDSHAKE_FAILURES,
+        ),
+    ];
+
+    let mut out = String::new();
+    for (name, kind, help, value) in metrics {
+        out.push_str(&format!(
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n",
+            name = name,
+            help = help,
+            kind = kind,
+            value = value.load(Ordering::Relaxed)
+        ));
+    }
+    out
+}
+
+/// Answers every HTTP request on `listener` with `render_metrics`, whatever
+/// its path. Runs until accepting fails.
+pub async fn serve(listener: TcpListener) -> io::Result<()> {
+    loop {
+        let (mut stream, _) = runtime::accept(&listener).await?;
+        runtime::spawn(async move {
+            // The request itself doesn't matter; read up to the end of its
+            // headers so the client isn't reset before it reads the reply.
+            let mut request = Vec::new();
+            let read_headers = async {
+                let mut buf = [0; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return false,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                true
+            };
+            if runtime::timeout(REQUEST_TIMEOUT, read_headers).await != Some(true) {
+                return;
+            }
+
+            let body = render_metrics();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+            let _ = stream.close().await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The value `render_metrics` gives for `name`.
+    fn rendered(name: &str) -> u64 {
+        render_metrics()
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .expect("metric missing")
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn rendering_reflects_the_counters() {
+        // Other tests run servers too, so the counters only ever grow by at
+        // least what is done here.
+        let received = rendered("ws_messages_received_total");
+        let failures = rendered("ws_handshake_failures_total");
+        let total = rendered("ws_connections_total");
+        for _ in 0..3 {
+            message_received();
+        }
+        handshake_failed();
+        connection_opened();
+        connection_closed();
+
+        assert!(rendered("ws_messages_received_total") >= received + 3);
+        assert!(rendered("ws_handshake_failures_total") > failures);
+        assert!(rendered("ws_connections_total") > total);
+    }
+
+    #[test]
+    fn every_metric_is_described() {
+        let out = render_metrics();
+        for name in [
+            "ws_messages_received_total",
+            "ws_messages_sent_total",
+            "ws_connections_total",
+            "ws_connections_active",
+            "ws_handshake_failures_total",
+        ] {
+            assert!(out.contains(&format!("# HELP {} ", name)), "{}", name);
+            assert!(out.contains(&format!("# TYPE {} ", name)), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! Chat nicknames. A peer registers a name with `/nick <name>`; names are
+//! unique across the server and released again on disconnect.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::ConnectionId;
+
+pub type NameMap = Arc<Mutex<HashMap<ConnectionId, String>>>;
+
+/// Parses a `/nick <name>` command, returning the requested name.
+pub fn parse_nick(text: &str) -> Option<&str> {
+    let name = text.strip_prefix("/nick ")?.trim();
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// Parses a `/msg <name> <text>` command into the recipient and the text.
+pub fn parse_msg(text: &str) -> Option<(&str, &str)> {
+    let rest = text.strip_prefix("/msg ")?.trim_start();
+    let (name, body) = rest.split_once(char::is_whitespace)?;
+    let body = body.trim();
+    if body.is_empty() {
+        None
+    } else {
+        Some((name, body))
+    }
+}
+
+/// Finds the peer currently registered as `name`.
+pub fn lookup(names: &NameMap, name: &str) -> Option<ConnectionId> {
+    names
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(_, taken)| taken.as_str() == name)
+        .map(|(id, _)| *id)
+}
+
+/// Registers `name` for `id`, replacing any name it had before. Fails if
+/// another peer already holds the name.
+pub fn register(names: &NameMap, id: ConnectionId, name: &str) -> Result<(), String> {
+    let mut names = names.lock().unwrap();
+    if names
+        .iter()
+        .any(|(other, taken)| other != &id && taken == name)
+    {
+        return Err(format!("The name {} is already taken", name));
+    }
+    names.insert(id, name.to_string());
+    Ok(())
+}
+
+/// Frees the name held by `id`, if any.
+pub fn release(names: &NameMap, id: ConnectionId) {
+    names.lock().unwrap().remove(&id);
+}
+
+/// The name `id` is shown as: its nickname, or its connection id until it has
+/// registered one.
+pub fn display_name(names: &NameMap, id: ConnectionId) -> String {
+    names
+        .lock()
+        .unwrap()
+        .get(&id)
+        .cloned()
+        .unwrap_or_else(|| id.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn taken_names_are_refused_until_released() {
+        let names = NameMap::default();
+        register(&names, ConnectionId(1), "alice").unwrap();
+        assert!(register(&names, ConnectionId(2), "alice").is_err());
+        // Registering again under the same name is no conflict.
+        register(&names, ConnectionId(1), "alice").unwrap();
+
+        release(&names, ConnectionId(1));
+        assert_eq!(lookup(&names, "alice"), None);
+        register(&names, ConnectionId(2), "alice").unwrap();
+        assert_eq!(lookup(&names, "alice"), Some(ConnectionId(2)));
+    }
+
+    #[test]
+    fn unregistered_peers_go_by_their_id() {
+        let names = NameMap::default();
+        assert_eq!(display_name(&names, ConnectionId(7)), "#7");
+        register(&names, ConnectionId(7), "bob").unwrap();
+        assert_eq!(display_name(&names, ConnectionId(7)), "bob");
+    }
+
+    #[test]
+    fn nick_needs_a_name() {
+        assert_eq!(parse_nick("/nick  carol "), Some("carol"));
+        assert_eq!(parse_nick("/nick "), None);
+        assert_eq!(parse_nick("nick carol"), None);
+    }
+}
@@ -0,0 +1,455 @@
+//! Commands clients send in text frames.
+//!
+//! By default these are the slash commands (`/join <room>`, `/nick <name>`,
+//! `/msg <name> <text>`, `/ping`, `/auth <token>`, `/move <dx> <dy>`) plus
+//! any custom `/name args` command, with any other text being chat. With the
+//! `serde` feature every text frame is instead a JSON object tagged by
+//! `type`, e.g. `{"type": "join", "room": "lobby"}`.
+//!
+//! Chat is relayed to the room as built by `chat_message`, numbered so that
+//! clients can detect gaps.
+//!
+//! JSON `chat` and `msg` commands may carry an `id`. The sender then gets a
+//! receipt once the message has been handed on: `{"type":"ack","id":"m1",
+//! "delivered":2}` with the number of room members reached, or
+//! `{"type":"ack","id":"m1","recipient":"bo"}` for a private message.
+//!
+//! With `ServerConfig.batch_window` set, clients receive every text frame
+//! wrapped in a JSON array, see `batch_message`.
+//!
+//! JSON commands may also carry the protocol `version` the client speaks,
+//! e.g. `{"type":"ping","version":2}`. The connection keeps the last version
+//! it declared, and is closed if that is below
+//! `ServerConfig.min_protocol_version`. Fields the server doesn't know are
+//! ignored, so newer clients can send them to older servers.
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use async_tungstenite::tungstenite::protocol::Message;
+
+use crate::ConnectionId;
+#[cfg(not(feature = "serde"))]
+use crate::{names, rooms};
+
+/// The protocol version this server speaks.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// A command parsed from a client's text frame.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
+pub enum ClientCommand {
+    /// Move to another room.
+    Join { room: String },
+    /// Register a nickname.
+    Nick { name: String },
+    /// Say something to the current room. With an `id` the sender is sent
+    /// a receipt.
+    Chat {
+        text: String,
+        #[cfg_attr(
+            feature = "serde",
+            serde(default, skip_serializing_if = "Option::is_none")
+        )]
+        id: Option<String>,
+    },
+    /// Send a private message to the client called `to`. With an `id` the
+    /// sender is sent a receipt.
+    Msg {
+        to: String,
+        text: String,
+        #[cfg_attr(
+            feature = "serde",
+            serde(default, skip_serializing_if = "Option::is_none")
+        )]
+        id: Option<String>,
+    },
+    /// Ask the server to answer with `pong`.
+    Ping,
+    /// Ask for every room and how many are in it.
+    Rooms,
+    /// Authenticate with a token after connecting, see
+    /// `ServerConfig.anonymous_limits`.
+    Auth { token: String },
+    /// Move the client's player by the given offset.
+    Move { dx: f32, dy: f32 },
+    /// Any other command, handled through the `CommandRouter`.
+    Custom { name: String, args: String },
+}
+
+/// Event sent for every command a client sends, including chat.
+#[derive(Debug, Clone)]
+pub struct ClientCommandReceived {
+    pub id: ConnectionId,
+    pub command: ClientCommand,
+}
+
+/// Receipt for the message the sender tagged `id`, handed on to
+/// `delivered` room members.
+#[cfg(feature = "serde")]
+pub fn delivery_ack(id: &str, delivered: usize) -> Message {
+    Message::text(
+        serde_json::json!({ "type": "ack", "id": id, "delivered": delivered }).to_string(),
+    )
+}
+
+/// Receipt for the private message the sender tagged `id`.
+#[cfg(feature = "serde")]
+pub fn recipient_ack(id: &str, recipient: &str) -> Message {
+    Message::text(
+        serde_json::json!({ "type": "ack", "id": id, "recipient": recipient }).to_string(),
+    )
+}
+
+/// Receipt for the message the sender tagged `id`. Slash commands can't
+/// carry an id, so only JSON clients ever get one.
+#[cfg(not(feature = "serde"))]
+pub fn delivery_ack(id: &str, delivered: usize) -> Message {
+    Message::text(format!("* {} delivered to {}", id, delivered))
+}
+
+/// Receipt for the private message the sender tagged `id`.
+#[cfg(not(feature = "serde"))]
+pub fn recipient_ack(id: &str, recipient: &str) -> Message {
+    Message::text(format!("* {} delivered to {}", id, recipient))
+}
+
+/// Who is in `room`. Encoded as
+/// `{"type":"room_members","room":"lobby","members":["al","bo"]}`.
+#[cfg(feature = "serde")]
+pub fn room_members_message(room: &str, members: &[String]) -> Message {
+    Message::text(
+        serde_json::json!({ "type": "room_members", "room": room, "members": members }).to_string(),
+    )
+}
+
+/// Who is in `room`. Encoded as `* lobby: al, bo`.
+#[cfg(not(feature = "serde"))]
+pub fn room_members_message(room: &str, members: &[String]) -> Message {
+    Message::text(format!("* {}: {}", room, members.join(", ")))
+}
+
+/// Every room and its number of members, answering `/rooms`. Encoded as
+/// `{"type":"rooms","rooms":[{"room":"lobby","members":2}]}`.
+#[cfg(feature = "serde")]
+pub fn rooms_message(occupancy: &[(String, usize)]) -> Message {
+    let rooms: Vec<serde_json::Value> = occupancy
+        .iter()
+        .map(|(room, members)| serde_json::json!({ "room": room, "members": members }))
+        .collect();
+    Message::text(serde_json::json!({ "type": "rooms", "rooms": rooms }).to_string())
+}
+
+/// Every room and its number of members, answering `/rooms`. Encoded as
+/// `* rooms: arena (1), lobby (2)`.
+#[cfg(not(feature = "serde"))]
+pub fn rooms_message(occupancy: &[(String, usize)]) -> Message {
+    let rooms: Vec<String> = occupancy
+        .iter()
+        .map(|(room, members)| format!("{} ({})", room, members))
+        .collect();
+    Message::text(format!("* rooms: {}", rooms.join(", ")))
+}
+
+/// Several text messages written as one frame under
+/// `ServerConfig.batch_window`: a JSON array of the messages, in order.
+/// Messages that aren't JSON themselves are included as strings.
+#[cfg(feature = "serde")]
+pub fn batch_message(texts: &[String]) -> Message {
+    let messages: Vec<serde_json::Value> = texts
+        .iter()
+        .map(|text| {
+            serde_json::from_str(text).unwrap_or_else(|_| serde_json::Value::String(text.clone()))
+        })
+        .collect();
+    Message::text(serde_json::Value::Array(messages).to_string())
+}
+
+/// Several text messages written as one frame under
+/// `ServerConfig.batch_window`: a JSON array of the messages as strings, in
+/// order, e.g. `["[7] al: hi","[8] bo: hey"]`.
+#[cfg(not(feature = "serde"))]
+pub fn batch_message(texts: &[String]) -> Message {
+    let mut batch = String::from("[");
+    for (i, text) in texts.iter().enumerate() {
+        if i > 0 {
+            batch.push(',');
+        }
+        batch.push('"');
+        for c in text.chars() {
+            match c {
+                '"' => batch.push_str("\\\""),
+                '\\' => batch.push_str("\\\\"),
+                '\n' => batch.push_str("\\n"),
+                '\r' => batch.push_str("\\r"),
+                '\t' => batch.push_str("\\t"),
+                c if (c as u32) < 0x20 => batch.push_str(&format!("\\u{:04x}", c as u32)),
+                c => batch.push(c),
+            }
+        }
+        batch.push('"');
+    }
+    batch.push(']');
+    Message::text(batch)
+}
+
+/// A chat message relayed to a room: number `seq` in that room, said by
+/// `from`. Encoded as `{"type":"chat","seq":7,"from":"al","text":"hi"}`.
+#[cfg(feature = "serde")]
+pub fn chat_message(seq: u64, from: &str, text: &str) -> Message {
+    Message::text(
+        serde_json::json!({ "type": "chat", "seq": seq, "from": from, "text": text }).to_string(),
+    )
+}
+
+/// A chat message relayed to a room: number `seq` in that room, said by
+/// `from`. Encoded as `[7] al: hi`.
+#[cfg(not(feature = "serde"))]
+pub fn chat_message(seq: u64, from: &str, text: &str) -> Message {
+    Message::text(format!("[{}] {}: {}", seq, from, text))
+}
+
+/// A parsed text frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+    /// The protocol version the client declared in this frame, if any.
+    pub version: Option<u32>,
+    pub command: ClientCommand,
+}
+
+/// Parses a text frame into a command. Fails with a message suitable for
+/// sending back to the client.
+pub fn parse(text: &str) -> Result<ClientCommand, String> {
+    parse_envelope(text).map(|envelope| envelope.command)
+}
+
+/// Parses a text frame into a command and the version it declares.
+#[cfg(feature = "serde")]
+pub fn parse_envelope(text: &str) -> Result<Envelope, String> {
+    let value: serde_json::Value =
+        serde_json::from_str(text).map_err(|e| format!("Invalid command: {}", e))?;
+    let version = match value.get("version") {
+        None => None,
+        Some(version) => match version.as_u64() {
+            Some(version) => Some(version.min(u64::from(u32::MAX)) as u32),
+            None => return Err("Invalid command: version must be a number".to_string()),
+        },
+    };
+    let command =
+        ClientCommand::deserialize(value).map_err(|e| format!("Invalid command: {}", e))?;
+    Ok(Envelope { version, command })
+}
+
+/// Slash commands can't declare a version, so the version is always
+/// `None`.
+#[cfg(not(feature = "serde"))]
+pub fn parse_envelope(text: &str) -> Result<Envelope, String> {
+    parse_command(text).map(|command| Envelope {
+        version: None,
+        command,
+    })
+}
+
+/// Parses a slash command. Text not starting with `/` is chat, so this only
+/// fails for a malformed `/move`.
+#[cfg(not(feature = "serde"))]
+fn parse_command(text: &str) -> Result<ClientCommand, String> {
+    if let Some(room) = rooms::parse_join(text) {
+        return Ok(ClientCommand::Join {
+            room: room.to_string(),
+        });
+    }
+    if let Some(name) = names::parse_nick(text) {
+        return Ok(ClientCommand::Nick {
+            name: name.to_string(),
+        });
+    }
+    if let Some((to, text)) = names::parse_msg(text) {
+        return Ok(ClientCommand::Msg {
+            to: to.to_string(),
+            text: text.to_string(),
+            id: None,
+        });
+    }
+    if text.trim() == "/ping" {
+        return Ok(ClientCommand::Ping);
+    }
+    if text.trim() == "/rooms" {
+        return Ok(ClientCommand::Rooms);
+    }
+    if let Some(token) = text.strip_prefix("/auth ") {
+        return Ok(ClientCommand::Auth {
+            token: token.trim().to_string(),
+        });
+    }
+    if let Some(args) = text.strip_prefix("/move ") {
+        let mut args = args.split_whitespace().map(str::parse::<f32>);
+        return match (args.next(), args.next(), args.next()) {
+            (Some(Ok(dx)), Some(Ok(dy)), None) => Ok(ClientCommand::Move { dx, dy }),
+            _ => Err("Usage: /move <dx> <dy>".to_string()),
+        };
+    }
+    if let Some(command) = text.strip_prefix('/') {
+        let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+        if !name.is_empty() {
+            return Ok(ClientCommand::Custom {
+                name: name.to_string(),
+                args: args.trim().to_string(),
+            });
+        }
+    }
+    Ok(ClientCommand::Chat {
+        text: text.to_string(),
+        id: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn every_command_parses() {
+        let cases = [
+            (
+                r#"{"type":"join","room":"arena"}"#,
+                ClientCommand::Join {
+                    room: "arena".to_string(),
+                },
+            ),
+            (
+                r#"{"type":"nick","name":"al"}"#,
+                ClientCommand::Nick {
+                    name: "al".to_string(),
+                },
+            ),
+            (
+                r#"{"type":"chat","text":"hi","id":"m1"}"#,
+                ClientCommand::Chat {
+                    text: "hi".to_string(),
+                    id: Some("m1".to_string()),
+                },
+            ),
+            (
+                r#"{"type":"msg","to":"bo","text":"psst"}"#,
+                ClientCommand::Msg {
+                    to: "bo".to_string(),
+                    text: "psst".to_string(),
+                    id: None,
+                },
+            ),
+            (r#"{"type":"ping"}"#, ClientCommand::Ping),
+            (r#"{"type":"rooms"}"#, ClientCommand::Rooms),
+            (
+                r#"{"type":"auth","token":"secret"}"#,
+                ClientCommand::Auth {
+                    token: "secret".to_string(),
+                },
+            ),
+            (
+                r#"{"type":"move","dx":1.5,"dy":-2}"#,
+                ClientCommand::Move { dx: 1.5, dy: -2.0 },
+            ),
+            (
+                r#"{"type":"custom","name":"roll","args":"2d6"}"#,
+                ClientCommand::Custom {
+                    name: "roll".to_string(),
+                    args: "2d6".to_string(),
+                },
+            ),
+        ];
+        for (text, command) in cases {
+            assert_eq!(parse(text), Ok(command), "{}", text);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn malformed_commands_are_refused() {
+        for text in [
+            "hello",
+            r#"{"type":"join"}"#,
+            r#"{"type":"dance"}"#,
+            r#"{"type":"ping","version":"2"}"#,
+        ] {
+            let error = parse(text).unwrap_err();
+            assert!(error.starts_with("Invalid command: "), "{}", error);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn versions_are_read_and_unknown_fields_ignored() {
+        let envelope = parse_envelope(r#"{"type":"ping","version":2,"colour":"red"}"#).unwrap();
+        assert_eq!(envelope.version, Some(2));
+        assert_eq!(envelope.command, ClientCommand::Ping);
+    }
+
+    #[cfg(not(feature = "serde"))]
+    #[test]
+    fn every_command_parses() {
+        let cases = [
+            (
+                "/join arena",
+                ClientCommand::Join {
+                    room: "arena".to_string(),
+                },
+            ),
+            (
+                "/nick al",
+                ClientCommand::Nick {
+                    name: "al".to_string(),
+                },
+            ),
+            (
+                "hi there",
+                ClientCommand::Chat {
+                    text: "hi there".to_string(),
+                    id: None,
+                },
+            ),
+            (
+                "/msg bo psst",
+                ClientCommand::Msg {
+                    to: "bo".to_string(),
+                    text: "psst".to_string(),
+                    id: None,
+                },
+            ),
+            ("/ping", ClientCommand::Ping),
+            ("/rooms", ClientCommand::Rooms),
+            (
+                "/auth secret",
+                ClientCommand::Auth {
+                    token: "secret".to_string(),
+                },
+            ),
+            ("/move 1.5 -2", ClientCommand::Move { dx: 1.5, dy: -2.0 }),
+            (
+                "/roll 2d6",
+                ClientCommand::Custom {
+                    name: "roll".to_string(),
+                    args: "2d6".to_string(),
+                },
+            ),
+        ];
+        for (text, command) in cases {
+            assert_eq!(parse(text), Ok(command), "{}", text);
+        }
+    }
+
+    #[cfg(not(feature = "serde"))]
+    #[test]
+    fn malformed_commands_are_refused() {
+        for text in ["/move 1", "/move 1 two", "/move 1 2 3"] {
+            assert_eq!(
+                parse(text),
+                Err("Usage: /move <dx> <dy>".to_string()),
+                "{}",
+                text
+            );
+        }
+    }
+}
@@ -0,0 +1,801 @@
+//! Per-peer outgoing message queues.
+//!
+//! Each connection has a queue of messages waiting to be written to its
+//! socket. The queue holds at most `ServerConfig.peer_buffer` data messages;
+//! what happens to a message that doesn't fit is decided by the
+//! `OverflowPolicy`. Control frames (Ping, Pong, Close) are always queued,
+//! and never dropped to make room, so heartbeats and shutdown keep working
+//! for a client that has fallen behind.
+//!
+//! `Priority::High` messages go in a second lane that is written out before
+//! anything at `Priority::Low`, so they aren't stuck behind a backlog of
+//! chat. Both lanes share the capacity; a full queue drops low-priority
+//! messages first.
+//!
+//! A binary message can also be streamed in fragments with
+//! `Tx::start_stream`. Its fragments are written back to back: once the
+//! first has been taken, only control frames come out of either lane until
+//! the last one has.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+use async_tungstenite::tungstenite::protocol::{frame::CloseFrame, Message};
+use futures::{future, Future, Stream};
+
+/// What to do with a message for a peer whose queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Discard the oldest queued data message to make room.
+    #[default]
+    DropOldest,
+    /// Discard the message being sent.
+    DropNewest,
+    /// Disconnect the peer.
+    Disconnect,
+}
+
+/// Which lane of a peer's queue a message goes in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    /// Written in order after every high-priority message.
+    #[default]
+    Low,
+    /// Written ahead of everything at `Low`.
+    High,
+}
+
+/// Why a message couldn't be queued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    /// The connection has gone away.
+    Disconnected,
+    /// The connection is being closed; nothing more is sent after its
+    /// Close frame.
+    Closing,
+    /// The queue was full and the policy is `Disconnect`; the peer is being
+    /// disconnected.
+    Overflow,
+}
+
+struct State {
+    messages: VecDeque<Message>,
+    urgent: VecDeque<Message>,
+    /// Set once the receiving side has been dropped.
+    closed: bool,
+    /// Set when a `Disconnect` overflow has happened.
+    overflowed: bool,
+    /// Set once a Close frame has been queued with `close`.
+    closing: bool,
+    /// Why the peer was kicked, if its Close frame was queued by `kick`.
+    kicked: Option<String>,
+    waker: Option<Waker>,
+    /// Senders waiting in `send_ready` for the queue to have room, or for
+    /// a streamed message to make way.
+    blocked: Vec<Waker>,
+    /// The binary message being streamed, if any.
+    stream: Option<Streamed>,
+}
+
+/// A binary message queued in fragments by a `Streaming`.
+struct Streamed {
+    /// Low-priority messages queued before the stream began, which are
+    /// written ahead of it.
+    ahead: usize,
+    fragments: VecDeque<Vec<u8>>,
+    /// Set once the first fragment has been taken.
+    started: bool,
+    /// Set once the last fragment has been queued.
+    finished: bool,
+}
+
+/// What the connection writer takes from the queue next.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Outgoing {
+    Message(Message),
+    /// Part of a streamed binary message.
+    Fragment(Vec<u8>, Position),
+}
+
+/// Where a fragment goes in its message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Position {
+    First,
+    Middle,
+    Last,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+/// Sending half of a peer's queue. Cloning shares the same queue.
+#[derive(Clone)]
+pub struct Tx(Arc<Shared>);
+
+/// Receiving half of a peer's queue, read by the connection task.
+pub struct Rx(Arc<Shared>);
+
+/// Reads how many messages are waiting in a peer's queue, without being
+/// able to send to it.
+#[derive(Clone)]
+pub struct QueueDepth(Arc<Shared>);
+
+/// Creates a queue holding up to `capacity` data messages.
+///
+/// Panics if `capacity` is 0: no message could ever be queued, and sending
+/// would wait forever for room.
+pub fn channel(capacity: usize, policy: OverflowPolicy) -> (Tx, Rx) {
+    assert!(capacity > 0, "a queue needs room for at least one message");
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            messages: VecDeque::new(),
+            urgent: VecDeque::new(),
+            closed: false,
+            overflowed: false,
+            closing: false,
+            kicked: None,
+            waker: None,
+            blocked: Vec::new(),
+            stream: None,
+        }),
+        capacity,
+        policy,
+    });
+    (Tx(shared.clone()), Rx(shared))
+}
+
+/// Ping, Pong and Close frames, which are queued whatever the policy.
+fn is_control(msg: &Message) -> bool {
+    matches!(msg, Message::Ping(_) | Message::Pong(_) | Message::Close(_))
+}
+
+/// Drops the oldest message in `lane` that isn't a control frame, returning
+/// where it was.
+fn evict_oldest(lane: &mut VecDeque<Message>) -> Option<usize> {
+    let oldest = lane.iter().position(|msg| !is_control(msg))?;
+    lane.remove(oldest);
+    Some(oldest)
+}
+
+/// Takes the first Ping or Pong out of `lane`, or also the first Close with
+/// `close`, leaving data messages queued.
+fn take_control(lane: &mut VecDeque<Message>, close: bool) -> Option<Message> {
+    let at = lane.iter().position(|msg| match msg {
+        Message::Ping(_) | Message::Pong(_) => true,
+        Message::Close(_) => close,
+        _ => false,
+    })?;
+    lane.remove(at)
+}
+
+impl State {
+    fn len(&self) -> usize {
+        self.messages.len() + self.urgent.len()
+    }
+
+    fn lane(&mut self, priority: Priority) -> &mut VecDeque<Message> {
+        match priority {
+            Priority::Low => &mut self.messages,
+            Priority::High => &mut self.urgent,
+        }
+    }
+
+    /// Takes what is to be written next. Low-priority messages queued after
+    /// a stream began wait for its last fragment, and once its first has
+    /// been taken, high-priority data messages wait too.
+    fn pop(&mut self) -> Option<Outgoing> {
+        let streaming = self.stream.as_ref().is_some_and(|stream| stream.started);
+        let urgent = if streaming {
+            take_control(&mut self.urgent, true)
+        } else {
+            self.urgent.pop_front()
+        };
+        if let Some(msg) = urgent {
+            return Some(self.taken(msg));
+        }
+
+        let ahead = self
+            .stream
+            .as_ref()
+            .map_or(usize::MAX, |stream| stream.ahead);
+        if ahead > 0 {
+            let msg = self.messages.pop_front()?;
+            if let Some(stream) = &mut self.stream {
+                stream.ahead -= 1;
+            }
+            return Some(self.taken(msg));
+        }
+
+        let stream = self.stream.as_mut()?;
+        let fragment = stream.fragments.pop_front();
+        if fragment.is_none() && !stream.finished {
+            // Heartbeats needn't wait for the next fragment.
+            return take_control(&mut self.messages, false).map(Outgoing::Message);
+        }
+        let first = !stream.started;
+        let last = stream.finished && stream.fragments.is_empty();
+        stream.started = true;
+        if last {
+            self.stream = None;
+        }
+        let data = fragment.unwrap_or_default();
+        Some(match (first, last) {
+            (true, true) => Outgoing::Message(Message::Binary(data)),
+            (true, false) => Outgoing::Fragment(data, Position::First),
+            (false, false) => Outgoing::Fragment(data, Position::Middle),
+            (false, true) => Outgoing::Fragment(data, Position::Last),
+        })
+    }
+
+    fn taken(&mut self, msg: Message) -> Outgoing {
+        if let Message::Close(_) = msg {
+            // Only left over after a high-priority close, or a stream cut
+            // short by one.
+            self.messages.clear();
+            self.stream = None;
+        }
+        Outgoing::Message(msg)
+    }
+}
+
+impl Tx {
+    /// Queues `msg` at `Priority::Low`, applying the overflow policy if the
+    /// queue is full. Silently dropping a message under `DropOldest` or
+    /// `DropNewest` still counts as success.
+    pub fn send(&self, msg: Message) -> Result<(), SendError> {
+        self.send_with(msg, Priority::Low)
+    }
+
+    /// `send`, in the lane for `priority`.
+    pub fn send_with(&self, msg: Message, priority: Priority) -> Result<(), SendError> {
+        let mut state = self.0.state.lock().unwrap();
+        if state.closed {
+            return Err(SendError::Disconnected);
+        }
+        if state.closing {
+            return Err(SendError::Closing);
+        }
+        if state.overflowed {
+            return Err(SendError::Overflow);
+        }
+
+        if !is_control(&msg) && state.len() >= self.0.capacity {
+            match self.0.policy {
+                // Control frames stay queued; if nothing else is, the queue
+                // holds one message more for a while.
+                OverflowPolicy::DropOldest => match evict_oldest(&mut state.messages) {
+                    Some(evicted) => {
+                        if let Some(stream) = &mut state.stream {
+                            if evicted < stream.ahead {
+                                stream.ahead -= 1;
+                            }
+                        }
+                    }
+                    None => {
+                        evict_oldest(&mut state.urgent);
+                    }
+                },
+                OverflowPolicy::DropNewest => return Ok(()),
+                OverflowPolicy::Disconnect => {
+                    drop(state);
+                    self.disconnect();
+                    return Err(SendError::Overflow);
+                }
+            }
+        }
+
+        state.lane(priority).push_back(msg);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Queues `msg` once there is room for it, waiting instead of applying
+    /// the overflow policy. Use with a timeout; a peer that stops reading
+    /// never makes room.
+    pub async fn send_ready(&self, msg: Message) -> Result<(), SendError> {
+        let mut msg = Some(msg);
+        future::poll_fn(|cx| {
+            let mut state = self.0.state.lock().unwrap();
+            if state.closed {
+                return Poll::Ready(Err(SendError::Disconnected));
+            }
+            if state.closing {
+                return Poll::Ready(Err(SendError::Closing));
+            }
+            if state.overflowed {
+                return Poll::Ready(Err(SendError::Overflow));
+            }
+            if state.len() >= self.0.capacity {
+                state.blocked.push(cx.waker().clone());
+                return Poll::Pending;
+            }
+            if let Some(msg) = msg.take() {
+                state.messages.push_back(msg);
+            }
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+            Poll::Ready(Ok(()))
+        })
+        .await
+    }
+
+    /// Queues a Close frame behind everything already queued, so those
+    /// messages are still written first, and refuses any sent after it.
+    pub fn close(&self, frame: CloseFrame<'static>) {
+        self.close_with(frame, Priority::Low)
+    }
+
+    /// `close`, with the Close frame in the lane for `priority`. At `High`
+    /// it only waits for other high-priority messages, and whatever is
+    /// still queued at `Low` is never written.
+    pub fn close_with(&self, frame: CloseFrame<'static>, priority: Priority) {
+        self.close_for(frame, priority, None);
+    }
+
+    /// `close_with`, recording `reason` for `kicked`. Nothing is recorded
+    /// for a queue that was already closing.
+    pub fn kick(&self, frame: CloseFrame<'static>, priority: Priority, reason: String) {
+        self.close_for(frame, priority, Some(reason));
+    }
+
+    fn close_for(&self, frame: CloseFrame<'static>, priority: Priority, kicked: Option<String>) {
+        let mut state = self.0.state.lock().unwrap();
+        if state.closed || state.closing || state.overflowed {
+            return;
+        }
+        state.closing = true;
+        state.kicked = kicked;
+        state.lane(priority).push_back(Message::Close(Some(frame)));
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// The reason given to `kick`, if the peer's Close frame came from it.
+    pub fn kicked(&self) -> Option<String> {
+        self.0.state.lock().unwrap().kicked.clone()
+    }
+
+    /// Starts streaming a binary message to the peer in fragments, once
+    /// any message already being streamed to it has been written.
+    ///
+    /// Low-priority messages queued before this are written first. Data
+    /// messages queued after it wait until the last fragment has been
+    /// written, while Pings, Pongs and high-priority messages go ahead of
+    /// the stream until its first fragment has.
+    pub async fn start_stream(&self) -> Result<Streaming, SendError> {
+        future::poll_fn(|cx| {
+            let mut state = self.0.state.lock().unwrap();
+            if state.closed {
+                return Poll::Ready(Err(SendError::Disconnected));
+            }
+            if state.closing {
+                return Poll::Ready(Err(SendError::Closing));
+            }
+            if state.overflowed {
+                return Poll::Ready(Err(SendError::Overflow));
+            }
+            if state.stream.is_some() {
+                state.blocked.push(cx.waker().clone());
+                return Poll::Pending;
+            }
+            state.stream = Some(Streamed {
+                ahead: state.messages.len(),
+                fragments: VecDeque::new(),
+                started: false,
+                finished: false,
+            });
+            Poll::Ready(Ok(()))
+        })
+        .await?;
+        Ok(Streaming {
+            tx: self.clone(),
+            finished: false,
+        })
+    }
+
+    /// Ends the queue as if it had overflowed under the `Disconnect`
+    /// policy, which disconnects the peer.
+    pub fn disconnect(&self) {
+        let mut state = self.0.state.lock().unwrap();
+        state.overflowed = true;
+        state.messages.clear();
+        state.urgent.clear();
+        state.stream = None;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Number of messages waiting to be written.
+    pub fn len(&self) -> usize {
+        self.0.state.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// A handle reading this queue's `len`.
+    pub fn depth(&self) -> QueueDepth {
+        QueueDepth(self.0.clone())
+    }
+}
+
+/// Queues the fragments of a binary message streamed with
+/// `Tx::start_stream`. Up to the queue's capacity of fragments wait to be
+/// written at a time.
+///
+/// The message ends with `finish`. Dropping the handle before then drops
+/// the message if none of it has been written yet, and otherwise
+/// disconnects the peer, whose message can't be taken back.
+pub struct Streaming {
+    tx: Tx,
+    finished: bool,
+}
+
+impl Streaming {
+    /// Queues the next fragment once there is room for it.
+    pub async fn send(&mut self, fragment: Vec<u8>) -> Result<(), SendError> {
+        let shared = &self.tx.0;
+        let mut fragment = Some(fragment);
+        future::poll_fn(|cx| {
+            let mut guard = shared.state.lock().unwrap();
+            let state = &mut *guard;
+            if state.closed {
+                return Poll::Ready(Err(SendError::Disconnected));
+            }
+            if state.closing {
+                return Poll::Ready(Err(SendError::Closing));
+            }
+            if state.overflowed {
+                return Poll::Ready(Err(SendError::Overflow));
+            }
+            let stream = match &mut state.stream {
+                Some(stream) => stream,
+                None => return Poll::Ready(Err(SendError::Closing)),
+            };
+            if stream.fragments.len() >= shared.capacity {
+                state.blocked.push(cx.waker().clone());
+                return Poll::Pending;
+            }
+            if let Some(fragment) = fragment.take() {
+                stream.fragments.push_back(fragment);
+            }
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+            Poll::Ready(Ok(()))
+        })
+        .await
+    }
+
+    /// Ends the message after the fragments sent so far.
+    pub fn finish(mut self) {
+        self.finished = true;
+        let mut state = self.tx.0.state.lock().unwrap();
+        if let Some(stream) = &mut state.stream {
+            stream.finished = true;
+        }
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for Streaming {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        let mut guard = self.tx.0.state.lock().unwrap();
+        let state = &mut *guard;
+        let started = match state.stream.take() {
+            Some(stream) => stream.started,
+            None => return,
+        };
+        if started && !state.closing {
+            drop(guard);
+            self.tx.disconnect();
+            return;
+        }
+        if started {
+            // A Close may follow the fragments written so far, but nothing
+            // else can.
+            state.messages.retain(is_control);
+            state.urgent.retain(is_control);
+        }
+        for waker in state.blocked.drain(..) {
+            waker.wake();
+        }
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl QueueDepth {
+    /// Messages waiting to be written right now.
+    pub fn get(&self) -> usize {
+        self.0.state.lock().unwrap().len()
+    }
+
+    /// The queue's capacity (see `ServerConfig.peer_buffer`).
+    pub fn capacity(&self) -> usize {
+        self.0.capacity
+    }
+}
+
+impl fmt::Debug for QueueDepth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("QueueDepth").field(&self.get()).finish()
+    }
+}
+
+impl Rx {
+    /// Whether the queue ended because it overflowed under the `Disconnect`
+    /// policy.
+    pub fn overflowed(&self) -> bool {
+        self.0.state.lock().unwrap().overflowed
+    }
+
+    /// Polls for what is to be written next, keeping streamed fragments
+    /// apart from whole messages. Ends only after a `Disconnect` overflow.
+    pub(crate) fn poll_outgoing(&mut self, cx: &mut Context<'_>) -> Poll<Option<Outgoing>> {
+        let mut state = self.0.state.lock().unwrap();
+        if state.overflowed {
+            return Poll::Ready(None);
+        }
+        match state.pop() {
+            Some(outgoing) => {
+                for waker in state.blocked.drain(..) {
+                    waker.wake();
+                }
+                Poll::Ready(Some(outgoing))
+            }
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    /// The next thing to write, as from `poll_outgoing`.
+    pub(crate) fn next_outgoing(&mut self) -> impl Future<Output = Option<Outgoing>> + Unpin + '_ {
+        future::poll_fn(move |cx| self.poll_outgoing(cx))
+    }
+}
+
+/// Yields queued messages in order, high-priority ones first. The fragments
+/// of a streamed message come out as binary messages of their own. Ends
+/// only after a `Disconnect` overflow.
+impl Stream for Rx {
+    type Item = Message;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        self.get_mut().poll_outgoing(cx).map(|outgoing| {
+            outgoing.map(|outgoing| match outgoing {
+                Outgoing::Message(msg) => msg,
+                Outgoing::Fragment(data, _) => Message::Binary(data),
+            })
+        })
+    }
+}
+
+impl Drop for Rx {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        state.closed = true;
+        state.messages.clear();
+        state.urgent.clear();
+        state.stream = None;
+        for waker in state.blocked.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+    use futures::{FutureExt, StreamExt};
+
+    use super::*;
+
+    /// Everything waiting in `rx`, without blocking.
+    fn queued(rx: &mut Rx) -> Vec<Message> {
+        std::iter::from_fn(|| rx.next().now_or_never().flatten()).collect()
+    }
+
+    /// What `rx` has to write, fragments included, without blocking.
+    fn outgoing(rx: &mut Rx) -> Vec<Outgoing> {
+        std::iter::from_fn(|| rx.next_outgoing().now_or_never().flatten()).collect()
+    }
+
+    /// A queue of two that has been sent three messages, `1`, `2` and `3`.
+    fn saturated(policy: OverflowPolicy) -> (Tx, Rx, Result<(), SendError>) {
+        let (tx, rx) = channel(2, policy);
+        tx.send(Message::text("1")).unwrap();
+        tx.send(Message::text("2")).unwrap();
+        let third = tx.send(Message::text("3"));
+        (tx, rx, third)
+    }
+
+    #[test]
+    fn drop_oldest_makes_room() {
+        let (_tx, mut rx, third) = saturated(OverflowPolicy::DropOldest);
+        assert_eq!(third, Ok(()));
+        assert_eq!(queued(&mut rx), [Message::text("2"), Message::text("3")]);
+    }
+
+    #[test]
+    fn drop_oldest_spares_control_frames() {
+        let (tx, mut rx) = channel(2, OverflowPolicy::DropOldest);
+        tx.send(Message::Ping(vec![1])).unwrap();
+        tx.send(Message::text("1")).unwrap();
+        tx.send(Message::text("2")).unwrap();
+        assert_eq!(
+            queued(&mut rx),
+            [Message::Ping(vec![1]), Message::text("2")]
+        );
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_backlog() {
+        let (_tx, mut rx, third) = saturated(OverflowPolicy::DropNewest);
+        assert_eq!(third, Ok(()));
+        assert_eq!(queued(&mut rx), [Message::text("1"), Message::text("2")]);
+    }
+
+    #[test]
+    fn disconnect_ends_the_queue() {
+        let (tx, mut rx, third) = saturated(OverflowPolicy::Disconnect);
+        assert_eq!(third, Err(SendError::Overflow));
+        assert!(rx.overflowed());
+        assert_eq!(rx.next().now_or_never(), Some(None));
+        assert_eq!(tx.send(Message::text("4")), Err(SendError::Overflow));
+    }
+
+    #[test]
+    fn control_frames_always_fit() {
+        let (tx, mut rx) = channel(1, OverflowPolicy::Disconnect);
+        tx.send(Message::text("1")).unwrap();
+        tx.send(Message::Ping(Vec::new())).unwrap();
+        assert_eq!(
+            queued(&mut rx),
+            [Message::text("1"), Message::Ping(Vec::new())]
+        );
+    }
+
+    #[test]
+    fn nothing_is_queued_after_a_close() {
+        let (tx, mut rx) = channel(4, OverflowPolicy::DropOldest);
+        tx.send(Message::text("bye")).unwrap();
+        tx.close(CloseFrame {
+            code: CloseCode::Normal,
+            reason: "done".into(),
+        });
+        assert_eq!(tx.send(Message::text("late")), Err(SendError::Closing));
+        assert_eq!(queued(&mut rx).len(), 2);
+
+        drop(rx);
+        assert_eq!(tx.send(Message::text("gone")), Err(SendError::Disconnected));
+    }
+
+    #[test]
+    fn only_the_close_that_queued_records_its_kick() {
+        let close = |reason: &str| CloseFrame {
+            code: CloseCode::Policy,
+            reason: reason.to_string().into(),
+        };
+        let (tx, _rx) = channel(4, OverflowPolicy::DropOldest);
+        tx.kick(close("cheating"), Priority::High, "cheating".to_string());
+        tx.kick(close("again"), Priority::High, "again".to_string());
+        assert_eq!(tx.kicked().as_deref(), Some("cheating"));
+
+        let (tx, _rx) = channel(4, OverflowPolicy::DropOldest);
+        tx.close(close("done"));
+        tx.kick(close("late"), Priority::High, "late".to_string());
+        assert_eq!(tx.kicked(), None);
+    }
+
+    #[test]
+    fn high_priority_messages_skip_the_backlog() {
+        let (tx, mut rx) = channel(8, OverflowPolicy::DropOldest);
+        for text in ["1", "2", "3"] {
+            tx.send(Message::text(text)).unwrap();
+        }
+        tx.send_with(Message::text("urgent"), Priority::High)
+            .unwrap();
+        assert_eq!(
+            queued(&mut rx),
+            [
+                Message::text("urgent"),
+                Message::text("1"),
+                Message::text("2"),
+                Message::text("3")
+            ]
+        );
+    }
+
+    #[test]
+    fn the_depth_follows_the_backlog() {
+        let (tx, mut rx) = channel(3, OverflowPolicy::DropOldest);
+        let depth = tx.depth();
+        assert_eq!((depth.get(), depth.capacity()), (0, 3));
+        for text in ["1", "2", "3", "4"] {
+            tx.send(Message::text(text)).unwrap();
+        }
+        assert_eq!(depth.get(), 3);
+
+        rx.next().now_or_never();
+        assert_eq!(depth.get(), 2);
+    }
+
+    #[test]
+    fn streamed_fragments_are_written_back_to_back() {
+        let message = |text: &str| Outgoing::Message(Message::text(text));
+        let (tx, mut rx) = channel(4, OverflowPolicy::DropOldest);
+        tx.send(Message::text("before")).unwrap();
+        let mut streaming = tx.start_stream().now_or_never().unwrap().unwrap();
+        tx.send(Message::text("after")).unwrap();
+        streaming.send(vec![1]).now_or_never().unwrap().unwrap();
+        assert_eq!(
+            outgoing(&mut rx),
+            [
+                message("before"),
+                Outgoing::Fragment(vec![1], Position::First)
+            ]
+        );
+
+        // Between fragments only control frames are written.
+        tx.send(Message::Ping(vec![9])).unwrap();
+        tx.send_with(Message::text("urgent"), Priority::High)
+            .unwrap();
+        assert_eq!(
+            outgoing(&mut rx),
+            [Outgoing::Message(Message::Ping(vec![9]))]
+        );
+        assert!(tx.start_stream().now_or_never().is_none());
+
+        streaming.send(vec![2]).now_or_never().unwrap().unwrap();
+        streaming.send(vec![3]).now_or_never().unwrap().unwrap();
+        streaming.finish();
+        assert_eq!(
+            outgoing(&mut rx),
+            [
+                Outgoing::Fragment(vec![2], Position::Middle),
+                Outgoing::Fragment(vec![3], Position::Last),
+                message("urgent"),
+                message("after"),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_stream_cut_short_disconnects_once_written_from() {
+        let (tx, mut rx) = channel(4, OverflowPolicy::DropOldest);
+        let mut streaming = tx.start_stream().now_or_never().unwrap().unwrap();
+        streaming.send(vec![1]).now_or_never().unwrap().unwrap();
+        drop(streaming);
+        tx.send(Message::text("still here")).unwrap();
+        assert_eq!(queued(&mut rx), [Message::text("still here")]);
+
+        let mut streaming = tx.start_stream().now_or_never().unwrap().unwrap();
+        streaming.send(vec![1]).now_or_never().unwrap().unwrap();
+        assert_eq!(queued(&mut rx), [Message::binary(vec![1])]);
+        drop(streaming);
+        assert!(rx.overflowed());
+    }
+}
@@ -0,0 +1,195 @@
+//! Per-connection token bucket limiting how fast a client may send, and
+//! the frame counter capping how many frames it may send.
+
+use std::time::{Duration, Instant};
+
+use async_tungstenite::tungstenite::Message;
+
+use crate::clock::SharedClock;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitConfig {
+    /// Messages regained per second.
+    pub refill_per_sec: f64,
+    /// Most messages that can be sent back to back.
+    pub burst: f64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            refill_per_sec: 5.0,
+            burst: 10.0,
+        }
+    }
+}
+
+/// Bucket state owned by a single connection task, so no locking is needed.
+#[derive(Debug)]
+pub struct TokenBucket {
+    config: RateLimitConfig,
+    clock: SharedClock,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket.
+    pub fn new(config: RateLimitConfig) -> Self {
+        TokenBucket::with_clock(config, SharedClock::default())
+    }
+
+    /// A full bucket refilled according to `clock`.
+    pub fn with_clock(config: RateLimitConfig, clock: SharedClock) -> Self {
+        TokenBucket {
+            config,
+            tokens: config.burst,
+            last_refill: clock.now(),
+            clock,
+        }
+    }
+
+    /// Switches to new limits, keeping the tokens already earned up to the
+    /// new burst size.
+    pub fn reconfigure(&mut self, config: RateLimitConfig) {
+        self.config = config;
+        self.tokens = self.tokens.min(config.burst);
+    }
+
+    /// Takes a token if one is available.
+    pub fn try_take(&mut self) -> bool {
+        let now = self.clock.now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.config.refill_per_sec).min(self.config.burst);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// What happens to frames beyond a `FrameLimit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FrameLimitAction {
+    /// Drop them unread until the next second starts.
+    #[default]
+    Drop,
+    /// Close the connection with a policy violation.
+    Disconnect,
+}
+
+/// Caps on how many frames a client may send each second, however small
+/// they are. Control frames (Ping, Pong, Close) are counted apart from
+/// text and binary ones, so a client chatting at its limit still answers
+/// heartbeats. Pongs over the cap are dropped like any other frame but
+/// still count as heartbeat answers.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameLimit {
+    pub data_per_sec: u32,
+    pub control_per_sec: u32,
+    pub action: FrameLimitAction,
+}
+
+impl Default for FrameLimit {
+    fn default() -> Self {
+        FrameLimit {
+            data_per_sec: 100,
+            control_per_sec: 20,
+            action: FrameLimitAction::default(),
+        }
+    }
+}
+
+/// Counts a connection's frames in one-second windows.
+#[derive(Debug)]
+pub struct FrameCounter {
+    clock: SharedClock,
+    window_start: Instant,
+    data: u32,
+    control: u32,
+}
+
+impl FrameCounter {
+    pub fn new(clock: SharedClock) -> Self {
+        FrameCounter {
+            window_start: clock.now(),
+            clock,
+            data: 0,
+            control: 0,
+        }
+    }
+
+    /// Counts `msg`, returning whether it is still within `limit`.
+    pub fn count(&mut self, msg: &Message, limit: &FrameLimit) -> bool {
+        let now = self.clock.now();
+        if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.data = 0;
+            self.control = 0;
+        }
+
+        let (count, max) = match msg {
+            Message::Text(_) | Message::Binary(_) => (&mut self.data, limit.data_per_sec),
+            _ => (&mut self.control, limit.control_per_sec),
+        };
+        *count = count.saturating_add(1);
+        *count <= max
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    const LIMIT: RateLimitConfig = RateLimitConfig {
+        refill_per_sec: 2.0,
+        burst: 3.0,
+    };
+
+    #[test]
+    fn a_burst_empties_the_bucket() {
+        let mut bucket = TokenBucket::with_clock(LIMIT, SharedClock::new(MockClock::new()));
+        let taken = (0..5).filter(|_| bucket.try_take()).count();
+        assert_eq!(taken, 3);
+    }
+
+    #[test]
+    fn the_bucket_refills_up_to_the_burst() {
+        let clock = MockClock::new();
+        let mut bucket = TokenBucket::with_clock(LIMIT, SharedClock::new(clock.clone()));
+        while bucket.try_take() {}
+
+        clock.advance(Duration::from_millis(500));
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+
+        clock.advance(Duration::from_secs(60));
+        let taken = (0..5).filter(|_| bucket.try_take()).count();
+        assert_eq!(taken, 3);
+    }
+
+    #[test]
+    fn frames_are_counted_per_second_and_kind() {
+        let clock = MockClock::new();
+        let mut counter = FrameCounter::new(SharedClock::new(clock.clone()));
+        let limit = FrameLimit {
+            data_per_sec: 2,
+            control_per_sec: 1,
+            action: FrameLimitAction::Drop,
+        };
+        let text = Message::text("hi");
+        assert!(counter.count(&text, &limit));
+        assert!(counter.count(&text, &limit));
+        assert!(!counter.count(&text, &limit));
+        assert!(counter.count(&Message::Ping(Vec::new()), &limit));
+        assert!(!counter.count(&Message::Pong(Vec::new()), &limit));
+
+        clock.advance(Duration::from_secs(1));
+        assert!(counter.count(&text, &limit));
+    }
+}
@@ -0,0 +1,225 @@
+//! Chat rooms. Every peer is a member of exactly one room at a time and
+//! broadcasts only reach the other members of that room.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use bevy::prelude::*;
+
+use crate::ConnectionId;
+
+pub type RoomMap = Arc<Mutex<HashMap<String, HashSet<ConnectionId>>>>;
+
+/// Room every peer is placed in when it connects, unless it asks for
+/// another with a `?room=` query parameter.
+pub const DEFAULT_ROOM: &str = "lobby";
+
+/// Longest room name a client can ask for when it connects.
+pub const MAX_ROOM_NAME: usize = 32;
+
+/// Whether a client may ask for `name` when it connects: up to
+/// `MAX_ROOM_NAME` ASCII letters, digits, `-` and `_`.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_ROOM_NAME
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// The room a handshake asks for with its `room` query parameter, `Err`
+/// naming it if it isn't a `valid_name`.
+pub fn from_query(query: Option<&str>) -> Result<Option<String>, String> {
+    let name = query.and_then(|query| query.split('&').find_map(|pair| pair.strip_prefix("room=")));
+    match name {
+        Some(name) if valid_name(name) => Ok(Some(name.to_string())),
+        Some(name) => Err(name.to_string()),
+        None => Ok(None),
+    }
+}
+
+/// Parses a `/join <room>` command, returning the room name.
+pub fn parse_join(text: &str) -> Option<&str> {
+    let room = text.strip_prefix("/join ")?.trim();
+    if room.is_empty() {
+        None
+    } else {
+        Some(room)
+    }
+}
+
+/// Moves `id` into `room`, leaving whatever room it was in before. Returns
+/// the room it left if that is now empty.
+pub fn join(rooms: &RoomMap, id: ConnectionId, room: &str) -> Option<String> {
+    let mut rooms = rooms.lock().unwrap();
+    let emptied = remove_member(&mut rooms, id);
+    rooms.entry(room.to_string()).or_default().insert(id);
+    emptied.filter(|emptied| emptied != room)
+}
+
+/// Removes `id` from its room, dropping the room if it is now empty.
+/// Returns the dropped room.
+pub fn leave(rooms: &RoomMap, id: ConnectionId) -> Option<String> {
+    remove_member(&mut rooms.lock().unwrap(), id)
+}
+
+/// The name of the room `id` is in.
+pub fn room_of(rooms: &RoomMap, id: ConnectionId) -> Option<String> {
+    rooms
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(_, members)| members.contains(&id))
+        .map(|(room, _)| room.clone())
+}
+
+/// Returns the members of the room `id` is in, including `id` itself.
+pub fn room_members(rooms: &RoomMap, id: ConnectionId) -> HashSet<ConnectionId> {
+    rooms
+        .lock()
+        .unwrap()
+        .values()
+        .find(|members| members.contains(&id))
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Returns the members of `room`, which is empty if nobody is in it.
+pub fn members_of(rooms: &RoomMap, room: &str) -> HashSet<ConnectionId> {
+    rooms.lock().unwrap().get(room).cloned().unwrap_or_default()
+}
+
+/// Resource holding a snapshot of the `RoomMap`, refreshed every frame.
+/// Members are listed in id order. Cloning shares the snapshot.
+#[derive(Debug, Clone, Default)]
+pub struct Rooms(Arc<HashMap<String, Vec<ConnectionId>>>);
+
+impl Rooms {
+    /// The rooms in `rooms` as they are now.
+    pub fn snapshot(rooms: &RoomMap) -> Rooms {
+        let snapshot = rooms
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(room, members)| {
+                let mut members: Vec<ConnectionId> = members.iter().copied().collect();
+                members.sort();
+                (room.clone(), members)
+            })
+            .collect();
+        Rooms(Arc::new(snapshot))
+    }
+
+    /// The members of `room`, or `None` if nobody is in it.
+    pub fn get(&self, room: &str) -> Option<&[ConnectionId]> {
+        self.0.get(room).map(Vec::as_slice)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Vec<ConnectionId>)> {
+        self.0.iter()
+    }
+
+    /// Every room with its number of members, sorted by name.
+    pub fn occupancy(&self) -> Vec<(String, usize)> {
+        let mut occupancy: Vec<(String, usize)> = self
+            .0
+            .iter()
+            .map(|(room, members)| (room.clone(), members.len()))
+            .collect();
+        occupancy.sort();
+        occupancy
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Refreshes the `Rooms` resource from the `RoomMap`.
+pub fn snapshot_rooms(map: Res<RoomMap>, mut rooms: ResMut<Rooms>) {
+    *rooms = Rooms::snapshot(&map);
+}
+
+fn remove_member(
+    rooms: &mut HashMap<String, HashSet<ConnectionId>>,
+    id: ConnectionId,
+) -> Option<String> {
+    let room = rooms
+        .iter()
+        .find(|(_, members)| members.contains(&id))
+        .map(|(room, _)| room.clone())?;
+    let members = rooms.get_mut(&room)?;
+    members.remove(&id);
+    if members.is_empty() {
+        rooms.remove(&room);
+        Some(room)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map() -> RoomMap {
+        let rooms = RoomMap::default();
+        join(&rooms, ConnectionId(3), "lobby");
+        join(&rooms, ConnectionId(1), "lobby");
+        join(&rooms, ConnectionId(2), "arena");
+        rooms
+    }
+
+    #[test]
+    fn rooms_are_read_from_the_query() {
+        assert_eq!(from_query(None), Ok(None));
+        assert_eq!(
+            from_query(Some("format=json&room=foo")),
+            Ok(Some("foo".to_string()))
+        );
+        assert_eq!(
+            from_query(Some("room=no%20spaces")),
+            Err("no%20spaces".to_string())
+        );
+        assert_eq!(from_query(Some("room=")), Err(String::new()));
+        assert!(valid_name(&"x".repeat(MAX_ROOM_NAME)));
+        assert!(!valid_name(&"x".repeat(MAX_ROOM_NAME + 1)));
+    }
+
+    #[test]
+    fn snapshots_list_members_in_id_order() {
+        let rooms = Rooms::snapshot(&map());
+        assert_eq!(
+            rooms.get("lobby"),
+            Some(&[ConnectionId(1), ConnectionId(3)][..])
+        );
+        assert_eq!(
+            rooms.occupancy(),
+            [("arena".to_string(), 1), ("lobby".to_string(), 2)]
+        );
+        assert_eq!(rooms.get("attic"), None);
+    }
+
+    #[test]
+    fn the_resource_follows_the_map() {
+        let map = map();
+        let mut app = App::build();
+        app.insert_resource(map.clone())
+            .insert_resource(Rooms::default())
+            .add_system(snapshot_rooms.system());
+        let mut app = app.app;
+        app.update();
+        assert_eq!(app.world.get_resource::<Rooms>().unwrap().len(), 2);
+
+        leave(&map, ConnectionId(2));
+        app.update();
+        let rooms = app.world.get_resource::<Rooms>().unwrap();
+        assert_eq!(rooms.occupancy(), [("lobby".to_string(), 2)]);
+    }
+}
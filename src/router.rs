@@ -0,0 +1,129 @@
+//! Handlers for custom commands.
+//!
+//! Any `/name args` text that isn't one of the built-in commands arrives as
+//! `ClientCommand::Custom`. Register a handler for `name` on the
+//! `CommandRouter` resource and `route_commands` calls it on the main thread.
+
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+
+use crate::{ClientCommand, ClientCommandReceived, ConnectionId};
+
+/// Called with the sender and everything after the command name.
+pub type CommandHandler = Box<dyn Fn(ConnectionId, &str) + Send + Sync>;
+
+/// Resource mapping custom command names, without the leading `/`, to
+/// their handlers.
+pub struct CommandRouter {
+    handlers: HashMap<String, CommandHandler>,
+    fallback: CommandHandler,
+}
+
+impl Default for CommandRouter {
+    /// A router with no handlers whose fallback logs the unknown command.
+    fn default() -> Self {
+        CommandRouter {
+            handlers: HashMap::new(),
+            fallback: Box::new(|id, name| info!("Unknown command from {}: {}", id, name)),
+        }
+    }
+}
+
+impl CommandRouter {
+    /// Registers `handler` for `/name`, replacing any previous one.
+    pub fn register(
+        &mut self,
+        name: &str,
+        handler: impl Fn(ConnectionId, &str) + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.handlers.insert(name.to_string(), Box::new(handler));
+        self
+    }
+
+    /// Sets the handler for commands nobody registered. It is passed the
+    /// command name instead of its arguments.
+    pub fn set_fallback(
+        &mut self,
+        handler: impl Fn(ConnectionId, &str) + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.fallback = Box::new(handler);
+        self
+    }
+
+    /// Runs the handler for `name`, or the fallback.
+    pub fn dispatch(&self, id: ConnectionId, name: &str, args: &str) {
+        match self.handlers.get(name) {
+            Some(handler) => handler(id, args),
+            None => (self.fallback)(id, name),
+        }
+    }
+}
+
+/// Passes every `ClientCommand::Custom` to the `CommandRouter`.
+pub fn route_commands(
+    mut commands: EventReader<ClientCommandReceived>,
+    router: Res<CommandRouter>,
+) {
+    for received in commands.iter() {
+        if let ClientCommand::Custom { name, args } = &received.command {
+            router.dispatch(received.id, name, args);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use bevy::app::Events;
+
+    use super::*;
+
+    fn custom(id: u64, name: &str, args: &str) -> ClientCommandReceived {
+        ClientCommandReceived {
+            id: ConnectionId(id),
+            command: ClientCommand::Custom {
+                name: name.to_string(),
+                args: args.to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn registered_commands_reach_their_handler() {
+        let rolls = Arc::new(Mutex::new(Vec::new()));
+        let unknown = Arc::new(Mutex::new(Vec::new()));
+        let mut router = CommandRouter::default();
+        let seen = rolls.clone();
+        router.register("roll", move |id, args| {
+            seen.lock().unwrap().push((id, args.to_string()))
+        });
+        let seen = unknown.clone();
+        router.set_fallback(move |_, name| seen.lock().unwrap().push(name.to_string()));
+
+        let mut builder = App::build();
+        builder
+            .add_event::<ClientCommandReceived>()
+            .insert_resource(router)
+            .add_system(route_commands.system());
+        let mut app = builder.app;
+        let mut events = app
+            .world
+            .get_resource_mut::<Events<ClientCommandReceived>>()
+            .unwrap();
+        events.send(custom(1, "roll", "2d6"));
+        events.send(custom(2, "dance", ""));
+        events.send(ClientCommandReceived {
+            id: ConnectionId(3),
+            command: ClientCommand::Ping,
+        });
+        app.update();
+
+        assert_eq!(
+            *rolls.lock().unwrap(),
+            [(ConnectionId(1), "2d6".to_string())]
+        );
+        assert_eq!(*unknown.lock().unwrap(), ["dance"]);
+    }
+}
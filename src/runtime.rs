@@ -0,0 +1,236 @@
+//! Async runtime selection.
+//!
+//! Exactly one of the `async-std-runtime` and `tokio-runtime` features must be
+//! enabled. The rest of the crate only uses the networking types, spawn and
+//! timer functions re-exported here, so the server logic is shared between
+//! both runtimes. `block_on` runs a future from synchronous code, such as a
+//! test, on either one. Accepted and connected streams always implement the
+//! `futures` IO traits, which is what `async_tungstenite::accept_async` and
+//! friends expect.
+
+use std::time::Duration;
+
+use futures::{
+    io::{AsyncRead, AsyncWrite},
+    prelude::*,
+};
+
+#[cfg(all(feature = "async-std-runtime", feature = "tokio-runtime"))]
+compile_error!("features `async-std-runtime` and `tokio-runtime` are mutually exclusive");
+
+#[cfg(not(any(feature = "async-std-runtime", feature = "tokio-runtime")))]
+compile_error!("one of the features `async-std-runtime` or `tokio-runtime` must be enabled");
+
+#[cfg(feature = "async-std-runtime")]
+mod imp {
+    use std::{io, net::SocketAddr, time::Duration};
+
+    use futures::Future;
+    use socket2::{SockRef, TcpKeepalive};
+
+    pub use async_std::net::{TcpListener, TcpStream};
+    pub use async_std::task::sleep;
+
+    pub async fn accept(listener: &TcpListener) -> io::Result<(TcpStream, SocketAddr)> {
+        listener.accept().await
+    }
+
+    /// Takes over a listener bound in non-blocking mode.
+    pub fn listener_from_std(listener: std::net::TcpListener) -> io::Result<TcpListener> {
+        Ok(TcpListener::from(listener))
+    }
+
+    pub fn set_nodelay(stream: &TcpStream, nodelay: bool) -> io::Result<()> {
+        stream.set_nodelay(nodelay)
+    }
+
+    pub fn set_keepalive(stream: &TcpStream, keepalive: &TcpKeepalive) -> io::Result<()> {
+        SockRef::from(stream).set_tcp_keepalive(keepalive)
+    }
+
+    pub async fn connect(addr: &str) -> io::Result<TcpStream> {
+        TcpStream::connect(addr).await
+    }
+
+    #[cfg(all(unix, feature = "uds"))]
+    pub use async_std::os::unix::net::{UnixListener, UnixStream};
+
+    #[cfg(all(unix, feature = "uds"))]
+    pub async fn bind_unix(path: &std::path::Path) -> io::Result<UnixListener> {
+        UnixListener::bind(path).await
+    }
+
+    #[cfg(all(unix, feature = "uds"))]
+    pub async fn accept_unix(listener: &UnixListener) -> io::Result<UnixStream> {
+        let (stream, _) = listener.accept().await?;
+        Ok(stream)
+    }
+
+    #[cfg(all(unix, feature = "uds"))]
+    pub async fn connect_unix(path: &std::path::Path) -> io::Result<UnixStream> {
+        UnixStream::connect(path).await
+    }
+
+    pub fn spawn<F>(future: F)
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        async_std::task::spawn(future);
+    }
+
+    /// `spawn`, naming the task as async-std's own logging and
+    /// `task::current().name()` show it.
+    pub fn spawn_named<F>(name: String, future: F)
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        async_std::task::Builder::new()
+            .name(name)
+            .spawn(future)
+            .expect("cannot spawn task");
+    }
+
+    pub fn spawn_blocking<F, T>(f: F)
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        async_std::task::spawn_blocking(f);
+    }
+
+    pub async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+        async_std::future::timeout(duration, future).await.ok()
+    }
+
+    /// Runs `future` to completion on the calling thread. It is boxed so a
+    /// large one isn't copied down the calling thread's stack.
+    pub fn block_on<F: Future>(future: F) -> F::Output {
+        async_std::task::block_on(Box::pin(future))
+    }
+}
+
+#[cfg(all(feature = "tokio-runtime", not(feature = "async-std-runtime")))]
+mod imp {
+    use std::{io, net::SocketAddr, time::Duration};
+
+    use async_tungstenite::tokio::TokioAdapter;
+    use futures::Future;
+    use once_cell::sync::Lazy;
+    use socket2::{SockRef, TcpKeepalive};
+    use tokio::runtime::Runtime;
+
+    pub use tokio::net::TcpListener;
+    pub use tokio::time::sleep;
+
+    /// Accepted tokio sockets, adapted to the `futures` IO traits.
+    pub type TcpStream = TokioAdapter<tokio::net::TcpStream>;
+
+    pub async fn accept(listener: &TcpListener) -> io::Result<(TcpStream, SocketAddr)> {
+        let (stream, addr) = listener.accept().await?;
+        Ok((TokioAdapter::new(stream), addr))
+    }
+
+    /// Takes over a listener bound in non-blocking mode.
+    pub fn listener_from_std(listener: std::net::TcpListener) -> io::Result<TcpListener> {
+        TcpListener::from_std(listener)
+    }
+
+    pub fn set_nodelay(stream: &TcpStream, nodelay: bool) -> io::Result<()> {
+        stream.get_ref().set_nodelay(nodelay)
+    }
+
+    pub fn set_keepalive(stream: &TcpStream, keepalive: &TcpKeepalive) -> io::Result<()> {
+        SockRef::from(stream.get_ref()).set_tcp_keepalive(keepalive)
+    }
+
+    pub async fn connect(addr: &str) -> io::Result<TcpStream> {
+        let stream = tokio::net::TcpStream::connect(addr).await?;
+        Ok(TokioAdapter::new(stream))
+    }
+
+    #[cfg(all(unix, feature = "uds"))]
+    pub use tokio::net::UnixListener;
+
+    /// Accepted Unix socket connections, adapted like `TcpStream`.
+    #[cfg(all(unix, feature = "uds"))]
+    pub type UnixStream = TokioAdapter<tokio::net::UnixStream>;
+
+    #[cfg(all(unix, feature = "uds"))]
+    pub async fn bind_unix(path: &std::path::Path) -> io::Result<UnixListener> {
+        UnixListener::bind(path)
+    }
+
+    #[cfg(all(unix, feature = "uds"))]
+    pub async fn accept_unix(listener: &UnixListener) -> io::Result<UnixStream> {
+        let (stream, _) = listener.accept().await?;
+        Ok(TokioAdapter::new(stream))
+    }
+
+    #[cfg(all(unix, feature = "uds"))]
+    pub async fn connect_unix(path: &std::path::Path) -> io::Result<UnixStream> {
+        let stream = tokio::net::UnixStream::connect(path).await?;
+        Ok(TokioAdapter::new(stream))
+    }
+
+    // Bevy's task pools don't provide a tokio reactor, so everything the
+    // server spawns runs on a runtime of its own.
+    static RUNTIME: Lazy<Runtime> = Lazy::new(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("Failed to start the tokio runtime")
+    });
+
+    pub fn spawn<F>(future: F)
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        RUNTIME.spawn(future);
+    }
+
+    /// `spawn`. tokio only names tasks when built with `tokio_unstable`, so
+    /// the name is dropped.
+    pub fn spawn_named<F>(_name: String, future: F)
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        RUNTIME.spawn(future);
+    }
+
+    pub fn spawn_blocking<F, T>(f: F)
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        RUNTIME.spawn_blocking(f);
+    }
+
+    pub async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+        tokio::time::timeout(duration, future).await.ok()
+    }
+
+    /// Runs `future` to completion on the calling thread, inside the
+    /// runtime everything else is spawned on. It is boxed as under async-std.
+    pub fn block_on<F: Future>(future: F) -> F::Output {
+        RUNTIME.block_on(Box::pin(future))
+    }
+}
+
+pub use imp::*;
+
+/// Byte streams a WebSocket connection can run over.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T> AsyncStream for T where T: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+/// A stream that yields once every `period`, starting one period from now.
+pub fn interval(period: Duration) -> impl Stream<Item = ()> {
+    stream::unfold((), move |()| async move {
+        sleep(period).await;
+        Some(((), ()))
+    })
+}
@@ -0,0 +1,102 @@
+//! A minimal async semaphore, used to cap how many handshakes run at once.
+
+use std::{
+    sync::{Arc, Mutex},
+    task::{Poll, Waker},
+};
+
+use futures::future;
+
+struct State {
+    available: usize,
+    waiters: Vec<Waker>,
+}
+
+#[derive(Clone)]
+pub(crate) struct Semaphore(Arc<Mutex<State>>);
+
+/// One unit of the semaphore, given back when dropped.
+pub(crate) struct Permit(Arc<Mutex<State>>);
+
+impl Semaphore {
+    pub(crate) fn new(permits: usize) -> Self {
+        Semaphore(Arc::new(Mutex::new(State {
+            available: permits,
+            waiters: Vec::new(),
+        })))
+    }
+
+    /// Waits until a permit is free and takes it.
+    pub(crate) async fn acquire(&self) -> Permit {
+        future::poll_fn(|cx| {
+            let mut state = self.0.lock().unwrap();
+            if state.available == 0 {
+                state.waiters.push(cx.waker().clone());
+                return Poll::Pending;
+            }
+            state.available -= 1;
+            Poll::Ready(Permit(self.0.clone()))
+        })
+        .await
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.0.lock().unwrap();
+        state.available += 1;
+        for waker in state.waiters.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::{executor::block_on, FutureExt};
+
+    use super::*;
+
+    /// Lets the other futures of a `join_all` run once.
+    async fn yield_now() {
+        let mut yielded = false;
+        future::poll_fn(|cx| {
+            if yielded {
+                return Poll::Ready(());
+            }
+            yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        })
+        .await
+    }
+
+    #[test]
+    fn acquiring_waits_for_a_permit_to_be_given_back() {
+        let semaphore = Semaphore::new(1);
+        let permit = semaphore.acquire().now_or_never().unwrap();
+        assert!(semaphore.acquire().now_or_never().is_none());
+        drop(permit);
+        assert!(semaphore.acquire().now_or_never().is_some());
+    }
+
+    #[test]
+    fn holders_never_exceed_the_permits() {
+        let semaphore = Semaphore::new(2);
+        let inflight = AtomicUsize::new(0);
+        let most = AtomicUsize::new(0);
+        let handshakes = (0..6).map(|_| async {
+            let _permit = semaphore.acquire().await;
+            let now = inflight.fetch_add(1, Ordering::SeqCst) + 1;
+            most.fetch_max(now, Ordering::SeqCst);
+            for _ in 0..3 {
+                yield_now().await;
+            }
+            inflight.fetch_sub(1, Ordering::SeqCst);
+        });
+        block_on(future::join_all(handshakes));
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,340 @@
+//! The server without Bevy, for embedding in another async application.
+//!
+//! `Server::start` binds and starts serving on the async runtime. The
+//! receiving ends of the bridge channels are public fields to read incoming
+//! messages and events from, and the methods send to clients and shut the
+//! server down. `setup` wraps the same type for the Bevy app.
+
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex, Weak},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use async_tungstenite::tungstenite::protocol::Message;
+use bevy::log::{error, info, warn};
+use crossbeam_channel::Receiver;
+use futures::{channel::oneshot, future::BoxFuture, prelude::*};
+
+use crate::{
+    broadcast_reliable,
+    channel::{self, BridgeReceiver, BridgeSender},
+    queue::SendError,
+    runtime, BanList, Bridge, ClientCommandReceived, ConnectionEvent, ConnectionId, DeafSet,
+    Directory, EntityMap, LiveConfig, OutboundMessage, PeerMap, RoomMap, Rooms, ServerConfig,
+    ServerError, ShutdownHandle, WsMessageReceived, WsOutbox, WsStats, SHUTDOWN_GRACE,
+};
+
+/// Messages a `MessageStream` holds before dropping the oldest.
+const MESSAGE_STREAM_CAPACITY: usize = 1024;
+
+/// A running server.
+pub struct Server {
+    pub messages: BridgeReceiver<WsMessageReceived>,
+    pub connections: BridgeReceiver<ConnectionEvent>,
+    pub commands: BridgeReceiver<ClientCommandReceived>,
+    pub stats: WsStats,
+    pub bans: BanList,
+    pub deaf: DeafSet,
+    pub directory: Directory,
+    /// Replace the settings through this to reconfigure the running server.
+    pub config: LiveConfig,
+    /// Lets the app forward messages as if a client had sent them.
+    pub(crate) message_sender: BridgeSender<WsMessageReceived>,
+    pub(crate) peers: PeerMap,
+    pub(crate) entities: EntityMap,
+    pub(crate) rooms: RoomMap,
+    pub(crate) streams: MessageStreams,
+    pub(crate) outbox: WsOutbox,
+    pub(crate) handle: ShutdownHandle,
+    local_addrs: Vec<SocketAddr>,
+}
+
+impl Server {
+    /// Binds every address in `addrs` (see `bind_all`) and starts serving
+    /// on them.
+    pub async fn start(addrs: &[String], config: ServerConfig) -> Result<Server, ServerError> {
+        let listeners = crate::bind_all_with(addrs, &config).await?;
+        let local_addrs = local_addrs(&listeners);
+        let mut server = Server::launch(config, move |bridge, outbox, config, shutdown| {
+            crate::run_on_all(listeners, bridge, outbox, config, shutdown).boxed()
+        });
+        server.local_addrs = local_addrs;
+        Ok(server)
+    }
+
+    /// Like `start`, but serves `wss://` using the PEM certificate chain and
+    /// private key at the given paths (see `run_tls`). Fails without
+    /// binding if they can't be loaded.
+    #[cfg(feature = "tls")]
+    pub async fn start_tls(
+        addrs: &[String],
+        config: ServerConfig,
+        cert_path: &std::path::Path,
+        key_path: &std::path::Path,
+    ) -> Result<Server, ServerError> {
+        let acceptor = crate::tls::load_acceptor(cert_path, key_path).map_err(ServerError::Tls)?;
+        let listeners = crate::bind_all_with(addrs, &config).await?;
+        let local_addrs = local_addrs(&listeners);
+        let mut server = Server::launch(config, move |bridge, outbox, config, shutdown| {
+            crate::serve_tls(listeners, acceptor, bridge, outbox, config, shutdown).boxed()
+        });
+        server.local_addrs = local_addrs;
+        Ok(server)
+    }
+
+    /// Binds a Unix domain socket at `path` and starts serving on it (see
+    /// `run_uds`).
+    #[cfg(all(unix, feature = "uds"))]
+    pub async fn start_uds(
+        path: impl AsRef<std::path::Path>,
+        config: ServerConfig,
+    ) -> Result<Server, ServerError> {
+        let path = path.as_ref().to_path_buf();
+        let listener = runtime::bind_unix(&path)
+            .await
+            .map_err(|source| ServerError::Bind {
+                addr: path.display().to_string(),
+                source,
+            })?;
+        Ok(Server::launch(
+            config,
+            move |bridge, outbox, config, shutdown| {
+                crate::serve_uds(listener, path, bridge, outbox, config, shutdown).boxed()
+            },
+        ))
+    }
+
+    /// The addresses `start` ended up listening on, e.g. to find the port
+    /// picked for port 0.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// Every room and its members as they are now.
+    pub fn rooms(&self) -> Rooms {
+        Rooms::snapshot(&self.rooms)
+    }
+
+    /// Creates the bridge channels and spawns `serve` with them on the
+    /// async runtime. Failures are logged through Bevy's `error!` once the
+    /// server has stopped.
+    pub(crate) fn launch<F>(config: ServerConfig, serve: F) -> Server
+    where
+        F: FnOnce(
+            Bridge,
+            Receiver<OutboundMessage>,
+            ServerConfig,
+            BoxFuture<'static, ()>,
+        ) -> BoxFuture<'static, Result<(), ServerError>>,
+    {
+        let (message_sender, messages) = channel::unbounded::<WsMessageReceived>();
+        let (connection_sender, connections) = channel::unbounded::<ConnectionEvent>();
+        let (outbox_sender, outbox) = crossbeam_channel::unbounded::<OutboundMessage>();
+        let (command_sender, commands) = channel::unbounded::<ClientCommandReceived>();
+
+        let bridge = Bridge {
+            messages: message_sender.clone(),
+            connections: connection_sender,
+            commands: command_sender,
+            stats: WsStats::default(),
+            bans: BanList::default(),
+            deaf: DeafSet::default(),
+            directory: Directory::default(),
+            peers: PeerMap::default(),
+            entities: EntityMap::default(),
+            rooms: RoomMap::default(),
+            streams: MessageStreams::default(),
+            config: LiveConfig::new(config.clone()),
+        };
+        let (trigger, shutdown) = oneshot::channel::<()>();
+        let (finished_sender, finished) = crossbeam_channel::bounded::<()>(1);
+
+        let server = Server {
+            messages,
+            connections,
+            commands,
+            stats: bridge.stats.clone(),
+            bans: bridge.bans.clone(),
+            deaf: bridge.deaf.clone(),
+            directory: bridge.directory.clone(),
+            config: bridge.config.clone(),
+            message_sender,
+            peers: bridge.peers.clone(),
+            entities: bridge.entities.clone(),
+            rooms: bridge.rooms.clone(),
+            streams: bridge.streams.clone(),
+            outbox: WsOutbox(outbox_sender),
+            handle: ShutdownHandle {
+                trigger: Some(trigger),
+                finished,
+            },
+            local_addrs: Vec::new(),
+        };
+
+        let running = serve(bridge, outbox, config, shutdown.map(|_| ()).boxed());
+        runtime::spawn(async move {
+            if let Err(e) = running.await {
+                error!("Server stopped: {}", e);
+            }
+            let _ = finished_sender.send(());
+        });
+
+        server
+    }
+
+    /// Sends `msg` to every client, waiting up to the configured write
+    /// timeout for each to have room for it. Returns the clients that were
+    /// disconnected for not making room in time.
+    pub async fn broadcast(&self, msg: Message) -> Vec<ConnectionId> {
+        broadcast_reliable(&self.peers, &msg, self.config.current().write_timeout).await
+    }
+
+    /// Sends `msg` to one client, waiting up to the configured write timeout
+    /// for it to have room. A client that doesn't make room in time is
+    /// disconnected.
+    pub async fn send_to(&self, id: ConnectionId, msg: Message) -> Result<(), SendError> {
+        // Cloned out so the map isn't locked while waiting.
+        let tx = match self.peers.get(&id) {
+            Some(tx) => tx.clone(),
+            None => return Err(SendError::Disconnected),
+        };
+        let write_timeout = self.config.current().write_timeout;
+        match runtime::timeout(write_timeout, tx.send_ready(msg)).await {
+            Some(result) => result,
+            None => {
+                info!("Disconnecting {}: couldn't keep up", id);
+                tx.disconnect();
+                self.peers.remove(&id);
+                Err(SendError::Overflow)
+            }
+        }
+    }
+
+    /// Every message received from a client, as a `Stream`. While any of
+    /// these streams is open, messages go to them instead of the `messages`
+    /// channel, and each stream gets all of them.
+    ///
+    /// A consumer that falls more than 1024 messages behind loses the oldest
+    /// ones, with a warning logged. The stream doesn't end while the
+    /// `Server` is around.
+    pub fn messages(&self) -> MessageStream {
+        let inbox = Arc::new(Mutex::new(Inbox::default()));
+        self.streams.open(&inbox);
+        MessageStream(inbox)
+    }
+
+    /// Queues an `OutboundMessage` without waiting, like the `WsOutbox`
+    /// resource does.
+    pub fn send(&self, outbound: OutboundMessage) {
+        self.outbox.send(outbound);
+    }
+
+    /// A `WsOutbox` sending through this server, for systems of an app the
+    /// server wasn't set up by.
+    pub fn outbox(&self) -> WsOutbox {
+        self.outbox.clone()
+    }
+
+    /// Stops accepting connections and closes every open one, waiting up to
+    /// `SHUTDOWN_GRACE` for that to finish.
+    pub async fn shutdown(mut self) {
+        let trigger = match self.handle.trigger.take() {
+            Some(trigger) => trigger,
+            None => return,
+        };
+        let _ = trigger.send(());
+
+        let finished = async {
+            while self.handle.finished.try_recv().is_err() {
+                runtime::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        let _ = runtime::timeout(SHUTDOWN_GRACE, finished).await;
+    }
+}
+
+fn local_addrs(listeners: &[runtime::TcpListener]) -> Vec<SocketAddr> {
+    listeners
+        .iter()
+        .filter_map(|listener| listener.local_addr().ok())
+        .collect()
+}
+
+#[derive(Default)]
+struct Inbox {
+    queue: VecDeque<(ConnectionId, Message)>,
+    waker: Option<Waker>,
+    /// Set while messages are being dropped, so the warning is logged once
+    /// per backlog.
+    lagging: bool,
+}
+
+impl Inbox {
+    fn push(&mut self, id: ConnectionId, msg: Message) {
+        if self.queue.len() >= MESSAGE_STREAM_CAPACITY {
+            self.queue.pop_front();
+            if !self.lagging {
+                self.lagging = true;
+                warn!("Message stream consumer is lagging, dropping old messages");
+            }
+        }
+        self.queue.push_back((id, msg));
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// The `MessageStream`s open on a server, shared with its connection tasks.
+#[derive(Clone, Default)]
+pub(crate) struct MessageStreams(Arc<Mutex<Vec<Weak<Mutex<Inbox>>>>>);
+
+impl MessageStreams {
+    fn open(&self, inbox: &Arc<Mutex<Inbox>>) {
+        self.0.lock().unwrap().push(Arc::downgrade(inbox));
+    }
+
+    /// Gives every open stream a copy of `received`, handing it back if
+    /// there are none. Dropped streams are forgotten here.
+    pub(crate) fn deliver(&self, received: WsMessageReceived) -> Option<WsMessageReceived> {
+        let mut streams = self.0.lock().unwrap();
+        let open: Vec<_> = streams.iter().filter_map(Weak::upgrade).collect();
+        streams.retain(|inbox| inbox.strong_count() > 0);
+        drop(streams);
+        if open.is_empty() {
+            return Some(received);
+        }
+        for inbox in open {
+            let msg = received.msg.clone();
+            inbox.lock().unwrap().push(received.id, msg);
+        }
+        None
+    }
+}
+
+/// Stream of inbound messages returned by `Server::messages`.
+pub struct MessageStream(Arc<Mutex<Inbox>>);
+
+impl Stream for MessageStream {
+    type Item = (ConnectionId, Message);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut inbox = self.0.lock().unwrap();
+        match inbox.queue.pop_front() {
+            Some(message) => {
+                if inbox.queue.is_empty() {
+                    inbox.lagging = false;
+                }
+                Poll::Ready(Some(message))
+            }
+            None => {
+                inbox.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! `wss://` support. Accepted TCP streams are wrapped in a rustls session
+//! before the WebSocket handshake runs over them, and so are client
+//! connections made with `client::connect`.
+
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use futures_rustls::rustls::{
+    Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerConfig,
+};
+use rustls_pemfile::Item;
+
+pub use futures_rustls::{TlsAcceptor, TlsConnector};
+
+/// Resource pointing `setup` at a PEM certificate chain and private key.
+/// When present the server only accepts `wss://` connections.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+/// Builds a `TlsAcceptor` from a PEM certificate chain and a PEM private key
+/// (PKCS#8, PKCS#1 or SEC1).
+pub fn load_acceptor(cert_path: &Path, key_path: &Path) -> io::Result<TlsAcceptor> {
+    let certs = load_certs(cert_path)?;
+    let key = load_key(key_path)?;
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let certs =
+        rustls_pemfile::certs(&mut open(path)?).map_err(|e| invalid_data(path, &e.to_string()))?;
+    if certs.is_empty() {
+        return Err(invalid_data(path, "no certificates found"));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKey> {
+    let items = rustls_pemfile::read_all(&mut open(path)?)
+        .map_err(|e| invalid_data(path, &e.to_string()))?;
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| invalid_data(path, "no private key found"))
+}
+
+fn open(path: &Path) -> io::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+fn invalid_data(path: &Path, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), reason),
+    )
+}
+
+/// Builds a `TlsConnector` that trusts the Mozilla root certificates.
+pub fn client_connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
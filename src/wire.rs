@@ -0,0 +1,248 @@
+//! How game structs are encoded on the wire, chosen per connection.
+//!
+//! A client picks its format with a `?format=json` or `?format=bincode`
+//! query parameter, or by negotiating a `json` or `bincode` subprotocol;
+//! otherwise it gets `ServerConfig.wire_format`. `serialize` and
+//! `deserialize` need the `serde` feature, and `Bincode` the `bincode`
+//! feature.
+//!
+//! `broadcast_event` and `send_event_to` send a game struct to clients in
+//! whichever format each of them reads.
+
+use std::fmt;
+
+#[cfg(feature = "serde")]
+use bevy::log::warn;
+
+#[cfg(feature = "serde")]
+use async_tungstenite::tungstenite::protocol::Message;
+#[cfg(feature = "serde")]
+use serde::{de::DeserializeOwned, Serialize};
+
+#[cfg(feature = "serde")]
+use crate::{ConnectionId, OutboundMessage, WsOutbox};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireFormat {
+    /// JSON in text frames, readable in browser dev tools.
+    #[default]
+    Json,
+    /// bincode in binary frames. bincode isn't self-describing, so it can't
+    /// decode internally tagged enums such as `ClientCommand`; use plain
+    /// structs or externally tagged enums.
+    #[cfg(feature = "bincode")]
+    Bincode,
+}
+
+impl WireFormat {
+    /// The format called `name`, as used in query strings and subprotocols.
+    pub fn from_name(name: &str) -> Option<WireFormat> {
+        match name {
+            "json" => Some(WireFormat::Json),
+            #[cfg(feature = "bincode")]
+            "bincode" => Some(WireFormat::Bincode),
+            _ => None,
+        }
+    }
+
+    /// The format a handshake asks for with its `format` query parameter,
+    /// `Err` naming the format if it isn't supported.
+    pub fn from_query(query: Option<&str>) -> Result<Option<WireFormat>, String> {
+        let name = query.and_then(|query| {
+            query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("format="))
+        });
+        match name {
+            Some(name) => WireFormat::from_name(name)
+                .map(Some)
+                .ok_or_else(|| name.to_string()),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Why a message couldn't be encoded or decoded.
+#[derive(Debug)]
+pub enum WireError {
+    /// The frame wasn't the kind the format uses, e.g. binary for `Json`.
+    WrongFrame,
+    #[cfg(feature = "serde")]
+    Json(serde_json::Error),
+    #[cfg(feature = "bincode")]
+    Bincode(bincode::Error),
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireError::WrongFrame => write!(f, "unexpected frame type"),
+            #[cfg(feature = "serde")]
+            WireError::Json(e) => write!(f, "invalid JSON: {}", e),
+            #[cfg(feature = "bincode")]
+            WireError::Bincode(e) => write!(f, "invalid bincode: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
+
+/// Encodes `value` as a message in `format`. Values that can't be encoded,
+/// such as maps with non-string keys in JSON, are an `Err` rather than an
+/// empty frame; don't send anything for them.
+#[cfg(feature = "serde")]
+pub fn serialize<T: Serialize>(format: WireFormat, value: &T) -> Result<Message, WireError> {
+    match format {
+        WireFormat::Json => serde_json::to_string(value)
+            .map(Message::text)
+            .map_err(WireError::Json),
+        #[cfg(feature = "bincode")]
+        WireFormat::Bincode => bincode::serialize(value)
+            .map(Message::binary)
+            .map_err(WireError::Bincode),
+    }
+}
+
+/// Decodes a message in `format`.
+#[cfg(feature = "serde")]
+pub fn deserialize<T: DeserializeOwned>(format: WireFormat, msg: &Message) -> Result<T, WireError> {
+    match (format, msg) {
+        (WireFormat::Json, Message::Text(text)) => {
+            serde_json::from_str(text).map_err(WireError::Json)
+        }
+        #[cfg(feature = "bincode")]
+        (WireFormat::Bincode, Message::Binary(data)) => {
+            bincode::deserialize(data).map_err(WireError::Bincode)
+        }
+        _ => Err(WireError::WrongFrame),
+    }
+}
+
+/// A value encoded in every format, so each recipient can be sent the one it
+/// reads.
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone)]
+pub struct Encoded {
+    json: Message,
+    #[cfg(feature = "bincode")]
+    bincode: Message,
+}
+
+#[cfg(feature = "serde")]
+impl Encoded {
+    pub fn new<T: Serialize>(value: &T) -> Result<Encoded, WireError> {
+        Ok(Encoded {
+            json: serialize(WireFormat::Json, value)?,
+            #[cfg(feature = "bincode")]
+            bincode: serialize(WireFormat::Bincode, value)?,
+        })
+    }
+
+    /// The message for a client reading `format`.
+    pub fn get(&self, format: WireFormat) -> &Message {
+        match format {
+            WireFormat::Json => &self.json,
+            #[cfg(feature = "bincode")]
+            WireFormat::Bincode => &self.bincode,
+        }
+    }
+}
+
+/// Sends `value` to every client, encoded in each one's format. Values that
+/// can't be encoded are logged and not sent.
+#[cfg(feature = "serde")]
+pub fn broadcast_event<T: Serialize>(outbox: &WsOutbox, value: &T) {
+    match Encoded::new(value) {
+        Ok(event) => outbox.send(OutboundMessage::BroadcastEvent(event)),
+        Err(e) => warn!("Not broadcasting event: {}", e),
+    }
+}
+
+/// Sends `value` to a single client, encoded in its format, like
+/// `broadcast_event`.
+#[cfg(feature = "serde")]
+pub fn send_event_to<T: Serialize>(outbox: &WsOutbox, id: ConnectionId, value: &T) {
+    match Encoded::new(value) {
+        Ok(event) => outbox.send(OutboundMessage::EventTo(id, event)),
+        Err(e) => warn!("Not sending event to {}: {}", id, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_are_read_from_the_query() {
+        assert_eq!(WireFormat::from_query(None), Ok(None));
+        assert_eq!(
+            WireFormat::from_query(Some("room=den&format=json")),
+            Ok(Some(WireFormat::Json))
+        );
+        assert_eq!(
+            WireFormat::from_query(Some("format=xml")),
+            Err("xml".to_string())
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    mod round_trips {
+        use serde::{Deserialize, Serialize};
+
+        use super::*;
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Move {
+            dx: f32,
+            dy: f32,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct State {
+            tick: u64,
+            players: Vec<(u64, f32, f32)>,
+        }
+
+        fn formats() -> Vec<WireFormat> {
+            vec![
+                WireFormat::Json,
+                #[cfg(feature = "bincode")]
+                WireFormat::Bincode,
+            ]
+        }
+
+        #[test]
+        fn game_structs_survive_every_format() {
+            let state = State {
+                tick: 42,
+                players: vec![(1, 0.5, -3.0), (2, 10.0, 7.25)],
+            };
+            for format in formats() {
+                let moved = Move { dx: 1.5, dy: -2.0 };
+                let msg = serialize(format, &moved).unwrap();
+                assert_eq!(deserialize::<Move>(format, &msg).unwrap(), moved);
+                let msg = serialize(format, &state).unwrap();
+                assert_eq!(deserialize::<State>(format, &msg).unwrap(), state);
+            }
+        }
+
+        #[test]
+        fn each_format_uses_its_own_frames() {
+            let msg = serialize(WireFormat::Json, &Move { dx: 1.0, dy: 0.0 }).unwrap();
+            assert!(msg.is_text());
+            #[cfg(feature = "bincode")]
+            {
+                assert!(matches!(
+                    deserialize::<Move>(WireFormat::Bincode, &msg),
+                    Err(WireError::WrongFrame)
+                ));
+                let msg = serialize(WireFormat::Bincode, &Move { dx: 1.0, dy: 0.0 }).unwrap();
+                assert!(msg.is_binary());
+                assert!(matches!(
+                    deserialize::<Move>(WireFormat::Json, &msg),
+                    Err(WireError::WrongFrame)
+                ));
+            }
+        }
+    }
+}
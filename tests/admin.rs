@@ -0,0 +1,55 @@
+//! Operators streaming the server's logs from `/admin/logs`.
+
+mod common;
+
+use async_tungstenite::tungstenite::protocol::Message;
+use bevy::utils::tracing::subscriber;
+use tracing_subscriber::{prelude::*, Registry};
+use ws_async::{client, AdminLogs, LogFeed, ServerConfig, TokenValidator, UserId};
+
+use common::block_on;
+
+fn config(feed: &LogFeed) -> ServerConfig {
+    ServerConfig {
+        admin_logs: Some(AdminLogs::new(
+            feed.clone(),
+            TokenValidator::new(|token| (token == "ops").then(|| UserId("ops".to_string()))),
+        )),
+        ..common::config()
+    }
+}
+
+#[test]
+fn admin_clients_need_their_token() {
+    block_on(async {
+        let server = common::start(config(&LogFeed::new())).await;
+        let request = common::request(&server, "/admin/logs");
+        assert_eq!(common::refusal(&server, request).await, 401);
+        let request = common::request(&server, "/admin/logs?token=guess");
+        assert_eq!(common::refusal(&server, request).await, 401);
+    });
+}
+
+#[test]
+fn admin_clients_receive_what_is_logged_from_then_on() {
+    let feed = LogFeed::new();
+    subscriber::set_global_default(Registry::default().with(feed.layer())).unwrap();
+
+    block_on(async {
+        let server = common::start(config(&feed)).await;
+        let url = format!("{}/admin/logs?token=ops", common::url(&server));
+        let (_admin_sink, mut admin) = client::connect(&url).await.unwrap();
+        common::eventually(|| (feed.subscribers() == 1).then_some(())).await;
+
+        let (id, mut sink, _source) = common::join(&server).await;
+        common::nick(&server, id, &mut sink, "alice").await;
+        let renamed = format!("{} is now known as alice", id);
+        loop {
+            match common::next(&mut admin).await {
+                Message::Text(line) if line.contains(&renamed) => break,
+                Message::Text(_) => continue,
+                other => panic!("Expected a log line, got {:?}", other),
+            }
+        }
+    });
+}
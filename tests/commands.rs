@@ -0,0 +1,97 @@
+//! Commands clients send, and what the server answers.
+
+mod common;
+
+#[cfg(feature = "serde")]
+use async_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use async_tungstenite::tungstenite::protocol::Message;
+use futures::prelude::*;
+use ws_async::protocol;
+#[cfg(feature = "serde")]
+use ws_async::{DisconnectReason, ServerConfig};
+
+use common::block_on;
+
+#[cfg(feature = "serde")]
+const MALFORMED: &str = r#"{"type":"join"#;
+#[cfg(not(feature = "serde"))]
+const MALFORMED: &str = "/move 1";
+
+#[test]
+fn malformed_commands_are_answered_with_an_error() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let (id, mut sink, mut source) = common::join(&server).await;
+        let (_, _, mut listener) = common::join(&server).await;
+
+        sink.send(Message::text(MALFORMED)).await.unwrap();
+        match common::next(&mut source).await {
+            Message::Text(text) => assert!(text.starts_with("Error: "), "{}", text),
+            other => panic!("Expected an error, got {:?}", other),
+        }
+        // The connection carries on, and the room heard nothing of it.
+        sink.send(common::say("still here")).await.unwrap();
+        assert_eq!(
+            common::next(&mut listener).await,
+            protocol::chat_message(1, &id.to_string(), "still here")
+        );
+    });
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn clients_declaring_an_outdated_version_are_closed() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            min_protocol_version: 2,
+            ..common::config()
+        })
+        .await;
+        let (id, mut sink, mut source) = common::join(&server).await;
+
+        sink.send(Message::text(r#"{"type":"ping","version":1}"#))
+            .await
+            .unwrap();
+        match common::next(&mut source).await {
+            Message::Close(Some(frame)) => {
+                assert_eq!(frame.code, CloseCode::Protocol);
+                assert!(frame.reason.contains("2 or later"), "{}", frame.reason);
+            }
+            other => panic!("Expected a Close, got {:?}", other),
+        }
+        common::disconnected(&mut source).await;
+        assert_eq!(
+            common::closed(&server, id).await,
+            DisconnectReason::OutdatedProtocol {
+                version: 1,
+                minimum: 2
+            }
+        );
+    });
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn fields_from_newer_versions_are_ignored() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            min_protocol_version: 1,
+            ..common::config()
+        })
+        .await;
+        let (id, mut sink, _source) = common::join(&server).await;
+        let (_, _, mut listener) = common::join(&server).await;
+
+        sink.send(Message::text(
+            r#"{"type":"chat","text":"from the future","version":3,"colour":"red"}"#,
+        ))
+        .await
+        .unwrap();
+        assert_eq!(
+            common::next(&mut listener).await,
+            protocol::chat_message(1, &id.to_string(), "from the future")
+        );
+        let info = server.directory.get(&id).unwrap();
+        assert_eq!(info.protocol_version, Some(3));
+    });
+}
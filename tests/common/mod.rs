@@ -0,0 +1,241 @@
+//! Helpers shared by the integration tests: a server on a free loopback
+//! port, and clients talking to it.
+
+#![allow(dead_code)]
+
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use async_tungstenite::{
+    tungstenite::{
+        client::IntoClientRequest,
+        error::Error as WsError,
+        handshake::client::{Request, Response},
+        protocol::Message,
+    },
+    WebSocketStream,
+};
+use futures::prelude::*;
+use ws_async::{
+    channel::BridgeChannel,
+    client::{self, ClientSink, ClientSource},
+    protocol::ClientCommand,
+    runtime, ConnectionEvent, ConnectionId, DisconnectReason, Server, ServerConfig,
+};
+
+/// How long a test waits for something that should happen.
+pub const PATIENCE: Duration = Duration::from_secs(5);
+
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    runtime::block_on(future)
+}
+
+/// The default settings, without the member lists that would otherwise
+/// arrive in between the messages a test is waiting for.
+pub fn config() -> ServerConfig {
+    ServerConfig {
+        member_list_delay: None,
+        ..ServerConfig::default()
+    }
+}
+
+/// Starts a server with `config` on a free loopback port.
+pub async fn start(config: ServerConfig) -> Server {
+    Server::start(&["127.0.0.1:0".to_string()], config)
+        .await
+        .expect("Couldn't start the server")
+}
+
+/// The `ws://` URL of the server's first listener.
+pub fn url(server: &Server) -> String {
+    format!("ws://{}", server.local_addrs()[0])
+}
+
+/// Connects a client and waits for the server to register it, returning
+/// its id along with both halves of the connection.
+pub async fn join(server: &Server) -> (ConnectionId, ClientSink, ClientSource) {
+    let (sink, source) = client::connect(&url(server))
+        .await
+        .expect("Couldn't connect");
+    (opened(server).await, sink, source)
+}
+
+/// Waits for the server to report the next new connection.
+pub async fn opened(server: &Server) -> ConnectionId {
+    eventually(|| match BridgeChannel::try_recv(&server.connections) {
+        Some(ConnectionEvent::Connected { id, .. }) => Some(id),
+        _ => None,
+    })
+    .await
+}
+
+/// Waits for the server to report that `id` disconnected, returning why.
+/// Other connection events are skipped.
+pub async fn closed(server: &Server, id: ConnectionId) -> DisconnectReason {
+    eventually(|| match BridgeChannel::try_recv(&server.connections) {
+        Some(ConnectionEvent::Disconnected {
+            id: closed, reason, ..
+        }) if closed == id => Some(reason),
+        _ => None,
+    })
+    .await
+}
+
+/// Calls `check` until it returns something, panicking after `PATIENCE`.
+pub async fn eventually<T>(mut check: impl FnMut() -> Option<T>) -> T {
+    let started = Instant::now();
+    loop {
+        if let Some(value) = check() {
+            return value;
+        }
+        assert!(started.elapsed() < PATIENCE, "Gave up waiting");
+        runtime::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// The next message from the server that isn't a heartbeat. Panics if
+/// none arrives within `PATIENCE`.
+pub async fn next<S>(source: &mut S) -> Message
+where
+    S: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    loop {
+        let msg = runtime::timeout(PATIENCE, source.next())
+            .await
+            .expect("Nothing received in time")
+            .expect("Connection closed")
+            .expect("Connection failed");
+        if !matches!(msg, Message::Ping(_) | Message::Pong(_)) {
+            return msg;
+        }
+    }
+}
+
+/// `command` as a client sends it: a JSON object with the `serde` feature,
+/// a slash command without.
+#[cfg(feature = "serde")]
+pub fn command(command: ClientCommand) -> Message {
+    Message::text(serde_json::to_string(&command).unwrap())
+}
+
+/// `command` as a client sends it: a JSON object with the `serde` feature,
+/// a slash command without.
+#[cfg(not(feature = "serde"))]
+pub fn command(command: ClientCommand) -> Message {
+    Message::text(match command {
+        ClientCommand::Join { room } => format!("/join {}", room),
+        ClientCommand::Nick { name } => format!("/nick {}", name),
+        ClientCommand::Chat { text, .. } => text,
+        ClientCommand::Msg { to, text, .. } => format!("/msg {} {}", to, text),
+        ClientCommand::Ping => "/ping".to_string(),
+        ClientCommand::Rooms => "/rooms".to_string(),
+        ClientCommand::Auth { token } => format!("/auth {}", token),
+        ClientCommand::Move { dx, dy } => format!("/move {} {}", dx, dy),
+        ClientCommand::Custom { name, args } => format!("/{} {}", name, args),
+    })
+}
+
+/// A chat message saying `text`.
+pub fn say(text: &str) -> Message {
+    command(ClientCommand::Chat {
+        text: text.to_string(),
+        id: None,
+    })
+}
+
+/// Registers `name` for the client `id` and waits for the server to take it.
+pub async fn nick(server: &Server, id: ConnectionId, sink: &mut ClientSink, name: &str) {
+    sink.send(command(ClientCommand::Nick {
+        name: name.to_string(),
+    }))
+    .await
+    .unwrap();
+    eventually(|| {
+        let info = server.directory.get(&id)?;
+        (info.name.as_deref() == Some(name)).then_some(())
+    })
+    .await
+}
+
+/// Reads until the server ends the connection, with or without a Close
+/// frame. Panics if it is still open after `PATIENCE`.
+pub async fn disconnected<S>(source: &mut S)
+where
+    S: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    let ended = async {
+        while let Some(Ok(msg)) = source.next().await {
+            if msg.is_close() {
+                break;
+            }
+        }
+    };
+    runtime::timeout(PATIENCE, ended)
+        .await
+        .expect("The connection stayed open")
+}
+
+/// Panics if anything but a heartbeat arrives within `within`.
+pub async fn quiet<S>(source: &mut S, within: Duration)
+where
+    S: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    let received = async {
+        loop {
+            match source.next().await {
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                other => return other,
+            }
+        }
+    };
+    if let Some(msg) = runtime::timeout(within, received).await {
+        panic!("Expected nothing, got {:?}", msg);
+    }
+}
+
+/// A handshake request for `path` on the server, for tests to add headers
+/// to.
+pub fn request(server: &Server, path: &str) -> Request {
+    format!("{}{}", url(server), path)
+        .into_client_request()
+        .unwrap()
+}
+
+/// Connects with `request`, returning the server's answer whether or not
+/// it accepted the handshake.
+pub async fn handshake(
+    server: &Server,
+    request: Request,
+) -> Result<(WebSocketStream<runtime::TcpStream>, Response), WsError> {
+    let stream = runtime::connect(&server.local_addrs()[0].to_string())
+        .await
+        .unwrap();
+    async_tungstenite::client_async(request, stream).await
+}
+
+/// The status the server refused `request` with. Panics if it was
+/// accepted.
+pub async fn refusal(server: &Server, request: Request) -> u16 {
+    match handshake(server, request).await {
+        Err(WsError::Http(response)) => response.status().as_u16(),
+        Err(e) => panic!("Expected an HTTP error, got {}", e),
+        Ok(_) => panic!("The handshake was accepted"),
+    }
+}
+
+/// The whole answer to a plain HTTP `GET` for `path`, without an upgrade.
+pub async fn http_get(server: &Server, path: &str) -> String {
+    let mut raw = runtime::connect(&server.local_addrs()[0].to_string())
+        .await
+        .unwrap();
+    let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+    raw.write_all(request.as_bytes()).await.unwrap();
+    let mut answer = String::new();
+    runtime::timeout(PATIENCE, raw.read_to_string(&mut answer))
+        .await
+        .expect("The server kept the connection open")
+        .unwrap();
+    answer
+}
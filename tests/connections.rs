@@ -0,0 +1,297 @@
+//! Connections as the server keeps track of them.
+
+mod common;
+
+use std::time::Duration;
+
+use async_tungstenite::tungstenite::protocol::Message;
+use bevy::{
+    app::{Events, ManualEventReader},
+    prelude::*,
+};
+use futures::{future, io::AsyncWriteExt, pin_mut, prelude::*};
+use ws_async::{
+    client, directory::snapshot_connections, protocol::ClientCommand, sync_connections,
+    ConnectionClosed, ConnectionEntities, ConnectionLimits, ConnectionOpened, Connections,
+    DisconnectReason, EntityMap, HeartbeatConfig, MockClock, PlayerJoined, PlayerLeft,
+    PlayerLifecyclePlugin, ServerConfig, SharedClock, TokenValidator, UserId,
+};
+
+use common::block_on;
+
+#[test]
+fn port_zero_is_resolved_to_the_bound_port() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let addr = server.local_addrs()[0];
+        assert!(addr.ip().is_loopback());
+        assert_ne!(addr.port(), 0);
+        let _ = common::join(&server).await;
+    });
+}
+
+#[test]
+fn reconnecting_clients_get_a_new_id() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let (first, mut sink, _source) = common::join(&server).await;
+        let addr = server.directory.get(&first).unwrap().addr;
+        sink.close().await.unwrap();
+        common::eventually(|| server.directory.get(&first).is_none().then_some(())).await;
+
+        let (second, _sink, _source) = common::join(&server).await;
+        let second_addr = server.directory.get(&second).unwrap().addr;
+        assert_eq!(addr.ip(), second_addr.ip());
+        assert_ne!(first, second);
+    });
+}
+
+#[test]
+fn the_connections_resource_shows_named_clients() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let mut builder = App::build();
+        builder
+            .insert_resource(server.directory.clone())
+            .init_resource::<Connections>()
+            .add_system(snapshot_connections.system());
+        let mut app = builder.app;
+        app.update();
+        assert!(app.world.get_resource::<Connections>().unwrap().is_empty());
+
+        let (id, mut sink, _source) = common::join(&server).await;
+        common::nick(&server, id, &mut sink, "alice").await;
+        app.update();
+        let connections = app.world.get_resource::<Connections>().unwrap();
+        assert_eq!(connections.len(), 1);
+        let info = connections.get(id).unwrap();
+        assert_eq!(info.name.as_deref(), Some("alice"));
+        assert_eq!(info.room, "lobby");
+    });
+}
+
+#[test]
+fn heartbeats_measure_latency() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            heartbeat: HeartbeatConfig {
+                interval: Duration::from_millis(50),
+                timeout: Duration::from_secs(10),
+            },
+            ..common::config()
+        })
+        .await;
+        // Reading is what answers the Pings.
+        let (id, _sink, mut source) = common::join(&server).await;
+        let reading = async { while source.next().await.is_some() {} };
+        let measured = common::eventually(|| server.directory.get(&id)?.latency_ms);
+        let latency = match future::select(reading.boxed(), measured.boxed()).await {
+            future::Either::Right((latency, _)) => latency,
+            future::Either::Left(_) => panic!("The connection closed"),
+        };
+        assert!((0.0..1000.0).contains(&latency), "{} ms", latency);
+    });
+}
+
+#[test]
+fn a_connection_failing_midway_is_cleaned_up() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let (mut ws, _) = common::handshake(&server, common::request(&server, "/"))
+            .await
+            .unwrap();
+        let id = common::opened(&server).await;
+        ws.send(common::command(ClientCommand::Nick {
+            name: "alice".to_string(),
+        }))
+        .await
+        .unwrap();
+        ws.send(common::command(ClientCommand::Join {
+            room: "den".to_string(),
+        }))
+        .await
+        .unwrap();
+        common::eventually(|| {
+            let info = server.directory.get(&id)?;
+            (info.name.as_deref() == Some("alice") && info.room == "den").then_some(())
+        })
+        .await;
+
+        // Not a WebSocket frame the server can read.
+        ws.get_mut().write_all(&[0xff; 16]).await.unwrap();
+        let reason = common::closed(&server, id).await;
+        assert!(
+            matches!(reason, DisconnectReason::Protocol(_)),
+            "{:?}",
+            reason
+        );
+        assert!(server.directory.get(&id).is_none());
+        assert!(server.rooms().get("den").is_none());
+
+        // The name was given up along with everything else.
+        let (other, mut sink, _source) = common::join(&server).await;
+        common::nick(&server, other, &mut sink, "alice").await;
+    });
+}
+
+#[test]
+fn traffic_counts_the_bytes_each_way() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let (id, mut sink, mut source) = common::join(&server).await;
+        let traffic = server.directory.get(&id).unwrap().traffic.clone();
+
+        sink.send(Message::binary(vec![7; 1000])).await.unwrap();
+        common::eventually(|| (traffic.bytes_in() == 1000).then_some(())).await;
+        server
+            .send_to(id, Message::text("x".repeat(300)))
+            .await
+            .unwrap();
+        common::next(&mut source).await;
+        // Counted once the write has finished, which the client may see first.
+        common::eventually(|| (traffic.bytes_out() == 300).then_some(())).await;
+        assert_eq!(traffic.total(), 1300);
+    });
+}
+
+#[test]
+fn silent_clients_time_out_as_the_clock_moves_on() {
+    block_on(async {
+        let clock = MockClock::new();
+        let server = common::start(ServerConfig {
+            heartbeat: HeartbeatConfig {
+                interval: Duration::from_secs(10),
+                timeout: Duration::from_secs(30),
+            },
+            clock: SharedClock::new(clock.clone()),
+            ..common::config()
+        })
+        .await;
+        // Not reading, so the Pings go unanswered.
+        let (id, _sink, _source) = common::join(&server).await;
+        let (other, _other_sink, mut other_source) = common::join(&server).await;
+        let answering = async { while other_source.next().await.is_some() {} };
+
+        let timed_out = common::eventually(|| {
+            clock.advance(Duration::from_secs(10));
+            server.directory.get(&id).is_none().then_some(())
+        });
+        pin_mut!(answering, timed_out);
+        if let future::Either::Left(_) = future::select(answering, timed_out).await {
+            panic!("The answering client was disconnected");
+        }
+        assert_eq!(common::closed(&server, id).await, DisconnectReason::Timeout);
+        assert!(server.directory.get(&other).is_some());
+    });
+}
+
+#[test]
+fn new_clients_get_the_motd_once() {
+    block_on(async {
+        let motd = Message::text("Welcome!");
+        let server = common::start(ServerConfig {
+            motd: Some(motd.clone()),
+            ..common::config()
+        })
+        .await;
+        let (_, _, mut first) = common::join(&server).await;
+        assert_eq!(common::next(&mut first).await, motd);
+
+        let (id, mut sink, mut second) = common::join(&server).await;
+        assert_eq!(common::next(&mut second).await, motd);
+        common::quiet(&mut first, Duration::from_millis(200)).await;
+
+        // Nothing a client does later sends it again.
+        sink.send(common::command(ClientCommand::Join {
+            room: "arena".to_string(),
+        }))
+        .await
+        .unwrap();
+        common::eventually(|| (server.rooms().get("arena") == Some(&[id][..])).then_some(())).await;
+        common::quiet(&mut second, Duration::from_millis(200)).await;
+    });
+}
+
+#[test]
+fn the_motd_can_wait_for_authentication() {
+    block_on(async {
+        let motd = Message::text("Welcome back!");
+        let server = common::start(ServerConfig {
+            motd: Some(motd.clone()),
+            motd_after_auth: true,
+            auth: Some(TokenValidator::new(|token| {
+                (token == "secret").then(|| UserId("admin".to_string()))
+            })),
+            // Lets clients in without a token.
+            anonymous_limits: Some(ConnectionLimits::default()),
+            ..common::config()
+        })
+        .await;
+        let (_, mut sink, mut source) = common::join(&server).await;
+        common::quiet(&mut source, Duration::from_millis(200)).await;
+
+        sink.send(common::command(ClientCommand::Auth {
+            token: "secret".to_string(),
+        }))
+        .await
+        .unwrap();
+        assert_eq!(common::next(&mut source).await, motd);
+    });
+}
+
+#[test]
+fn players_join_with_the_name_they_registered() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let mut builder = App::build();
+        builder
+            .add_event::<ConnectionOpened>()
+            .add_event::<ConnectionClosed>()
+            .insert_resource(server.connections.clone())
+            .insert_resource(server.directory.clone())
+            .insert_resource(EntityMap::default())
+            .init_resource::<ConnectionEntities>()
+            .init_resource::<Connections>()
+            .add_system(sync_connections.system())
+            .add_system(snapshot_connections.system())
+            .add_plugin(PlayerLifecyclePlugin);
+        let mut app = builder.app;
+        let mut joined = ManualEventReader::<PlayerJoined>::default();
+        let mut left = ManualEventReader::<PlayerLeft>::default();
+
+        // The app takes the connection events, so the id comes from it.
+        let (mut sink, mut source) = client::connect(&common::url(&server)).await.unwrap();
+        let id = common::eventually(|| {
+            app.update();
+            let entities = app.world.get_resource::<ConnectionEntities>().unwrap();
+            entities.0.keys().next().copied()
+        })
+        .await;
+        app.update();
+        let events = app.world.get_resource::<Events<PlayerJoined>>().unwrap();
+        assert_eq!(joined.iter(events).count(), 0, "Joined without a name");
+
+        common::nick(&server, id, &mut sink, "alice").await;
+        let player = common::eventually(|| {
+            app.update();
+            let events = app.world.get_resource::<Events<PlayerJoined>>().unwrap();
+            joined.iter(events).next().cloned()
+        })
+        .await;
+        assert_eq!(player.id, id);
+        assert_eq!(player.name, "alice");
+        assert_eq!(player.room, "lobby");
+        assert_eq!(player.user, None);
+
+        sink.close().await.unwrap();
+        common::disconnected(&mut source).await;
+        let departure = common::eventually(|| {
+            app.update();
+            let events = app.world.get_resource::<Events<PlayerLeft>>().unwrap();
+            left.iter(events).next().cloned()
+        })
+        .await;
+        assert_eq!(departure.id, id);
+        assert_eq!(departure.reason, DisconnectReason::Normal);
+    });
+}
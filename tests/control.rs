@@ -0,0 +1,103 @@
+//! Control frames from clients, which are never relayed.
+
+mod common;
+
+use std::time::Duration;
+
+use async_tungstenite::tungstenite::{protocol::Message, Error as WsError};
+use futures::prelude::*;
+use ws_async::{
+    channel, runtime, BridgeChannel, DisconnectReason, ServerConfig, WsMessageReceived,
+};
+
+use common::block_on;
+
+const QUIET: Duration = Duration::from_millis(200);
+
+/// Reads until the Pong answering a Ping, returning its payload.
+async fn pong<S>(source: &mut S) -> Vec<u8>
+where
+    S: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    let answered = async {
+        while let Some(Ok(msg)) = source.next().await {
+            if let Message::Pong(payload) = msg {
+                return payload;
+            }
+        }
+        panic!("The connection closed");
+    };
+    runtime::timeout(common::PATIENCE, answered)
+        .await
+        .expect("No Pong arrived")
+}
+
+#[test]
+fn pings_are_answered_and_not_relayed() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let (_, mut sink, mut source) = common::join(&server).await;
+        let (_, _, mut listener) = common::join(&server).await;
+
+        sink.send(Message::Ping(b"are you there".to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(pong(&mut source).await, b"are you there");
+        common::quiet(&mut listener, QUIET).await;
+        assert!(BridgeChannel::try_recv(&server.messages).is_none());
+    });
+}
+
+#[test]
+fn pings_reach_the_app_when_forwarded() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            forward_pings: true,
+            ..common::config()
+        })
+        .await;
+        let (id, mut sink, mut source) = common::join(&server).await;
+
+        sink.send(Message::Ping(b"hi".to_vec())).await.unwrap();
+        assert_eq!(pong(&mut source).await, b"hi");
+        let received = common::eventually(|| BridgeChannel::try_recv(&server.messages)).await;
+        assert_eq!(received.id, id);
+        assert_eq!(received.msg, Message::Ping(b"hi".to_vec()));
+    });
+}
+
+#[test]
+fn unsolicited_pongs_are_dropped() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let (_, mut sink, _source) = common::join(&server).await;
+        let (_, _, mut listener) = common::join(&server).await;
+
+        sink.send(Message::Pong(b"unasked".to_vec())).await.unwrap();
+        common::quiet(&mut listener, QUIET).await;
+        // The connection is still open.
+        sink.send(common::say("still here")).await.unwrap();
+        assert!(common::next(&mut listener).await.is_text());
+        assert!(channel::drain(&server.messages)
+            .all(|received: WsMessageReceived| !received.msg.is_pong()));
+    });
+}
+
+#[test]
+fn a_close_frame_ends_only_its_sender() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let (id, mut sink, mut source) = common::join(&server).await;
+        let (other, mut other_sink, _other_source) = common::join(&server).await;
+        let (_, _, mut listener) = common::join(&server).await;
+
+        sink.send(Message::Close(None)).await.unwrap();
+        common::disconnected(&mut source).await;
+        assert_eq!(common::closed(&server, id).await, DisconnectReason::Normal);
+        common::quiet(&mut listener, QUIET).await;
+
+        other_sink.send(common::say("carry on")).await.unwrap();
+        assert!(common::next(&mut listener).await.is_text());
+        assert!(server.directory.get(&other).is_some());
+    });
+}
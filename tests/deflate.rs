@@ -0,0 +1,118 @@
+//! permessage-deflate between the server and clients that do and don't
+//! compress.
+
+#![cfg(feature = "deflate")]
+
+mod common;
+
+use async_tungstenite::tungstenite::{
+    client::IntoClientRequest, handshake::client::Request, http::HeaderValue,
+};
+use futures::prelude::*;
+use miniz_oxide::{deflate::compress_to_vec, inflate::decompress_to_vec};
+use ws_async::{protocol, runtime, Server, ServerConfig};
+
+use common::block_on;
+
+const OFFER: &str = "permessage-deflate; client_max_window_bits";
+
+/// A handshake request offering permessage-deflate.
+fn offering(server: &Server) -> Request {
+    let mut request = common::url(server).into_client_request().unwrap();
+    request
+        .headers_mut()
+        .insert("Sec-WebSocket-Extensions", HeaderValue::from_static(OFFER));
+    request
+}
+
+/// A compressed text frame as a client writes it, with a mask of zeroes.
+fn compressed_frame(text: &str) -> Vec<u8> {
+    let mut payload = compress_to_vec(text.as_bytes(), 6);
+    payload.push(0x00);
+    let mut frame = vec![0x80 | 0x40 | 0x1];
+    match payload.len() {
+        len if len < 126 => frame.push(0x80 | len as u8),
+        len => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(&[0; 4]);
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+/// The first byte and payload of the next data frame the server writes to
+/// `stream`, skipping Pings.
+async fn server_frame(stream: &mut runtime::TcpStream) -> (u8, Vec<u8>) {
+    loop {
+        let mut head = [0; 2];
+        stream.read_exact(&mut head).await.unwrap();
+        let len = match head[1] & 0x7f {
+            126 => {
+                let mut len = [0; 2];
+                stream.read_exact(&mut len).await.unwrap();
+                u16::from_be_bytes(len) as usize
+            }
+            127 => {
+                let mut len = [0; 8];
+                stream.read_exact(&mut len).await.unwrap();
+                u64::from_be_bytes(len) as usize
+            }
+            len => len as usize,
+        };
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).await.unwrap();
+        if head[0] & 0x0f != 0x9 {
+            return (head[0], payload);
+        }
+    }
+}
+
+#[test]
+fn compressed_and_plain_clients_share_a_broadcast() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            compression: true,
+            ..common::config()
+        })
+        .await;
+        let (mut compressed, response) =
+            common::handshake(&server, offering(&server)).await.unwrap();
+        assert_eq!(
+            response.headers()["Sec-WebSocket-Extensions"],
+            "permessage-deflate; server_no_context_takeover; client_no_context_takeover"
+        );
+        let compressed_id = common::opened(&server).await;
+        let (plain_id, mut plain_sink, mut plain) = common::join(&server).await;
+        let (_, _, mut bystander) = common::join(&server).await;
+
+        // What the compressing client says reaches the others inflated.
+        let hello = common::say("hello").into_text().unwrap();
+        let frame = compressed_frame(&hello);
+        compressed.get_mut().write_all(&frame).await.unwrap();
+        let expected = protocol::chat_message(1, &compressed_id.to_string(), "hello");
+        assert_eq!(common::next(&mut plain).await, expected);
+        assert_eq!(common::next(&mut bystander).await, expected);
+
+        // One broadcast goes out compressed to it and plain to the rest.
+        let long = "na".repeat(500);
+        plain_sink.send(common::say(&long)).await.unwrap();
+        let expected = protocol::chat_message(2, &plain_id.to_string(), &long);
+        assert_eq!(common::next(&mut bystander).await, expected);
+        let (first, payload) = server_frame(compressed.get_mut()).await;
+        assert_eq!(first & 0x40, 0x40, "the message isn't compressed");
+        assert!(payload.len() < expected.len());
+        let inflated = String::from_utf8(decompress_to_vec(&payload).unwrap()).unwrap();
+        assert_eq!(inflated, expected.into_text().unwrap());
+    });
+}
+
+#[test]
+fn the_offer_is_declined_unless_compression_is_on() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let (_, response) = common::handshake(&server, offering(&server)).await.unwrap();
+        assert!(response.headers().get("Sec-WebSocket-Extensions").is_none());
+    });
+}
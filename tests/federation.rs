@@ -0,0 +1,44 @@
+//! Servers relaying to each other through a `ClientPool`.
+
+mod common;
+
+use std::time::Duration;
+
+use futures::prelude::*;
+use ws_async::{
+    channel, client::ClientPool, protocol, runtime, BridgeChannel, ConnectionEvent,
+    HeartbeatConfig, WsMessageReceived,
+};
+
+use common::block_on;
+
+#[test]
+fn messages_are_federated_over_one_pooled_connection() {
+    block_on(async {
+        let here = common::start(common::config()).await;
+        let there = common::start(common::config()).await;
+        let (_, mut sink, _source) = common::join(&here).await;
+        let (_, _, mut remote) = common::join(&there).await;
+        let pool = ClientPool::new(HeartbeatConfig::default());
+
+        for text in ["first", "second"].iter() {
+            sink.send(common::say(text)).await.unwrap();
+            let WsMessageReceived { msg, .. } =
+                common::eventually(|| BridgeChannel::try_recv(&here.messages)).await;
+            pool.send(&common::url(&there), msg);
+        }
+        let relay = common::opened(&there).await;
+        assert!(pool.is_connected(&common::url(&there)));
+        for (seq, text) in (1..).zip(["first", "second"].iter()) {
+            assert_eq!(
+                common::next(&mut remote).await,
+                protocol::chat_message(seq, &relay.to_string(), text)
+            );
+        }
+
+        // Both went over the same connection.
+        runtime::sleep(Duration::from_millis(200)).await;
+        assert!(!channel::drain(&there.connections)
+            .any(|event| matches!(event, ConnectionEvent::Connected { .. })));
+    });
+}
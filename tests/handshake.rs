@@ -0,0 +1,268 @@
+//! Handshakes the server refuses, and clients that never get as far as
+//! one.
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use async_tungstenite::tungstenite::{handshake::client::Request, http::StatusCode};
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use ws_async::{
+    channel::BridgeChannel, runtime, AcceptCallback, ConnectionEvent, Server, ServerConfig,
+    TokenValidator, UserId,
+};
+
+use common::block_on;
+
+#[test]
+fn garbage_instead_of_a_handshake_is_survived() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let addr = server.local_addrs()[0].to_string();
+
+        // Half a TLS ClientHello, which the server waits in vain to see the
+        // end of.
+        let mut raw = runtime::connect(&addr).await.unwrap();
+        raw.write_all(b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc")
+            .await
+            .unwrap();
+        drop(raw);
+
+        // Something that ends like a request but isn't one is answered as
+        // a plain HTTP request would be.
+        let mut raw = runtime::connect(&addr).await.unwrap();
+        raw.write_all(b"\xff\xfe\r\n\r\n").await.unwrap();
+        let mut answer = Vec::new();
+        runtime::timeout(common::PATIENCE, raw.read_to_end(&mut answer))
+            .await
+            .expect("The server kept the connection open")
+            .unwrap();
+        assert!(answer.starts_with(b"HTTP/1.1 426 "));
+
+        let _ = common::join(&server).await;
+    });
+}
+
+#[test]
+fn clients_that_never_send_a_handshake_are_dropped() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            handshake_timeout: Duration::from_millis(200),
+            max_connections: 1,
+            ..common::config()
+        })
+        .await;
+        let started = Instant::now();
+        let mut raw = runtime::connect(&server.local_addrs()[0].to_string())
+            .await
+            .unwrap();
+        let mut answer = Vec::new();
+        runtime::timeout(common::PATIENCE, raw.read_to_end(&mut answer))
+            .await
+            .expect("The server kept the connection open")
+            .unwrap();
+        assert!(answer.is_empty());
+        assert!(started.elapsed() >= Duration::from_millis(200));
+
+        // The slot it held is free again.
+        let _ = common::join(&server).await;
+    });
+}
+
+#[test]
+fn health_checks_are_answered_without_an_upgrade() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let answer = common::http_get(&server, "/health").await;
+        assert!(answer.starts_with("HTTP/1.1 200 "), "{}", answer);
+        assert!(answer.contains("Content-Type: application/json\r\n"));
+        assert!(
+            answer.ends_with("\r\n\r\n{\"status\":\"ok\"}"),
+            "{}",
+            answer
+        );
+
+        let answer = common::http_get(&server, "/chat").await;
+        assert!(answer.starts_with("HTTP/1.1 426 "), "{}", answer);
+        let _ = common::join(&server).await;
+    });
+}
+
+fn from_origin(server: &Server, origin: &str) -> Request {
+    let mut request = common::request(server, "/");
+    request
+        .headers_mut()
+        .insert("Origin", origin.parse().unwrap());
+    request
+}
+
+#[test]
+fn only_allowed_origins_may_connect() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            allowed_origins: Some(vec!["https://game.example".to_string()]),
+            ..common::config()
+        })
+        .await;
+
+        let allowed = from_origin(&server, "https://game.example");
+        assert!(common::handshake(&server, allowed).await.is_ok());
+        let elsewhere = from_origin(&server, "https://evil.example");
+        assert_eq!(common::refusal(&server, elsewhere).await, 403);
+        let nowhere = common::request(&server, "/");
+        assert_eq!(common::refusal(&server, nowhere).await, 403);
+    });
+}
+
+fn offering(server: &Server, protocols: &str) -> Request {
+    let mut request = common::request(server, "/");
+    request
+        .headers_mut()
+        .insert("Sec-WebSocket-Protocol", protocols.parse().unwrap());
+    request
+}
+
+/// Waits for the connection, returning the subprotocol it was opened with.
+async fn negotiated(server: &Server) -> Option<String> {
+    common::eventually(|| match BridgeChannel::try_recv(&server.connections) {
+        Some(ConnectionEvent::Connected { subprotocol, .. }) => Some(subprotocol),
+        _ => None,
+    })
+    .await
+}
+
+fn speaking(protocols: &[&str], require_subprotocol: bool) -> ServerConfig {
+    ServerConfig {
+        subprotocols: protocols.iter().map(|p| p.to_string()).collect(),
+        require_subprotocol,
+        ..common::config()
+    }
+}
+
+#[test]
+fn a_matching_subprotocol_is_echoed_back() {
+    block_on(async {
+        let server = common::start(speaking(&["chat.v2", "chat.v1"], false)).await;
+
+        let request = offering(&server, "chat.v3, chat.v1");
+        let (_ws, response) = common::handshake(&server, request).await.unwrap();
+        assert_eq!(response.headers()["Sec-WebSocket-Protocol"], "chat.v1");
+        assert_eq!(negotiated(&server).await.as_deref(), Some("chat.v1"));
+    });
+}
+
+#[test]
+fn unknown_subprotocols_are_refused_only_when_required() {
+    block_on(async {
+        let lenient = common::start(speaking(&["chat.v1"], false)).await;
+        let request = offering(&lenient, "chat.v9");
+        let (_ws, response) = common::handshake(&lenient, request).await.unwrap();
+        assert!(!response.headers().contains_key("Sec-WebSocket-Protocol"));
+        assert_eq!(negotiated(&lenient).await, None);
+
+        let strict = common::start(speaking(&["chat.v1"], true)).await;
+        let request = offering(&strict, "chat.v9");
+        assert_eq!(common::refusal(&strict, request).await, 400);
+    });
+}
+
+#[test]
+fn clients_offering_no_subprotocol_proceed_without_one() {
+    block_on(async {
+        let lenient = common::start(speaking(&["chat.v1"], false)).await;
+        let request = common::request(&lenient, "/");
+        let (_ws, response) = common::handshake(&lenient, request).await.unwrap();
+        assert!(!response.headers().contains_key("Sec-WebSocket-Protocol"));
+        assert_eq!(negotiated(&lenient).await, None);
+
+        let strict = common::start(speaking(&["chat.v1"], true)).await;
+        let request = common::request(&strict, "/");
+        assert_eq!(common::refusal(&strict, request).await, 400);
+    });
+}
+
+#[test]
+fn the_accept_callback_can_refuse_with_its_own_answer() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            accept_callback: Some(AcceptCallback::new(|request| match request.uri().query() {
+                Some("key=open-sesame") => Ok(()),
+                _ => Err((StatusCode::UNAUTHORIZED, "Say the magic word".to_string())),
+            })),
+            ..common::config()
+        })
+        .await;
+
+        let welcome = common::request(&server, "/?key=open-sesame");
+        assert!(common::handshake(&server, welcome).await.is_ok());
+
+        let refused = common::request(&server, "/?key=please");
+        assert_eq!(common::refusal(&server, refused).await, 401);
+        // The client doesn't read the body of a refusal, so ask by hand.
+        let mut raw = runtime::connect(&server.local_addrs()[0].to_string())
+            .await
+            .unwrap();
+        raw.write_all(
+            b"GET /?key=please HTTP/1.1\r\n\
+              Host: localhost\r\n\
+              Upgrade: websocket\r\n\
+              Connection: Upgrade\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              Sec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .await
+        .unwrap();
+        let mut answer = String::new();
+        runtime::timeout(common::PATIENCE, raw.read_to_string(&mut answer))
+            .await
+            .expect("The server kept the connection open")
+            .unwrap();
+        assert!(answer.starts_with("HTTP/1.1 401 "), "{}", answer);
+        assert!(answer.ends_with("\r\n\r\nSay the magic word"), "{}", answer);
+    });
+}
+
+fn with_tokens() -> ServerConfig {
+    ServerConfig {
+        auth: Some(TokenValidator::new(|token| {
+            token
+                .strip_prefix("valid-")
+                .map(|user| UserId(user.to_string()))
+        })),
+        ..common::config()
+    }
+}
+
+#[test]
+fn valid_tokens_let_their_user_in() {
+    block_on(async {
+        let server = common::start(with_tokens()).await;
+
+        let by_query = common::request(&server, "/?token=valid-alice");
+        let _alice = common::handshake(&server, by_query).await.unwrap();
+        let id = common::opened(&server).await;
+        let user = server.directory.get(&id).unwrap().user.clone();
+        assert_eq!(user, Some(UserId("alice".to_string())));
+
+        let mut by_header = common::request(&server, "/");
+        by_header
+            .headers_mut()
+            .insert("Authorization", "Bearer valid-bob".parse().unwrap());
+        let _bob = common::handshake(&server, by_header).await.unwrap();
+        let id = common::opened(&server).await;
+        let user = server.directory.get(&id).unwrap().user.clone();
+        assert_eq!(user, Some(UserId("bob".to_string())));
+    });
+}
+
+#[test]
+fn invalid_and_missing_tokens_are_refused() {
+    block_on(async {
+        let server = common::start(with_tokens()).await;
+
+        let invalid = common::request(&server, "/?token=forged");
+        assert_eq!(common::refusal(&server, invalid).await, 401);
+        let missing = common::request(&server, "/");
+        assert_eq!(common::refusal(&server, missing).await, 401);
+    });
+}
@@ -0,0 +1,326 @@
+//! Clients turned away or disconnected for going over a limit.
+
+mod common;
+
+use async_tungstenite::tungstenite::protocol::Message;
+use std::time::Duration;
+
+use bevy::{app::Events, prelude::*};
+use futures::prelude::*;
+use ws_async::{
+    apply_config_updates, client,
+    protocol::{self, ClientCommand},
+    ConnectionLimits, DisconnectReason, FrameLimit, FrameLimitAction, MockClock, OutboundMessage,
+    RateLimitConfig, ServerConfig, SharedClock, TokenValidator, UpdateServerConfig, UserId,
+};
+
+use common::block_on;
+
+#[test]
+fn clients_beyond_max_connections_are_refused() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            max_connections: 1,
+            ..common::config()
+        })
+        .await;
+        let _first = common::join(&server).await;
+
+        let (_sink, mut source) = client::connect(&common::url(&server)).await.unwrap();
+        assert_eq!(
+            common::next(&mut source).await,
+            Message::Close(Some(DisconnectReason::ServerFull.close_frame()))
+        );
+    });
+}
+
+#[test]
+fn clients_beyond_max_per_ip_are_refused() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            max_per_ip: Some(2),
+            ..common::config()
+        })
+        .await;
+        let (first, mut first_sink, mut first_source) = common::join(&server).await;
+        let _second = common::join(&server).await;
+
+        let (_sink, mut source) = client::connect(&common::url(&server)).await.unwrap();
+        assert_eq!(
+            common::next(&mut source).await,
+            Message::Close(Some(DisconnectReason::TooManyFromAddress.close_frame()))
+        );
+
+        // Once one of them leaves there is room again.
+        first_sink.close().await.unwrap();
+        common::disconnected(&mut first_source).await;
+        common::closed(&server, first).await;
+        let _third = common::join(&server).await;
+    });
+}
+
+#[test]
+fn oversized_messages_disconnect_only_their_sender() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            max_message_size: Some(1024),
+            max_frame_size: Some(1024),
+            ..common::config()
+        })
+        .await;
+        let (_, mut sender, mut sender_source) = common::join(&server).await;
+        let (_, mut other, _other_source) = common::join(&server).await;
+        let (_, _, mut listener) = common::join(&server).await;
+
+        sender.send(Message::binary(vec![0; 2048])).await.unwrap();
+        common::disconnected(&mut sender_source).await;
+
+        other.send(Message::binary(vec![1; 16])).await.unwrap();
+        assert_eq!(
+            common::next(&mut listener).await,
+            Message::binary(vec![1; 16])
+        );
+    });
+}
+
+#[test]
+fn a_new_message_size_limit_applies_to_new_connections() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let mut builder = App::build();
+        builder
+            .add_event::<UpdateServerConfig>()
+            .insert_resource(server.config.clone())
+            .add_system(apply_config_updates.system());
+        let mut app = builder.app;
+        let (_, mut before, _before_source) = common::join(&server).await;
+
+        app.world
+            .get_resource_mut::<Events<UpdateServerConfig>>()
+            .unwrap()
+            .send(UpdateServerConfig(ServerConfig {
+                max_message_size: Some(1024),
+                max_frame_size: Some(1024),
+                ..common::config()
+            }));
+        app.update();
+        let (_, mut after, mut after_source) = common::join(&server).await;
+        let (_, _, mut listener) = common::join(&server).await;
+
+        // The connection made earlier keeps the limit it started with.
+        before.send(Message::binary(vec![0; 2048])).await.unwrap();
+        assert_eq!(
+            common::next(&mut listener).await,
+            Message::binary(vec![0; 2048])
+        );
+        after.send(Message::binary(vec![0; 2048])).await.unwrap();
+        common::disconnected(&mut after_source).await;
+    });
+}
+
+#[test]
+fn bursts_are_cut_down_to_the_rate_limit() {
+    block_on(async {
+        // The clock stands still, so the bucket never refills.
+        let server = common::start(ServerConfig {
+            rate_limit: RateLimitConfig {
+                refill_per_sec: 1.0,
+                burst: 3.0,
+            },
+            clock: SharedClock::new(MockClock::new()),
+            ..common::config()
+        })
+        .await;
+        let (_, mut sender, mut sender_source) = common::join(&server).await;
+        let (_, _, mut listener) = common::join(&server).await;
+
+        for i in 0..6u8 {
+            sender.send(Message::binary(vec![i])).await.unwrap();
+        }
+        for i in 0..3u8 {
+            assert_eq!(common::next(&mut listener).await, Message::binary(vec![i]));
+        }
+        assert_eq!(
+            common::next(&mut sender_source).await,
+            Message::text("You are sending messages too quickly, some were dropped")
+        );
+        common::quiet(&mut sender_source, Duration::from_millis(200)).await;
+        common::quiet(&mut listener, Duration::from_millis(200)).await;
+    });
+}
+
+#[test]
+fn clients_that_stop_reading_are_dropped() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            write_timeout: Duration::from_millis(200),
+            peer_buffer: 8,
+            ..common::config()
+        })
+        .await;
+        // Never read from, so the socket fills up and writes stall.
+        let (id, _sink, _source) = common::join(&server).await;
+
+        for _ in 0..64 {
+            server.send(OutboundMessage::To(id, Message::binary(vec![0; 1 << 20])));
+        }
+        assert_eq!(
+            common::closed(&server, id).await,
+            DisconnectReason::WriteTimeout
+        );
+        assert!(server.directory.get(&id).is_none());
+    });
+}
+
+#[test]
+fn clients_over_their_byte_quota_are_disconnected() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            byte_quota: Some(1000),
+            ..common::config()
+        })
+        .await;
+        let (id, mut sink, mut source) = common::join(&server).await;
+        sink.send(Message::binary(vec![0; 600])).await.unwrap();
+        sink.send(Message::binary(vec![0; 600])).await.unwrap();
+        common::disconnected(&mut source).await;
+        assert_eq!(
+            common::closed(&server, id).await,
+            DisconnectReason::QuotaExceeded
+        );
+    });
+}
+
+#[test]
+fn anonymous_clients_are_held_to_the_stricter_limit() {
+    block_on(async {
+        // The clock only moves when told to, so buckets refill on cue.
+        let clock = MockClock::new();
+        let server = common::start(ServerConfig {
+            auth: Some(TokenValidator::new(|token| {
+                token
+                    .strip_prefix("valid-")
+                    .map(|user| UserId(user.to_string()))
+            })),
+            rate_limit: RateLimitConfig {
+                refill_per_sec: 1.0,
+                burst: 5.0,
+            },
+            anonymous_limits: Some(ConnectionLimits {
+                rate_limit: RateLimitConfig {
+                    refill_per_sec: 1.0,
+                    burst: 1.0,
+                },
+                ..ConnectionLimits::default()
+            }),
+            clock: SharedClock::new(clock.clone()),
+            ..common::config()
+        })
+        .await;
+        let (anonymous, mut anonymous_sink, mut anonymous_source) = common::join(&server).await;
+        let (mut member, _) =
+            common::handshake(&server, common::request(&server, "/?token=valid-alice"))
+                .await
+                .unwrap();
+        common::opened(&server).await;
+        let (_, _, mut listener) = common::join(&server).await;
+
+        for i in 0..3u8 {
+            anonymous_sink.send(Message::binary(vec![i])).await.unwrap();
+        }
+        assert_eq!(common::next(&mut listener).await, Message::binary(vec![0]));
+        assert_eq!(
+            common::next(&mut anonymous_source).await,
+            Message::text("You are sending messages too quickly, some were dropped")
+        );
+
+        for i in 10..13u8 {
+            member.send(Message::binary(vec![i])).await.unwrap();
+        }
+        for i in 10..13u8 {
+            assert_eq!(common::next(&mut listener).await, Message::binary(vec![i]));
+        }
+
+        // Authenticating later lifts the client to the usual limit. The
+        // command itself takes a token from the empty bucket, so refill it.
+        clock.advance(Duration::from_secs(10));
+        anonymous_sink
+            .send(common::command(ClientCommand::Auth {
+                token: "valid-carol".to_string(),
+            }))
+            .await
+            .unwrap();
+        common::eventually(|| server.directory.get(&anonymous)?.user.clone()).await;
+        clock.advance(Duration::from_secs(10));
+        for i in 20..23u8 {
+            anonymous_sink.send(Message::binary(vec![i])).await.unwrap();
+        }
+        for i in 20..23u8 {
+            assert_eq!(common::next(&mut listener).await, Message::binary(vec![i]));
+        }
+    });
+}
+
+#[test]
+fn floods_of_tiny_frames_are_disconnected() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            frame_limit: Some(FrameLimit {
+                data_per_sec: 5,
+                action: FrameLimitAction::Disconnect,
+                ..FrameLimit::default()
+            }),
+            rate_limit: RateLimitConfig {
+                refill_per_sec: 1000.0,
+                burst: 1000.0,
+            },
+            ..common::config()
+        })
+        .await;
+        let (id, mut sink, mut source) = common::join(&server).await;
+        for _ in 0..20 {
+            sink.send(Message::binary(vec![0])).await.unwrap();
+        }
+        assert!(common::next(&mut source).await.is_close());
+        // Reading on answers the Close, which lets the server finish.
+        common::disconnected(&mut source).await;
+        assert_eq!(
+            common::closed(&server, id).await,
+            DisconnectReason::FrameLimitExceeded
+        );
+    });
+}
+
+#[test]
+fn heartbeats_do_not_use_up_the_frame_limit() {
+    block_on(async {
+        // The clock stands still, so every frame falls in the same second.
+        let server = common::start(ServerConfig {
+            frame_limit: Some(FrameLimit {
+                data_per_sec: 3,
+                control_per_sec: 10,
+                action: FrameLimitAction::Drop,
+            }),
+            clock: SharedClock::new(MockClock::new()),
+            ..common::config()
+        })
+        .await;
+        let (id, mut sender, _sender_source) = common::join(&server).await;
+        let (_, _, mut listener) = common::join(&server).await;
+        for _ in 0..5 {
+            sender.send(Message::Ping(Vec::new())).await.unwrap();
+        }
+        for text in ["one", "two", "three", "four"] {
+            sender.send(common::say(text)).await.unwrap();
+        }
+
+        let name = id.to_string();
+        for (seq, text) in ["one", "two", "three"].iter().enumerate() {
+            assert_eq!(
+                common::next(&mut listener).await,
+                protocol::chat_message(seq as u64 + 1, &name, text)
+            );
+        }
+        common::quiet(&mut listener, Duration::from_millis(300)).await;
+    });
+}
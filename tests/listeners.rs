@@ -0,0 +1,81 @@
+//! Servers listening on more than one address.
+
+mod common;
+
+use std::io;
+
+use ws_async::{client, Server, ServerError};
+
+use common::block_on;
+
+#[test]
+fn every_listener_accepts_clients() {
+    block_on(async {
+        let addrs = ["127.0.0.1:0".to_string(), "127.0.0.1:0".to_string()];
+        let server = Server::start(&addrs, common::config()).await.unwrap();
+        let local = server.local_addrs().to_vec();
+        assert_eq!(local.len(), 2);
+        assert_ne!(local[0], local[1]);
+
+        for addr in local {
+            let _client = client::connect(&format!("ws://{}", addr)).await.unwrap();
+            common::opened(&server).await;
+        }
+    });
+}
+
+#[test]
+fn addresses_that_cant_be_bound_are_skipped() {
+    block_on(async {
+        let addrs = ["not an address".to_string(), "127.0.0.1:0".to_string()];
+        let server = Server::start(&addrs, common::config()).await.unwrap();
+        assert_eq!(server.local_addrs().len(), 1);
+        let _ = common::join(&server).await;
+
+        let nowhere = ["not an address".to_string()];
+        assert!(Server::start(&nowhere, common::config()).await.is_err());
+    });
+}
+
+#[cfg(unix)]
+#[test]
+fn servers_reusing_the_port_can_bind_it_together() {
+    block_on(async {
+        let config = || ws_async::ServerConfig {
+            reuse_port: true,
+            ..common::config()
+        };
+        let old = Server::start(&["127.0.0.1:0".to_string()], config())
+            .await
+            .unwrap();
+        let addr = old.local_addrs()[0];
+        let new = Server::start(&[addr.to_string()], config())
+            .await
+            .expect("The port couldn't be shared");
+        assert_eq!(new.local_addrs(), [addr]);
+
+        // With the old server drained, the new one takes every client.
+        old.shutdown().await;
+        let _client = client::connect(&format!("ws://{}", addr)).await.unwrap();
+        common::opened(&new).await;
+    });
+}
+
+#[test]
+fn a_port_in_use_is_an_error_not_a_panic() {
+    block_on(async {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = taken.local_addr().unwrap().to_string();
+        match Server::start(std::slice::from_ref(&addr), common::config()).await {
+            Err(ServerError::Bind {
+                addr: failed,
+                source,
+            }) => {
+                assert_eq!(failed, addr);
+                assert_eq!(source.kind(), io::ErrorKind::AddrInUse);
+            }
+            Err(e) => panic!("Expected a bind error, got {}", e),
+            Ok(_) => panic!("The port was bound twice"),
+        }
+    });
+}
@@ -0,0 +1,309 @@
+//! Clients the server is told to get rid of, or to stop listening to.
+
+mod common;
+
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
+
+use async_tungstenite::tungstenite::protocol::Message;
+use bevy::{app::Events, prelude::*};
+use futures::prelude::*;
+use ws_async::{
+    client, process_deaf_requests, process_mute_requests, protocol, reap_idle_connections,
+    CloseCodes, DisconnectReason, HeartbeatConfig, MockClock, MuteRequest, OutboundMessage,
+    RateLimitConfig, ServerConfig, SetDeaf, SharedClock,
+};
+
+use common::block_on;
+
+#[test]
+fn kicked_clients_are_closed_with_the_reason() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let (id, _sink, mut source) = common::join(&server).await;
+        let (bystander, _bystander_sink, _bystander_source) = common::join(&server).await;
+
+        server.send(OutboundMessage::Kick(id, "cheating".to_string()));
+        assert_eq!(
+            common::next(&mut source).await,
+            Message::Close(Some(
+                DisconnectReason::Kicked("cheating".to_string()).close_frame()
+            ))
+        );
+        // Reading on answers the Close, which lets the server finish.
+        common::disconnected(&mut source).await;
+        assert_eq!(
+            common::closed(&server, id).await,
+            DisconnectReason::Kicked("cheating".to_string())
+        );
+        assert!(server.directory.get(&bystander).is_some());
+    });
+}
+
+#[test]
+fn kicked_clients_still_get_what_was_queued_for_them() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let (id, _sink, mut source) = common::join(&server).await;
+
+        for n in 0..5 {
+            server.send(OutboundMessage::To(id, Message::text(n.to_string())));
+        }
+        server.send(OutboundMessage::Kick(id, "cheating".to_string()));
+        for n in 0..5 {
+            assert_eq!(
+                common::next(&mut source).await,
+                Message::text(n.to_string())
+            );
+        }
+        assert!(common::next(&mut source).await.is_close());
+        common::disconnected(&mut source).await;
+    });
+}
+
+#[test]
+fn banned_addresses_get_no_handshake() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        server
+            .bans
+            .lock()
+            .unwrap()
+            .insert(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert!(client::connect(&common::url(&server)).await.is_err());
+
+        server.bans.lock().unwrap().clear();
+        let _ = common::join(&server).await;
+    });
+}
+
+#[test]
+fn flooding_mutes_until_the_mute_lifts() {
+    block_on(async {
+        let clock = MockClock::new();
+        let server = common::start(ServerConfig {
+            rate_limit: RateLimitConfig {
+                refill_per_sec: 1.0,
+                burst: 2.0,
+            },
+            flood_mute: Some(Duration::from_secs(10)),
+            clock: SharedClock::new(clock.clone()),
+            ..common::config()
+        })
+        .await;
+        let (id, mut sink, mut source) = common::join(&server).await;
+        let (_, _, mut listener) = common::join(&server).await;
+        let name = id.to_string();
+
+        for text in ["one", "two", "three"].iter() {
+            sink.send(common::say(text)).await.unwrap();
+        }
+        for (seq, text) in (1..).zip(["one", "two"].iter()) {
+            assert_eq!(
+                common::next(&mut listener).await,
+                protocol::chat_message(seq, &name, text)
+            );
+        }
+        assert!(common::next(&mut source).await.is_text());
+
+        // The bucket has refilled, but the mute still holds.
+        clock.advance(Duration::from_secs(2));
+        sink.send(common::say("four")).await.unwrap();
+        let notice = common::next(&mut source).await;
+        assert!(
+            notice
+                .to_text()
+                .unwrap()
+                .starts_with("You are muted for another"),
+            "{:?}",
+            notice
+        );
+        common::quiet(&mut listener, Duration::from_millis(200)).await;
+
+        clock.advance(Duration::from_secs(10));
+        sink.send(common::say("five")).await.unwrap();
+        assert_eq!(
+            common::next(&mut listener).await,
+            protocol::chat_message(3, &name, "five")
+        );
+    });
+}
+
+#[test]
+fn mute_requests_silence_a_client_for_a_while() {
+    block_on(async {
+        let clock = MockClock::new();
+        let server = common::start(ServerConfig {
+            // Heartbeats far apart, so the jump past the mute doesn't time
+            // anyone out.
+            heartbeat: HeartbeatConfig {
+                interval: Duration::from_secs(3600),
+                timeout: Duration::from_secs(7200),
+            },
+            clock: SharedClock::new(clock.clone()),
+            ..common::config()
+        })
+        .await;
+        let mut builder = App::build();
+        builder
+            .add_event::<MuteRequest>()
+            .insert_resource(server.directory.clone())
+            .insert_resource(server.config.clone())
+            .add_system(process_mute_requests.system());
+        let mut app = builder.app;
+        let (id, mut sink, _source) = common::join(&server).await;
+        let (_, _, mut listener) = common::join(&server).await;
+
+        app.world
+            .get_resource_mut::<Events<MuteRequest>>()
+            .unwrap()
+            .send(MuteRequest {
+                id,
+                duration: Duration::from_secs(60),
+            });
+        app.update();
+        sink.send(common::say("hello?")).await.unwrap();
+        common::quiet(&mut listener, Duration::from_millis(200)).await;
+
+        clock.advance(Duration::from_secs(60));
+        sink.send(common::say("hello!")).await.unwrap();
+        assert_eq!(
+            common::next(&mut listener).await,
+            protocol::chat_message(1, &id.to_string(), "hello!")
+        );
+    });
+}
+
+#[test]
+fn deaf_clients_hear_no_broadcasts_but_can_still_talk() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let mut builder = App::build();
+        builder
+            .add_event::<SetDeaf>()
+            .insert_resource(server.deaf.clone())
+            .add_system(process_deaf_requests.system());
+        let mut app = builder.app;
+        let (speaker, mut speaker_sink, mut speaker_source) = common::join(&server).await;
+        let (deaf, mut deaf_sink, mut deaf_source) = common::join(&server).await;
+        let (_, _, mut bystander) = common::join(&server).await;
+        let mut set_deaf = |value| {
+            app.world
+                .get_resource_mut::<Events<SetDeaf>>()
+                .unwrap()
+                .send(SetDeaf {
+                    id: deaf,
+                    deaf: value,
+                });
+            app.update();
+        };
+
+        set_deaf(true);
+        speaker_sink.send(common::say("one")).await.unwrap();
+        assert_eq!(
+            common::next(&mut bystander).await,
+            protocol::chat_message(1, &speaker.to_string(), "one")
+        );
+        common::quiet(&mut deaf_source, Duration::from_millis(200)).await;
+
+        deaf_sink.send(common::say("two")).await.unwrap();
+        let heard = protocol::chat_message(2, &deaf.to_string(), "two");
+        assert_eq!(common::next(&mut speaker_source).await, heard);
+        assert_eq!(common::next(&mut bystander).await, heard);
+
+        set_deaf(false);
+        speaker_sink.send(common::say("three")).await.unwrap();
+        assert_eq!(
+            common::next(&mut deaf_source).await,
+            protocol::chat_message(3, &speaker.to_string(), "three")
+        );
+    });
+}
+
+#[test]
+fn idle_connections_are_reaped_once_the_clock_passes_max_idle() {
+    block_on(async {
+        let clock = MockClock::new();
+        let server = common::start(ServerConfig {
+            max_idle: Some(Duration::from_secs(60)),
+            // Heartbeats far apart, so only the reaper closes anyone.
+            heartbeat: HeartbeatConfig {
+                interval: Duration::from_secs(3600),
+                timeout: Duration::from_secs(7200),
+            },
+            clock: SharedClock::new(clock.clone()),
+            ..common::config()
+        })
+        .await;
+        let mut builder = App::build();
+        builder
+            .insert_resource(server.directory.clone())
+            .insert_resource(server.config.clone())
+            .insert_resource(server.outbox())
+            .add_system(reap_idle_connections.system());
+        let mut app = builder.app;
+        let (idle, mut idle_sink, mut idle_source) = common::join(&server).await;
+        let (active, mut active_sink, _active_source) = common::join(&server).await;
+
+        clock.advance(Duration::from_secs(40));
+        // Pongs alone don't count as activity.
+        idle_sink.send(Message::Pong(Vec::new())).await.unwrap();
+        active_sink.send(common::say("still here")).await.unwrap();
+        // Relayed once the server has seen it.
+        assert_eq!(
+            common::next(&mut idle_source).await,
+            protocol::chat_message(1, &active.to_string(), "still here")
+        );
+        clock.advance(Duration::from_secs(30));
+        app.update();
+
+        assert!(common::next(&mut idle_source).await.is_close());
+        common::disconnected(&mut idle_source).await;
+        assert!(matches!(
+            common::closed(&server, idle).await,
+            DisconnectReason::Kicked(_)
+        ));
+        assert!(server.directory.get(&active).is_some());
+    });
+}
+
+#[test]
+fn kicks_can_close_with_an_application_code() {
+    block_on(async {
+        let mut close_codes = CloseCodes::default();
+        close_codes
+            .insert_kick("cheating detected", 4001, "cheating detected")
+            .unwrap();
+        // A kick reason that happens to be a reason's name is still a kick.
+        close_codes
+            .insert("server_full", 3000, "come back later")
+            .unwrap();
+        let server = common::start(ServerConfig {
+            close_codes,
+            ..common::config()
+        })
+        .await;
+        let (id, _sink, mut source) = common::join(&server).await;
+        let (other, _other_sink, mut other_source) = common::join(&server).await;
+
+        server.send(OutboundMessage::Kick(id, "cheating detected".to_string()));
+        match common::next(&mut source).await {
+            Message::Close(Some(frame)) => {
+                assert_eq!(u16::from(frame.code), 4001);
+                assert_eq!(frame.reason, "cheating detected");
+            }
+            other => panic!("Expected a Close, got {:?}", other),
+        }
+
+        // Reasons without a code of their own keep the standard one.
+        server.send(OutboundMessage::Kick(other, "server_full".to_string()));
+        assert_eq!(
+            common::next(&mut other_source).await,
+            Message::Close(Some(
+                DisconnectReason::Kicked("server_full".to_string()).close_frame()
+            ))
+        );
+    });
+}
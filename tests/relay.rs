@@ -0,0 +1,534 @@
+//! Messages relayed from one client to the others.
+
+mod common;
+
+use std::time::Duration;
+
+use async_tungstenite::tungstenite::protocol::Message;
+use futures::{future, prelude::*};
+use ws_async::{
+    broadcast::{self, Delivery, Relayed},
+    client::{self, ClientSink},
+    protocol::{self, ClientCommand},
+    BroadcastStrategy, ClosePolicy, DisconnectReason, MessageFilter, MessageHandler, PeerMap,
+    ServerConfig, SharedStrategy,
+};
+
+use common::block_on;
+
+#[test]
+fn binary_messages_arrive_unchanged() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let (_, mut sender, _sender_source) = common::join(&server).await;
+        let (_, _, mut first) = common::join(&server).await;
+        let (_, _, mut second) = common::join(&server).await;
+
+        // Not valid UTF-8, so nothing may treat it as text.
+        let payload: Vec<u8> = (0..=255).rev().collect();
+        sender.send(Message::binary(payload.clone())).await.unwrap();
+        assert_eq!(
+            common::next(&mut first).await,
+            Message::Binary(payload.clone())
+        );
+        assert_eq!(common::next(&mut second).await, Message::Binary(payload));
+    });
+}
+
+#[test]
+fn newcomers_are_sent_the_recent_history() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            history_size: 2,
+            ..common::config()
+        })
+        .await;
+        let (id, mut sender, _sender_source) = common::join(&server).await;
+        let (_, _, mut listener) = common::join(&server).await;
+        let name = id.to_string();
+        for text in ["one", "two", "three"] {
+            sender.send(common::say(text)).await.unwrap();
+        }
+        // Once the last one has been relayed, it is in the history too.
+        for _ in 0..3 {
+            common::next(&mut listener).await;
+        }
+
+        let (_, _, mut newcomer) = common::join(&server).await;
+        assert_eq!(
+            common::next(&mut newcomer).await,
+            protocol::chat_message(2, &name, "two")
+        );
+        assert_eq!(
+            common::next(&mut newcomer).await,
+            protocol::chat_message(3, &name, "three")
+        );
+    });
+}
+
+#[test]
+fn sequence_numbers_carry_on_across_broadcasts_and_replays() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            history_size: 10,
+            ..common::config()
+        })
+        .await;
+        let (id, mut sender, _sender_source) = common::join(&server).await;
+        let (_, _, mut listener) = common::join(&server).await;
+        let name = id.to_string();
+        for (seq, text) in (1..).zip(["one", "two", "three"]) {
+            sender.send(common::say(text)).await.unwrap();
+            assert_eq!(
+                common::next(&mut listener).await,
+                protocol::chat_message(seq, &name, text)
+            );
+        }
+
+        // The replay keeps the numbers, so they can be told from new ones.
+        let (newcomer_id, mut newcomer_sink, mut newcomer) = common::join(&server).await;
+        for (seq, text) in (1..).zip(["one", "two", "three"]) {
+            assert_eq!(
+                common::next(&mut newcomer).await,
+                protocol::chat_message(seq, &name, text)
+            );
+        }
+        newcomer_sink.send(common::say("four")).await.unwrap();
+        assert_eq!(
+            common::next(&mut listener).await,
+            protocol::chat_message(4, &newcomer_id.to_string(), "four")
+        );
+    });
+}
+
+fn private(to: &str, text: &str) -> Message {
+    common::command(ClientCommand::Msg {
+        to: to.to_string(),
+        text: text.to_string(),
+        id: None,
+    })
+}
+
+#[test]
+fn private_messages_reach_only_their_recipient() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let (alice, mut alice_sink, mut alice_source) = common::join(&server).await;
+        let (bob, mut bob_sink, mut bob_source) = common::join(&server).await;
+        let (_, _, mut carol) = common::join(&server).await;
+        common::nick(&server, alice, &mut alice_sink, "alice").await;
+        common::nick(&server, bob, &mut bob_sink, "bob").await;
+
+        alice_sink.send(private("bob", "psst")).await.unwrap();
+        assert_eq!(
+            common::next(&mut bob_source).await,
+            Message::text("[private] alice: psst")
+        );
+
+        // Carol only hears what is said to the room, which comes after.
+        alice_sink.send(common::say("hello")).await.unwrap();
+        assert_eq!(
+            common::next(&mut carol).await,
+            protocol::chat_message(1, "alice", "hello")
+        );
+        assert_eq!(
+            common::next(&mut bob_source).await,
+            protocol::chat_message(1, "alice", "hello")
+        );
+        // Nor does Alice hear her own message back.
+        bob_sink.send(private("alice", "hi")).await.unwrap();
+        assert_eq!(
+            common::next(&mut alice_source).await,
+            Message::text("[private] bob: hi")
+        );
+    });
+}
+
+#[test]
+fn private_messages_to_an_unknown_name_are_refused() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let (_, mut sink, mut source) = common::join(&server).await;
+
+        sink.send(private("nobody", "hello?")).await.unwrap();
+        assert_eq!(
+            common::next(&mut source).await,
+            Message::text("Error: No one is called nobody")
+        );
+    });
+}
+
+#[test]
+fn filters_can_rewrite_messages() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            message_filter: MessageFilter::new(|_, msg| match msg {
+                Message::Text(text) => Some(Message::text(text.to_uppercase())),
+                other => Some(other.clone()),
+            }),
+            ..common::config()
+        })
+        .await;
+        let (id, mut sender, _sender_source) = common::join(&server).await;
+        let (_, _, mut listener) = common::join(&server).await;
+
+        sender.send(common::say("quiet please")).await.unwrap();
+        assert_eq!(
+            common::next(&mut listener).await,
+            protocol::chat_message(1, &id.to_string(), "QUIET PLEASE")
+        );
+    });
+}
+
+#[test]
+fn handlers_keep_state_for_each_connection() {
+    #[derive(Default)]
+    struct Count(usize);
+
+    block_on(async {
+        let server = common::start(ServerConfig {
+            message_handler: Some(MessageHandler::new(|_, _, state| {
+                let count = state.get_or_default::<Count>();
+                count.0 += 1;
+                Some(Message::text(format!("message {}", count.0)))
+            })),
+            ..common::config()
+        })
+        .await;
+        let (_, mut first, mut first_source) = common::join(&server).await;
+        // In a room of its own, so it isn't relayed the first client's chat.
+        let url = format!("{}/?room=apart", common::url(&server));
+        let (mut second, mut second_source) = client::connect(&url).await.unwrap();
+        common::opened(&server).await;
+
+        for n in 1..=2 {
+            first.send(common::say("hi")).await.unwrap();
+            assert_eq!(
+                common::next(&mut first_source).await,
+                Message::text(format!("message {}", n))
+            );
+        }
+        second.send(common::say("hi")).await.unwrap();
+        assert_eq!(
+            common::next(&mut second_source).await,
+            Message::text("message 1")
+        );
+    });
+}
+
+#[test]
+fn filters_can_drop_messages() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            message_filter: MessageFilter::new(|_, msg| match msg {
+                Message::Text(text) if text.contains("darn") => None,
+                other => Some(other.clone()),
+            }),
+            ..common::config()
+        })
+        .await;
+        let (id, mut sender, _sender_source) = common::join(&server).await;
+        let (_, _, mut listener) = common::join(&server).await;
+
+        sender.send(common::say("oh darn")).await.unwrap();
+        sender.send(common::say("oh well")).await.unwrap();
+        assert_eq!(
+            common::next(&mut listener).await,
+            protocol::chat_message(1, &id.to_string(), "oh well")
+        );
+    });
+}
+
+fn closing_under(policy: ClosePolicy) -> ServerConfig {
+    ServerConfig {
+        close_policy: policy,
+        ..common::config()
+    }
+}
+
+#[test]
+fn a_client_closing_goes_unnoticed_by_default() {
+    block_on(async {
+        let server = common::start(closing_under(ClosePolicy::Ignore)).await;
+        let (_, mut leaving, _leaving_source) = common::join(&server).await;
+        let (talker, mut talking, _talking_source) = common::join(&server).await;
+        let (_, _, mut listener) = common::join(&server).await;
+
+        leaving.close().await.unwrap();
+        talking.send(common::say("anyone there?")).await.unwrap();
+        assert_eq!(
+            common::next(&mut listener).await,
+            protocol::chat_message(1, &talker.to_string(), "anyone there?")
+        );
+    });
+}
+
+#[test]
+fn a_client_closing_is_announced_under_relay() {
+    block_on(async {
+        let server = common::start(closing_under(ClosePolicy::Relay)).await;
+        let (leaver, mut leaving, _leaving_source) = common::join(&server).await;
+        let (_, _, mut listener) = common::join(&server).await;
+
+        leaving.close().await.unwrap();
+        assert_eq!(
+            common::next(&mut listener).await,
+            Message::text(format!("* {} left", leaver))
+        );
+    });
+}
+
+fn join(room: &str) -> Message {
+    common::command(ClientCommand::Join {
+        room: room.to_string(),
+    })
+}
+
+#[test]
+fn joiners_are_sent_only_their_new_room_s_history() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let (lobbyist, mut lobby, mut lobby_source) = common::join(&server).await;
+        let (gladiator, mut arena, mut arena_source) = common::join(&server).await;
+        arena.send(join("arena")).await.unwrap();
+        common::eventually(|| (server.directory.get(&gladiator)?.room == "arena").then_some(()))
+            .await;
+
+        lobby.send(common::say("in the lobby")).await.unwrap();
+        arena.send(common::say("in the arena")).await.unwrap();
+        let (_, mut newcomer, mut newcomer_source) = common::join(&server).await;
+        assert_eq!(
+            common::next(&mut newcomer_source).await,
+            protocol::chat_message(1, &lobbyist.to_string(), "in the lobby")
+        );
+        newcomer.send(join("arena")).await.unwrap();
+        assert_eq!(
+            common::next(&mut newcomer_source).await,
+            protocol::chat_message(1, &gladiator.to_string(), "in the arena")
+        );
+        // Neither room heard the other.
+        common::quiet(&mut lobby_source, Duration::from_millis(100)).await;
+        common::quiet(&mut arena_source, Duration::from_millis(100)).await;
+    });
+}
+
+/// Says ten numbered things as `who`.
+async fn talk(sink: &mut ClientSink, who: &str) {
+    for n in 0..10 {
+        sink.send(common::say(&format!("{} {}", who, n)))
+            .await
+            .unwrap();
+    }
+}
+
+#[test]
+fn every_member_sees_the_same_order_under_total_order() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            total_order: true,
+            ..common::config()
+        })
+        .await;
+        let (_, mut first, _first_source) = common::join(&server).await;
+        let (_, mut second, _second_source) = common::join(&server).await;
+        let (_, _, mut left) = common::join(&server).await;
+        let (_, _, mut right) = common::join(&server).await;
+
+        future::join(talk(&mut first, "first"), talk(&mut second, "second")).await;
+        let mut seen_left = Vec::new();
+        let mut seen_right = Vec::new();
+        for _ in 0..20 {
+            seen_left.push(common::next(&mut left).await);
+            seen_right.push(common::next(&mut right).await);
+        }
+        assert_eq!(seen_left, seen_right);
+    });
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn senders_asking_for_receipts_get_acks() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let (_, mut sink, mut source) = common::join(&server).await;
+        let (bob, mut bob_sink, mut bob_source) = common::join(&server).await;
+        let (_, _, mut carol) = common::join(&server).await;
+        common::nick(&server, bob, &mut bob_sink, "bob").await;
+
+        sink.send(common::command(ClientCommand::Chat {
+            text: "hello all".to_string(),
+            id: Some("m1".to_string()),
+        }))
+        .await
+        .unwrap();
+        assert_eq!(
+            common::next(&mut source).await,
+            protocol::delivery_ack("m1", 2)
+        );
+        common::next(&mut bob_source).await;
+        common::next(&mut carol).await;
+
+        sink.send(common::command(ClientCommand::Msg {
+            to: "bob".to_string(),
+            text: "psst".to_string(),
+            id: Some("m2".to_string()),
+        }))
+        .await
+        .unwrap();
+        assert_eq!(
+            common::next(&mut source).await,
+            protocol::recipient_ack("m2", "bob")
+        );
+        assert!(common::next(&mut bob_source).await.is_text());
+    });
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn saved_history_is_replayed_after_a_restart() {
+    block_on(async {
+        let path =
+            std::env::temp_dir().join(format!("ws_async-{}-relay.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = || ServerConfig {
+            history_size: 2,
+            history_path: Some(path.clone()),
+            ..common::config()
+        };
+
+        let server = common::start(config()).await;
+        let (id, mut sender, sender_source) = common::join(&server).await;
+        let name = id.to_string();
+        for text in ["one", "two", "three"] {
+            sender.send(common::say(text)).await.unwrap();
+        }
+        // Saving happens on a thread of its own.
+        common::eventually(|| {
+            let saved = std::fs::read_to_string(&path).ok()?;
+            saved.contains("three").then_some(())
+        })
+        .await;
+        drop((sender, sender_source));
+        server.shutdown().await;
+
+        let server = common::start(config()).await;
+        let (newcomer_id, mut newcomer, mut newcomer_source) = common::join(&server).await;
+        assert_eq!(
+            common::next(&mut newcomer_source).await,
+            protocol::chat_message(2, &name, "two")
+        );
+        assert_eq!(
+            common::next(&mut newcomer_source).await,
+            protocol::chat_message(3, &name, "three")
+        );
+        // The numbering carries on from before the restart.
+        let (_, _, mut listener) = common::join(&server).await;
+        common::next(&mut listener).await;
+        common::next(&mut listener).await;
+        newcomer.send(common::say("four")).await.unwrap();
+        assert_eq!(
+            common::next(&mut listener).await,
+            protocol::chat_message(4, &newcomer_id.to_string(), "four")
+        );
+        let _ = std::fs::remove_file(&path);
+    });
+}
+
+/// A masked frame as a client writes it, with a mask of zeroes so the
+/// payload goes out as it is.
+fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+    match payload.len() {
+        len if len < 126 => frame.push(0x80 | len as u8),
+        len => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(&[0; 4]);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[test]
+fn fragmented_messages_are_reassembled() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let (mut sender, _) = common::handshake(&server, common::request(&server, "/"))
+            .await
+            .unwrap();
+        common::opened(&server).await;
+        let (_, _, mut listener) = common::join(&server).await;
+
+        let payload: Vec<u8> = (0..120_000u32).map(|i| i as u8).collect();
+        let mut frames = Vec::new();
+        for (i, chunk) in payload.chunks(40_000).enumerate() {
+            // A binary frame, then continuations, the last one final.
+            let opcode = if i == 0 { 0x2 } else { 0x0 };
+            frames.extend(client_frame(i == 2, opcode, chunk));
+        }
+        sender.get_mut().write_all(&frames).await.unwrap();
+        assert_eq!(common::next(&mut listener).await, Message::binary(payload));
+    });
+}
+
+#[test]
+fn every_fragment_counts_towards_the_message_size_limit() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            max_message_size: Some(1000),
+            ..common::config()
+        })
+        .await;
+        let (mut sender, _) = common::handshake(&server, common::request(&server, "/"))
+            .await
+            .unwrap();
+        let id = common::opened(&server).await;
+
+        // Each fragment is well within the limit, all three together aren't.
+        let mut frames = client_frame(false, 0x2, &[0; 400]);
+        frames.extend(client_frame(false, 0x0, &[0; 400]));
+        frames.extend(client_frame(true, 0x0, &[0; 400]));
+        sender.get_mut().write_all(&frames).await.unwrap();
+        assert!(matches!(
+            common::closed(&server, id).await,
+            DisconnectReason::Protocol(_)
+        ));
+    });
+}
+
+/// Relays only to connections with an even id.
+struct EvenIds;
+
+impl BroadcastStrategy for EvenIds {
+    fn deliver(&self, peers: &PeerMap, relayed: Relayed<'_>, msg: Message) -> Delivery {
+        broadcast::send_each(peers, &msg, |id| id.0 % 2 == 0 && *id != relayed.from)
+    }
+}
+
+#[test]
+fn a_custom_strategy_picks_who_hears_a_message() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            broadcast_strategy: SharedStrategy::new(EvenIds),
+            ..common::config()
+        })
+        .await;
+        let (id, mut sender, _sender_source) = common::join(&server).await;
+        let mut others = Vec::new();
+        for _ in 0..4 {
+            let (other, _, source) = common::join(&server).await;
+            others.push((other, source));
+        }
+
+        sender.send(common::say("evens only")).await.unwrap();
+        let expected = protocol::chat_message(1, &id.to_string(), "evens only");
+        for (other, source) in &mut others {
+            if other.0 % 2 == 0 {
+                assert_eq!(common::next(source).await, expected);
+            } else {
+                common::quiet(source, Duration::from_millis(200)).await;
+            }
+        }
+    });
+}
@@ -0,0 +1,171 @@
+//! Rooms and who is in them.
+
+mod common;
+
+use std::time::Duration;
+
+use futures::prelude::*;
+use ws_async::{
+    client,
+    protocol::{self, ClientCommand},
+    runtime, ConnectionId, ServerConfig,
+};
+
+use common::block_on;
+
+fn members(ids: &[ConnectionId]) -> Vec<String> {
+    let mut names: Vec<String> = ids.iter().map(ConnectionId::to_string).collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn members_are_sent_the_list_on_joins_and_leaves() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            member_list_delay: Some(Duration::from_millis(300)),
+            ..common::config()
+        })
+        .await;
+        // Joining in quick succession, so one list covers all three.
+        let (a, _a_sink, mut a_source) = common::join(&server).await;
+        let (b, _b_sink, mut b_source) = common::join(&server).await;
+        let (c, mut c_sink, mut c_source) = common::join(&server).await;
+        let everyone = protocol::room_members_message("lobby", &members(&[a, b, c]));
+        assert_eq!(common::next(&mut a_source).await, everyone);
+        assert_eq!(common::next(&mut b_source).await, everyone);
+        assert_eq!(common::next(&mut c_source).await, everyone);
+
+        c_sink.close().await.unwrap();
+        common::disconnected(&mut c_source).await;
+        let remaining = protocol::room_members_message("lobby", &members(&[a, b]));
+        assert_eq!(common::next(&mut a_source).await, remaining);
+        assert_eq!(common::next(&mut b_source).await, remaining);
+        common::quiet(&mut a_source, Duration::from_millis(500)).await;
+    });
+}
+
+#[test]
+fn rooms_are_listed_with_their_occupancy() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let (a, mut a_sink, _a_source) = common::join(&server).await;
+        let (_, _, _b_source) = common::join(&server).await;
+        let (_, mut asker, mut asker_source) = common::join(&server).await;
+        a_sink
+            .send(common::command(ClientCommand::Join {
+                room: "arena".to_string(),
+            }))
+            .await
+            .unwrap();
+        common::eventually(|| (server.rooms().get("arena") == Some(&[a][..])).then_some(())).await;
+        assert_eq!(
+            server.rooms().occupancy(),
+            [("arena".to_string(), 1), ("lobby".to_string(), 2)]
+        );
+
+        asker
+            .send(common::command(ClientCommand::Rooms))
+            .await
+            .unwrap();
+        assert_eq!(
+            common::next(&mut asker_source).await,
+            protocol::rooms_message(&server.rooms().occupancy())
+        );
+    });
+}
+
+#[test]
+fn clients_can_pick_their_room_when_connecting() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let (_, _, mut in_lobby) = common::join(&server).await;
+        let (speaker, mut speaker_sink, _speaker_source) = {
+            let url = format!("{}/?room=foo", common::url(&server));
+            let (sink, source) = client::connect(&url).await.unwrap();
+            (common::opened(&server).await, sink, source)
+        };
+        let (_, mut in_foo) = client::connect(&format!("{}/?room=foo", common::url(&server)))
+            .await
+            .unwrap();
+        let listener = common::opened(&server).await;
+        assert_eq!(server.rooms().get("foo"), Some(&[speaker, listener][..]));
+
+        speaker_sink.send(common::say("foo only")).await.unwrap();
+        assert_eq!(
+            common::next(&mut in_foo).await,
+            protocol::chat_message(1, &speaker.to_string(), "foo only")
+        );
+        common::quiet(&mut in_lobby, Duration::from_millis(200)).await;
+    });
+}
+
+#[test]
+fn invalid_room_names_are_refused() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let request = common::request(&server, "/?room=../etc");
+        assert_eq!(common::refusal(&server, request).await, 400);
+    });
+}
+
+#[test]
+fn repeated_joins_are_coalesced_into_one_update() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            member_list_delay: Some(Duration::from_millis(50)),
+            command_debounce: Some(Duration::from_secs(5)),
+            ..common::config()
+        })
+        .await;
+        let url = format!("{}/?room=foo", common::url(&server));
+        let (_, mut watcher) = client::connect(&url).await.unwrap();
+        let watching = common::opened(&server).await;
+        let (joiner, mut sink, _source) = common::join(&server).await;
+        assert_eq!(
+            common::next(&mut watcher).await,
+            protocol::room_members_message("foo", &members(&[watching]))
+        );
+
+        // Far enough apart that each would get a member list of its own.
+        for _ in 0..5 {
+            sink.send(common::command(ClientCommand::Join {
+                room: "foo".to_string(),
+            }))
+            .await
+            .unwrap();
+            runtime::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(
+            common::next(&mut watcher).await,
+            protocol::room_members_message("foo", &members(&[watching, joiner]))
+        );
+        common::quiet(&mut watcher, Duration::from_millis(300)).await;
+    });
+}
+
+#[test]
+fn a_refused_nick_can_be_retried_at_once() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            command_debounce: Some(Duration::from_secs(5)),
+            ..common::config()
+        })
+        .await;
+        let (first, mut first_sink, _first_source) = common::join(&server).await;
+        let (_, mut second_sink, mut second_source) = common::join(&server).await;
+        common::nick(&server, first, &mut first_sink, "ada").await;
+
+        // Each attempt at the taken name is answered, none ignored.
+        for _ in 0..2 {
+            second_sink
+                .send(common::command(ClientCommand::Nick {
+                    name: "ada".to_string(),
+                }))
+                .await
+                .unwrap();
+            let reply = common::next(&mut second_source).await.into_text().unwrap();
+            assert!(reply.starts_with("Error: "), "{}", reply);
+        }
+    });
+}
@@ -0,0 +1,72 @@
+//! Connections handled by the endpoint their request path routes to.
+
+mod common;
+
+use std::time::Duration;
+
+use futures::prelude::*;
+use ws_async::{protocol, BridgeChannel, Endpoint, ServerConfig, WsMessageReceived};
+
+use common::block_on;
+
+#[test]
+fn chat_and_game_paths_are_handled_apart() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            routes: vec![
+                ("/chat".to_string(), Endpoint::Chat),
+                ("/game".to_string(), Endpoint::Game),
+            ]
+            .into_iter()
+            .collect(),
+            ..common::config()
+        })
+        .await;
+        let connect = |path| common::handshake(&server, common::request(&server, path));
+        let (mut talker, _) = connect("/chat").await.unwrap();
+        let talker_id = common::opened(&server).await;
+        let (mut listener, _) = connect("/chat").await.unwrap();
+        common::opened(&server).await;
+        let (mut player, _) = connect("/game").await.unwrap();
+        let player_id = common::opened(&server).await;
+        assert_eq!(
+            server.directory.get(&player_id).unwrap().endpoint,
+            Endpoint::Game
+        );
+
+        // Chat is relayed to the room, which the player isn't in.
+        talker.send(common::say("hi")).await.unwrap();
+        assert_eq!(
+            common::next(&mut listener).await,
+            protocol::chat_message(1, &talker_id.to_string(), "hi")
+        );
+        common::quiet(&mut player, Duration::from_millis(200)).await;
+
+        // What the player sends only goes to the app.
+        player.send(common::say("move")).await.unwrap();
+        common::eventually(|| match BridgeChannel::try_recv(&server.messages) {
+            Some(WsMessageReceived { id, msg }) if id == player_id => Some(msg),
+            _ => None,
+        })
+        .await;
+        common::quiet(&mut listener, Duration::from_millis(200)).await;
+    });
+}
+
+#[test]
+fn unrouted_paths_are_not_found() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            routes: vec![("/chat".to_string(), Endpoint::Chat)]
+                .into_iter()
+                .collect(),
+            ..common::config()
+        })
+        .await;
+        let status = common::refusal(&server, common::request(&server, "/nowhere")).await;
+        assert_eq!(status, 404);
+        let _ = common::handshake(&server, common::request(&server, "/chat"))
+            .await
+            .unwrap();
+    });
+}
@@ -0,0 +1,266 @@
+//! Driving the server through the `Server` API, without a Bevy app.
+
+mod common;
+
+use std::time::Duration;
+
+use async_tungstenite::tungstenite::protocol::Message;
+use futures::{future, prelude::*};
+use ws_async::{
+    directory, queue::SendError, runtime, BridgeChannel, ConnectionId, OutboundMessage, Selector,
+    ServerConfig,
+};
+
+use common::block_on;
+
+#[test]
+fn broadcast_reaches_every_client() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let (_, _first_sink, mut first) = common::join(&server).await;
+        let (_, _second_sink, mut second) = common::join(&server).await;
+
+        let dropped = server.broadcast(Message::text("attention")).await;
+        assert!(dropped.is_empty());
+        let (first, second) =
+            future::join(common::next(&mut first), common::next(&mut second)).await;
+        assert_eq!(first, Message::text("attention"));
+        assert_eq!(second, Message::text("attention"));
+    });
+}
+
+#[test]
+fn send_to_reaches_only_its_client() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let (id, _sink, mut source) = common::join(&server).await;
+        let (other, _other_sink, mut other_source) = common::join(&server).await;
+
+        server.send_to(id, Message::text("for you")).await.unwrap();
+        server
+            .send_to(other, Message::text("and you"))
+            .await
+            .unwrap();
+        assert_eq!(common::next(&mut source).await, Message::text("for you"));
+        assert_eq!(
+            common::next(&mut other_source).await,
+            Message::text("and you")
+        );
+
+        let nobody = ConnectionId(u64::MAX);
+        assert_eq!(
+            server.send_to(nobody, Message::text("hello?")).await,
+            Err(SendError::Disconnected)
+        );
+    });
+}
+
+#[test]
+fn shutdown_stops_accepting_clients() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let url = common::url(&server);
+        server.shutdown().await;
+        assert!(ws_async::client::connect(&url).await.is_err());
+    });
+}
+
+#[test]
+fn where_reaches_only_the_selected_clients() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let (red, _red_sink, mut red_source) = common::join(&server).await;
+        let (blue, _blue_sink, mut blue_source) = common::join(&server).await;
+        let (_, _plain_sink, mut plain_source) = common::join(&server).await;
+        assert!(directory::add_tag(&server.directory, red, "red"));
+        assert!(directory::add_tag(&server.directory, blue, "blue"));
+
+        server.send(OutboundMessage::Where(
+            Selector::Tag("red".to_string()),
+            Message::text("red team"),
+        ));
+        assert_eq!(
+            common::next(&mut red_source).await,
+            Message::text("red team")
+        );
+        server.send(OutboundMessage::Where(
+            Selector::Not(Box::new(Selector::Tag("red".to_string()))),
+            Message::text("everyone else"),
+        ));
+        assert_eq!(
+            common::next(&mut blue_source).await,
+            Message::text("everyone else")
+        );
+        assert_eq!(
+            common::next(&mut plain_source).await,
+            Message::text("everyone else")
+        );
+        common::quiet(&mut red_source, Duration::from_millis(200)).await;
+    });
+}
+
+#[test]
+fn the_message_stream_sees_every_connection() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let mut messages = server.messages();
+        let (first, mut first_sink, _first_source) = common::join(&server).await;
+        let (second, mut second_sink, _second_source) = common::join(&server).await;
+
+        first_sink.send(common::say("one")).await.unwrap();
+        second_sink.send(common::say("two")).await.unwrap();
+        let mut senders = Vec::new();
+        for _ in 0..2 {
+            let (id, _) = runtime::timeout(common::PATIENCE, messages.next())
+                .await
+                .expect("Nothing received in time")
+                .unwrap();
+            senders.push(id);
+        }
+        senders.sort();
+        assert_eq!(senders, [first, second]);
+    });
+}
+
+#[test]
+fn each_message_stream_sees_every_message_until_dropped() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let mut streams = [server.messages(), server.messages()];
+        let (id, mut sink, _source) = common::join(&server).await;
+
+        sink.send(common::say("for both")).await.unwrap();
+        for stream in streams.iter_mut() {
+            let received = runtime::timeout(common::PATIENCE, stream.next())
+                .await
+                .expect("Nothing received in time");
+            assert_eq!(received, Some((id, common::say("for both"))));
+        }
+        assert!(BridgeChannel::try_recv(&server.messages).is_none());
+
+        // With no stream left, messages go to the channel again.
+        drop(streams);
+        sink.send(common::say("for the channel")).await.unwrap();
+        let received = common::eventually(|| BridgeChannel::try_recv(&server.messages)).await;
+        assert_eq!(
+            (received.id, received.msg),
+            (id, common::say("for the channel"))
+        );
+    });
+}
+
+#[test]
+fn tagged_messages_reach_only_the_tagged_clients() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let (first, _first_sink, mut first_source) = common::join(&server).await;
+        let (second, _second_sink, mut second_source) = common::join(&server).await;
+        let (_, _third_sink, mut third_source) = common::join(&server).await;
+        directory::add_tag(&server.directory, first, "staff");
+        directory::add_tag(&server.directory, second, "staff");
+
+        server.send(OutboundMessage::ToTag(
+            "staff".to_string(),
+            Message::text("staff meeting"),
+        ));
+        assert_eq!(
+            common::next(&mut first_source).await,
+            Message::text("staff meeting")
+        );
+        assert_eq!(
+            common::next(&mut second_source).await,
+            Message::text("staff meeting")
+        );
+        common::quiet(&mut third_source, Duration::from_millis(200)).await;
+
+        directory::remove_tag(&server.directory, second, "staff");
+        server.send(OutboundMessage::ToTag(
+            "staff".to_string(),
+            Message::text("again"),
+        ));
+        assert_eq!(
+            common::next(&mut first_source).await,
+            Message::text("again")
+        );
+        common::quiet(&mut second_source, Duration::from_millis(200)).await;
+    });
+}
+
+#[cfg(feature = "serde")]
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+struct ScoreUpdate {
+    player: String,
+    score: u32,
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn typed_events_arrive_in_each_clients_format() {
+    use ws_async::wire::{self, WireFormat};
+
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let (id, _sink, mut json) = common::join(&server).await;
+        #[cfg(feature = "bincode")]
+        let (_bincode_sink, mut bincode) = {
+            let url = format!("{}/?format=bincode", common::url(&server));
+            let client = ws_async::client::connect(&url).await.unwrap();
+            common::opened(&server).await;
+            client
+        };
+
+        let update = ScoreUpdate {
+            player: "ada".to_string(),
+            score: 12,
+        };
+        wire::broadcast_event(&server.outbox(), &update);
+        let received = common::next(&mut json).await;
+        assert_eq!(received, Message::text(r#"{"player":"ada","score":12}"#));
+        #[cfg(feature = "bincode")]
+        assert_eq!(
+            wire::deserialize::<ScoreUpdate>(
+                WireFormat::Bincode,
+                &common::next(&mut bincode).await
+            )
+            .unwrap(),
+            update
+        );
+
+        let only_for_ada = ScoreUpdate {
+            player: "ada".to_string(),
+            score: 13,
+        };
+        wire::send_event_to(&server.outbox(), id, &only_for_ada);
+        assert_eq!(
+            wire::deserialize::<ScoreUpdate>(WireFormat::Json, &common::next(&mut json).await)
+                .unwrap(),
+            only_for_ada
+        );
+        #[cfg(feature = "bincode")]
+        common::quiet(&mut bincode, Duration::from_millis(200)).await;
+    });
+}
+
+#[test]
+fn the_queue_depth_rises_for_a_client_that_stops_reading() {
+    block_on(async {
+        let server = common::start(ServerConfig {
+            peer_buffer: 64,
+            ..common::config()
+        })
+        .await;
+        // Never read, so once the socket buffers are full the backlog
+        // stays queued.
+        let (id, _sink, _source) = common::join(&server).await;
+        let depth = server.directory.get(&id).unwrap().queue_depth.clone();
+        assert_eq!(depth.get(), 0);
+
+        for _ in 0..48 {
+            let _ = server
+                .send_to(id, Message::binary(vec![0; 512 * 1024]))
+                .await;
+        }
+        common::eventually(|| (depth.get() >= 8).then_some(())).await;
+        assert!(depth.get() <= depth.capacity());
+    });
+}
@@ -0,0 +1,67 @@
+//! Shutting the server down with clients connected.
+
+mod common;
+
+use std::time::Duration;
+
+use async_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, Message};
+use futures::{
+    future,
+    io::{AsyncReadExt, AsyncWriteExt},
+    prelude::*,
+};
+use ws_async::runtime;
+
+use common::block_on;
+
+#[test]
+fn clients_are_sent_a_close_frame() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let (_, _sink, mut source) = common::join(&server).await;
+
+        let client = async {
+            match common::next(&mut source).await {
+                Message::Close(Some(frame)) => {
+                    assert_eq!(frame.code, CloseCode::Away);
+                    assert_eq!(frame.reason, "server shutting down");
+                }
+                other => panic!("Expected a Close frame, got {:?}", other),
+            }
+            // Reading on sends the client's answer to the Close.
+            while let Some(Ok(_)) = source.next().await {}
+        };
+        future::join(server.shutdown(), client).await;
+    });
+}
+
+#[test]
+fn clients_arriving_during_shutdown_are_turned_away() {
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let addr = server.local_addrs()[0].to_string();
+        // Never reading, so the Close goes unanswered and shutdown waits.
+        let (_, _sink, _holdout) = common::join(&server).await;
+
+        let latecomers = async {
+            runtime::sleep(Duration::from_millis(100)).await;
+            let mut raw = runtime::connect(&addr).await.unwrap();
+            raw.write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await
+                .unwrap();
+            let mut answer = String::new();
+            raw.read_to_string(&mut answer).await.unwrap();
+            assert!(answer.starts_with("HTTP/1.1 503 "), "{}", answer);
+
+            let stream = runtime::connect(&addr).await.unwrap();
+            let (mut ws, _) = async_tungstenite::client_async(format!("ws://{}", addr), stream)
+                .await
+                .unwrap();
+            match common::next(&mut ws).await {
+                Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Away),
+                other => panic!("Expected a Close frame, got {:?}", other),
+            }
+        };
+        future::join(server.shutdown(), latecomers).await;
+    });
+}
@@ -0,0 +1,81 @@
+//! `wss://` with the self-signed certificate in `tests/tls`, issued for
+//! `localhost`.
+
+#![cfg(feature = "tls")]
+
+mod common;
+
+use std::{convert::TryFrom, fs::File, io::BufReader, path::Path, sync::Arc};
+
+use async_tungstenite::tungstenite::protocol::Message;
+use futures::prelude::*;
+use futures_rustls::{
+    rustls::{Certificate, ClientConfig, RootCertStore, ServerName},
+    TlsConnector,
+};
+use ws_async::{client, runtime, Server};
+
+use common::block_on;
+
+const CERT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/tls/localhost.pem");
+const KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/tls/localhost-key.pem");
+
+/// Trusts only the test certificate.
+fn connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(CERT).unwrap())).unwrap();
+    for cert in certs {
+        roots.add(&Certificate(cert)).unwrap();
+    }
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+async fn start() -> Server {
+    let addrs = ["127.0.0.1:0".to_string()];
+    Server::start_tls(&addrs, common::config(), Path::new(CERT), Path::new(KEY))
+        .await
+        .expect("Couldn't start the server")
+}
+
+#[test]
+fn clients_talk_over_tls() {
+    block_on(async {
+        let server = start().await;
+        let port = server.local_addrs()[0].port();
+
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let tcp = runtime::connect(&format!("127.0.0.1:{}", port))
+                .await
+                .unwrap();
+            let name = ServerName::try_from("localhost").unwrap();
+            let tls = connector().connect(name, tcp).await.unwrap();
+            let url = format!("wss://localhost:{}", port);
+            let (ws_stream, _) = async_tungstenite::client_async(url, tls).await.unwrap();
+            common::opened(&server).await;
+            clients.push(ws_stream);
+        }
+
+        clients[0]
+            .send(Message::binary(vec![1, 2, 3]))
+            .await
+            .unwrap();
+        assert_eq!(
+            common::next(&mut clients[1]).await,
+            Message::Binary(vec![1, 2, 3])
+        );
+    });
+}
+
+#[test]
+fn plain_clients_are_refused() {
+    block_on(async {
+        let server = start().await;
+        let url = format!("ws://{}", server.local_addrs()[0]);
+        assert!(client::connect(&url).await.is_err());
+    });
+}
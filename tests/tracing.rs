@@ -0,0 +1,71 @@
+//! Log lines from a connection's task, as a `tracing` subscriber sees them.
+
+mod common;
+
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use bevy::utils::tracing::subscriber;
+use futures::prelude::*;
+use ws_async::protocol::ClientCommand;
+
+use common::block_on;
+
+/// Everything the subscriber writes, shared with the test.
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Captured {
+    fn lines(&self) -> Vec<String> {
+        let written = self.0.lock().unwrap();
+        String::from_utf8_lossy(&written)
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+impl io::Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn log_lines_carry_the_connection_id() {
+    let captured = Captured::default();
+    let writer = captured.clone();
+    let collector = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .finish();
+    subscriber::set_global_default(collector).unwrap();
+
+    block_on(async {
+        let server = common::start(common::config()).await;
+        let (id, mut sink, _source) = common::join(&server).await;
+        sink.send(common::command(ClientCommand::Join {
+            room: "arena".to_string(),
+        }))
+        .await
+        .unwrap();
+
+        let joined = format!("{} joined room arena", id);
+        let line = common::eventually(|| {
+            captured
+                .lines()
+                .into_iter()
+                .find(|line| line.contains(&joined))
+        })
+        .await;
+        let span = format!("connection{{id={} addr=", id);
+        assert!(line.contains(&span), "{}", line);
+    });
+}
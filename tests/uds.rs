@@ -0,0 +1,54 @@
+//! Clients connecting over a Unix domain socket.
+
+#![cfg(all(unix, feature = "uds"))]
+
+mod common;
+
+use std::path::PathBuf;
+
+use async_tungstenite::WebSocketStream;
+use futures::prelude::*;
+use ws_async::{protocol, runtime, Server, UDS_PEER_ADDR};
+
+use common::block_on;
+
+/// A socket path of this test run's own under the temp directory.
+fn socket_path() -> PathBuf {
+    let path = std::env::temp_dir().join(format!("ws_async-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+async fn connect(path: &std::path::Path) -> WebSocketStream<runtime::UnixStream> {
+    let stream = runtime::connect_unix(path).await.unwrap();
+    let (ws, _) = async_tungstenite::client_async("ws://localhost/", stream)
+        .await
+        .expect("Handshake failed");
+    ws
+}
+
+#[test]
+fn clients_talk_over_a_unix_socket() {
+    block_on(async {
+        let path = socket_path();
+        let server = Server::start_uds(&path, common::config())
+            .await
+            .expect("Couldn't start the server");
+
+        let mut sender = connect(&path).await;
+        let id = common::opened(&server).await;
+        let mut listener = connect(&path).await;
+        common::opened(&server).await;
+        assert_eq!(server.directory.get(&id).unwrap().addr, UDS_PEER_ADDR);
+
+        sender.send(common::say("over the socket")).await.unwrap();
+        assert_eq!(
+            common::next(&mut listener).await,
+            protocol::chat_message(1, &id.to_string(), "over the socket")
+        );
+
+        drop((sender, listener));
+        server.shutdown().await;
+        assert!(!path.exists(), "The socket file was left behind");
+    });
+}